@@ -1,5 +1,11 @@
-use criterion::{black_box, criterion_group, criterion_main, Criterion};
-use sosistab2::crypt::NonObfsAead;
+use std::time::{Duration, Instant};
+
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion};
+use smol::prelude::*;
+use sosistab2::{
+    crypt::NonObfsAead, AckEvent, CongestionControl, KeyedTimers, RelKind, Seqno, SeqnoRing,
+    Stream, StreamId, StreamMessage, StreamOptions, StreamState, TimerWheel,
+};
 
 fn nonobfs_seal(b: &mut criterion::Bencher, n: usize) {
     let buff = vec![0u8; n];
@@ -7,10 +13,144 @@ fn nonobfs_seal(b: &mut criterion::Bencher, n: usize) {
     b.iter(move || black_box(ngaead.encrypt(&buff)));
 }
 
+/// Slides a window of `window` segments along 10000 seqnos, as the inflight segments of a stream do: each is inserted at the back, looked up once, and removed at the front.
+fn seqno_ring(b: &mut criterion::Bencher, window: u64) {
+    b.iter(|| {
+        let mut ring = SeqnoRing::default();
+        for seqno in 0..10_000 {
+            ring.insert(Seqno(seqno), seqno);
+            black_box(ring.get(Seqno(seqno / 2)));
+            if let Some(oldest) = seqno.checked_sub(window) {
+                black_box(ring.remove(Seqno(oldest)));
+            }
+        }
+        ring
+    });
+}
+
+/// Sets 10000 retransmission timeouts 200ms out, 0.1ms apart, removing each once the earliest is found, as acks do.
+fn timer_wheel(b: &mut criterion::Bencher) {
+    let now = Instant::now();
+    b.iter(|| {
+        let mut wheel = TimerWheel::new();
+        for seqno in 0..10_000u64 {
+            let time = now + Duration::from_micros(100 * seqno + 200_000);
+            wheel.insert(time, Seqno(seqno));
+            if seqno >= 100 {
+                let (time, first) = wheel.first().unwrap();
+                black_box(wheel.remove(time, first));
+            }
+        }
+        wheel
+    });
+}
+
+/// Moves the timers of 100 keys forward 10000 times in turn, as streams are rescheduled to tick.
+fn keyed_timers(b: &mut criterion::Bencher) {
+    let now = Instant::now();
+    b.iter(|| {
+        let mut timers = KeyedTimers::new();
+        for i in 0..10_000u64 {
+            timers.set(
+                StreamId((i % 100) as u16),
+                now + Duration::from_micros(50 * i),
+            );
+            black_box(timers.first());
+        }
+        timers
+    });
+}
+
+/// A congestion controller with a fixed window and no pacing, so that the benchmarks measure bookkeeping rather than waiting.
+struct FixedWindow;
+
+impl CongestionControl for FixedWindow {
+    fn cwnd(&self) -> f64 {
+        1000.0
+    }
+
+    fn pacing_rate(&self, _min_rtt: std::time::Duration) -> f64 {
+        1e12
+    }
+
+    fn on_ack(&mut self, _ack: &AckEvent) {}
+
+    fn on_loss(&mut self, _now: Instant) {}
+
+    fn set_cwnd(&mut self, _cwnd: f64) {}
+}
+
+/// A sender and its handle, and a receiver and its handle.
+type StreamPair = (StreamState, Stream, StreamState, Stream);
+
+/// A sender with `len` bytes waiting to be sent, and a receiver for them.
+fn stream_pair(len: usize) -> StreamPair {
+    let (mut sender, handle) = StreamState::new_established(|| {}, StreamId(0), String::new());
+    sender.set_congestion_control(Box::new(FixedWindow));
+    handle.set_options(StreamOptions {
+        write_buffer: len,
+        ..Default::default()
+    });
+    smol::block_on(handle.clone().write_all(&vec![0u8; len])).unwrap();
+    // dropping the handle would close the stream
+    let (receiver, receiver_handle) =
+        StreamState::new_established(|| {}, StreamId(0), String::new());
+    (sender, handle, receiver, receiver_handle)
+}
+
+/// Transfers everything written to the sender, dropping the first transmission of every `drop_every`th packet, except near the end, where only a timeout would recover it.
+fn transfer((mut sender, handle, mut receiver, _receiver_handle): StreamPair, drop_every: u64) {
+    let total = handle.bytes_written();
+    let mut highest_sent = Seqno::ZERO;
+    let mut to_receiver = vec![];
+    let mut to_sender = vec![];
+    while handle.bytes_acked() < total {
+        sender.tick(|msg| to_receiver.push(msg));
+        for msg in to_receiver.drain(..) {
+            if let StreamMessage::Reliable {
+                kind: RelKind::Data,
+                seqno,
+                ..
+            } = &msg
+            {
+                let first_time = *seqno >= highest_sent;
+                highest_sent = highest_sent.max(seqno.next());
+                if first_time && seqno.0 % drop_every == drop_every - 1 && seqno.0 < 5000 {
+                    continue;
+                }
+            }
+            receiver.inject_incoming(msg);
+        }
+        receiver.tick(|msg| to_sender.push(msg));
+        for msg in to_sender.drain(..) {
+            sender.inject_incoming(msg);
+        }
+    }
+}
+
 fn criterion_benchmark(c: &mut Criterion) {
     let _ = env_logger::try_init();
 
     c.bench_function("nonobfs_seal_1024", |b| nonobfs_seal(b, 1024));
+    c.bench_function("seqno_ring_window_100", |b| seqno_ring(b, 100));
+    c.bench_function("seqno_ring_window_5000", |b| seqno_ring(b, 5000));
+    c.bench_function("timer_wheel_10k", timer_wheel);
+    c.bench_function("keyed_timers_10k", keyed_timers);
+    // roughly 6000 packets each
+    c.bench_function("stream_transfer_8m", |b| {
+        b.iter_batched(
+            || stream_pair(8_000_000),
+            |pair| transfer(pair, u64::MAX),
+            BatchSize::LargeInput,
+        )
+    });
+    c.bench_function("stream_transfer_8m_lossy", |b| {
+        b.iter_batched(
+            || stream_pair(8_000_000),
+            |pair| transfer(pair, 20),
+            BatchSize::LargeInput,
+        )
+    });
 }
 
 criterion_group!(benches, criterion_benchmark);
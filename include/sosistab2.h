@@ -0,0 +1,96 @@
+#ifndef SOSISTAB2_H
+#define SOSISTAB2_H
+
+/* Declares the C interface of src/ffi.rs; regenerate with cbindgen --config cbindgen.toml --output include/sosistab2.h rather than editing by hand. */
+
+#include <stddef.h>
+#include <stdint.h>
+
+// A multiplex, as handed to C.
+typedef struct SosistabMux SosistabMux;
+
+// A stream, as handed to C.
+typedef struct SosistabStream SosistabStream;
+
+// Called with a new multiplex, or with `NULL` and an error message.
+typedef void (*SosistabMuxCallback)(void *ctx, SosistabMux *mux, const char *err);
+
+// Called with a new stream, or with `NULL` and an error message.
+typedef void (*SosistabStreamCallback)(void *ctx, SosistabStream *stream, const char *err);
+
+// Called with the bytes read, no bytes at the end of the stream, or an error message.
+typedef void (*SosistabReadCallback)(void *ctx, const uint8_t *data, size_t len, const char *err);
+
+// Called with `NULL` once everything was written, or with an error message.
+typedef void (*SosistabWriteCallback)(void *ctx, const char *err);
+
+// Connects to the bridge described by a bridge line, see [BridgeDescriptor], and calls `cb` with the multiplex, or with an error if the line is invalid or no endpoint could be reached. The bridge's peer metadata is set to `metadata`.
+//
+// # Safety
+//
+// `line` and `metadata` must be NUL-terminated strings, which are copied before this returns.
+void sosistab_mux_connect(const char *line,
+                          const char *metadata,
+                          SosistabMuxCallback cb,
+                          void *ctx);
+
+// Frees a multiplex, closing it and every stream over it in the background, see [Multiplex::close]. Opens and accepts still waiting on it fail.
+//
+// # Safety
+//
+// `mux` must come from [sosistab_mux_connect], and not have been freed before.
+void sosistab_mux_free(SosistabMux *mux);
+
+// Opens a stream to the other side, labelled with `label`, and calls `cb` with it, or with an error, see [Multiplex::open_conn].
+//
+// # Safety
+//
+// `mux` must be a multiplex that was not freed, and `label` a NUL-terminated string, which is copied before this returns.
+void sosistab_mux_open(const SosistabMux *mux,
+                       const char *label,
+                       SosistabStreamCallback cb,
+                       void *ctx);
+
+// Waits for the other side to open a stream, and calls `cb` with it, or with an error once the multiplex closes, see [Multiplex::accept_conn].
+//
+// # Safety
+//
+// `mux` must be a multiplex that was not freed.
+void sosistab_mux_accept(const SosistabMux *mux, SosistabStreamCallback cb, void *ctx);
+
+// Reads up to `max_len` bytes from a stream, as soon as there are any, and calls `cb` with them. Only one read may be waiting on a stream at a time.
+//
+// # Safety
+//
+// `stream` must be a stream that was not freed.
+void sosistab_stream_read(const SosistabStream *stream,
+                          size_t max_len,
+                          SosistabReadCallback cb,
+                          void *ctx);
+
+// Writes `len` bytes to a stream, and calls `cb` once they are all buffered to be sent, which waits while the stream's write buffer is full. Only one write may be waiting on a stream at a time.
+//
+// # Safety
+//
+// `stream` must be a stream that was not freed, and `data` must point to `len` bytes, which are copied before this returns.
+void sosistab_stream_write(const SosistabStream *stream,
+                           const uint8_t *data,
+                           size_t len,
+                           SosistabWriteCallback cb,
+                           void *ctx);
+
+// Finishes writing to a stream, see [Stream::close_write]. It can still be read from.
+//
+// # Safety
+//
+// `stream` must be a stream that was not freed.
+void sosistab_stream_close_write(const SosistabStream *stream);
+
+// Frees a stream, closing it. Reads and writes still waiting on it carry on until they finish.
+//
+// # Safety
+//
+// `stream` must come from [sosistab_mux_open] or [sosistab_mux_accept], and not have been freed before.
+void sosistab_stream_free(SosistabStream *stream);
+
+#endif  /* SOSISTAB2_H */
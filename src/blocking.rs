@@ -0,0 +1,173 @@
+//! A blocking facade over multiplexes and streams, for callers that do not run an async runtime, such as Android VPN clients calling in from Kotlin threads.
+//!
+//! Every call runs on sosistab2's own thread pool and parks the calling thread until it is done, so any number of threads may block on the same multiplex or stream at once. Handles are `Send + Sync`, take `&self`, and are handed out in [Arc]s, with arguments and results in plain types such as [String] and [`Vec<u8>`], so that they map directly onto UniFFI objects. Nothing here may be called from async code, where it would block an executor thread.
+
+use std::sync::Arc;
+
+use smol::prelude::*;
+
+#[cfg(feature = "bridge")]
+use crate::BridgeDescriptor;
+use crate::{utilities::runtime, Multiplex, Stream};
+
+/// Runs a future on the thread pool, blocking until it resolves.
+fn block_on<T: Send + 'static>(fut: impl Future<Output = T> + Send + 'static) -> T {
+    smol::block_on(runtime::spawn(fut))
+}
+
+/// A [Multiplex] with blocking methods.
+pub struct BlockingMultiplex {
+    mux: Arc<Multiplex>,
+}
+
+impl BlockingMultiplex {
+    /// Connects to the bridge described by a bridge line, see [BridgeDescriptor::connect]. The bridge's peer metadata is set to `metadata`.
+    #[cfg(feature = "bridge")]
+    pub fn connect(line: String, metadata: String) -> std::io::Result<Arc<Self>> {
+        let descriptor: BridgeDescriptor = line
+            .parse()
+            .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidInput, err))?;
+        let mux = block_on(async move { descriptor.connect(&metadata).await })?;
+        Ok(Self::new(mux))
+    }
+
+    /// Wraps a multiplex that was set up some other way.
+    pub fn new(mux: Multiplex) -> Arc<Self> {
+        Arc::new(Self { mux: Arc::new(mux) })
+    }
+
+    /// The multiplex itself, for whatever this facade does not cover.
+    pub fn multiplex(&self) -> &Arc<Multiplex> {
+        &self.mux
+    }
+
+    /// Opens a stream to the other side, see [Multiplex::open_conn].
+    pub fn open(&self, label: String) -> std::io::Result<Arc<BlockingStream>> {
+        let mux = self.mux.clone();
+        let stream = block_on(async move { mux.open_conn(&label).await })?;
+        Ok(BlockingStream::new(stream))
+    }
+
+    /// Waits for the other side to open a stream, see [Multiplex::accept_conn]. Fails once the multiplex closes, which makes this a loop's natural end.
+    pub fn accept(&self) -> std::io::Result<Arc<BlockingStream>> {
+        let mux = self.mux.clone();
+        let stream = block_on(async move { mux.accept_conn().await })?;
+        Ok(BlockingStream::new(stream))
+    }
+
+    /// Closes the multiplex and every stream over it, see [Multiplex::close]. Calls blocked on it from other threads fail.
+    pub fn close(&self) -> std::io::Result<()> {
+        let mux = self.mux.clone();
+        block_on(async move { mux.close().await })
+    }
+}
+
+/// A [Stream] with blocking methods. The stream closes once this is dropped and the calls blocked on it have returned.
+pub struct BlockingStream {
+    stream: Stream,
+}
+
+impl BlockingStream {
+    /// Wraps a stream that was opened some other way.
+    pub fn new(stream: Stream) -> Arc<Self> {
+        Arc::new(Self { stream })
+    }
+
+    /// The stream itself, for whatever this facade does not cover.
+    pub fn stream(&self) -> &Stream {
+        &self.stream
+    }
+
+    pub fn label(&self) -> String {
+        self.stream.label().to_owned()
+    }
+
+    /// Reads up to `max_len` bytes, as soon as there are any. Returns no bytes at the end of the stream.
+    pub fn read(&self, max_len: u32) -> std::io::Result<Vec<u8>> {
+        let mut stream = self.stream.clone();
+        block_on(async move {
+            let mut buf = vec![0u8; max_len as usize];
+            let n = stream.read(&mut buf).await?;
+            buf.truncate(n);
+            Ok(buf)
+        })
+    }
+
+    /// Writes all of `data`, which waits while the stream's write buffer is full.
+    pub fn write(&self, data: Vec<u8>) -> std::io::Result<()> {
+        let mut stream = self.stream.clone();
+        block_on(async move { stream.write_all(&data).await })
+    }
+
+    /// Finishes writing, see [Stream::close_write]. The stream can still be read from.
+    pub fn close_write(&self) {
+        self.stream.close_write()
+    }
+}
+
+impl std::io::Read for &BlockingStream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let data = BlockingStream::read(self, buf.len().min(u32::MAX as usize) as u32)?;
+        buf[..data.len()].copy_from_slice(&data);
+        Ok(data.len())
+    }
+}
+
+impl std::io::Write for &BlockingStream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        BlockingStream::write(self, buf.to_vec())?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Read, time::Duration};
+
+    use super::BlockingMultiplex;
+    use crate::{
+        sim::{sim_pipe_pair, SimLink},
+        Multiplex, MuxSecret,
+    };
+
+    #[test]
+    fn echo_from_threads() {
+        let server_sk = MuxSecret::generate();
+        let server = BlockingMultiplex::new(Multiplex::new(server_sk.clone(), None));
+        let client = BlockingMultiplex::new(Multiplex::new(
+            MuxSecret::generate(),
+            Some(server_sk.to_public()),
+        ));
+        let (client_pipe, server_pipe) = sim_pipe_pair(SimLink {
+            delay: Duration::from_millis(10),
+            ..Default::default()
+        });
+        client.multiplex().add_pipe(client_pipe);
+        server.multiplex().add_pipe(server_pipe);
+
+        let echo = std::thread::spawn(move || {
+            let stream = server.accept().unwrap();
+            assert_eq!(stream.label(), "echo");
+            loop {
+                let data = stream.read(1000).unwrap();
+                if data.is_empty() {
+                    break;
+                }
+                stream.write(data).unwrap();
+            }
+            stream.close_write();
+        });
+        let stream = client.open("echo".into()).unwrap();
+        stream.write(b"hello world".to_vec()).unwrap();
+        stream.close_write();
+        let mut echoed = vec![];
+        (&*stream).read_to_end(&mut echoed).unwrap();
+        assert_eq!(echoed, b"hello world");
+        echo.join().unwrap();
+        client.close().unwrap();
+    }
+}
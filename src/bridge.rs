@@ -0,0 +1,378 @@
+use std::{fmt::Display, net::SocketAddr, str::FromStr};
+
+use thiserror::Error;
+
+use crate::{
+    ConnIdMode, ConnectError, Multiplex, MuxPublic, MuxSecret, Pipe, QuicPipe, TlsPipe, TlsVerify,
+    WsPipe,
+};
+
+/// The version of the bridge line format that [BridgeDescriptor] writes and reads.
+const BRIDGE_LINE_VERSION: &str = "1";
+
+/// Everything a client needs to connect to a bridge, in a form that can be handed out as a single line of text.
+///
+/// A bridge line looks like this, with the public key and bridge secret in hex:
+///
+/// ```text
+/// sosistab2/1 pk=<key> tls=203.0.113.5:443,example.com quic=203.0.113.5:443,example.com,<pin> ws=wss://example.com/path secret=<secret> conn-id
+/// ```
+///
+/// It starts with the format version, followed by space-separated fields in any order: the server's public key, any number of endpoints, and optionally the bridge secret and whether the server uses connection IDs. TLS and QUIC endpoints give the address, the SNI, and optionally the SHA-256 hash of the certificate to pin; without one, the certificate is checked against the usual web roots. Readers ignore fields they do not know, so that fields added later do not break older clients.
+///
+/// Lines are written with [ToString::to_string] and read with [str::parse].
+///
+/// ```
+/// use sosistab2::{BridgeDescriptor, BridgeEndpoint, MuxSecret};
+///
+/// let mut descriptor = BridgeDescriptor::new(MuxSecret::generate().to_public());
+/// descriptor.endpoints.push(BridgeEndpoint::Ws {
+///     url: "wss://example.com/path".into(),
+/// });
+/// descriptor.bridge_secret = Some(b"hunter2".to_vec());
+/// let line = descriptor.to_string();
+/// assert_eq!(line.parse::<BridgeDescriptor>().unwrap(), descriptor);
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BridgeDescriptor {
+    pub server_pk: MuxPublic,
+    pub endpoints: Vec<BridgeEndpoint>,
+    /// The secret given to [Multiplex::set_bridge_secret] on both sides, if the bridge requires cookies.
+    pub bridge_secret: Option<Vec<u8>>,
+    /// Whether the bridge issues connection IDs, which the client must then echo with [ConnIdMode::Echo].
+    pub conn_id: bool,
+}
+
+/// One way of reaching a bridge, as listed in a [BridgeDescriptor].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BridgeEndpoint {
+    /// A [crate::TlsListener].
+    Tls {
+        addr: SocketAddr,
+        sni: String,
+        pin: Option<[u8; 32]>,
+    },
+    /// A [crate::QuicListener].
+    Quic {
+        addr: SocketAddr,
+        server_name: String,
+        pin: Option<[u8; 32]>,
+    },
+    /// A [crate::WsListener], at a `ws://` or `wss://` URL.
+    Ws { url: String },
+}
+
+impl BridgeDescriptor {
+    /// A descriptor of the bridge with the given public key, without any endpoints yet.
+    pub fn new(server_pk: MuxPublic) -> Self {
+        Self {
+            server_pk,
+            endpoints: vec![],
+            bridge_secret: None,
+            conn_id: false,
+        }
+    }
+
+    /// Connects to the bridge: creates a multiplex that expects the bridge's public key, set up with its bridge secret and connection IDs, and adds a pipe to every endpoint that could be reached. The bridge's [Pipe::peer_metadata] is set to `metadata`. Fails only if no endpoint could be reached, with why the last of them could not.
+    pub async fn connect(&self, metadata: &str) -> Result<Multiplex, ConnectError> {
+        let mux = Multiplex::new(MuxSecret::generate(), Some(self.server_pk));
+        mux.set_bridge_secret(self.bridge_secret.as_deref());
+        if self.conn_id {
+            mux.set_conn_id(Some(ConnIdMode::Echo));
+        }
+        let pipes = futures_util::future::join_all(
+            self.endpoints
+                .iter()
+                .map(|endpoint| endpoint.connect(metadata)),
+        )
+        .await;
+        let mut last_err = None;
+        let mut connected = false;
+        for (endpoint, pipe) in self.endpoints.iter().zip(pipes) {
+            match pipe {
+                Ok(pipe) => {
+                    mux.add_pipe(pipe);
+                    connected = true;
+                }
+                Err(err) => {
+                    log::debug!("could not connect to bridge endpoint {endpoint}: {:?}", err);
+                    last_err = Some(err);
+                }
+            }
+        }
+        if !connected {
+            return Err(last_err.unwrap_or_else(|| {
+                ConnectError::InvalidInput("bridge descriptor lists no endpoints".into())
+            }));
+        }
+        Ok(mux)
+    }
+}
+
+impl BridgeEndpoint {
+    async fn connect(&self, metadata: &str) -> Result<Box<dyn Pipe>, ConnectError> {
+        let verify = |pin: &Option<[u8; 32]>| match pin {
+            Some(pin) => TlsVerify::Pinned(*pin),
+            None => TlsVerify::WebRoots,
+        };
+        Ok(match self {
+            BridgeEndpoint::Tls { addr, sni, pin } => {
+                Box::new(TlsPipe::connect(*addr, sni, verify(pin), metadata).await?)
+            }
+            BridgeEndpoint::Quic {
+                addr,
+                server_name,
+                pin,
+            } => Box::new(QuicPipe::connect(*addr, server_name, verify(pin), metadata).await?),
+            BridgeEndpoint::Ws { url } => Box::new(WsPipe::connect(url, metadata).await?),
+        })
+    }
+}
+
+impl Display for BridgeEndpoint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let (kind, addr, name, pin) = match self {
+            BridgeEndpoint::Tls { addr, sni, pin } => ("tls", addr, sni, pin),
+            BridgeEndpoint::Quic {
+                addr,
+                server_name,
+                pin,
+            } => ("quic", addr, server_name, pin),
+            BridgeEndpoint::Ws { url } => return write!(f, "ws={url}"),
+        };
+        write!(f, "{kind}={addr},{name}")?;
+        if let Some(pin) = pin {
+            write!(f, ",{}", hex::encode(pin))?;
+        }
+        Ok(())
+    }
+}
+
+impl Display for BridgeDescriptor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "sosistab2/{BRIDGE_LINE_VERSION} pk={}",
+            hex::encode(self.server_pk.as_bytes())
+        )?;
+        for endpoint in self.endpoints.iter() {
+            write!(f, " {endpoint}")?;
+        }
+        if let Some(secret) = &self.bridge_secret {
+            write!(f, " secret={}", hex::encode(secret))?;
+        }
+        if self.conn_id {
+            write!(f, " conn-id")?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for BridgeDescriptor {
+    type Err = BridgeLineError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut fields = s.split_whitespace();
+        let version = fields
+            .next()
+            .and_then(|header| header.strip_prefix("sosistab2/"))
+            .ok_or(BridgeLineError::NotABridgeLine)?;
+        if version != BRIDGE_LINE_VERSION {
+            return Err(BridgeLineError::UnsupportedVersion(version.to_owned()));
+        }
+        let mut server_pk = None;
+        let mut endpoints = vec![];
+        let mut bridge_secret = None;
+        let mut conn_id = false;
+        for field in fields {
+            let (key, value) = field.split_once('=').unwrap_or((field, ""));
+            let bad_value = || BridgeLineError::BadValue {
+                key: key.to_owned(),
+                value: value.to_owned(),
+            };
+            match key {
+                "pk" => {
+                    let pk: [u8; 32] = hex::decode(value)
+                        .ok()
+                        .and_then(|pk| pk.try_into().ok())
+                        .ok_or_else(bad_value)?;
+                    server_pk = Some(MuxPublic::from_bytes(pk));
+                }
+                "tls" | "quic" => {
+                    let mut parts = value.split(',');
+                    let addr: SocketAddr = parts
+                        .next()
+                        .and_then(|addr| addr.parse().ok())
+                        .ok_or_else(bad_value)?;
+                    let name = parts
+                        .next()
+                        .filter(|name| !name.is_empty())
+                        .ok_or_else(bad_value)?
+                        .to_owned();
+                    let pin = match parts.next() {
+                        Some(pin) => Some(
+                            hex::decode(pin)
+                                .ok()
+                                .and_then(|pin| pin.try_into().ok())
+                                .ok_or_else(bad_value)?,
+                        ),
+                        None => None,
+                    };
+                    if parts.next().is_some() {
+                        return Err(bad_value());
+                    }
+                    endpoints.push(if key == "tls" {
+                        BridgeEndpoint::Tls {
+                            addr,
+                            sni: name,
+                            pin,
+                        }
+                    } else {
+                        BridgeEndpoint::Quic {
+                            addr,
+                            server_name: name,
+                            pin,
+                        }
+                    });
+                }
+                "ws" => {
+                    if !(value.starts_with("ws://") || value.starts_with("wss://")) {
+                        return Err(bad_value());
+                    }
+                    endpoints.push(BridgeEndpoint::Ws {
+                        url: value.to_owned(),
+                    });
+                }
+                "secret" => {
+                    bridge_secret = Some(hex::decode(value).map_err(|_| bad_value())?);
+                }
+                "conn-id" => conn_id = true,
+                _ => log::debug!("ignoring unknown bridge line field {field:?}"),
+            }
+        }
+        Ok(Self {
+            server_pk: server_pk.ok_or(BridgeLineError::MissingPublicKey)?,
+            endpoints,
+            bridge_secret,
+            conn_id,
+        })
+    }
+}
+
+/// Returned when parsing a bridge line that is not valid.
+#[derive(Error, Debug)]
+pub enum BridgeLineError {
+    #[error("not a sosistab2 bridge line")]
+    NotABridgeLine,
+    #[error("unsupported bridge line version {0:?}")]
+    UnsupportedVersion(String),
+    #[error("bad {key} in bridge line: {value:?}")]
+    BadValue { key: String, value: String },
+    #[error("bridge line has no public key")]
+    MissingPublicKey,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bridge_line_round_trip() {
+        let mut descriptor = BridgeDescriptor::new(MuxSecret::generate().to_public());
+        descriptor.endpoints = vec![
+            BridgeEndpoint::Tls {
+                addr: "203.0.113.5:443".parse().unwrap(),
+                sni: "example.com".into(),
+                pin: None,
+            },
+            BridgeEndpoint::Quic {
+                addr: "[2001:db8::1]:443".parse().unwrap(),
+                server_name: "example.org".into(),
+                pin: Some([7; 32]),
+            },
+            BridgeEndpoint::Ws {
+                url: "wss://example.net/a,b".into(),
+            },
+        ];
+        descriptor.bridge_secret = Some(b"secret".to_vec());
+        descriptor.conn_id = true;
+        let line = descriptor.to_string();
+        assert_eq!(line.parse::<BridgeDescriptor>().unwrap(), descriptor);
+        // fields from later versions of the format are skipped
+        let extended = format!("{line} obfs=future");
+        assert_eq!(extended.parse::<BridgeDescriptor>().unwrap(), descriptor);
+    }
+
+    #[test]
+    fn bad_bridge_lines() {
+        let pk = hex::encode(MuxSecret::generate().to_public().as_bytes());
+        for line in [
+            "".to_owned(),
+            "obfs4 203.0.113.5:443".to_owned(),
+            format!("sosistab2/2 pk={pk}"),
+            "sosistab2/1 tls=203.0.113.5:443,example.com".to_owned(),
+            format!("sosistab2/1 pk={pk} tls=203.0.113.5,example.com"),
+            format!("sosistab2/1 pk={pk} quic=203.0.113.5:443,example.com,00"),
+            format!("sosistab2/1 pk={pk} ws=http://example.com"),
+            format!("sosistab2/1 pk=00{pk}"),
+        ] {
+            assert!(line.parse::<BridgeDescriptor>().is_err(), "{line}");
+        }
+    }
+
+    #[test]
+    fn connect_errors_say_what_failed() {
+        smol::block_on(async {
+            let mut descriptor = BridgeDescriptor::new(MuxSecret::generate().to_public());
+            assert!(matches!(
+                descriptor.connect("").await,
+                Err(ConnectError::InvalidInput(_))
+            ));
+
+            // a port nobody listens on
+            let closed = std::net::TcpListener::bind("127.0.0.1:0")
+                .unwrap()
+                .local_addr()
+                .unwrap();
+            descriptor.endpoints = vec![BridgeEndpoint::Tls {
+                addr: closed,
+                sni: "example.com".into(),
+                pin: None,
+            }];
+            assert!(matches!(
+                descriptor.connect("").await,
+                Err(ConnectError::Unreachable(_))
+            ));
+
+            // a server whose certificate is not the pinned one
+            let cert = rcgen::generate_simple_self_signed(vec!["example.com".into()]).unwrap();
+            let tls_config = rustls::ServerConfig::builder()
+                .with_safe_defaults()
+                .with_no_client_auth()
+                .with_single_cert(
+                    vec![rustls::Certificate(cert.serialize_der().unwrap())],
+                    rustls::PrivateKey(cert.serialize_private_key_der()),
+                )
+                .unwrap();
+            let listener = crate::TlsListener::bind(
+                "127.0.0.1:0".parse().unwrap(),
+                std::sync::Arc::new(tls_config),
+            )
+            .await
+            .unwrap();
+            descriptor.endpoints = vec![BridgeEndpoint::Tls {
+                addr: listener.local_addr(),
+                sni: "example.com".into(),
+                pin: Some([7; 32]),
+            }];
+            let Err(err) = descriptor.connect("").await else {
+                panic!("connected despite the wrong pin");
+            };
+            assert!(matches!(err, ConnectError::Authentication(_)), "{err:?}");
+            assert_eq!(
+                std::io::Error::from(err).kind(),
+                std::io::ErrorKind::PermissionDenied
+            );
+        })
+    }
+}
@@ -4,30 +4,77 @@ use bytes::Bytes;
 use once_cell::sync::Lazy;
 
 use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305};
-use std::sync::{
-    atomic::{AtomicU64, Ordering},
-    Arc,
+#[cfg(feature = "obfuscation")]
+use std::time::{SystemTime, UNIX_EPOCH};
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
 };
+#[cfg(feature = "obfuscation")]
+use subtle::ConstantTimeEq;
 use thiserror::Error;
 
 /// Non-obfuscated AEAD, with a straightforward counting nonce.
-#[derive(Debug, Clone)]
+#[derive(Clone)]
 pub struct NonObfsAead {
     key: Arc<LessSafeKey>,
+    secret: [u8; 32],
+    generation: u64,
     nonce: Arc<AtomicU64>,
+    sealed_bytes: Arc<AtomicU64>,
+}
+
+impl std::fmt::Debug for NonObfsAead {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // leaves out the secret, which the key is ratcheted from
+        f.debug_struct("NonObfsAead")
+            .field("key", &self.key)
+            .field("generation", &self.generation)
+            .field("nonce", &self.nonce)
+            .finish()
+    }
 }
 
 static SOSISTAB_NOCRYPT: Lazy<bool> = Lazy::new(|| std::env::var("SOSISTAB_NOCRYPT").is_ok());
 
 impl NonObfsAead {
     pub fn new(key: &[u8]) -> Self {
-        let ubk = UnboundKey::new(&CHACHA20_POLY1305, key).unwrap();
+        Self::with_nonce(*array_ref![key, 0, 32], 0, Arc::new(AtomicU64::new(0)))
+    }
+
+    fn with_nonce(secret: [u8; 32], generation: u64, nonce: Arc<AtomicU64>) -> Self {
+        let ubk = UnboundKey::new(&CHACHA20_POLY1305, &secret).unwrap();
         Self {
             key: Arc::new(LessSafeKey::new(ubk)),
-            nonce: Arc::new(AtomicU64::new(0)),
+            secret,
+            generation,
+            nonce,
+            sealed_bytes: Arc::new(AtomicU64::new(0)),
         }
     }
 
+    /// Returns the AEAD with the next key in the ratchet. The next key is derived one-way from this one, so once this one is forgotten, messages sealed with it cannot be opened even by someone who learns the keys that follow. Nonces continue where this one's left off, so they stay unique across keys.
+    pub fn ratchet(&self) -> Self {
+        Self::with_nonce(
+            blake3::derive_key("sosistab2 rekey", &self.secret),
+            self.generation + 1,
+            self.nonce.clone(),
+        )
+    }
+
+    /// How many times the key has been ratcheted since the handshake.
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// How many bytes of plaintext have been sealed with this key.
+    pub fn sealed_bytes(&self) -> u64 {
+        self.sealed_bytes.load(Ordering::Relaxed)
+    }
+
     /// Returns the overhead.
     pub fn overhead() -> usize {
         12 + CHACHA20_POLY1305.tag_len()
@@ -35,41 +82,61 @@ impl NonObfsAead {
 
     /// Encrypts a message, returning the ciphertext .
     pub fn encrypt(&self, msg: &[u8]) -> Bytes {
+        let (nonce, mut output) = self.seal(msg, 12);
+        output.extend_from_slice(&expand_nonce(nonce));
+        output.into()
+    }
+
+    /// Encrypts a message, returning the nonce separately from the ciphertext, so that the nonce can be sent in a more compact form.
+    pub fn encrypt_split(&self, msg: &[u8]) -> (u64, Bytes) {
+        let (nonce, output) = self.seal(msg, 0);
+        (nonce, output.into())
+    }
+
+    fn seal(&self, msg: &[u8], extra_capacity: usize) -> (u64, Vec<u8>) {
         let nonce = self.nonce.fetch_add(1, Ordering::SeqCst);
-        let mut bnonce = [0; 12];
-        bnonce[..8].copy_from_slice(&nonce.to_le_bytes());
+        self.sealed_bytes
+            .fetch_add(msg.len() as u64, Ordering::Relaxed);
 
         // make an output. it starts out containing the plaintext.
-        let mut output = Vec::with_capacity(msg.len() + 32);
+        let mut output = Vec::with_capacity(msg.len() + CHACHA20_POLY1305.tag_len() + extra_capacity);
         output.extend_from_slice(msg);
 
         // now we overwrite it
         if !*SOSISTAB_NOCRYPT {
             self.key
                 .seal_in_place_append_tag(
-                    Nonce::assume_unique_for_key(bnonce),
+                    Nonce::assume_unique_for_key(expand_nonce(nonce)),
                     Aad::empty(),
                     &mut output,
                 )
                 .unwrap();
         }
-        output.extend_from_slice(&bnonce);
-        output.into()
+        (nonce, output)
     }
 
     /// Decrypts a message.
     pub fn decrypt(&self, ctext: &[u8]) -> Result<(u64, Bytes), AeadError> {
-        if !*SOSISTAB_NOCRYPT && ctext.len() < 8 + CHACHA20_POLY1305.tag_len() {
+        if ctext.len() < 12 {
             return Err(AeadError::BadLength);
         }
         // nonce is last 12 bytes
         let (cytext, nonce) = ctext.split_at(ctext.len() - 12);
+        let nonce = u64::from_le_bytes(*array_ref![nonce, 0, 8]);
+        Ok((nonce, self.decrypt_split(nonce, cytext)?))
+    }
+
+    /// Decrypts a message whose nonce was sent separately.
+    pub fn decrypt_split(&self, nonce: u64, ctext: &[u8]) -> Result<Bytes, AeadError> {
+        if !*SOSISTAB_NOCRYPT && ctext.len() < CHACHA20_POLY1305.tag_len() {
+            return Err(AeadError::BadLength);
+        }
         // we now open
-        let mut ctext = cytext.to_vec();
+        let mut ctext = ctext.to_vec();
         if !*SOSISTAB_NOCRYPT {
             self.key
                 .open_in_place(
-                    Nonce::try_assume_unique_for_key(nonce).unwrap(),
+                    Nonce::assume_unique_for_key(expand_nonce(nonce)),
                     Aad::empty(),
                     &mut ctext,
                 )
@@ -78,8 +145,33 @@ impl NonObfsAead {
             let truncate_to = ctext.len() - CHACHA20_POLY1305.tag_len();
             ctext.truncate(truncate_to);
         }
-        let nonce = u64::from_le_bytes(*array_ref![nonce, 0, 8]);
-        Ok((nonce, ctext.into()))
+        Ok(ctext.into())
+    }
+}
+
+fn expand_nonce(nonce: u64) -> [u8; 12] {
+    let mut bnonce = [0; 12];
+    bnonce[..8].copy_from_slice(&nonce.to_le_bytes());
+    bnonce
+}
+
+/// When a multiplex replaces its session keys, set with [crate::Multiplex::set_rekey_policy].
+///
+/// Each side ratchets the key it sends with once it has sealed `max_bytes` with it, or used it for `max_age`, whichever comes first, and forgets the old key. Someone who later obtains the keys then cannot decrypt traffic sent before the last rekey, which keeps long-lived sessions forward-secret.
+#[derive(Clone, Copy, Debug)]
+pub struct RekeyPolicy {
+    /// How many bytes may be sealed with one key.
+    pub max_bytes: u64,
+    /// How long one key may be used.
+    pub max_age: Duration,
+}
+
+impl Default for RekeyPolicy {
+    fn default() -> Self {
+        Self {
+            max_bytes: 1 << 30,
+            max_age: Duration::from_secs(3600),
+        }
     }
 }
 
@@ -91,6 +183,73 @@ pub enum AeadError {
     DecryptionFailure,
 }
 
+#[cfg(feature = "obfuscation")]
+pub(crate) const COOKIE_LEN: usize = 16;
+#[cfg(feature = "obfuscation")]
+const COOKIE_EPOCH_SECS: u64 = 60;
+
+/// A cookie derived from a shared "bridge secret", appended to every datagram so that anybody who does not know the secret can be silently ignored.
+///
+/// Cookies are bound to the datagram contents and to the current minute, so captured datagrams cannot be replayed for more than a couple of minutes. Clocks must agree to within about a minute.
+#[derive(Clone)]
+#[cfg(feature = "obfuscation")]
+pub struct BridgeCookie {
+    key: [u8; 32],
+}
+
+#[cfg(feature = "obfuscation")]
+impl BridgeCookie {
+    /// Derives the cookie key from a bridge secret.
+    pub fn new(bridge_secret: &[u8]) -> Self {
+        Self {
+            key: blake3::derive_key("sosistab2 bridge cookie", bridge_secret),
+        }
+    }
+
+    /// Appends a cookie to a datagram.
+    pub fn seal(&self, pkt: &[u8]) -> Bytes {
+        let mut output = Vec::with_capacity(pkt.len() + COOKIE_LEN);
+        output.extend_from_slice(pkt);
+        output.extend_from_slice(&self.cookie(pkt, current_epoch()));
+        output.into()
+    }
+
+    /// Checks and strips the cookie of a datagram, returning None if it is invalid.
+    pub fn open(&self, pkt: &Bytes) -> Option<Bytes> {
+        let body_len = pkt.len().checked_sub(COOKIE_LEN)?;
+        let (body, cookie) = pkt.split_at(body_len);
+        let epoch = current_epoch();
+        // accept the neighboring epochs too, to tolerate clock skew and datagrams sent right at the boundary
+        let valid = [epoch.saturating_sub(1), epoch, epoch + 1]
+            .into_iter()
+            .fold(0u8, |valid, epoch| {
+                valid | self.cookie(body, epoch).ct_eq(cookie).unwrap_u8()
+            });
+        if valid == 1 {
+            Some(pkt.slice(..body_len))
+        } else {
+            None
+        }
+    }
+
+    fn cookie(&self, body: &[u8], epoch: u64) -> [u8; COOKIE_LEN] {
+        let mut hasher = blake3::Hasher::new_keyed(&self.key);
+        hasher.update(&epoch.to_le_bytes());
+        hasher.update(body);
+        let hash = hasher.finalize();
+        *array_ref![hash.as_bytes(), 0, COOKIE_LEN]
+    }
+}
+
+#[cfg(feature = "obfuscation")]
+fn current_epoch() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+        / COOKIE_EPOCH_SECS
+}
+
 /// A triple-ECDH handshake.
 pub fn triple_ecdh(
     my_long_sk: &x25519_dalek::StaticSecret,
@@ -115,3 +274,71 @@ pub fn triple_ecdh(
     };
     blake3::hash(&to_hash)
 }
+
+#[cfg(all(test, feature = "obfuscation"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cookies_need_the_secret() {
+        let cookie = BridgeCookie::new(b"hunter2");
+        let sealed = cookie.seal(b"hello");
+        assert_eq!(sealed.len(), 5 + COOKIE_LEN);
+        assert_eq!(&cookie.open(&sealed).unwrap()[..], b"hello");
+        assert!(BridgeCookie::new(b"hunter3").open(&sealed).is_none());
+
+        // the cookie covers the datagram, so it cannot be moved to another one
+        let mut tampered = sealed.to_vec();
+        tampered[0] ^= 1;
+        assert!(cookie.open(&tampered.into()).is_none());
+        assert!(cookie.open(&sealed.slice(..COOKIE_LEN - 1)).is_none());
+    }
+
+    #[test]
+    fn cookies_expire_after_the_neighbouring_epochs() {
+        let cookie = BridgeCookie::new(b"hunter2");
+        let epoch = current_epoch();
+        let sealed_in = |epoch: u64| {
+            let mut pkt = b"hello".to_vec();
+            pkt.extend_from_slice(&cookie.cookie(b"hello", epoch));
+            Bytes::from(pkt)
+        };
+        for skew in [epoch - 1, epoch, epoch + 1] {
+            assert!(cookie.open(&sealed_in(skew)).is_some());
+        }
+        // but not those two epochs away, so captured datagrams stop getting through after a couple of minutes
+        for skew in [epoch - 2, epoch + 2] {
+            assert!(cookie.open(&sealed_in(skew)).is_none());
+        }
+    }
+
+    #[test]
+    fn bridges_stay_silent_without_the_secret() {
+        use crate::{
+            sim::{sim_pipe_pair, SimLink},
+            utilities::runtime,
+            Multiplex, MuxSecret,
+        };
+
+        smol::block_on(async {
+            let server_sk = MuxSecret::generate();
+            let server = Multiplex::new(server_sk.clone(), None);
+            server.set_bridge_secret(Some(b"hunter2"));
+            let client = Multiplex::new(MuxSecret::generate(), Some(server_sk.to_public()));
+            let (client_pipe, server_pipe) = sim_pipe_pair(SimLink {
+                delay: Duration::from_millis(5),
+                ..Default::default()
+            });
+            client.add_pipe(client_pipe);
+            server.add_pipe(server_pipe);
+
+            // the client keeps saying hello, and hears nothing back
+            runtime::Timer::after(Duration::from_millis(2500)).await;
+            let stats = server.pipe_stats();
+            assert!(stats[0].recv_packets > 0);
+            assert_eq!(stats[0].sent_packets, 0);
+            #[cfg(feature = "metrics")]
+            assert_eq!(server.drop_stats().bad_cookie, stats[0].recv_packets);
+        })
+    }
+}
@@ -0,0 +1,345 @@
+//! A C interface, for apps that are not written in Rust, such as iOS and Android apps, to embed sosistab2 as a static or dynamic library. Only built with the `ffi` feature; `include/sosistab2.h` declares everything here for C, and is regenerated with `cbindgen --config cbindgen.toml --output include/sosistab2.h`.
+//!
+//! Everything that waits runs in the background, and reports back through a callback, called on a thread of sosistab2's with the `ctx` pointer it was given. Strings and buffers handed to callbacks are only valid during the call. Multiplexes and streams are opaque handles, which must be freed exactly once with [sosistab_mux_free] and [sosistab_stream_free], and may be used from any thread until then.
+
+use std::{
+    ffi::{c_char, c_void, CStr, CString},
+    sync::Arc,
+};
+
+use smol::prelude::*;
+
+use crate::{utilities::runtime, BridgeDescriptor, Multiplex, Stream};
+
+/// A multiplex, as handed to C.
+pub struct SosistabMux(Arc<Multiplex>);
+
+/// A stream, as handed to C.
+pub struct SosistabStream(Stream);
+
+/// Called with a new multiplex, or with `NULL` and an error message.
+pub type SosistabMuxCallback =
+    extern "C" fn(ctx: *mut c_void, mux: *mut SosistabMux, err: *const c_char);
+
+/// Called with a new stream, or with `NULL` and an error message.
+pub type SosistabStreamCallback =
+    extern "C" fn(ctx: *mut c_void, stream: *mut SosistabStream, err: *const c_char);
+
+/// Called with the bytes read, no bytes at the end of the stream, or an error message.
+pub type SosistabReadCallback =
+    extern "C" fn(ctx: *mut c_void, data: *const u8, len: usize, err: *const c_char);
+
+/// Called with `NULL` once everything was written, or with an error message.
+pub type SosistabWriteCallback = extern "C" fn(ctx: *mut c_void, err: *const c_char);
+
+/// The `ctx` pointer of a callback, which C hands over to whichever thread calls the callback.
+struct Ctx(*mut c_void);
+
+unsafe impl Send for Ctx {}
+
+/// Calls `f` with the error message as a C string.
+fn with_error<T>(err: impl ToString, f: impl FnOnce(*const c_char) -> T) -> T {
+    let err = CString::new(err.to_string().replace('\0', " ")).unwrap();
+    f(err.as_ptr())
+}
+
+/// Reads a C string that must not be `NULL`.
+unsafe fn c_str<'a>(s: *const c_char) -> Result<&'a str, &'static str> {
+    if s.is_null() {
+        return Err("NULL string");
+    }
+    CStr::from_ptr(s)
+        .to_str()
+        .map_err(|_| "string is not UTF-8")
+}
+
+/// Connects to the bridge described by a bridge line, see [BridgeDescriptor], and calls `cb` with the multiplex, or with an error if the line is invalid or no endpoint could be reached. The bridge's peer metadata is set to `metadata`.
+///
+/// # Safety
+///
+/// `line` and `metadata` must be NUL-terminated strings, which are copied before this returns.
+#[no_mangle]
+pub unsafe extern "C" fn sosistab_mux_connect(
+    line: *const c_char,
+    metadata: *const c_char,
+    cb: SosistabMuxCallback,
+    ctx: *mut c_void,
+) {
+    let args = c_str(line).and_then(|line| Ok((line.to_owned(), c_str(metadata)?.to_owned())));
+    let ctx = Ctx(ctx);
+    runtime::spawn(async move {
+        let ctx = ctx;
+        let result = async {
+            let (line, metadata) = args.map_err(|e| e.to_string())?;
+            let descriptor: BridgeDescriptor = line.parse().map_err(|e| format!("{e}"))?;
+            descriptor
+                .connect(&metadata)
+                .await
+                .map_err(|e| e.to_string())
+        }
+        .await;
+        match result {
+            Ok(mux) => cb(
+                ctx.0,
+                Box::into_raw(Box::new(SosistabMux(Arc::new(mux)))),
+                std::ptr::null(),
+            ),
+            Err(err) => with_error(err, |err| cb(ctx.0, std::ptr::null_mut(), err)),
+        }
+    })
+    .detach();
+}
+
+/// Frees a multiplex, closing it and every stream over it in the background, see [Multiplex::close]. Opens and accepts still waiting on it fail.
+///
+/// # Safety
+///
+/// `mux` must come from [sosistab_mux_connect], and not have been freed before.
+#[no_mangle]
+pub unsafe extern "C" fn sosistab_mux_free(mux: *mut SosistabMux) {
+    if mux.is_null() {
+        return;
+    }
+    // waits that are still running hold on to the multiplex, so it has to be closed rather than just dropped
+    let mux = Box::from_raw(mux).0;
+    runtime::spawn(async move {
+        let _ = mux.close().await;
+    })
+    .detach();
+}
+
+/// Opens a stream to the other side, labelled with `label`, and calls `cb` with it, or with an error, see [Multiplex::open_conn].
+///
+/// # Safety
+///
+/// `mux` must be a multiplex that was not freed, and `label` a NUL-terminated string, which is copied before this returns.
+#[no_mangle]
+pub unsafe extern "C" fn sosistab_mux_open(
+    mux: *const SosistabMux,
+    label: *const c_char,
+    cb: SosistabStreamCallback,
+    ctx: *mut c_void,
+) {
+    let mux = (*mux).0.clone();
+    let label = c_str(label).map(|label| label.to_owned());
+    stream_task(cb, Ctx(ctx), async move {
+        mux.open_conn(&label.map_err(|e| e.to_string())?)
+            .await
+            .map_err(|e| e.to_string())
+    });
+}
+
+/// Waits for the other side to open a stream, and calls `cb` with it, or with an error once the multiplex closes, see [Multiplex::accept_conn].
+///
+/// # Safety
+///
+/// `mux` must be a multiplex that was not freed.
+#[no_mangle]
+pub unsafe extern "C" fn sosistab_mux_accept(
+    mux: *const SosistabMux,
+    cb: SosistabStreamCallback,
+    ctx: *mut c_void,
+) {
+    let mux = (*mux).0.clone();
+    stream_task(cb, Ctx(ctx), async move {
+        mux.accept_conn().await.map_err(|e| e.to_string())
+    });
+}
+
+fn stream_task(
+    cb: SosistabStreamCallback,
+    ctx: Ctx,
+    stream: impl Future<Output = Result<Stream, String>> + Send + 'static,
+) {
+    runtime::spawn(async move {
+        let ctx = ctx;
+        match stream.await {
+            Ok(stream) => cb(
+                ctx.0,
+                Box::into_raw(Box::new(SosistabStream(stream))),
+                std::ptr::null(),
+            ),
+            Err(err) => with_error(err, |err| cb(ctx.0, std::ptr::null_mut(), err)),
+        }
+    })
+    .detach();
+}
+
+/// Reads up to `max_len` bytes from a stream, as soon as there are any, and calls `cb` with them. Only one read may be waiting on a stream at a time.
+///
+/// # Safety
+///
+/// `stream` must be a stream that was not freed.
+#[no_mangle]
+pub unsafe extern "C" fn sosistab_stream_read(
+    stream: *const SosistabStream,
+    max_len: usize,
+    cb: SosistabReadCallback,
+    ctx: *mut c_void,
+) {
+    let mut stream = (*stream).0.clone();
+    let ctx = Ctx(ctx);
+    runtime::spawn(async move {
+        let ctx = ctx;
+        let mut buf = vec![0u8; max_len];
+        match stream.read(&mut buf).await {
+            Ok(n) => cb(ctx.0, buf.as_ptr(), n, std::ptr::null()),
+            Err(err) => with_error(err, |err| cb(ctx.0, std::ptr::null(), 0, err)),
+        }
+    })
+    .detach();
+}
+
+/// Writes `len` bytes to a stream, and calls `cb` once they are all buffered to be sent, which waits while the stream's write buffer is full. Only one write may be waiting on a stream at a time.
+///
+/// # Safety
+///
+/// `stream` must be a stream that was not freed, and `data` must point to `len` bytes, which are copied before this returns.
+#[no_mangle]
+pub unsafe extern "C" fn sosistab_stream_write(
+    stream: *const SosistabStream,
+    data: *const u8,
+    len: usize,
+    cb: SosistabWriteCallback,
+    ctx: *mut c_void,
+) {
+    let mut stream = (*stream).0.clone();
+    let data = if len == 0 {
+        vec![]
+    } else {
+        std::slice::from_raw_parts(data, len).to_vec()
+    };
+    let ctx = Ctx(ctx);
+    runtime::spawn(async move {
+        let ctx = ctx;
+        match stream.write_all(&data).await {
+            Ok(()) => cb(ctx.0, std::ptr::null()),
+            Err(err) => with_error(err, |err| cb(ctx.0, err)),
+        }
+    })
+    .detach();
+}
+
+/// Finishes writing to a stream, see [Stream::close_write]. It can still be read from.
+///
+/// # Safety
+///
+/// `stream` must be a stream that was not freed.
+#[no_mangle]
+pub unsafe extern "C" fn sosistab_stream_close_write(stream: *const SosistabStream) {
+    (*stream).0.close_write();
+}
+
+/// Frees a stream, closing it. Reads and writes still waiting on it carry on until they finish.
+///
+/// # Safety
+///
+/// `stream` must come from [sosistab_mux_open] or [sosistab_mux_accept], and not have been freed before.
+#[no_mangle]
+pub unsafe extern "C" fn sosistab_stream_free(stream: *mut SosistabStream) {
+    if !stream.is_null() {
+        drop(Box::from_raw(stream));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        ffi::{c_char, c_void, CStr},
+        sync::Arc,
+    };
+
+    use smol::channel::Sender;
+
+    use super::*;
+    use crate::{
+        sim::{sim_pipe_pair, SimLink},
+        Multiplex, MuxSecret,
+    };
+
+    /// What a callback got, sent on to the test through the channel that `ctx` points to, since panicking in a callback would abort.
+    enum Called {
+        Stream(*mut SosistabStream),
+        Read(Vec<u8>),
+        Written,
+        Failed(String),
+    }
+
+    unsafe impl Send for Called {}
+
+    fn ctx(send: &Sender<Called>) -> *mut c_void {
+        send as *const Sender<Called> as *mut c_void
+    }
+
+    unsafe fn report(ctx: *mut c_void, err: *const c_char, called: impl FnOnce() -> Called) {
+        let send = &*(ctx as *const Sender<Called>);
+        let called = if err.is_null() {
+            called()
+        } else {
+            Called::Failed(CStr::from_ptr(err).to_string_lossy().into_owned())
+        };
+        let _ = send.try_send(called);
+    }
+
+    extern "C" fn on_stream(ctx: *mut c_void, stream: *mut SosistabStream, err: *const c_char) {
+        unsafe { report(ctx, err, || Called::Stream(stream)) }
+    }
+
+    extern "C" fn on_read(ctx: *mut c_void, data: *const u8, len: usize, err: *const c_char) {
+        unsafe {
+            report(ctx, err, || {
+                Called::Read(std::slice::from_raw_parts(data, len).to_vec())
+            })
+        }
+    }
+
+    extern "C" fn on_write(ctx: *mut c_void, err: *const c_char) {
+        unsafe { report(ctx, err, || Called::Written) }
+    }
+
+    #[test]
+    fn echo_through_c() {
+        smol::block_on(async {
+            let server_sk = MuxSecret::generate();
+            let server = SosistabMux(Arc::new(Multiplex::new(server_sk.clone(), None)));
+            let client = SosistabMux(Arc::new(Multiplex::new(
+                MuxSecret::generate(),
+                Some(server_sk.to_public()),
+            )));
+            let (client_pipe, server_pipe) = sim_pipe_pair(SimLink::default());
+            client.0.add_pipe(client_pipe);
+            server.0.add_pipe(server_pipe);
+
+            let (send, recv) = smol::channel::unbounded();
+            unsafe {
+                sosistab_mux_open(&client, c"hello".as_ptr(), on_stream, ctx(&send));
+                sosistab_mux_accept(&server, on_stream, ctx(&send));
+                let mut streams = vec![];
+                for _ in 0..2 {
+                    match recv.recv().await.unwrap() {
+                        Called::Stream(stream) => streams.push(stream),
+                        Called::Failed(err) => panic!("{err}"),
+                        _ => panic!("expected a stream"),
+                    }
+                }
+                // one is the client's end and the other the server's, in whichever order they came
+                let (a, b) = (streams[0], streams[1]);
+
+                sosistab_stream_write(a, b"ping".as_ptr(), 4, on_write, ctx(&send));
+                assert!(matches!(recv.recv().await.unwrap(), Called::Written));
+                sosistab_stream_read(b, 100, on_read, ctx(&send));
+                assert!(
+                    matches!(recv.recv().await.unwrap(), Called::Read(data) if data == b"ping")
+                );
+
+                sosistab_stream_close_write(a);
+                sosistab_stream_read(b, 100, on_read, ctx(&send));
+                assert!(
+                    matches!(recv.recv().await.unwrap(), Called::Read(data) if data.is_empty())
+                );
+                sosistab_stream_free(a);
+                sosistab_stream_free(b);
+            }
+        })
+    }
+}
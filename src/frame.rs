@@ -3,8 +3,119 @@ use serde::{Deserialize, Serialize};
 
 use crate::MuxPublic;
 
-/// A sequence number.
-pub type Seqno = u64;
+/// The sequence number of a reliable stream message.
+///
+/// Sequence numbers never wrap around: a stream could not use up 64 bits of them in centuries, so only a misbehaving peer gets near the end of the space, and arithmetic on them saturates there instead of panicking or wrapping. Distances between sequence numbers are plain `u64`s, so that a count of packets cannot be mistaken for a position in the stream.
+#[derive(
+    Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+#[serde(transparent)]
+pub struct Seqno(pub u64);
+
+impl Seqno {
+    pub const ZERO: Seqno = Seqno(0);
+    pub const MAX: Seqno = Seqno(u64::MAX);
+
+    /// The sequence number right after this one.
+    pub fn next(self) -> Seqno {
+        self + 1
+    }
+
+    /// How far this sequence number is past `earlier`, or `None` if it is before it.
+    pub fn checked_since(self, earlier: Seqno) -> Option<u64> {
+        self.0.checked_sub(earlier.0)
+    }
+
+    /// How far this sequence number is past `earlier`, or 0 if it is before it.
+    pub fn since(self, earlier: Seqno) -> u64 {
+        self.0.saturating_sub(earlier.0)
+    }
+
+    /// The sequence number `n` before this one, or `None` if there is none.
+    pub fn checked_back(self, n: u64) -> Option<Seqno> {
+        self.0.checked_sub(n).map(Seqno)
+    }
+}
+
+impl std::ops::Add<u64> for Seqno {
+    type Output = Seqno;
+
+    fn add(self, n: u64) -> Seqno {
+        Seqno(self.0.saturating_add(n))
+    }
+}
+
+impl std::ops::AddAssign<u64> for Seqno {
+    fn add_assign(&mut self, n: u64) {
+        *self = *self + n;
+    }
+}
+
+impl std::ops::Sub<u64> for Seqno {
+    type Output = Seqno;
+
+    fn sub(self, n: u64) -> Seqno {
+        Seqno(self.0.saturating_sub(n))
+    }
+}
+
+impl std::ops::SubAssign<u64> for Seqno {
+    fn sub_assign(&mut self, n: u64) {
+        *self = *self - n;
+    }
+}
+
+impl std::fmt::Display for Seqno {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+/// The ID of a stream within a multiplex.
+///
+/// The side that opened the multiplex opens streams with even IDs, and the other side with odd ones, so that both can pick IDs for new streams without agreeing on them first.
+#[derive(
+    Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+#[serde(transparent)]
+pub struct StreamId(pub u16);
+
+impl StreamId {
+    /// Turns a random number into the ID of a stream opened by the side that opened the multiplex, if `initiator`, or by the other side otherwise.
+    pub fn for_opener(random: u16, initiator: bool) -> StreamId {
+        StreamId((random & !1) | u16::from(!initiator))
+    }
+
+    /// Whether the stream was opened by the side that opened the multiplex.
+    pub fn opened_by_initiator(self) -> bool {
+        self.0 & 1 == 0
+    }
+}
+
+impl std::fmt::Display for StreamId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+/// The protocol version advertised in our hellos.
+///
+/// - 1: the original protocol
+/// - 2: understands [Frame::CompactMsg]
+/// - 3: understands [crate::RelKind::DataAckRanges]
+/// - 4: understands [crate::RelKind::DataAckCompact]
+/// - 5: understands [crate::RelKind::WindowUpdate]
+/// - 6: understands [Frame::Rekey]
+/// - 7: understands [crate::StreamMessage::Datagram]
+/// - 8: understands [crate::RelKind::Eof]
+/// - 9: understands [crate::StreamMessage::GoAway]
+/// - 10: understands [crate::StreamMessage::Ping]
+/// - 11: understands [crate::StreamMessage::Close]
+/// - 12: understands [crate::RelKind::DataFrag]
+/// - 13: understands [crate::RelKind::Pause] and [crate::RelKind::Resume]
+/// - 14: understands [crate::RelKind::ReadRate]
+/// - 15: resets messages for streams it does not know with a code saying so, which lets early data overtake the SYN
+pub const PROTOCOL_VERSION: u64 = 15;
 
 /// An outer message.
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -25,4 +136,10 @@ pub enum Frame {
 
     /// Non-handshake messages; inner = serialized EncryptedFrame
     EncryptedMsg { inner: Bytes },
+
+    /// Like EncryptedMsg, but with the AEAD nonce sent as a varint rather than as 12 trailing bytes, which saves 7 or more bytes per message. Only sent to peers whose hello advertises version 2 or later.
+    CompactMsg { nonce: u64, inner: Bytes },
+
+    /// Announces that the sender ratcheted its key: this and all later messages are sealed with the next key. Laid out like CompactMsg, with an empty message sealed with the new key, so that only the peer can make the other side switch. Only sent to peers whose hello advertises version 6 or later.
+    Rekey { nonce: u64, inner: Bytes },
 }
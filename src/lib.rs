@@ -1,10 +1,30 @@
+pub mod blocking;
+
+#[cfg(feature = "bridge")]
+mod bridge;
+#[cfg(feature = "bridge")]
+pub use bridge::{BridgeDescriptor, BridgeEndpoint, BridgeLineError};
+
 pub mod crypt;
+pub use crypt::RekeyPolicy;
+
+#[cfg(feature = "ffi")]
+pub mod ffi;
 
 mod frame;
+pub use frame::{Seqno, StreamId};
 mod multiplex;
 pub use multiplex::*;
 
 mod pipe;
+pub use async_trait::async_trait;
 pub use pipe::*;
 
+#[cfg(any(test, feature = "sim"))]
+pub mod sim;
+
 mod utilities;
+pub use utilities::reorderer::Reorderer;
+#[cfg(feature = "bench")]
+#[doc(hidden)]
+pub use utilities::timer_wheel::{KeyedTimers, TimerWheel};
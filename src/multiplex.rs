@@ -1,13 +1,52 @@
+mod acceptor;
+#[cfg(feature = "multipath")]
+mod bonding;
+#[cfg(feature = "obfuscation")]
+mod conn_id;
+mod constants;
+mod crypto_pool;
+mod datagram;
+mod drop_stats;
+mod fairness;
 mod multiplex_state;
+mod mux_stats;
+mod path_congestion;
+mod path_mtu;
+mod path_profile;
 mod pipe_pool;
+mod pipe_stats;
+mod power_profile;
+#[cfg(feature = "protolog")]
+mod protolog;
+mod relay;
+#[cfg(feature = "replay")]
+mod replay;
+mod reverse_tunnel;
+mod rng;
+mod routes;
+mod rpc;
+mod scheduler;
+mod setup_timings;
+#[cfg(all(test, feature = "soak"))]
+mod soak;
 mod stream;
+mod stream_pipe;
+mod tick_stats;
 mod trace;
+#[cfg(feature = "metrics")]
+mod trace_file;
+#[cfg(feature = "metrics")]
+mod windowed;
 use std::{
     any::Any,
-    sync::Arc,
-    time::{Duration, Instant},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
 };
 
+use bytes::Bytes;
 use concurrent_queue::ConcurrentQueue;
 
 use futures_intrusive::sync::ManualResetEvent;
@@ -20,17 +59,79 @@ use smol::{
 };
 use stdcode::StdcodeSerializeExt;
 
-use crate::Pipe;
+use crate::{
+    crypt::RekeyPolicy,
+    frame::Frame,
+    utilities::runtime::{self, TimeoutExt},
+    Pipe,
+};
 
 #[allow(deprecated)]
 pub use stream::MuxStream;
 
 pub use stream::stream_state::StreamState;
+pub use stream::{Datagrams, LossStats, StreamStats, LOSS_BUCKETS};
 pub use stream::RelKind;
+#[cfg(feature = "bench")]
+#[doc(hidden)]
+pub use stream::SeqnoRing;
 pub use stream::Stream;
 pub use stream::StreamMessage;
-
-use self::{multiplex_state::MultiplexState, pipe_pool::PipePool};
+pub use stream::{CloseReason, ProtocolViolation};
+pub use stream::{PacingMode, PacingPolicy, StreamOptions, UrelOverflow, UrelPolicy};
+pub use stream::{
+    AckEvent, Bbr, Bic, CongestionAlgorithm, CongestionControl, Cubic, Highspeed, Ledbat,
+};
+pub use acceptor::{AcceptFairness, StreamAcceptor};
+#[cfg(feature = "multipath")]
+pub use bonding::BondingStats;
+#[cfg(feature = "obfuscation")]
+pub use conn_id::{decode_conn_id, ConnIdMode, CONN_ID_LEN};
+pub use crypto_pool::set_crypto_workers;
+pub use datagram::DatagramPolicy;
+#[cfg(feature = "metrics")]
+pub use drop_stats::{DropRates, DropStats};
+pub use fairness::FairnessStats;
+pub use mux_stats::MultiplexStats;
+pub use path_profile::{PathProfile, UnknownPathProfile};
+pub use pipe_pool::{
+    AddressChange, AddressChangeHook, CaptureDirection, CaptureHook, CapturedPacket,
+    FailoverPolicy, MultipathPolicy, PipeSwitchPolicy,
+};
+pub use pipe_stats::PipeStats;
+pub use power_profile::PowerProfile;
+#[cfg(feature = "protolog")]
+pub use protolog::{
+    protolog_counts, set_protolog_rate_limit, ProtoEvent, ProtoEventCounts,
+};
+pub use relay::{copy_bidirectional, relay_multiplex, relay_streams, serve_relay};
+#[cfg(feature = "replay")]
+pub use replay::{replay_trace, ReplayReport};
+pub use reverse_tunnel::{expose_tcp, serve_reverse_tunnels};
+pub use rng::MuxRng;
+pub use rpc::{serve_rpc, RpcChannel};
+pub use setup_timings::SetupTimings;
+pub use stream_pipe::StreamPipe;
+#[cfg(feature = "metrics")]
+pub use tick_stats::{TickRates, TickStats};
+#[cfg(feature = "metrics")]
+pub use trace_file::{
+    read_trace, read_trace_events, set_trace_redactor, TraceEvent, TraceRecord, TraceRedactor,
+};
+#[cfg(feature = "metrics")]
+pub use windowed::WindowRates;
+
+use self::{
+    constants::{
+        CLOSE_TIMEOUT, DEFAULT_ACCEPT_BACKLOG, OPEN_PIPELINE, PING_ATTEMPTS, PING_TIMEOUT,
+    },
+    crypto_pool::crypto_pool,
+    datagram::DatagramQueues,
+    drop_stats::{DropCounters, DropReason},
+    multiplex_state::{MultiplexState, Opened, Sealer},
+    pipe_pool::PipePool,
+    pipe_stats::PipeCounters,
+};
 
 /// A multiplex session over a sosistab session, implementing both reliable "streams" and unreliable messages.
 pub struct Multiplex {
@@ -38,8 +139,12 @@ pub struct Multiplex {
     state: Arc<Mutex<MultiplexState>>,
     friends: ConcurrentQueue<Box<dyn Any + Send>>,
     recv_accepted: Receiver<Stream>,
+    accept_backlog: Arc<AtomicUsize>,
+    datagrams: Arc<DatagramQueues>,
+    #[cfg(feature = "metrics")]
+    drops: Arc<DropCounters>,
 
-    _task: smol::Task<()>,
+    task: Option<runtime::Task<()>>,
 }
 
 fn to_ioerror<T: Into<Box<dyn std::error::Error + Send + Sync>>>(val: T) -> std::io::Error {
@@ -50,29 +155,47 @@ impl Multiplex {
     /// Creates a new multiplexed Pipe. If `their_long_pk` is given, verify that the other side has the given public key.
     pub fn new(local_sk: MuxSecret, preshared_peer_pk: Option<MuxPublic>) -> Self {
         let stream_update = Arc::new(ManualResetEvent::new(false));
+        let drops = Arc::new(DropCounters::default());
         let state = Arc::new(Mutex::new(MultiplexState::new(
             stream_update.clone(),
             local_sk,
             preshared_peer_pk,
+            drops.clone(),
         )));
-        let pipe_pool = Arc::new(PipePool::new(10, preshared_peer_pk.is_none()));
+        let pipe_pool = Arc::new(PipePool::new(
+            10,
+            preshared_peer_pk.is_none(),
+            drops.clone(),
+        ));
+        state.lock().set_routes(pipe_pool.routes());
         let (send_accepted, recv_accepted) = smol::channel::unbounded();
-        let _task = smolscale::spawn(multiplex_loop(
+        let accept_backlog = Arc::new(AtomicUsize::new(DEFAULT_ACCEPT_BACKLOG));
+        let mux_loop = multiplex_loop(
             state.clone(),
             stream_update,
             pipe_pool.clone(),
             send_accepted,
-        ));
+            accept_backlog.clone(),
+            drops.clone(),
+        );
+        #[cfg(feature = "tracing")]
+        let mux_loop = tracing::Instrument::instrument(mux_loop, state.lock().span());
+        let task = runtime::spawn(mux_loop);
+        let datagrams = state.lock().datagrams();
         Self {
             pipe_pool,
             state,
             friends: ConcurrentQueue::unbounded(),
             recv_accepted,
-            _task,
+            accept_backlog,
+            datagrams,
+            #[cfg(feature = "metrics")]
+            drops,
+            task: Some(task),
         }
     }
 
-    /// Returns this side's public key.
+    /// Returns this side's public key. With [Multiplex::set_extra_identities], this is the key of the identity the peer turned out to expect, once its first message shows which one that is.
     pub fn local_pk(&self) -> MuxPublic {
         self.state.lock().local_lsk.to_public()
     }
@@ -82,14 +205,133 @@ impl Multiplex {
         self.state.lock().peer_lpk
     }
 
+    /// Whether an encrypted frame was sealed by the other side of this session and not seen before, i.e. opens under its keys and gets past its replay filter. Its nonce is spent, so that neither this frame nor a replay of it is taken again. Always false before the handshake.
+    pub(crate) fn claims(&self, frame: Frame) -> bool {
+        self.state.lock().claim(frame)
+    }
+
+    /// How many pipes are still open, leaving out those that failed to receive.
+    pub(crate) fn open_pipes(&self) -> usize {
+        self.pipe_pool.open_pipes()
+    }
+
     /// Adds an arbitrary "friend" that will be dropped together with the multiplex. This is useful for managing RAII resources like tasks, tables etc that should live exactly as long as a particular multiplex.
     pub fn add_drop_friend(&self, friend: impl Any + Send) {
         self.friends.push(Box::new(friend)).unwrap()
     }
 
-    /// Adds a Pipe to the Multiplex
+    /// Adds a Pipe to the Multiplex. This can be any [Pipe], including one defined outside this crate or a `Box<dyn Pipe>`, and pipes can be added at any time during the session; traffic moves to them as the pipe pool finds them better. The oldest pipe is dropped once there are too many.
     pub fn add_pipe(&self, pipe: impl Pipe) {
-        self.pipe_pool.add_pipe(pipe)
+        self.pipe_pool.add_pipe(pipe);
+        self.state.lock().on_pipe_added();
+    }
+
+    /// Sets a hook that is called with every raw datagram handed to or received from any pipe of this multiplex, together with a timestamp and the pipe it went through. Pass `None` to remove it.
+    ///
+    /// This shows what the wire actually carried (sizes and timing of ciphertext frames), as opposed to the decrypted view given by the trace files.
+    pub fn set_capture_hook(&self, hook: Option<CaptureHook>) {
+        self.pipe_pool.set_capture_hook(hook)
+    }
+
+    /// Sets a hook that is called whenever the other side of a pipe is heard from at an address other than before, with both addresses, or removes it with `None`. Over UDP-based pipes such as [crate::QuicPipe], this is how a server notices that a NAT rebound a client to another port or address. How often each pipe's address changed is in [Multiplex::pipe_stats].
+    pub fn set_address_change_hook(&self, hook: Option<AddressChangeHook>) {
+        self.pipe_pool.set_address_change_hook(hook)
+    }
+
+    /// Makes a pipe whose other side is heard from at another address carry nothing but probes until one is answered from the new address, so that nothing is sent to an address the peer does not actually hold. Off by default, in which case traffic follows the new address at once.
+    pub fn set_revalidate_on_address_change(&self, enabled: bool) {
+        self.pipe_pool.set_revalidate_on_address_change(enabled)
+    }
+
+    /// Leaves liveness probes unanswered on a pipe until it has carried a message that opened with the session's keys, so that the service does not answer probes from anyone who does not hold them. Off by default, since the other side may probe pipes it sends nothing else over, such as to pick the fastest one or to check that a spare is alive, and with this on such pipes look dead to it. With [Multiplex::set_bridge_secret], datagrams without the cookie go unanswered either way.
+    pub fn set_silent_until_authenticated(&self, enabled: bool) {
+        self.pipe_pool.set_silent_until_authenticated(enabled)
+    }
+
+    /// Sets the thresholds that decide when traffic moves to a pipe with a lower RTT. By default, traffic moves to whichever pipe answers probes fastest, which can oscillate between paths with similar RTTs; requiring a minimum improvement sustained for a while prevents that.
+    pub fn set_pipe_switch_policy(&self, policy: PipeSwitchPolicy) {
+        self.pipe_pool.set_switch_policy(policy)
+    }
+
+    /// Sets how outgoing traffic is spread over the pipes: all on the fastest one, which is the default, in turns weighted by RTT, or duplicated on the two fastest. See [MultipathPolicy].
+    pub fn set_multipath_policy(&self, policy: MultipathPolicy) {
+        self.pipe_pool.set_multipath_policy(policy)
+    }
+
+    /// Sets when the key that the multiplex seals what it sends with is replaced, or stops replacing it with `None`. Defaults to [RekeyPolicy::default]. Keys are only replaced when talking to peers that support it.
+    pub fn set_rekey_policy(&self, policy: Option<RekeyPolicy>) {
+        self.state.lock().set_rekey_policy(policy)
+    }
+
+    /// Detects pipes that stopped working and moves traffic off them, as the policy says, or stops doing so with `None`, the default. See [FailoverPolicy].
+    pub fn set_failover_policy(&self, policy: Option<FailoverPolicy>) {
+        self.pipe_pool.set_failover_policy(policy)
+    }
+
+    /// Returns how long the multiplex took to get going: to finish the handshake, and to connect its first stream, counted from when its first pipe was added. How long each pipe took to dial is in [Multiplex::pipe_stats].
+    pub fn setup_timings(&self) -> SetupTimings {
+        self.state.lock().setup_timings()
+    }
+
+    /// Returns statistics of every pipe, including the RTTs that [MultipathPolicy] goes by.
+    pub fn pipe_stats(&self) -> Vec<PipeStats> {
+        self.pipe_pool.pipe_stats()
+    }
+
+    /// Requires a cookie derived from the given shared "bridge secret" on every datagram, or stops requiring it with `None`. Datagrams without a valid cookie, such as those from scanners and active probers, are silently dropped without any response, so the service cannot be fingerprinted.
+    ///
+    /// Both sides must set the same secret, before adding any pipes.
+    #[cfg(feature = "obfuscation")]
+    pub fn set_bridge_secret(&self, secret: Option<&[u8]>) {
+        self.pipe_pool.set_bridge_secret(secret)
+    }
+
+    /// Starts every datagram with a connection ID that layer-4 load balancers can route on, or stops doing so with `None`. Backends use [ConnIdMode::Issue] and clients use [ConnIdMode::Echo]; both sides must enable connection IDs before adding any pipes.
+    ///
+    /// This only helps with pipes that put datagrams on the wire as-is, so that the connection ID is at a fixed place in the packets the load balancer sees.
+    #[cfg(feature = "obfuscation")]
+    pub fn set_conn_id(&self, mode: Option<ConnIdMode>) {
+        self.pipe_pool.set_conn_id(mode)
+    }
+
+    /// Makes the multiplex draw its randomness from the given source instead of the operating system's RNG: handshake keys, the IDs of streams it opens, and connection-ID nonces. With [MuxRng::seeded], runs over simulated links can be replayed exactly, and deployments that must use a certified DRBG can plug it in with [MuxRng::from_fn].
+    ///
+    /// Must be set before adding any pipes, since the handshake keys are replaced.
+    pub fn set_rng(&self, rng: MuxRng) {
+        self.pipe_pool.set_rng(rng.clone());
+        self.state.lock().set_rng(rng)
+    }
+
+    /// Also accepts handshakes from peers that expect one of these identities instead of the one the multiplex was created with, so that a server can rotate its key while clients still have the old public key configured. During the transition, the server is created with the new key and given the old one here, or the other way around before clients learn the new one.
+    ///
+    /// Hellos are answered once for each identity, so peers see all their public keys. The identity the peer expects becomes the only one once its first message arrives, and [Multiplex::local_pk] then shows which it was. Must be set before adding any pipes.
+    pub fn set_extra_identities(&self, secrets: Vec<MuxSecret>) {
+        self.state.lock().set_extra_identities(secrets)
+    }
+
+    /// Makes the multiplex silently ignore handshakes whose timestamp is further than the given age from the local clock, so that captured handshakes cannot be replayed later to check whether this is a sosistab2 service. `None`, the default, accepts handshakes regardless of the peer's clock.
+    pub fn set_max_hello_age(&self, max_age: Option<Duration>) {
+        self.state.lock().set_max_hello_age(max_age)
+    }
+
+    /// Returns counts of incoming datagrams that were dropped without any response: undecodable ones, replays, handshakes from the wrong key, and ones without a valid bridge-secret cookie.
+    ///
+    /// The multiplex never answers such datagrams, so they reveal nothing to an active prober.
+    #[cfg(feature = "metrics")]
+    pub fn drop_stats(&self) -> DropStats {
+        self.drops.snapshot()
+    }
+
+    /// Returns the rates at which incoming datagrams were dropped over the last second, ten seconds and minute, so that a monitor gets them without keeping snapshots of [Multiplex::drop_stats] around.
+    #[cfg(feature = "metrics")]
+    pub fn drop_rates(&self) -> WindowRates<DropRates> {
+        self.drops.rates()
+    }
+
+    /// Zeroes the counts of dropped datagrams, and the history their rates are taken from, returning the counts as they were just before. No drop counted concurrently is lost in between.
+    #[cfg(feature = "metrics")]
+    pub fn reset_drop_stats(&self) -> DropStats {
+        self.drops.reset()
     }
 
     /// Obtains the pipe last used by this multiplex for sending.
@@ -112,22 +354,421 @@ impl Multiplex {
         self.pipe_pool.retain(f)
     }
 
+    /// Sets the relative weight of a named bandwidth-sharing group; groups default to a weight of 1. Streams are put into groups with [Stream::set_group].
+    ///
+    /// A group's weight is split evenly among its streams, and a stream with a share of `w` grows and backs off its congestion window like `w` streams would. On a shared bottleneck a heavier group therefore gets a share of bandwidth about proportional to its weight, however many streams each group has. Streams outside any group behave as if they had weight 1.
+    ///
+    /// Fails for weights that are not positive, and for weights other than 1 while the congestion control algorithm cannot honour them, as with [CongestionAlgorithm::Bbr], or while streams share a congestion window.
+    pub fn set_group_weight(&self, group: &str, weight: f64) -> anyhow::Result<()> {
+        self.state.lock().set_group_weight(group, weight)
+    }
+
+    /// Returns histograms of loss burst lengths and inter-loss gaps, aggregated over all streams of this multiplex.
+    ///
+    /// These mix the losses of every pipe that carried stream data, which under [MultipathPolicy::WeightedRoundRobin], [MultipathPolicy::Bonded] or a switch of pipes is more than one. [crate::PipeStats::loss] has the losses of each pipe.
+    pub fn loss_stats(&self) -> LossStats {
+        self.state.lock().loss_stats()
+    }
+
+    /// Tells new streams what kind of path they run over, so that they start with RTT estimates, and thus retransmission timeouts and pacing rates, and congestion windows suited to it. `None` restores the conservative defaults.
+    ///
+    /// Only streams opened or accepted afterwards are affected, and both sides should agree on the profile.
+    pub fn set_path_profile(&self, profile: Option<PathProfile>) {
+        self.state.lock().set_path_profile(profile)
+    }
+
+    /// Switches between responsiveness and saving power, e.g. when a mobile app goes to the background. [PowerProfile::LowPower] batches acks for longer, coalesces the wakeups of idle timers, and stops probing pipes; [PowerProfile::Normal], the default, undoes that.
+    ///
+    /// Takes effect right away, for all streams.
+    pub fn set_power_profile(&self, profile: PowerProfile) {
+        self.pipe_pool.set_probing(profile.probes_pipes());
+        self.state.lock().set_power_profile(profile)
+    }
+
+    /// Tells new streams to assume the given RTT until they measure one, overriding the typical RTT of the path profile. Useful when the RTT is known in advance, e.g. from an earlier connection to the same peer.
+    pub fn set_initial_rtt(&self, rtt: Option<Duration>) {
+        self.state.lock().set_initial_rtt(rtt)
+    }
+
+    /// Sets which unreliable datagrams streams drop instead of delivering: those arriving before a stream is established, those arriving after or left unreceived when it closes, and those sent while too many are already waiting to be sent. Only streams opened or accepted afterwards are affected.
+    pub fn set_urel_policy(&self, policy: UrelPolicy) {
+        self.state.lock().set_urel_policy(policy)
+    }
+
+    /// Sets how long streams keep delivering what was written to them once every handle to them is dropped, and how long a dropped multiplex keeps its streams going, before they are closed. Defaults to 5 seconds; `None` closes them right away, losing whatever they had not delivered, as happened before.
+    ///
+    /// A dropped stream closes as soon as everything written to it is acked, and a dropped multiplex closes as [Multiplex::graceful_close] would, in the background, so that applications that just drop what they are done with still get it delivered and closed cleanly on the wire. Streams still held once the multiplex is dropped keep working while it lingers. Applies to existing streams too.
+    pub fn set_drop_linger(&self, linger: Option<Duration>) {
+        self.state.lock().set_drop_linger(linger)
+    }
+
+    /// Sets how streams are paced, and which are exempt from pacing, e.g. those carrying RPCs, whose first response byte would otherwise wait for the pacing rate. See [PacingPolicy]. Only streams opened or accepted afterwards are affected.
+    pub fn set_pacing_policy(&self, policy: PacingPolicy) {
+        self.state.lock().set_pacing_policy(policy)
+    }
+
+    /// Sets how large the datagrams of [Multiplex::send_datagram] may be, and how many may wait to be sent or received. See [DatagramPolicy].
+    pub fn set_datagram_policy(&self, policy: DatagramPolicy) {
+        self.datagrams.set_policy(policy)
+    }
+
+    /// Returns diagnostics about how evenly streams share bandwidth, including how many times a stream was found starved: having data to send, but not being scheduled for many ticks of the multiplex. Starved streams are also logged as warnings.
+    pub fn fairness_stats(&self) -> FairnessStats {
+        self.state.lock().fairness_stats()
+    }
+
+    /// Returns a snapshot of the congestion control and queue state of all open streams, totalled. See [Stream::stats] for the same per stream.
+    pub fn stats(&self) -> MultiplexStats {
+        self.state.lock().stats()
+    }
+
+    #[cfg(all(test, feature = "soak"))]
+    pub(crate) fn check_invariants(&self) -> anyhow::Result<()> {
+        self.state.lock().check_invariants()
+    }
+
+    /// Returns counts of how often the multiplex ticked its streams, and of how often streams asked for that. Streams ask on every read, write, and incoming packet, but however often a stream asks between two ticks, it is ticked once, and ticks are at least an ack delay apart (see [PowerProfile]), so a busy multiplex does not spin.
+    #[cfg(feature = "metrics")]
+    pub fn tick_stats(&self) -> TickStats {
+        self.state.lock().tick_stats()
+    }
+
+    /// Returns the rates at which the multiplex ticked, and streams asked it to, over the last second, ten seconds and minute.
+    #[cfg(feature = "metrics")]
+    pub fn tick_rates(&self) -> WindowRates<TickRates> {
+        self.state.lock().tick_rates()
+    }
+
+    /// Zeroes the counts of [Multiplex::tick_stats], and the history their rates are taken from, returning the counts as they were just before. No tick counted concurrently is lost in between.
+    #[cfg(feature = "metrics")]
+    pub fn reset_tick_stats(&self) -> TickStats {
+        self.state.lock().reset_tick_stats()
+    }
+
+    /// Sets how many ticks of the multiplex a stream with data to send may go without being scheduled before it counts as starved. Defaults to 10000.
+    pub fn set_starvation_threshold(&self, ticks: u64) {
+        self.state.lock().set_starvation_threshold(ticks)
+    }
+
+    /// Sets whether streams respond to spurious retransmissions, detected when a retransmitted packet is acked faster than any round trip could take. When enabled, a congestion window reduction caused only by spurious retransmissions is undone, and the retransmission timeout is raised to cover the RTT of the delayed original, as in the Eifel response algorithm (RFC 4015). This helps on paths with sudden delay spikes, such as cellular links. Off by default; spurious retransmissions are counted in [StreamStats] either way.
+    ///
+    /// Only streams opened or accepted afterwards are affected.
+    pub fn set_eifel_response(&self, enabled: bool) {
+        self.state.lock().set_eifel_response(enabled)
+    }
+
+    /// Sets whether streams check whether a retransmission timeout was spurious before acting on it, as in F-RTO (RFC 5682). When a packet times out, only that packet is retransmitted and new data keeps flowing; if acks then show that packets sent before the timeout were delivered after all, the timeout was caused by a delay spike, and the congestion window is restored instead of everything in flight being retransmitted. On by default.
+    ///
+    /// Only streams opened or accepted afterwards are affected.
+    pub fn set_frto(&self, enabled: bool) {
+        self.state.lock().set_frto(enabled)
+    }
+
+    /// Sets how many packets a stream may retransmit per round trip. When many packets are found lost at once, for example after a timeout with a large congestion window, retransmissions are spread over several round trips instead of being sent in one burst that would likely overflow the same queue that dropped them. Defaults to 64.
+    ///
+    /// Only streams opened or accepted afterwards are affected.
+    pub fn set_retransmit_burst(&self, burst: usize) {
+        self.state.lock().set_retransmit_burst(burst)
+    }
+
+    /// Sets how many packets sent after an unacked one must be acknowledged before a stream considers it lost and retransmits it right away, instead of waiting a whole retransmission timeout. Defaults to 5.
+    ///
+    /// Lower values recover from loss sooner; higher values avoid needless retransmissions on paths that reorder packets, such as multiple pipes of different latencies in use at once.
+    ///
+    /// Only streams opened or accepted afterwards are affected.
+    pub fn set_fast_retransmit_threshold(&self, threshold: u64) {
+        self.state.lock().set_fast_retransmit_threshold(threshold)
+    }
+
+    /// Sets whether streams tell the other side how fast the application reads them. Off by default.
+    ///
+    /// When the application reads more slowly than data arrives, unread data piles up in memory even though the network could carry more. With this on, once a stream has a sizeable backlog it reports the rate at which the application actually reads, and the other side paces its sending to that rate until the backlog clears. Peers that predate this feature are not sent the reports.
+    ///
+    /// Only streams opened or accepted afterwards are affected.
+    pub fn set_read_rate_feedback(&self, enabled: bool) {
+        self.state.lock().set_read_rate_feedback(enabled)
+    }
+
+    /// Sets how many bytes a stream may write before its data is striped over several pipes with [MultipathPolicy::WeightedRoundRobin] or [MultipathPolicy::Bonded]. Until then, its data stays on the pipe with the lowest RTT, as with [StreamOptions::single_path], so that short streams such as requests and small responses are not held back by data over a slower pipe, while bulk transfers still add up the bandwidth of every pipe. Defaults to 0, which stripes every stream.
+    ///
+    /// Only streams opened or accepted afterwards are affected.
+    pub fn set_single_path_below(&self, bytes: u64) {
+        self.state.lock().set_single_path_below(bytes)
+    }
+
+    /// Sets the congestion control algorithm of streams, which defaults to [CongestionAlgorithm::Bic]. Individual streams can override it with [Stream::set_congestion_control].
+    ///
+    /// Only streams opened or accepted afterwards are affected. Fails if the algorithm cannot honour the weights given to bandwidth-sharing groups.
+    pub fn set_congestion_control(&self, algo: CongestionAlgorithm) -> anyhow::Result<()> {
+        self.state.lock().set_congestion_control(algo)
+    }
+
+    /// Sets whether streams share a single congestion controller and pacer, like the streams of HTTP/2 over one TCP connection, rather than each having a congestion window of its own. Many parallel streams are then together no more aggressive on the bottleneck than one, instead of taking about as many times its share. A stream may use whatever the others leave of the shared window, but gets at least an equal share among the streams that have data to send. Off by default.
+    ///
+    /// Only streams opened or accepted afterwards share the controller, which uses the algorithm set with [Multiplex::set_congestion_control]; changing the algorithm starts a new shared controller for the streams after that. A stream given an algorithm of its own with [Stream::set_congestion_control] leaves the shared one, and the shared window cannot be weighted, so this fails while bandwidth-sharing groups have weights other than 1.
+    pub fn set_shared_congestion(&self, enabled: bool) -> anyhow::Result<()> {
+        self.state.lock().set_shared_congestion(enabled)
+    }
+
+    /// Returns the maximum segment size currently used for stream data.
+    pub fn mss(&self) -> usize {
+        self.pipe_pool.mss()
+    }
+
+    /// Changes the maximum segment size, e.g. after the usable payload size of the pipes shrinks. Active streams segment all data written from now on with the new size, and retransmit larger segments already in flight in pieces, unless the other side predates that.
+    ///
+    /// With [Multiplex::set_path_mtu_discovery] on, this only applies until the path MTU is found.
+    pub fn set_mss(&self, mss: usize) {
+        self.pipe_pool.set_mss(mss);
+        self.state.lock().set_mss(self.pipe_pool.mss());
+    }
+
+    /// Sets whether the multiplex searches for the largest datagrams that get across each of its pipes, and sizes stream segments to fill them. Off by default, when segments are sized for datagrams of 1200 bytes, which get across almost any path but waste some of the room on most.
+    ///
+    /// Each pipe is probed with datagrams padded to various sizes, which the other side answers, up to the 1472 bytes that fit in an Ethernet frame; the MSS then follows the smallest size found among the live pipes, see [PipeStats::path_mtu]. The size found is confirmed every 30 seconds, and should datagrams of that size stop getting through, as over a path that silently drops them, segments go back to the default size while the search starts over. Only this side's segments are affected, and the other side answers probes either way.
+    pub fn set_path_mtu_discovery(&self, enabled: bool) {
+        self.pipe_pool.set_path_mtu_discovery(enabled);
+    }
+
     /// Open a reliable conn to the other end.
+    ///
+    /// Either side can open conns, whichever side dialed: a server can open conns to a client as well, to push data or to tunnel connections back through it. The other side gets them from [Multiplex::accept_conn], with `additional` as their [Stream::label], so that it can tell what they are for.
     pub async fn open_conn(&self, additional: &str) -> std::io::Result<Stream> {
+        self.open_conn_with_options(additional, StreamOptions::default())
+            .await
+    }
+
+    /// Opens a reliable conn to the other end, like [Multiplex::open_conn], with the given buffer sizes and priority.
+    pub async fn open_conn_with_options(
+        &self,
+        additional: &str,
+        options: StreamOptions,
+    ) -> std::io::Result<Stream> {
         // create a pre-open stream, then wait until the ticking makes it open
         let stream = self
             .state
             .lock()
-            .start_open_stream(additional)
+            .start_open_stream(additional, false)
             .map_err(to_ioerror)?;
+        stream.set_options(options);
         stream.wait_connected().await?;
+        self.state.lock().on_stream_ready();
         Ok(stream)
     }
 
-    /// Accept a reliable conn from the other end.
+    /// Opens a reliable conn to the other end without waiting for it to accept the conn. Data written right away is sent immediately behind the opening handshake, up to an initial congestion window, so a short request can get its response one round trip sooner than with [Multiplex::open_conn].
+    ///
+    /// Use [Stream::wait_connected] to learn whether the other side accepted the conn. If it refused, data sent early is lost and the stream is closed. Peers that predate early data are only sent data once they accept the conn, as with [Multiplex::open_conn].
+    pub fn open_conn_early(&self, additional: &str) -> std::io::Result<Stream> {
+        self.state
+            .lock()
+            .start_open_stream(additional, true)
+            .map_err(to_ioerror)
+    }
+
+    /// Opens a pooled request/response channel over `width` persistent streams, all labelled with `label`. The other side should hand the streams it accepts with that label to [serve_rpc].
+    ///
+    /// This is much cheaper than opening a stream per request when making many short requests.
+    pub async fn rpc_channel(&self, label: &str, width: usize) -> std::io::Result<RpcChannel> {
+        let mut streams = vec![];
+        for _ in 0..width.max(1) {
+            streams.push(self.open_conn(label).await?);
+        }
+        Ok(RpcChannel::new(streams))
+    }
+
+    /// Sets how many incoming streams may wait for [Multiplex::accept_conn] at once. Once that many are waiting, further streams the other side opens are refused, failing its [Multiplex::open_conn] with [std::io::ErrorKind::ConnectionRefused]. Defaults to 1024.
+    pub fn set_accept_backlog(&self, backlog: usize) {
+        self.accept_backlog.store(backlog, Ordering::Relaxed);
+    }
+
+    /// Accept a reliable conn from the other end. Clients accept the conns their server opens the same way servers accept those of their clients.
+    ///
+    /// Conns that are never accepted still take up the backlog; see [Multiplex::set_accept_backlog].
     pub async fn accept_conn(&self) -> std::io::Result<Stream> {
-        self.recv_accepted.recv().await.map_err(to_ioerror)
+        let stream = self.recv_accepted.recv().await.map_err(|e| {
+            if self.state.lock().is_peer_closed() {
+                std::io::Error::new(
+                    std::io::ErrorKind::ConnectionAborted,
+                    "the other side closed the multiplex",
+                )
+            } else {
+                to_ioerror(e)
+            }
+        })?;
+        self.state.lock().on_stream_ready();
+        Ok(stream)
+    }
+
+    /// Accepts a conn from the other end if one is waiting, without waiting for one.
+    pub(crate) fn try_accept_conn(&self) -> Option<Stream> {
+        let stream = self.recv_accepted.try_recv().ok()?;
+        self.state.lock().on_stream_ready();
+        Some(stream)
+    }
+
+    /// Sends an unreliable datagram that belongs to no stream, for applications that forward packets of their own, like a VPN, and would only be held up by opening streams and by their ordering.
+    ///
+    /// Datagrams sent before the handshake is done wait for it, and the oldest are dropped once too many wait, as set with [Multiplex::set_datagram_policy]. Fails if the datagram is larger than the policy allows. Peers that predate datagrams never get them.
+    pub async fn send_datagram(&self, dgram: Bytes) -> std::io::Result<()> {
+        let max_size = self
+            .datagrams
+            .policy()
+            .max_size
+            .unwrap_or_else(|| self.pipe_pool.mss());
+        if dgram.len() > max_size {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "datagram of {} bytes is larger than {max_size}",
+                    dgram.len()
+                ),
+            ));
+        }
+        self.datagrams.push_send(dgram);
+        Ok(())
+    }
+
+    /// Receives an unreliable datagram sent with [Multiplex::send_datagram] by the other side.
+    pub async fn recv_datagram(&self) -> Bytes {
+        self.datagrams.recv().await
+    }
+
+    /// Measures the round-trip time to the other side through the multiplex, from sending a ping to getting its pong, which the other side sends as soon as the ping arrives.
+    ///
+    /// Pings sent before the handshake is done wait for it, and a ping that gets lost is sent again, a few times, before this fails with [std::io::ErrorKind::TimedOut]. Fails with [std::io::ErrorKind::Unsupported] if the other side predates pings.
+    pub async fn ping(&self) -> std::io::Result<Duration> {
+        let unsupported = |e| std::io::Error::new(std::io::ErrorKind::Unsupported, e);
+        for _ in 0..PING_ATTEMPTS {
+            let (id, pong) = self
+                .state
+                .lock()
+                .start_ping()
+                .map_err(|e| unsupported(e.to_string()))?;
+            let rtt = pong.recv().timeout(PING_TIMEOUT).await;
+            self.state.lock().cancel_ping(id);
+            match rtt {
+                Some(Ok(rtt)) => return Ok(rtt),
+                Some(Err(_)) => {
+                    return Err(unsupported(
+                        "the other side does not understand pings".into(),
+                    ))
+                }
+                None => log::debug!("ping {id} got no pong"),
+            }
+        }
+        Err(std::io::Error::new(
+            std::io::ErrorKind::TimedOut,
+            "no pong from the other side",
+        ))
+    }
+
+    /// Closes the multiplex without losing what was written to its streams, rather than cutting everything off the way dropping it does.
+    ///
+    /// From now on, [Multiplex::accept_conn] and [Multiplex::open_conn] fail, and streams the other side opens are refused, closing them with [CloseReason::PeerGoingAway]. The other side is told not to open any more, and those of its opens that are already on their way fail the same way; peers that predate this only learn it from the refusals. Existing streams keep working until everything written to them is acked, or until `timeout` runs out, which fails with [std::io::ErrorKind::TimedOut]. Either way, the other side is then told that the multiplex is closed, as with [Multiplex::close], and the pipes are closed, so that nothing more goes through the multiplex.
+    pub async fn graceful_close(&self, timeout: Duration) -> std::io::Result<()> {
+        self.recv_accepted.close();
+        let result = drain(&self.state, timeout).await;
+        close_streams(&self.state);
+        let _ = close_handshake(&self.state).await;
+        self.pipe_pool.close_all();
+        result
+    }
+
+    /// Closes the multiplex right away, and tells the other side, so that it releases everything within a round trip and its streams close with [CloseReason::MultiplexClosedByPeer], rather than lingering until they time out. Dropping the multiplex does the same in the background, after first delivering what was written to its streams, see [Multiplex::set_drop_linger].
+    ///
+    /// Streams on this side close with [CloseReason::MultiplexClosed], losing whatever they had not yet delivered; [Multiplex::graceful_close] waits for that first. From now on, [Multiplex::accept_conn] and [Multiplex::open_conn] fail, and once the other side acks, the pipes are closed; if it never does, they are closed anyway, and this fails with [std::io::ErrorKind::TimedOut]. Before the handshake is done, there is nobody to tell, and peers that predate this are not told either.
+    pub async fn close(&self) -> std::io::Result<()> {
+        self.recv_accepted.close();
+        close_streams(&self.state);
+        let result = close_handshake(&self.state).await;
+        self.pipe_pool.close_all();
+        result
+    }
+}
+
+impl Drop for Multiplex {
+    fn drop(&mut self) {
+        let Some(task) = self.task.take() else {
+            return;
+        };
+        let mut state = self.state.lock();
+        let linger = state.drop_linger();
+        if linger.is_none() {
+            state.close_streams(CloseReason::MultiplexClosed);
+            if !state.start_close() {
+                return;
+            }
+        }
+        drop(state);
+        // the rest is done in the background, with the multiplex's tasks running until the other side acks
+        let state = self.state.clone();
+        runtime::spawn(async move {
+            if let Some(linger) = linger {
+                let _ = drain(&state, linger).await;
+                close_streams(&state);
+            }
+            let _ = close_handshake(&state).await;
+            drop(task);
+        })
+        .detach();
+    }
+}
+
+/// Closes the streams on this side, starting the close at the same time, so that the other side is not sent resets for them before it learns that the multiplex is closed.
+fn close_streams(state: &Mutex<MultiplexState>) {
+    let mut state = state.lock();
+    state.close_streams(CloseReason::MultiplexClosed);
+    state.start_close();
+}
+
+/// Stops the multiplex from taking new streams, and waits until everything written to its streams is acked, as [Multiplex::graceful_close] does, failing with [std::io::ErrorKind::TimedOut] if that takes longer than `timeout`.
+async fn drain(state: &Mutex<MultiplexState>, timeout: Duration) -> std::io::Result<()> {
+    state.lock().start_going_away();
+    let drained = async {
+        loop {
+            let listener = {
+                let state = state.lock();
+                if state.is_drained() {
+                    return;
+                }
+                state.listen_drained()
+            };
+            listener.await;
+        }
+    };
+    match drained.timeout(timeout).await {
+        Some(()) => Ok(()),
+        None => Err(std::io::Error::new(
+            std::io::ErrorKind::TimedOut,
+            "streams did not drain before the multiplex closed",
+        )),
+    }
+}
+
+/// Tells the other side that the multiplex is closing for good, and waits until it acks, failing with [std::io::ErrorKind::TimedOut] if it does not within [CLOSE_TIMEOUT]. Succeeds right away if there is nobody to tell.
+async fn close_handshake(state: &Mutex<MultiplexState>) -> std::io::Result<()> {
+    if !state.lock().start_close() {
+        return Ok(());
     }
+    let acked = async {
+        loop {
+            let listener = {
+                let state = state.lock();
+                if state.is_close_acked() || state.is_peer_closed() {
+                    return;
+                }
+                state.listen_close_acked()
+            };
+            listener.await;
+        }
+    };
+    acked.timeout(CLOSE_TIMEOUT).await.ok_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::TimedOut,
+            "the other side did not ack the close",
+        )
+    })
 }
 
 /// The master loop that starts the other loops
@@ -136,46 +777,107 @@ async fn multiplex_loop(
     stream_update: Arc<ManualResetEvent>,
     pipe_pool: Arc<PipePool>,
     send_accepted: Sender<Stream>,
+    accept_backlog: Arc<AtomicUsize>,
+    drops: Arc<DropCounters>,
 ) {
     // we don't spawn more things to avoid unnecessary contention over mutexes etc
     let ticker = tick_loop(state.clone(), stream_update, pipe_pool.clone());
-    let incomer = incoming_loop(state, pipe_pool, send_accepted);
+    let incomer = incoming_loop(state, pipe_pool, send_accepted, accept_backlog, drops);
     if let Err(err) = ticker.race(incomer).await {
         log::error!("BUG: ticker or incomer died: {:?}", err)
     }
 }
 
+/// An incoming frame, or an encrypted message being opened by the crypto workers. Encrypted messages come with the counters of their pipe, which is marked authenticated if the message turns out to be.
+enum Incoming {
+    Frame(Frame, Option<Arc<PipeCounters>>),
+    Opening(
+        smol::future::Boxed<anyhow::Result<Opened>>,
+        Arc<PipeCounters>,
+    ),
+}
+
 /// Handle incoming messages
 async fn incoming_loop(
     state: Arc<Mutex<MultiplexState>>,
     pipe_pool: Arc<PipePool>,
     send_accepted: Sender<Stream>,
+    accept_backlog: Arc<AtomicUsize>,
+    drops: Arc<DropCounters>,
 ) -> anyhow::Result<()> {
-    let mut send_queue = vec![];
-    loop {
-        let incoming = pipe_pool.recv().await?;
-        log::trace!("incoming {} bytes", incoming.len());
-        if let Ok(incoming) = stdcode::deserialize(&incoming) {
+    // messages are handed to the crypto workers as they come in, and processed in the same order once opened
+    let (send_incoming, recv_incoming) = smol::channel::bounded(OPEN_PIPELINE);
+    let receive = async {
+        loop {
+            let (incoming, counters) = pipe_pool.recv().await?;
+            log::trace!("incoming {} bytes", incoming.len());
+            let Ok(frame) = stdcode::deserialize::<Frame>(&incoming) else {
+                drops.record(DropReason::Malformed);
+                continue;
+            };
+            let sealed = matches!(
+                frame,
+                Frame::EncryptedMsg { .. } | Frame::CompactMsg { .. } | Frame::Rekey { .. }
+            );
+            let opener = crypto_pool()
+                .filter(|_| sealed)
+                .and_then(|pool| Some((pool, state.lock().opener()?)));
+            let incoming = match opener {
+                Some((pool, opener)) => {
+                    Incoming::Opening(pool.spawn(move || opener.open(frame)).boxed(), counters)
+                }
+                None => Incoming::Frame(frame, sealed.then_some(counters)),
+            };
+            // only fails once processing stopped, which never happens while this runs
+            let _ = send_incoming.send(incoming).await;
+        }
+    };
+    let process = async {
+        let mut send_queue = vec![];
+        let mut accept = |stream| {
+            send_accepted.len() < accept_backlog.load(Ordering::Relaxed)
+                && send_accepted.try_send(stream).is_ok()
+        };
+        while let Ok(incoming) = recv_incoming.recv().await {
             // have the state process the message
-            state
-                .lock()
-                .recv_msg(
-                    incoming,
-                    |msg| send_queue.push(msg),
-                    |stream| {
-                        let _ = send_accepted.try_send(stream);
-                    },
-                )
-                .unwrap_or_else(|e| {
-                    log::trace!("could not process message: {:?}", e);
-                });
+            let (processed, sealed_by) = match incoming {
+                Incoming::Frame(frame, counters) => (
+                    state
+                        .lock()
+                        .recv_msg(frame, |msg| send_queue.push(msg), &mut accept),
+                    counters,
+                ),
+                Incoming::Opening(opening, counters) => {
+                    let opened = opening.await;
+                    (
+                        state
+                            .lock()
+                            .recv_opened(opened, |msg| send_queue.push(msg), &mut accept),
+                        Some(counters),
+                    )
+                }
+            };
+            match processed {
+                // it opened and was no replay, so whoever sent it over the pipe holds the session keys
+                Ok(()) => {
+                    if let Some(counters) = sealed_by {
+                        counters.set_authenticated();
+                    }
+                }
+                Err(e) => log::trace!("could not process message: {:?}", e),
+            }
+            if state.lock().is_peer_closed() {
+                send_accepted.close();
+            }
 
             // send all possible replies
             for msg in send_queue.drain(..) {
                 pipe_pool.send(msg.stdcode().into()).await;
             }
         }
-    }
+        Ok(())
+    };
+    receive.race(process).await
 }
 
 /// Handle "ticking" the streams
@@ -184,19 +886,44 @@ async fn tick_loop(
     stream_update: Arc<ManualResetEvent>,
     pipe_pool: Arc<PipePool>,
 ) -> anyhow::Result<()> {
-    let mut timer = smol::Timer::after(Duration::from_secs(0));
+    let mut timer = runtime::Timer::after(Duration::from_secs(0));
     let mut next_tick;
     let mut send_queue = vec![];
+    let mut to_seal = vec![];
+    let mut failovers = pipe_pool.failovers();
     loop {
-        next_tick = state.lock().tick(|msg| send_queue.push(msg));
+        let power_profile;
+        let sealer;
+        // listen before checking, so that a failover in between is not missed
+        let failover = pipe_pool.listen_failover();
+        next_tick = {
+            let mut state = state.lock();
+            state.set_mss(pipe_pool.mss());
+            if pipe_pool.failovers() != failovers {
+                failovers = pipe_pool.failovers();
+                state.on_failover();
+            }
+            power_profile = state.power_profile();
+            let next_tick = state.tick(|msg| send_queue.push(msg), |msg| to_seal.push(msg));
+            sealer = state.sealer();
+            power_profile.coalesce(next_tick, runtime::now())
+        };
 
         // transmit all the queue
         for msg in send_queue.drain(..) {
             pipe_pool.send(msg.stdcode().into()).await;
         }
+        if let Some(sealer) = sealer {
+            // which data segment each packet carries, for the pipe pool to count against the pipe it goes over
+            let segments: Vec<_> = to_seal.iter().map(StreamMessage::segment).collect();
+            let sealed = seal_all(sealer, std::mem::take(&mut to_seal)).await;
+            for (pkt, segment) in sealed.into_iter().zip(segments) {
+                pipe_pool.send_segment(pkt, segment).await;
+            }
+        }
         // sleep first to prevent too aggressively looping around
         // this is also the basis for the brand of delayed-ack handling we do
-        timer.set_at(Instant::now() + Duration::from_millis(10));
+        timer.set_at(runtime::now() + power_profile.ack_delay());
         (&mut timer).await;
         timer.set_at(next_tick);
         // horrifying hax
@@ -209,10 +936,28 @@ async fn tick_loop(
             (&mut timer).await;
             log::trace!("timer woken");
         })
+        .or(async {
+            failover.await;
+            log::trace!("failover woken");
+        })
         .await;
     }
 }
 
+/// Seals messages and encodes them into packets, on the crypto workers if there are any.
+async fn seal_all(sealer: Sealer, msgs: Vec<StreamMessage>) -> Vec<Bytes> {
+    match crypto_pool() {
+        Some(pool) if msgs.len() > 1 => {
+            pool.map(msgs, move |msg| sealer.seal(&msg).stdcode().into())
+                .await
+        }
+        _ => msgs
+            .iter()
+            .map(|msg| sealer.seal(msg).stdcode().into())
+            .collect(),
+    }
+}
+
 /// A server public key for the end-to-end multiplex.
 #[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
 #[serde(transparent)]
@@ -0,0 +1,163 @@
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+
+use event_listener::Event;
+use futures_util::future::select_all;
+use parking_lot::Mutex;
+use smol::future::FutureExt;
+
+use crate::{Multiplex, Stream};
+
+/// Whose streams a [StreamAcceptor] takes next, when several clients have streams waiting.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum AcceptFairness {
+    /// Clients take turns: each accept goes on from the client after the one that was served last, so that a client with thousands of streams waiting gets no more of them accepted than a client with one.
+    #[default]
+    RoundRobin,
+    /// Clients added earlier always go first, so that a client added later only gets its streams accepted while those before it have none waiting.
+    Priority,
+}
+
+/// Accepts the streams that the clients of many multiplexes open, as a server that multiplexes many clients would, taking them from each client in turn rather than in the order they came in, so that one client opening streams faster than they are accepted cannot hold up the others. See [AcceptFairness].
+///
+/// Streams wait in the backlogs of their own multiplexes until they are accepted, so each client is still refused streams beyond [Multiplex::set_accept_backlog] on its own.
+#[derive(Default)]
+pub struct StreamAcceptor {
+    muxes: Mutex<Vec<Arc<Multiplex>>>,
+    fairness: Mutex<AcceptFairness>,
+    // where round robin goes on from
+    next: AtomicUsize,
+    changed: Event,
+}
+
+impl StreamAcceptor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets whose streams are accepted first. Defaults to [AcceptFairness::RoundRobin].
+    pub fn set_fairness(&self, fairness: AcceptFairness) {
+        *self.fairness.lock() = fairness;
+    }
+
+    /// Accepts streams from another client's multiplex too, until it closes or is removed.
+    pub fn add(&self, mux: Arc<Multiplex>) {
+        self.muxes.lock().push(mux);
+        self.changed.notify(usize::MAX);
+    }
+
+    /// Stops accepting streams from a multiplex.
+    pub fn remove(&self, mux: &Arc<Multiplex>) {
+        self.muxes.lock().retain(|other| !Arc::ptr_eq(other, mux));
+        self.changed.notify(usize::MAX);
+    }
+
+    /// The multiplexes streams are accepted from.
+    pub fn multiplexes(&self) -> Vec<Arc<Multiplex>> {
+        self.muxes.lock().clone()
+    }
+
+    /// Waits for a stream from any of the multiplexes, and returns it along with the multiplex it came over. Multiplexes that close are removed.
+    pub async fn accept(&self) -> (Arc<Multiplex>, Stream) {
+        loop {
+            let changed = self.changed.listen();
+            let muxes = self.multiplexes();
+            if muxes.is_empty() {
+                changed.await;
+                continue;
+            }
+            let start = match *self.fairness.lock() {
+                AcceptFairness::RoundRobin => self.next.load(Ordering::Relaxed) % muxes.len(),
+                AcceptFairness::Priority => 0,
+            };
+            // the first in turn with a stream waiting gets it accepted
+            for i in 0..muxes.len() {
+                let idx = (start + i) % muxes.len();
+                if let Some(stream) = muxes[idx].try_accept_conn() {
+                    self.next.store(idx + 1, Ordering::Relaxed);
+                    return (muxes[idx].clone(), stream);
+                }
+            }
+            // otherwise, whichever gets one first
+            let accepted = async {
+                let (accepted, idx, _) =
+                    select_all(muxes.iter().map(|mux| Box::pin(mux.accept_conn()))).await;
+                Some((idx, accepted))
+            }
+            .or(async {
+                changed.await;
+                None
+            })
+            .await;
+            match accepted {
+                Some((idx, Ok(stream))) => {
+                    self.next.store(idx + 1, Ordering::Relaxed);
+                    return (muxes[idx].clone(), stream);
+                }
+                Some((idx, Err(_))) => self.remove(&muxes[idx]),
+                None => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use super::{AcceptFairness, StreamAcceptor};
+    use crate::{
+        sim::{sim_pipe_pair, SimLink},
+        utilities::runtime::{self, TimeoutExt},
+        Multiplex, MuxSecret,
+    };
+
+    /// Long enough for anything these tests wait for, however busy the machine, so that a hang fails the test rather than stalling the suite.
+    const TIMEOUT: Duration = Duration::from_secs(30);
+
+    #[test]
+    fn clients_take_turns() {
+        smol::block_on(async {
+            let server_sk = MuxSecret::generate();
+            let acceptor = StreamAcceptor::new();
+            let mut clients = vec![];
+            for _ in 0..2 {
+                let server = Arc::new(Multiplex::new(server_sk.clone(), None));
+                let client = Multiplex::new(MuxSecret::generate(), Some(server_sk.to_public()));
+                let (client_pipe, server_pipe) = sim_pipe_pair(SimLink::default());
+                client.add_pipe(client_pipe);
+                server.add_pipe(server_pipe);
+                acceptor.add(server);
+                clients.push(client);
+            }
+
+            for fairness in [AcceptFairness::RoundRobin, AcceptFairness::Priority] {
+                acceptor.set_fairness(fairness);
+                // the first client opens a flood of streams before the second opens one
+                let mut opened = vec![];
+                for i in 0..5 {
+                    let label = format!("a{i}");
+                    let stream = clients[0].open_conn(&label).timeout(TIMEOUT).await;
+                    opened.push(stream.expect("open timed out").unwrap());
+                }
+                let stream = clients[1].open_conn("b").timeout(TIMEOUT).await;
+                opened.push(stream.expect("open timed out").unwrap());
+                runtime::Timer::after(Duration::from_millis(100)).await;
+                let mut labels = vec![];
+                for _ in 0..opened.len() {
+                    let Some((_, stream)) = acceptor.accept().timeout(TIMEOUT).await else {
+                        panic!("{fairness:?}: accept timed out after {labels:?}");
+                    };
+                    labels.push(stream.label().to_owned());
+                }
+                let position = labels.iter().position(|label| label == "b").unwrap();
+                match fairness {
+                    AcceptFairness::RoundRobin => assert!(position <= 1, "{labels:?}"),
+                    AcceptFairness::Priority => assert_eq!(position, 5, "{labels:?}"),
+                }
+            }
+        })
+    }
+}
@@ -0,0 +1,379 @@
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+
+use crate::utilities::runtime;
+
+/// How often every pipe is asked how much it delivered, under [crate::MultipathPolicy::Bonded].
+pub(crate) const REPORT_INTERVAL: Duration = Duration::from_millis(100);
+/// A pipe whose reports stop coming for this long gets no more traffic, until they come again.
+const REPORT_TIMEOUT: Duration = Duration::from_secs(2);
+/// Reports this close together are too noisy to take a rate from, so the later one waits for the next.
+const MIN_SAMPLE_INTERVAL: Duration = Duration::from_millis(50);
+/// A pipe that delivers less than this fraction of what went into it is taken to be full.
+const MIN_DELIVERED_FRACTION: f64 = 0.97;
+/// A pipe whose RTT grows this much over its minimum is taken to be queueing, and so to be full.
+const QUEUEING_FACTOR: f64 = 1.25;
+const QUEUEING_SLACK: Duration = Duration::from_millis(5);
+/// The minimum RTT is forgotten after this long, in case the path changed.
+const MIN_RTT_WINDOW: Duration = Duration::from_secs(10);
+/// How much the capacity of a pipe that is not full is raised with each report, to find out how much more it carries.
+const GROWTH: f64 = 1.05;
+/// The capacity is only raised when the pipe delivered at least this fraction of it.
+const MIN_PROBING_UTILIZATION: f64 = 0.8;
+/// No pipe is credited with less than this many bytes per second, so that every pipe keeps getting enough traffic to find out whether it carries more.
+const MIN_CAPACITY: f64 = 10_000.0;
+/// A capacity raised past this is as good as unlimited.
+const MAX_CAPACITY: f64 = 1e10;
+/// The weight of each new sample in the moving average of the delivery rate.
+const ALPHA: f64 = 0.25;
+
+const REQUEST_MAGIC: &[u8] = b"!!bndq!!";
+const REPORT_MAGIC: &[u8] = b"!!bndr!!";
+
+/// What a pipe achieves under [crate::MultipathPolicy::Bonded], as part of [crate::PipeStats].
+#[derive(Clone, Copy, Debug)]
+pub struct BondingStats {
+    /// Bytes per second that the other side reports receiving over the pipe, averaged over the recent reports.
+    pub delivery_rate: f64,
+    /// Bytes per second that the pipe is estimated to carry, or `None` if it has not been found full, or not for a long time. Once every pipe is full, segments are striped over them in proportion to this.
+    pub capacity: Option<f64>,
+    /// The fraction of the estimated capacity that is being used, `delivery_rate / capacity`.
+    pub utilization: Option<f64>,
+    /// The fraction of what all pipes deliver that goes over this one.
+    pub share: f64,
+}
+
+/// Asks the other side how many bytes it has received over the pipe, telling it how many were sent and when, so that it can echo them back.
+pub(crate) fn request(sent_bytes: u64, sent_micros: u64) -> Bytes {
+    [
+        REQUEST_MAGIC,
+        &sent_bytes.to_le_bytes(),
+        &sent_micros.to_le_bytes(),
+    ]
+    .concat()
+    .into()
+}
+
+/// If the datagram is a request, returns the report answering it.
+pub(crate) fn answer_request(pkt: &[u8], recv_bytes: impl FnOnce() -> u64) -> Option<Bytes> {
+    let echoed = pkt.strip_prefix(REQUEST_MAGIC)?;
+    if echoed.len() != 16 {
+        return None;
+    }
+    Some(
+        [REPORT_MAGIC, echoed, &recv_bytes().to_le_bytes()]
+            .concat()
+            .into(),
+    )
+}
+
+/// A report of how many bytes the other side received over a pipe by the time a request arrived.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Report {
+    /// How many bytes had been sent over the pipe when the request was, and when that was.
+    pub sent_bytes: u64,
+    pub sent_micros: u64,
+    pub recv_bytes: u64,
+}
+
+/// If the datagram is a report, parses it.
+pub(crate) fn parse_report(pkt: &[u8]) -> Option<Report> {
+    let fields = pkt.strip_prefix(REPORT_MAGIC)?;
+    if fields.len() != 24 {
+        return None;
+    }
+    let field = |i: usize| u64::from_le_bytes(fields[i * 8..][..8].try_into().unwrap());
+    Some(Report {
+        sent_bytes: field(0),
+        sent_micros: field(1),
+        recv_bytes: field(2),
+    })
+}
+
+/// Estimates how much a pipe carries from the reports of the other side.
+///
+/// Requests travel behind the data sent before them, so a report counts exactly what arrived of what was sent before its request, and comparing two reports tells how much of what went into the pipe in between came out, and how fast. A full pipe, one that loses datagrams or whose RTT grows, carries what it delivers; one that delivers everything without queueing is not full, so its capacity is raised report by report until it is found full again.
+#[derive(Default)]
+pub(crate) struct BondEstimator {
+    first_request: Option<Instant>,
+    // the last report that a rate was taken from, and when it arrived
+    last: Option<(Report, Instant)>,
+    last_heard: Option<Instant>,
+    min_rtt: Option<(Duration, Instant)>,
+    delivery_rate: f64,
+    // None until the pipe is found full
+    capacity: Option<f64>,
+    // when everything scheduled over the pipe so far will have left this side, at the estimated capacity
+    busy_until: Option<Instant>,
+}
+
+impl BondEstimator {
+    pub fn on_request_sent(&mut self) {
+        self.first_request.get_or_insert_with(Instant::now);
+    }
+
+    /// Takes in a report that arrived at `now`, with the RTT of the request it answers.
+    pub fn on_report(&mut self, report: Report, rtt: Duration, now: Instant) {
+        self.last_heard = Some(now);
+        if self.min_rtt.is_none_or(|(min, since)| {
+            rtt <= min || now.saturating_duration_since(since) > MIN_RTT_WINDOW
+        }) {
+            self.min_rtt = Some((rtt, now));
+        }
+        let Some((last, arrived)) = self.last else {
+            self.last = Some((report, now));
+            return;
+        };
+        let elapsed = now.saturating_duration_since(arrived);
+        // a report to an earlier request that arrived late says nothing new
+        if elapsed < MIN_SAMPLE_INTERVAL || report.sent_bytes < last.sent_bytes {
+            return;
+        }
+        self.last = Some((report, now));
+        let delivered = report.recv_bytes.saturating_sub(last.recv_bytes) as f64;
+        let sent = report.sent_bytes - last.sent_bytes;
+        let sample = delivered / elapsed.as_secs_f64();
+        self.delivery_rate = if self.delivery_rate == 0.0 {
+            sample
+        } else {
+            ALPHA * sample + (1.0 - ALPHA) * self.delivery_rate
+        };
+        let lossy = sent > 0 && delivered < sent as f64 * MIN_DELIVERED_FRACTION;
+        let queueing = self
+            .min_rtt
+            .is_some_and(|(min, _)| rtt > min.mul_f64(QUEUEING_FACTOR) + QUEUEING_SLACK);
+        self.capacity = if lossy || queueing {
+            // the average lags behind when the pipe just started getting more than it carries
+            Some(sample.max(self.delivery_rate).max(MIN_CAPACITY))
+        } else {
+            // only a pipe that got about as much as it is thought to carry shows that it carries more
+            self.capacity
+                .map(|capacity| {
+                    if sample >= capacity * MIN_PROBING_UTILIZATION {
+                        capacity * GROWTH
+                    } else {
+                        capacity
+                    }
+                })
+                .filter(|capacity| *capacity < MAX_CAPACITY)
+        };
+    }
+
+    /// Whether the pipe should get traffic: it answers requests, or has not been asked for long.
+    pub fn is_reporting(&self) -> bool {
+        match self.last_heard {
+            Some(heard) => runtime::elapsed(heard) < REPORT_TIMEOUT,
+            None => self
+                .first_request
+                .is_none_or(|first| runtime::elapsed(first) < REPORT_TIMEOUT),
+        }
+    }
+
+    /// When a datagram of `len` bytes handed to the pipe at `now` would arrive at the other side, going by the estimated capacity, the datagrams scheduled before it, and half the minimum RTT.
+    ///
+    /// Sending each datagram over the pipe it would arrive first over puts everything on the pipe with the lowest delay until it is full, then spills over onto the others in proportion to their capacity, so that datagrams arrive in about the order they were sent, however much the delays of the pipes differ.
+    pub fn arrival(&self, len: usize, now: Instant) -> Instant {
+        let one_way = self.min_rtt.map_or(Duration::ZERO, |(min, _)| min / 2);
+        self.departure(len, now) + one_way
+    }
+
+    /// Schedules a datagram of `len` bytes over the pipe.
+    pub fn schedule(&mut self, len: usize, now: Instant) {
+        self.busy_until = Some(self.departure(len, now));
+    }
+
+    fn departure(&self, len: usize, now: Instant) -> Instant {
+        let start = self.busy_until.map_or(now, |busy| busy.max(now));
+        match self.capacity {
+            Some(capacity) => start + Duration::from_secs_f64(len as f64 / capacity),
+            None => start,
+        }
+    }
+
+    /// The statistics so far, except for the share, which depends on the other pipes.
+    pub fn stats(&self) -> Option<BondingStats> {
+        self.last_heard?;
+        Some(BondingStats {
+            delivery_rate: self.delivery_rate,
+            capacity: self.capacity,
+            utilization: self.capacity.map(|capacity| self.delivery_rate / capacity),
+            share: 0.0,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            atomic::{AtomicU64, Ordering},
+            Arc,
+        },
+        time::{Duration, Instant},
+    };
+
+    use smol::prelude::*;
+
+    use super::{BondEstimator, Report, REPORT_INTERVAL};
+    use crate::{
+        sim::{sim_pipe_pair, SimLink},
+        utilities::runtime,
+        MultipathPolicy, Multiplex, MuxSecret,
+    };
+
+    /// A link that carries `bandwidth` bytes per second after a delay of `rtt`, queueing up to a fifth of a second of what it is given beyond that and dropping the rest, in simulated time.
+    struct FluidLink {
+        bandwidth: f64,
+        rtt: Duration,
+        queued: f64,
+        sent: u64,
+        dropped: f64,
+        delivered: f64,
+    }
+
+    impl FluidLink {
+        fn new(bandwidth: f64, rtt: Duration) -> Self {
+            Self {
+                bandwidth,
+                rtt,
+                queued: 0.0,
+                sent: 0,
+                dropped: 0.0,
+                delivered: 0.0,
+            }
+        }
+
+        fn send(&mut self, len: usize) {
+            self.sent += len as u64;
+            let room = self.bandwidth * 0.2 - self.queued;
+            self.dropped += (len as f64 - room.max(0.0)).max(0.0);
+            self.queued = (self.queued + len as f64).min(self.bandwidth * 0.2);
+        }
+
+        fn drain(&mut self, elapsed: Duration) {
+            let carried = self.queued.min(self.bandwidth * elapsed.as_secs_f64());
+            self.queued -= carried;
+            self.delivered += carried;
+        }
+
+        /// A report answering a request sent now, which travels behind everything sent before it, and the RTT it comes back with.
+        fn report(&self) -> (Report, Duration) {
+            let report = Report {
+                sent_bytes: self.sent,
+                sent_micros: 0,
+                recv_bytes: (self.sent as f64 - self.dropped) as u64,
+            };
+            let queueing = Duration::from_secs_f64(self.queued / self.bandwidth);
+            (report, self.rtt + queueing)
+        }
+    }
+
+    #[test]
+    fn test_bonding_adds_up_links() {
+        // something like DSL and LTE: a fast link, and a slower one with more delay
+        let mut links = [
+            FluidLink::new(1_000_000.0, Duration::from_millis(30)),
+            FluidLink::new(500_000.0, Duration::from_millis(80)),
+        ];
+        let mut estimators = [BondEstimator::default(), BondEstimator::default()];
+        // a sender with more to send than both links carry together, in datagrams of 1000 bytes every half millisecond
+        let start = Instant::now();
+        let tick = Duration::from_micros(500);
+        let mut delivered_before = [0.0; 2];
+        for step in 1..=40_000u32 {
+            let now = start + tick * step;
+            let pick = (0..2)
+                .min_by_key(|&i| estimators[i].arrival(1000, now))
+                .unwrap();
+            estimators[pick].schedule(1000, now);
+            links[pick].send(1000);
+            for link in links.iter_mut() {
+                link.drain(tick);
+            }
+            if step.is_multiple_of(REPORT_INTERVAL.as_micros() as u32 / tick.as_micros() as u32) {
+                for (link, estimator) in links.iter().zip(estimators.iter_mut()) {
+                    let (report, rtt) = link.report();
+                    estimator.on_report(report, rtt, now + rtt);
+                }
+            }
+            // measured over the second half, once the estimates settled
+            if step == 20_000 {
+                delivered_before = [links[0].delivered, links[1].delivered];
+            }
+        }
+
+        let throughput: f64 = (0..2)
+            .map(|i| (links[i].delivered - delivered_before[i]) / 10.0)
+            .sum();
+        assert!(
+            throughput > 1_500_000.0 * 0.9,
+            "bonded throughput {throughput}"
+        );
+        // each pipe is found to carry about what its link does
+        for (link, estimator) in links.iter().zip(estimators.iter()) {
+            let capacity = estimator
+                .stats()
+                .unwrap()
+                .capacity
+                .expect("pipe never found full");
+            assert!(
+                (link.bandwidth * 0.8..link.bandwidth * 1.2).contains(&capacity),
+                "estimated {capacity} for a link of {}",
+                link.bandwidth
+            );
+        }
+    }
+
+    #[test]
+    fn bonded_pipes_report_what_they_deliver() {
+        let links = [
+            SimLink {
+                delay: Duration::from_millis(15),
+                bandwidth: Some(1_000_000.0),
+                ..Default::default()
+            },
+            SimLink {
+                delay: Duration::from_millis(40),
+                bandwidth: Some(500_000.0),
+                ..Default::default()
+            },
+        ];
+        smol::block_on(async {
+            let server_sk = MuxSecret::generate();
+            let server = Multiplex::new(server_sk.clone(), None);
+            let client = Multiplex::new(MuxSecret::generate(), Some(server_sk.to_public()));
+            client.set_multipath_policy(MultipathPolicy::Bonded);
+            for link in links {
+                let (client_pipe, server_pipe) = sim_pipe_pair(link);
+                client.add_pipe(client_pipe);
+                server.add_pipe(server_pipe);
+            }
+
+            let mut stream = client.open_conn("").await.unwrap();
+            let mut incoming = server.accept_conn().await.unwrap();
+            let received = Arc::new(AtomicU64::new(0));
+            let _send = runtime::spawn(async move {
+                let chunk = vec![0u8; 65536];
+                while stream.write_all(&chunk).await.is_ok() {}
+            });
+            let _recv = runtime::spawn({
+                let received = received.clone();
+                async move {
+                    let mut buf = vec![0u8; 65536];
+                    while let Ok(n) = incoming.read(&mut buf).await {
+                        received.fetch_add(n as u64, Ordering::Relaxed);
+                    }
+                }
+            });
+
+            // how much the links carry depends on how busy the machine running the test is, so that is left to the test above
+            runtime::Timer::after(Duration::from_secs(2)).await;
+            assert!(received.load(Ordering::Relaxed) > 0);
+            for stats in client.pipe_stats() {
+                let bonding = stats.bonding.expect("no bonding statistics");
+                assert!(bonding.delivery_rate > 0.0, "{bonding:?}");
+            }
+        })
+    }
+}
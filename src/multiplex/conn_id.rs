@@ -0,0 +1,79 @@
+use arrayref::array_ref;
+use bytes::Bytes;
+use parking_lot::Mutex;
+
+use super::rng::MuxRng;
+
+/// Length of the connection-ID prefix that starts every datagram when connection IDs are enabled.
+pub const CONN_ID_LEN: usize = 16;
+
+/// How a multiplex prefixes its datagrams with connection IDs, which let layer-4 load balancers send every datagram of a session to the same backend without decrypting anything. See [crate::Multiplex::set_conn_id].
+#[derive(Clone, Debug)]
+pub enum ConnIdMode {
+    /// Used by backends. Every datagram gets a fresh connection ID that encrypts `server_id` under `lb_key`, a key shared with the load balancer, which recovers the server ID with [decode_conn_id]. Without the key, connection IDs cannot be linked to each other.
+    Issue { lb_key: [u8; 32], server_id: u64 },
+    /// Used by clients. Every datagram carries the connection ID most recently received from the other side, or a random one before anything was received.
+    Echo,
+}
+
+/// Recovers the server ID from the connection-ID prefix of a datagram, given the load balancer key. Returns None if the datagram is too short.
+///
+/// Datagrams sent before the client heard from any backend carry random connection IDs, which decode to random server IDs; load balancers should fall back to some other routing, such as hashing the source address, for server IDs they do not know.
+pub fn decode_conn_id(lb_key: &[u8; 32], datagram: &[u8]) -> Option<u64> {
+    if datagram.len() < CONN_ID_LEN {
+        return None;
+    }
+    let nonce = array_ref![datagram, 0, 8];
+    let sealed = u64::from_le_bytes(*array_ref![datagram, 8, 8]);
+    Some(sealed ^ mask(lb_key, nonce))
+}
+
+fn mask(lb_key: &[u8; 32], nonce: &[u8; 8]) -> u64 {
+    let hash = blake3::keyed_hash(lb_key, nonce);
+    u64::from_le_bytes(*array_ref![hash.as_bytes(), 0, 8])
+}
+
+pub(crate) struct ConnIdState {
+    mode: ConnIdMode,
+    last_received: Mutex<[u8; CONN_ID_LEN]>,
+}
+
+impl ConnIdState {
+    pub fn new(mode: ConnIdMode, rng: &MuxRng) -> Self {
+        Self {
+            mode,
+            last_received: Mutex::new(rng.bytes()),
+        }
+    }
+
+    /// Prepends a connection ID to an outgoing datagram, drawing any nonce it needs from `rng`.
+    pub fn prefix(&self, pkt: &[u8], rng: &MuxRng) -> Bytes {
+        let conn_id = match &self.mode {
+            ConnIdMode::Issue { lb_key, server_id } => {
+                let nonce: [u8; 8] = rng.bytes();
+                let mut conn_id = [0u8; CONN_ID_LEN];
+                conn_id[..8].copy_from_slice(&nonce);
+                conn_id[8..].copy_from_slice(&(server_id ^ mask(lb_key, &nonce)).to_le_bytes());
+                conn_id
+            }
+            ConnIdMode::Echo => *self.last_received.lock(),
+        };
+        let mut output = Vec::with_capacity(CONN_ID_LEN + pkt.len());
+        output.extend_from_slice(&conn_id);
+        output.extend_from_slice(pkt);
+        output.into()
+    }
+
+    /// Strips the connection ID from an incoming datagram, returning None if it is too short to have one.
+    pub fn strip(&self, pkt: &Bytes) -> Option<Bytes> {
+        if pkt.len() < CONN_ID_LEN {
+            return None;
+        }
+        if matches!(self.mode, ConnIdMode::Echo) {
+            self.last_received
+                .lock()
+                .copy_from_slice(&pkt[..CONN_ID_LEN]);
+        }
+        Some(pkt.slice(CONN_ID_LEN..))
+    }
+}
@@ -0,0 +1,66 @@
+//! The constants of the protocol and the defaults of its tunables, in one place, so that what the configuration knobs start out at, and what they are measured in, can be looked up and changed without hunting through the modules that use them.
+//!
+//! Constants that only tune the inner workings of a single algorithm, such as the gains of a congestion controller or the search steps of path MTU discovery, stay next to it.
+
+use std::time::Duration;
+
+// ---- segment sizes ----
+
+/// The default maximum segment size, used until the pipe pool says otherwise.
+pub(crate) const MSS: usize = 1150;
+/// The datagram size every path is assumed to carry until a probe shows otherwise, that of the smallest IPv6 MTU after IP and UDP headers.
+pub(crate) const BASE_PLPMTU: usize = 1200;
+/// How much framing and encryption add to a segment of stream data, at most. The default MSS fills a datagram of [BASE_PLPMTU] with exactly this much to spare.
+pub(crate) const FRAME_OVERHEAD: usize = BASE_PLPMTU - MSS;
+
+// ---- stream buffers ----
+
+/// How many bytes may wait to be sent before writes wait, unless [crate::StreamOptions::write_buffer] says otherwise.
+pub(crate) const DEFAULT_WRITE_BUFFER: usize = 100_000;
+/// How many received bytes may wait to be read, unless [crate::StreamOptions::read_buffer] says otherwise.
+pub(crate) const DEFAULT_READ_BUFFER: usize = 10_000_000;
+/// How many unread bytes must pile up before a receiver reports the application's read rate to the sender.
+pub(crate) const READ_RATE_BACKLOG: usize = 256 * 1024;
+
+// ---- stream timers ----
+
+/// How often a SYN goes out again until it is answered.
+pub(crate) const SYN_RESEND_INTERVAL: Duration = Duration::from_secs(1);
+/// How often a sender that the other side paused sends a single segment anyway, so that a lost resume cannot stall the stream forever.
+pub(crate) const PERSIST_INTERVAL: Duration = Duration::from_secs(1);
+/// How long after resuming a receiver keeps repeating the resume alongside its acks.
+pub(crate) const RESUME_REPEAT: Duration = Duration::from_secs(5);
+/// How often a receiver repeats its read rate report while the backlog lasts.
+pub(crate) const READ_RATE_INTERVAL: Duration = Duration::from_millis(100);
+/// How long a sender honors a read rate report that is not repeated.
+pub(crate) const READ_RATE_TTL: Duration = Duration::from_secs(1);
+
+// ---- retransmission and scheduling ----
+
+/// The fewest packets a bulk stream may keep in flight while it yields to latency-sensitive streams.
+pub(crate) const MIN_QUEUE_CAP: usize = 4;
+/// How many packets may be retransmitted per round trip by default.
+pub(crate) const DEFAULT_RETRANSMIT_BURST: usize = 64;
+/// How many packets sent after an unacked one must be acked, by default, before it is retransmitted without waiting for a timeout.
+pub(crate) const DEFAULT_FAST_RETRANSMIT_THRESHOLD: u64 = 5;
+/// How many ticks of a multiplex a stream with data to send may go without being ticked itself before it counts as starved, unless configured otherwise.
+pub(crate) const DEFAULT_STARVATION_TICKS: u64 = 10_000;
+
+// ---- multiplex ----
+
+/// How long the previous receive-side key is kept after the peer rekeys, for messages it sealed before and that are still on their way.
+pub(crate) const REKEY_GRACE: Duration = Duration::from_secs(10);
+/// Default number of incoming streams that may wait to be accepted.
+pub(crate) const DEFAULT_ACCEPT_BACKLOG: usize = 1024;
+/// How long [crate::Multiplex::ping] waits for a pong before giving up on the ping as lost.
+pub(crate) const PING_TIMEOUT: Duration = Duration::from_secs(3);
+/// How many pings [crate::Multiplex::ping] sends, one after another lost, before it fails.
+pub(crate) const PING_ATTEMPTS: usize = 3;
+/// How often a multiplex that is closing tells the other side so again, until it acks.
+pub(crate) const CLOSE_RESEND_INTERVAL: Duration = Duration::from_millis(500);
+/// How long a multiplex that is closing waits for the other side to ack, before it releases everything anyway.
+pub(crate) const CLOSE_TIMEOUT: Duration = Duration::from_secs(3);
+/// How long dropped streams and multiplexes keep delivering what was written to them by default, see [crate::Multiplex::set_drop_linger].
+pub(crate) const DEFAULT_DROP_LINGER: Duration = Duration::from_secs(5);
+/// How many incoming messages may be waiting to be opened by the crypto workers at once.
+pub(crate) const OPEN_PIPELINE: usize = 256;
@@ -0,0 +1,89 @@
+use std::sync::Arc;
+
+use once_cell::sync::Lazy;
+use parking_lot::RwLock;
+use smol::channel::{Receiver, Sender};
+
+static POOL: Lazy<RwLock<Option<Arc<CryptoPool>>>> = Lazy::new(Default::default);
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// Sets how many dedicated threads encrypt and decrypt packets for all multiplexes in the process. By default, or with 0, each multiplex does this on its own task, so a single busy multiplex, such as one carrying the traffic of a relay, is limited by the speed of one core; with workers, its packets are sealed and opened on several cores while it goes on with other work.
+///
+/// Workers only pay off when there is much more traffic than one core can encrypt, since handing packets to them costs a little for each. Changing the count replaces the workers, after those already running finish what was handed to them.
+pub fn set_crypto_workers(count: usize) {
+    *POOL.write() = (count > 0).then(|| Arc::new(CryptoPool::new(count)));
+}
+
+/// The crypto workers, if any were set up with [set_crypto_workers].
+pub(crate) fn crypto_pool() -> Option<Arc<CryptoPool>> {
+    POOL.read().clone()
+}
+
+/// Threads that run jobs handed to them, in any order. They exit once the pool is dropped and no jobs are left.
+pub(crate) struct CryptoPool {
+    jobs: Sender<Job>,
+    workers: usize,
+}
+
+impl CryptoPool {
+    fn new(workers: usize) -> Self {
+        let (jobs, recv_jobs) = smol::channel::unbounded::<Job>();
+        for _ in 0..workers {
+            let recv_jobs = recv_jobs.clone();
+            std::thread::Builder::new()
+                .name("sosistab2-crypto".into())
+                .spawn(move || {
+                    while let Ok(job) = recv_jobs.recv_blocking() {
+                        job()
+                    }
+                })
+                .expect("could not spawn crypto worker");
+        }
+        Self { jobs, workers }
+    }
+
+    /// Runs a job on some worker, returning what it computes.
+    pub fn spawn<R: Send + 'static>(
+        &self,
+        job: impl FnOnce() -> R + Send + 'static,
+    ) -> impl std::future::Future<Output = R> + Send + 'static {
+        let (send_result, recv_result) = smol::channel::bounded(1);
+        let _ = self.jobs.try_send(Box::new(move || {
+            let _ = send_result.try_send(job());
+        }));
+        wait_result(recv_result)
+    }
+
+    /// Applies `f` to every item, split evenly among the workers, and returns the results in the same order as the items.
+    pub async fn map<T: Send + 'static, R: Send + 'static>(
+        &self,
+        mut items: Vec<T>,
+        f: impl Fn(T) -> R + Send + Sync + 'static,
+    ) -> Vec<R> {
+        let f = Arc::new(f);
+        let chunk_size = items.len().div_ceil(self.workers).max(1);
+        let mut chunks = vec![];
+        while !items.is_empty() {
+            let rest = items.split_off(chunk_size.min(items.len()));
+            let chunk = std::mem::replace(&mut items, rest);
+            let f = f.clone();
+            chunks.push(
+                self.spawn(move || chunk.into_iter().map(|item| f(item)).collect::<Vec<R>>()),
+            );
+        }
+        let mut results = Vec::with_capacity(chunks.len() * chunk_size);
+        for chunk in chunks {
+            results.extend(chunk.await);
+        }
+        results
+    }
+}
+
+async fn wait_result<R>(recv_result: Receiver<R>) -> R {
+    // the job always runs, since workers only exit once every job handed to them is done
+    recv_result
+        .recv()
+        .await
+        .expect("crypto worker dropped a job")
+}
@@ -0,0 +1,147 @@
+use std::{collections::VecDeque, sync::Arc};
+
+use bytes::Bytes;
+use event_listener::Event;
+use futures_intrusive::sync::ManualResetEvent;
+use parking_lot::Mutex;
+
+/// Limits of the datagrams carried by [crate::Multiplex::send_datagram] and [crate::Multiplex::recv_datagram].
+#[derive(Clone, Copy, Debug)]
+pub struct DatagramPolicy {
+    /// The largest datagram that may be sent; [crate::Multiplex::send_datagram] refuses larger ones. `None`, the default, means the current [crate::Multiplex::mss], which is what fits in a single datagram of the pipes.
+    pub max_size: Option<usize>,
+    /// How many datagrams may wait to be sent, e.g. while the handshake is still going on, and how many received ones may wait for [crate::Multiplex::recv_datagram]. Beyond that, the oldest waiting one is dropped, so that what gets through is as fresh as possible. Defaults to 1000.
+    pub queue_limit: usize,
+}
+
+impl Default for DatagramPolicy {
+    fn default() -> Self {
+        Self {
+            max_size: None,
+            queue_limit: 1000,
+        }
+    }
+}
+
+#[derive(Default)]
+struct Queues {
+    policy: DatagramPolicy,
+    send: VecDeque<Bytes>,
+    recv: VecDeque<Bytes>,
+}
+
+impl Queues {
+    fn push(queue: &mut VecDeque<Bytes>, limit: usize, dgram: Bytes) {
+        if limit == 0 {
+            return;
+        }
+        while queue.len() >= limit {
+            queue.pop_front();
+        }
+        queue.push_back(dgram);
+    }
+}
+
+/// The datagrams of a multiplex that belong to no stream, waiting to be sent or received. Kept apart from the rest of the state, so that forwarding datagrams does not contend with the streams for it.
+pub(crate) struct DatagramQueues {
+    queues: Mutex<Queues>,
+    recv_event: Event,
+    tick_notify: Arc<ManualResetEvent>,
+}
+
+impl DatagramQueues {
+    pub fn new(tick_notify: Arc<ManualResetEvent>) -> Self {
+        Self {
+            queues: Default::default(),
+            recv_event: Event::new(),
+            tick_notify,
+        }
+    }
+
+    pub fn policy(&self) -> DatagramPolicy {
+        self.queues.lock().policy
+    }
+
+    pub fn set_policy(&self, policy: DatagramPolicy) {
+        let mut queues = self.queues.lock();
+        queues.policy = policy;
+        let excess = queues.send.len().saturating_sub(policy.queue_limit);
+        queues.send.drain(..excess);
+        let excess = queues.recv.len().saturating_sub(policy.queue_limit);
+        queues.recv.drain(..excess);
+    }
+
+    /// Queues a datagram to be sent with the next tick, and wakes the ticker up for it.
+    pub fn push_send(&self, dgram: Bytes) {
+        let mut queues = self.queues.lock();
+        let limit = queues.policy.queue_limit;
+        Queues::push(&mut queues.send, limit, dgram);
+        drop(queues);
+        self.tick_notify.set();
+    }
+
+    pub fn take_send(&self) -> VecDeque<Bytes> {
+        std::mem::take(&mut self.queues.lock().send)
+    }
+
+    pub fn push_recv(&self, dgram: Bytes) {
+        let mut queues = self.queues.lock();
+        let limit = queues.policy.queue_limit;
+        Queues::push(&mut queues.recv, limit, dgram);
+        drop(queues);
+        self.recv_event.notify(1);
+    }
+
+    pub async fn recv(&self) -> Bytes {
+        loop {
+            let listener = self.recv_event.listen();
+            if let Some(dgram) = self.queues.lock().recv.pop_front() {
+                return dgram;
+            }
+            listener.await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+
+    use super::DatagramPolicy;
+    use crate::{
+        sim::{sim_pipe_pair, SimLink},
+        Multiplex, MuxSecret,
+    };
+
+    #[test]
+    fn datagrams_without_streams() {
+        smol::block_on(async {
+            let server_sk = MuxSecret::generate();
+            let server = Multiplex::new(server_sk.clone(), None);
+            let client = Multiplex::new(MuxSecret::generate(), Some(server_sk.to_public()));
+            client.set_datagram_policy(DatagramPolicy {
+                max_size: Some(100),
+                queue_limit: 2,
+            });
+            assert!(client
+                .send_datagram(Bytes::from(vec![0u8; 101]))
+                .await
+                .is_err());
+            // only the freshest ones wait for the handshake
+            for i in 0u8..5 {
+                client.send_datagram(Bytes::from(vec![i])).await.unwrap();
+            }
+            let (client_pipe, server_pipe) = sim_pipe_pair(SimLink::default());
+            client.add_pipe(client_pipe);
+            server.add_pipe(server_pipe);
+            assert_eq!(server.recv_datagram().await, Bytes::from(vec![3]));
+            assert_eq!(server.recv_datagram().await, Bytes::from(vec![4]));
+
+            server
+                .send_datagram(Bytes::from_static(b"pong"))
+                .await
+                .unwrap();
+            assert_eq!(client.recv_datagram().await, Bytes::from_static(b"pong"));
+        })
+    }
+}
@@ -0,0 +1,89 @@
+#[cfg(feature = "metrics")]
+use super::windowed::{WindowRates, Windowed, WindowedCounters};
+
+/// Counts of incoming datagrams that a [crate::Multiplex] dropped without any response. A steady trickle of these on an otherwise healthy session usually means somebody is probing the service.
+///
+/// Liveness probes are not counted here, and are answered whoever sends them unless [crate::Multiplex::set_silent_until_authenticated] is on.
+#[cfg(feature = "metrics")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DropStats {
+    /// Datagrams without a valid bridge-secret cookie.
+    pub bad_cookie: u64,
+    /// Datagrams that could not be decoded.
+    pub malformed: u64,
+    /// Handshakes from the wrong key, and messages that failed to decrypt.
+    pub unauthenticated: u64,
+    /// Replayed messages, and handshakes older than the configured maximum age.
+    pub replayed: u64,
+}
+
+/// Rates of [DropStats], in datagrams per second. See [crate::Multiplex::drop_rates].
+#[cfg(feature = "metrics")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DropRates {
+    pub bad_cookie: f64,
+    pub malformed: f64,
+    pub unauthenticated: f64,
+    pub replayed: f64,
+}
+
+#[cfg(feature = "metrics")]
+impl Windowed for DropStats {
+    type Rates = DropRates;
+
+    fn rates_since(&self, earlier: &Self, secs: f64) -> DropRates {
+        DropRates {
+            bad_cookie: (self.bad_cookie - earlier.bad_cookie) as f64 / secs,
+            malformed: (self.malformed - earlier.malformed) as f64 / secs,
+            unauthenticated: (self.unauthenticated - earlier.unauthenticated) as f64 / secs,
+            replayed: (self.replayed - earlier.replayed) as f64 / secs,
+        }
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct DropCounters {
+    #[cfg(feature = "metrics")]
+    counters: WindowedCounters<DropStats>,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum DropReason {
+    #[cfg(feature = "obfuscation")]
+    BadCookie,
+    Malformed,
+    Unauthenticated,
+    Replayed,
+}
+
+impl DropCounters {
+    #[cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+    pub fn record(&self, reason: DropReason) {
+        #[cfg(feature = "metrics")]
+        self.counters.update(|stats| {
+            let counter = match reason {
+                #[cfg(feature = "obfuscation")]
+                DropReason::BadCookie => &mut stats.bad_cookie,
+                DropReason::Malformed => &mut stats.malformed,
+                DropReason::Unauthenticated => &mut stats.unauthenticated,
+                DropReason::Replayed => &mut stats.replayed,
+            };
+            *counter += 1;
+        })
+    }
+
+    #[cfg(feature = "metrics")]
+    pub fn snapshot(&self) -> DropStats {
+        self.counters.snapshot()
+    }
+
+    #[cfg(feature = "metrics")]
+    pub fn rates(&self) -> WindowRates<DropRates> {
+        self.counters.rates()
+    }
+
+    #[cfg(feature = "metrics")]
+    pub fn reset(&self) -> DropStats {
+        self.counters.reset()
+    }
+}
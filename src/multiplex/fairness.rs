@@ -0,0 +1,191 @@
+use ahash::AHashMap;
+
+use super::{constants::DEFAULT_STARVATION_TICKS, stream::stream_state::StreamState};
+use crate::frame::StreamId;
+
+/// Diagnostics about how evenly a [crate::Multiplex] shares bandwidth among its streams.
+#[derive(Clone, Copy, Debug)]
+pub struct FairnessStats {
+    /// Streams with data waiting to be sent or in flight.
+    pub active_streams: usize,
+    /// Jain's fairness index of the send throughput of active streams, each divided by the stream's weight. It is 1.0 when every stream gets its share, and approaches `1 / active_streams` as a single stream takes everything.
+    pub jain_index: f64,
+    /// The lowest weighted send throughput of an active stream divided by the highest.
+    pub min_max_ratio: f64,
+    /// How many times a stream with data to send was found not to have been ticked for too long.
+    pub starvation_events: u64,
+}
+
+impl FairnessStats {
+    /// Computes the stats from the weighted send throughput of every active stream.
+    pub(crate) fn new(shares: &[f64], starvation_events: u64) -> Self {
+        let sum: f64 = shares.iter().sum();
+        let sum_squares: f64 = shares.iter().map(|share| share * share).sum();
+        let max = shares.iter().copied().fold(0.0, f64::max);
+        let min = shares.iter().copied().fold(f64::INFINITY, f64::min);
+        Self {
+            active_streams: shares.len(),
+            // nobody sending anything is perfectly fair
+            jain_index: if sum_squares > 0.0 {
+                sum * sum / (shares.len() as f64 * sum_squares)
+            } else {
+                1.0
+            },
+            min_max_ratio: if max > 0.0 { min / max } else { 1.0 },
+            starvation_events,
+        }
+    }
+}
+
+/// Watches for streams that have data to send but are not getting ticked.
+pub(crate) struct StarvationWatchdog {
+    threshold: u64,
+    ticks: u64,
+    // the tick at which each stream was last ticked, and whether it was reported as starved since
+    last_ticked: AHashMap<StreamId, (u64, bool)>,
+    events: u64,
+}
+
+impl StarvationWatchdog {
+    pub fn new() -> Self {
+        Self {
+            threshold: DEFAULT_STARVATION_TICKS,
+            ticks: 0,
+            last_ticked: AHashMap::new(),
+            events: 0,
+        }
+    }
+
+    /// Sets how many ticks a stream with data to send may go without being ticked.
+    pub fn set_threshold(&mut self, ticks: u64) {
+        self.threshold = ticks.max(1);
+    }
+
+    /// Called once every tick of the multiplex, before any stream is ticked. Every `threshold` ticks, looks for starved streams, logging and counting each one once until it gets ticked again.
+    pub fn on_tick(&mut self, streams: &AHashMap<StreamId, StreamState>) {
+        self.ticks += 1;
+        if !self.ticks.is_multiple_of(self.threshold) {
+            return;
+        }
+        for (stream_id, stream) in streams {
+            // streams not seen before start counting now
+            let (last_ticked, reported) = self
+                .last_ticked
+                .entry(*stream_id)
+                .or_insert((self.ticks, false));
+            if !*reported
+                && self.ticks - *last_ticked >= self.threshold
+                && stream.has_pending_data()
+            {
+                log::warn!(
+                    "stream {} has data to send but was not ticked for {} ticks",
+                    stream_id,
+                    self.ticks - *last_ticked
+                );
+                *reported = true;
+                self.events += 1;
+            }
+        }
+    }
+
+    /// Called whenever a stream is ticked.
+    pub fn on_stream_ticked(&mut self, stream_id: StreamId) {
+        self.last_ticked.insert(stream_id, (self.ticks, false));
+    }
+
+    /// Called when a stream is gone.
+    pub fn on_stream_removed(&mut self, stream_id: StreamId) {
+        self.last_ticked.remove(&stream_id);
+    }
+
+    /// How many starved streams were found so far.
+    pub fn events(&self) -> u64 {
+        self.events
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            atomic::{AtomicU64, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use smol::prelude::*;
+
+    use super::*;
+    use crate::{
+        sim::{sim_pipe_pair, SimLink},
+        utilities::runtime,
+        Multiplex, MuxSecret,
+    };
+
+    #[test]
+    fn test_fairness_index() {
+        let even = FairnessStats::new(&[5.0, 5.0, 5.0, 5.0], 0);
+        assert!((even.jain_index - 1.0).abs() < 1e-9);
+        assert!((even.min_max_ratio - 1.0).abs() < 1e-9);
+
+        let hog = FairnessStats::new(&[10.0, 0.0, 0.0, 0.0], 0);
+        assert!((hog.jain_index - 0.25).abs() < 1e-9);
+        assert_eq!(hog.min_max_ratio, 0.0);
+
+        let idle = FairnessStats::new(&[], 0);
+        assert_eq!(idle.jain_index, 1.0);
+    }
+
+    #[test]
+    fn test_bulk_streams_share_bandwidth() {
+        const STREAMS: usize = 6;
+
+        smol::block_on(async {
+            let server_sk = MuxSecret::generate();
+            let server = Multiplex::new(server_sk.clone(), None);
+            let client = Multiplex::new(MuxSecret::generate(), Some(server_sk.to_public()));
+            let (client_pipe, server_pipe) = sim_pipe_pair(SimLink {
+                delay: Duration::from_millis(20),
+                bandwidth: Some(1_000_000.0),
+                ..Default::default()
+            });
+            client.add_pipe(client_pipe);
+            server.add_pipe(server_pipe);
+
+            let mut received = vec![];
+            let mut tasks = vec![];
+            for i in 0..STREAMS {
+                let mut stream = client.open_conn(&i.to_string()).await.unwrap();
+                let mut incoming = server.accept_conn().await.unwrap();
+                let count = Arc::new(AtomicU64::new(0));
+                received.push(count.clone());
+                tasks.push(runtime::spawn(async move {
+                    let chunk = vec![0u8; 65536];
+                    while stream.write_all(&chunk).await.is_ok() {}
+                }));
+                tasks.push(runtime::spawn(async move {
+                    let mut buf = vec![0u8; 65536];
+                    while let Ok(n) = incoming.read(&mut buf).await {
+                        count.fetch_add(n as u64, Ordering::Relaxed);
+                    }
+                }));
+            }
+
+            // let congestion control settle before measuring
+            runtime::Timer::after(Duration::from_secs(3)).await;
+            let before: Vec<u64> = received.iter().map(|c| c.load(Ordering::Relaxed)).collect();
+            runtime::Timer::after(Duration::from_secs(5)).await;
+            let shares: Vec<f64> = received
+                .iter()
+                .zip(before)
+                .map(|(c, before)| (c.load(Ordering::Relaxed) - before) as f64)
+                .collect();
+            let measured = FairnessStats::new(&shares, 0);
+            assert!(measured.jain_index > 0.8, "unfair shares {:?}", shares);
+            assert!(measured.min_max_ratio > 0.25, "unfair shares {:?}", shares);
+            assert_eq!(client.fairness_stats().starvation_events, 0);
+            drop(tasks);
+        })
+    }
+}
@@ -1,50 +1,140 @@
 use std::{
-    cmp::Reverse,
+    sync::atomic::{AtomicBool, Ordering},
     time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
 use ahash::AHashMap;
 use anyhow::Context;
-
 use bytes::Bytes;
-use clone_macro::clone;
+
 use crossbeam_queue::SegQueue;
+use event_listener::{Event, EventListener};
 use futures_intrusive::sync::ManualResetEvent;
-use priority_queue::PriorityQueue;
-use rand::Rng;
-use rand_chacha::rand_core::OsRng;
 use replay_filter::ReplayFilter;
+use smol::channel::{Receiver, Sender};
 use std::sync::Arc;
 use stdcode::StdcodeSerializeExt;
 
 use crate::{
-    crypt::{triple_ecdh, NonObfsAead},
-    frame::Frame,
+    crypt::{triple_ecdh, AeadError, NonObfsAead, RekeyPolicy},
+    frame::{Frame, Seqno, StreamId, PROTOCOL_VERSION},
     multiplex::{
-        stream::RelKind,
-        trace::{trace_incoming_msg, trace_outgoing_msg},
+        stream::{CloseReason, CongestionAlgorithm, PacingPolicy, RelKind, ResetCode, UrelPolicy},
+        trace::{trace_incoming_msg, trace_lifecycle, trace_outgoing_msg},
     },
+    utilities::{runtime, timer_wheel::KeyedTimers},
     MuxPublic, MuxSecret, Stream,
 };
 
-use super::stream::{stream_state::StreamState, StreamMessage};
+use super::{
+    constants::{
+        CLOSE_RESEND_INTERVAL, DEFAULT_DROP_LINGER, DEFAULT_FAST_RETRANSMIT_THRESHOLD,
+        DEFAULT_RETRANSMIT_BURST, MSS, REKEY_GRACE,
+    },
+    datagram::DatagramQueues,
+    drop_stats::{DropCounters, DropReason},
+    fairness::{FairnessStats, StarvationWatchdog},
+    mux_stats::MultiplexStats,
+    path_profile::{PathProfile, PathSeed},
+    power_profile::PowerProfile,
+    rng::MuxRng,
+    routes::Routes,
+    scheduler::DataScheduler,
+    setup_timings::{SetupClock, SetupTimings},
+    stream::{stream_state::StreamState, LossStats, SharedCongestion, StreamMessage},
+    tick_stats::TickCounters,
+};
+#[cfg(feature = "metrics")]
+use super::{
+    tick_stats::{TickRates, TickStats},
+    windowed::WindowRates,
+};
 
 /// An encapsulation of the entire state of a Multiplex.
 pub struct MultiplexState {
     local_esk_send: x25519_dalek::StaticSecret,
     local_esk_recv: x25519_dalek::StaticSecret,
     send_aead: Option<NonObfsAead>,
-    recv_aead: Option<NonObfsAead>,
+    // a hash of the secret the send-side key was first derived from, to recognize duplicate serverhellos without keeping the secret around
+    send_secret_hash: Option<blake3::Hash>,
+    // when the send-side key was last replaced
+    send_rekeyed: Instant,
+    rekey_policy: Option<RekeyPolicy>,
+    recv_keys: Option<Opener>,
     replay_filter: ReplayFilter,
+    rng: MuxRng,
 
     pub local_lsk: MuxSecret,
     pub peer_lpk: Option<MuxPublic>,
+    // whether we dialed the other side, which decides which half of the stream IDs we open streams with
+    initiator: bool,
+    // other identities that handshakes are accepted against, until the peer shows which one it expects
+    extra_lsks: Vec<MuxSecret>,
+    // receive-side keys under each identity the peer may expect, until one of them opens a message
+    recv_candidates: Vec<(MuxSecret, NonObfsAead)>,
+    // the ephemeral key from a serverhello that came before we knew which identity the peer expects
+    pending_serverhello: Option<x25519_dalek::PublicKey>,
+    // protocol version from the peer's hello, or 0 if we haven't seen one
+    peer_version: u64,
+    // whether this side is closing the multiplex, and so refuses new streams, and whether the peer was told so
+    going_away: bool,
+    goaway_sent: bool,
+    // whether the peer said it is closing the multiplex, and so would refuse new streams
+    peer_going_away: bool,
+    // notified after every tick while going away, for whoever waits for the streams to drain
+    drain_event: Event,
+    // whether this side is closing the multiplex for good, when it last told the peer so, and whether the peer acked
+    closing: bool,
+    close_sent: Option<Instant>,
+    close_acked: bool,
+    close_event: Event,
+    // whether the peer closed the multiplex for good
+    peer_closed: bool,
+    // pings waiting for the next tick to be sent, and those sent, with when, waiting for their pongs
+    pings_to_send: Vec<(u64, Sender<Duration>)>,
+    pings_sent: AHashMap<u64, (Instant, Sender<Duration>)>,
+    next_ping_id: u64,
 
-    stream_tab: AHashMap<u16, StreamState>,
+    stream_tab: AHashMap<StreamId, StreamState>,
+    datagrams: Arc<DatagramQueues>,
     // notify this when the streams need to be rescanned
     stream_tick_notify: Arc<ManualResetEvent>,
-    force_ticks: Arc<SegQueue<u16>>,
-    tick_times: PriorityQueue<u16, Reverse<Instant>>,
+    // streams that asked to be ticked, with the flag that keeps each of them from being queued more than once
+    force_ticks: Arc<SegQueue<(StreamId, Arc<AtomicBool>)>>,
+    tick_counters: Arc<TickCounters>,
+    tick_times: KeyedTimers<StreamId>,
+    scheduler: DataScheduler,
+    mss: usize,
+    // loss statistics of streams that no longer exist
+    retired_loss_stats: LossStats,
+
+    group_weights: AHashMap<String, f64>,
+    groups_dirty: bool,
+
+    max_hello_age: Option<Duration>,
+    drops: Arc<DropCounters>,
+
+    path_profile: Option<PathProfile>,
+    power_profile: PowerProfile,
+    initial_rtt: Option<Duration>,
+    urel_policy: UrelPolicy,
+    drop_linger: Option<Duration>,
+    pacing_policy: PacingPolicy,
+    eifel_response: bool,
+    frto: bool,
+    retransmit_burst: usize,
+    fast_retransmit_threshold: u64,
+    read_rate_feedback: bool,
+    single_path_below: u64,
+    congestion: CongestionAlgorithm,
+    // what new streams share, if they share a congestion controller
+    shared_congestion: Option<Arc<SharedCongestion>>,
+    // the pipes that new streams' segments go out over
+    routes: Option<Arc<Routes>>,
+    watchdog: StarvationWatchdog,
+    setup: SetupClock,
+    #[cfg(feature = "tracing")]
+    span: tracing::Span,
 }
 
 impl MultiplexState {
@@ -53,96 +143,720 @@ impl MultiplexState {
         stream_update: Arc<ManualResetEvent>,
         local_lsk: MuxSecret,
         peer_lpk: Option<MuxPublic>,
+        drops: Arc<DropCounters>,
     ) -> Self {
-        let local_esk_send = x25519_dalek::StaticSecret::new(OsRng {});
-        let local_esk_recv = x25519_dalek::StaticSecret::new(OsRng {});
+        let rng = MuxRng::default();
+        let local_esk_send = x25519_dalek::StaticSecret::new(rng.clone());
+        let local_esk_recv = x25519_dalek::StaticSecret::new(rng.clone());
+        #[cfg(feature = "tracing")]
+        let span = tracing::info_span!(
+            "multiplex",
+            local_pk = %hex::encode(&local_lsk.to_public().as_bytes()[..4]),
+            initiator = peer_lpk.is_some(),
+        );
         Self {
             local_esk_send,
             local_esk_recv,
             send_aead: None,
-            recv_aead: None,
+            send_secret_hash: None,
+            send_rekeyed: runtime::now(),
+            rekey_policy: Some(RekeyPolicy::default()),
+            recv_keys: None,
             replay_filter: ReplayFilter::default(),
+            rng,
             local_lsk,
+            initiator: peer_lpk.is_some(),
             peer_lpk,
+            extra_lsks: vec![],
+            recv_candidates: vec![],
+            pending_serverhello: None,
+            peer_version: 0,
+            going_away: false,
+            goaway_sent: false,
+            peer_going_away: false,
+            drain_event: Event::new(),
+            closing: false,
+            close_sent: None,
+            close_acked: false,
+            close_event: Event::new(),
+            peer_closed: false,
+            pings_to_send: vec![],
+            pings_sent: AHashMap::new(),
+            next_ping_id: 0,
             stream_tab: AHashMap::new(),
+            datagrams: Arc::new(DatagramQueues::new(stream_update.clone())),
             force_ticks: Arc::new(SegQueue::new()),
             stream_tick_notify: stream_update,
-            tick_times: PriorityQueue::new(),
+            tick_counters: Arc::new(TickCounters::default()),
+            tick_times: KeyedTimers::new(),
+            scheduler: DataScheduler::default(),
+            mss: MSS,
+            retired_loss_stats: LossStats::default(),
+
+            group_weights: AHashMap::new(),
+            groups_dirty: false,
+
+            max_hello_age: None,
+            drops,
+
+            path_profile: None,
+            power_profile: PowerProfile::default(),
+            initial_rtt: None,
+            urel_policy: UrelPolicy::default(),
+            drop_linger: Some(DEFAULT_DROP_LINGER),
+            pacing_policy: PacingPolicy::default(),
+            eifel_response: false,
+            frto: true,
+            retransmit_burst: DEFAULT_RETRANSMIT_BURST,
+            fast_retransmit_threshold: DEFAULT_FAST_RETRANSMIT_THRESHOLD,
+            read_rate_feedback: false,
+            single_path_below: 0,
+            congestion: CongestionAlgorithm::default(),
+            shared_congestion: None,
+            routes: None,
+            watchdog: StarvationWatchdog::new(),
+            setup: SetupClock::default(),
+            #[cfg(feature = "tracing")]
+            span,
         }
     }
 
-    /// "Ticks" the state forward once. Returns the time before which this method should be called again.
-    pub fn tick(&mut self, mut raw_callback: impl FnMut(Frame)) -> Instant {
+    /// The datagrams that belong to no stream, which are sent with the ticks.
+    pub fn datagrams(&self) -> Arc<DatagramQueues> {
+        self.datagrams.clone()
+    }
+
+    /// The span that everything traced about this multiplex goes in.
+    #[cfg(feature = "tracing")]
+    pub fn span(&self) -> tracing::Span {
+        self.span.clone()
+    }
+
+    /// "Ticks" the state forward once. Handshake frames are passed to `raw_callback`, and messages of streams to `msg_callback`, to be sealed with [MultiplexState::sealer]. Returns the time before which this method should be called again.
+    pub fn tick(
+        &mut self,
+        mut raw_callback: impl FnMut(Frame),
+        mut msg_callback: impl FnMut(StreamMessage),
+    ) -> Instant {
         // if we do not have a send_aead, we send a hello and wait a second
         if self.send_aead.is_none() {
-            let hello = Frame::ClientHello {
-                long_pk: self.local_lsk.to_public(),
-                eph_pk: (&self.local_esk_send).into(),
-                version: 1,
-                timestamp: (SystemTime::now().duration_since(UNIX_EPOCH).unwrap()).as_secs(),
-            };
             log::debug!("no send aead, cannot send anything yet. sending another clienthello");
-            raw_callback(hello);
-            return Instant::now() + Duration::from_secs(1);
+            // one for every identity, since the peer only answers the one it expects
+            for lsk in self.identities() {
+                trace_lifecycle(
+                    "ClientHelloSent",
+                    "",
+                    format_args!(
+                        "version {PROTOCOL_VERSION} for {}",
+                        hex::encode(lsk.to_public().as_bytes())
+                    ),
+                );
+                raw_callback(Frame::ClientHello {
+                    long_pk: lsk.to_public(),
+                    eph_pk: (&self.local_esk_send).into(),
+                    version: PROTOCOL_VERSION,
+                    timestamp: (SystemTime::now().duration_since(UNIX_EPOCH).unwrap()).as_secs(),
+                });
+            }
+            return runtime::now() + Duration::from_secs(1);
         }
 
-        let start = Instant::now();
+        let start = runtime::now();
+        self.tick_counters.on_tick();
+        self.maybe_rekey(&mut raw_callback);
+        if let Some(keys) = self.recv_keys.as_mut() {
+            keys.forget_previous_after(REKEY_GRACE);
+        }
 
-        // encryption
+        // encryption happens outside, so that it does not hold up everything else that needs the state
         let mut outgoing_callback = |msg: StreamMessage| {
             log::trace!("send in tick {:?}", msg);
             trace_outgoing_msg(&msg);
-            if let Some(send_aead) = self.send_aead.as_ref() {
-                let inner = send_aead.encrypt(&msg.stdcode());
-                raw_callback(Frame::EncryptedMsg { inner })
-            }
+            msg_callback(msg)
         };
 
+        // datagrams are as urgent as anything, and wait until it is known whether the peer understands them
+        if self.peer_version >= 7 {
+            for payload in self.datagrams.take_send() {
+                outgoing_callback(StreamMessage::Datagram { payload });
+            }
+        } else if self.peer_version > 0 {
+            let dropped = self.datagrams.take_send().len();
+            if dropped > 0 {
+                log::debug!("dropping {dropped} datagrams for a peer that predates them");
+            }
+        }
+
+        // sent as late as possible, so that the RTT does not include the time spent ticking
+        if self.peer_version >= 10 {
+            let now = runtime::now();
+            for (id, pong) in self.pings_to_send.drain(..) {
+                outgoing_callback(StreamMessage::Ping { id });
+                self.pings_sent.insert(id, (now, pong));
+            }
+        } else if self.peer_version > 0 {
+            // dropping them fails the pings
+            self.pings_to_send.clear();
+        }
+
+        // the peer has to be told before it opens streams that would only be refused
+        if self.going_away && !self.goaway_sent && self.peer_version >= 9 {
+            trace_lifecycle("GoAwaySent", "", "");
+            outgoing_callback(StreamMessage::GoAway);
+            self.goaway_sent = true;
+        }
+        if self.closing
+            && !self.close_acked
+            && !self.peer_closed
+            && self.peer_version >= 11
+            && self
+                .close_sent
+                .is_none_or(|sent| runtime::elapsed(sent) >= CLOSE_RESEND_INTERVAL)
+        {
+            trace_lifecycle("CloseSent", "", "");
+            outgoing_callback(StreamMessage::Close);
+            self.close_sent = Some(runtime::now());
+        }
+
+        self.watchdog.on_tick(&self.stream_tab);
+
         // push the force-ticks into the tick queue
-        while let Some(val) = self.force_ticks.pop() {
+        while let Some((val, pending)) = self.force_ticks.pop() {
+            // cleared before the stream ticks, so that anything it is notified of from now on is picked up by the next tick
+            pending.store(false, Ordering::Release);
             if self.stream_tab.contains_key(&val) {
-                self.tick_times.push(val, Reverse(start));
+                self.tick_times.set(val, start);
             }
         }
+        // bulk streams keep their queues within the tightest latency budget of the latency-sensitive ones
+        let queue_budget = self
+            .stream_tab
+            .values()
+            .filter_map(|stream| stream.latency_budget())
+            .min();
+        // acks also wait for a tick on each side, which keeps packets in flight without queueing them
+        let ack_slack = self.power_profile.ack_delay() * 2;
+        for stream in self.stream_tab.values_mut() {
+            stream.set_queue_budget(queue_budget.map(|budget| budget + ack_slack));
+        }
+
         // tick only the streams that need to be ticked
-        while let Some((stream_id, Reverse(time))) = self.tick_times.pop() {
+        while let Some((time, stream_id)) = self.tick_times.first() {
             if time > start {
-                self.tick_times.push(stream_id, Reverse(time));
                 break;
             }
+            self.tick_times.remove(stream_id);
             let stream = self
                 .stream_tab
                 .get_mut(&stream_id)
                 .expect("inconsistency between stream table and tick time table");
-            if let Some(next_time) = stream.tick(&mut outgoing_callback) {
-                self.tick_times.push(stream_id, Reverse(next_time));
+            let priority = stream.priority();
+            let latency_sensitive = stream.latency_budget().is_some();
+            let next_time = stream.tick(|msg| {
+                if latency_sensitive {
+                    // goes out ahead of whatever bulk data the scheduler holds
+                    outgoing_callback(msg)
+                } else {
+                    self.scheduler
+                        .push(stream_id, priority, msg, &mut outgoing_callback)
+                }
+            });
+            self.tick_counters.on_stream_tick();
+            self.watchdog.on_stream_ticked(stream_id);
+            if stream.sync_group() {
+                self.groups_dirty = true;
+            }
+            if let Some(next_time) = next_time {
+                self.tick_times.set(stream_id, next_time);
             } else {
-                self.tick_times.remove(&stream_id);
-                self.stream_tab.remove(&stream_id);
+                self.tick_times.remove(stream_id);
+                self.watchdog.on_stream_removed(stream_id);
+                if let Some(stream) = self.stream_tab.remove(&stream_id) {
+                    self.retired_loss_stats.merge(stream.loss_stats());
+                    if stream.group().is_some() {
+                        self.groups_dirty = true;
+                    }
+                }
+            }
+        }
+        // data goes out interleaved by stream priority, rather than one stream's whole burst after another's
+        self.scheduler.drain(&mut outgoing_callback);
+        if self.groups_dirty {
+            self.reweigh_groups();
+        }
+        if self.going_away {
+            self.drain_event.notify(usize::MAX);
+        }
+
+        let insta = self.tick_times.first().map(|(time, _)| time);
+        let next_tick = insta.unwrap_or_else(|| runtime::now() + Duration::from_secs(86400));
+        // keys must not outlive their welcome just because nothing else is going on
+        [
+            self.rekey_deadline(),
+            self.recv_keys
+                .as_ref()
+                .and_then(|keys| keys.previous_deadline(REKEY_GRACE)),
+            self.close_sent
+                .filter(|_| !self.close_acked)
+                .map(|sent| sent + CLOSE_RESEND_INTERVAL),
+        ]
+        .into_iter()
+        .flatten()
+        .fold(next_tick, Instant::min)
+    }
+
+    /// Starts closing the multiplex: from now on, new streams are refused, and the next tick tells the peer to open no more.
+    pub fn start_going_away(&mut self) {
+        self.going_away = true;
+        self.stream_tick_notify.set();
+    }
+
+    /// Whether the multiplex is done closing, once [MultiplexState::start_going_away] was called: the peer was told, unless it cannot be, and every stream has had everything it sent acked.
+    pub fn is_drained(&self) -> bool {
+        let told =
+            self.goaway_sent || self.send_aead.is_none() || (1..9).contains(&self.peer_version);
+        told && !self.stream_tab.values().any(|stream| stream.has_pending_data())
+    }
+
+    /// Starts a ping, which goes out with the next tick. Returns its ID, and where the RTT arrives once the pong does. Fails right away if the peer is known to predate pings; if it turns out to, the receiver closes instead.
+    pub fn start_ping(&mut self) -> anyhow::Result<(u64, Receiver<Duration>)> {
+        if (1..10).contains(&self.peer_version) {
+            anyhow::bail!("the other side does not understand pings");
+        }
+        let id = self.next_ping_id;
+        self.next_ping_id += 1;
+        let (send_rtt, recv_rtt) = smol::channel::bounded(1);
+        self.pings_to_send.push((id, send_rtt));
+        self.stream_tick_notify.set();
+        Ok((id, recv_rtt))
+    }
+
+    /// Forgets a ping that was given up on, so that a late pong is ignored.
+    pub fn cancel_ping(&mut self, id: u64) {
+        self.pings_to_send.retain(|(pending, _)| *pending != id);
+        self.pings_sent.remove(&id);
+    }
+
+    /// Listens for the next tick while going away, after which [MultiplexState::is_drained] may have changed.
+    pub fn listen_drained(&self) -> EventListener {
+        self.drain_event.listen()
+    }
+
+    /// Starts closing the multiplex for good: from now on, no streams are opened or accepted, and the ticks tell the peer until it acks. Returns whether there is anyone to tell, which there is not before the handshake is done, once the peer closed the multiplex itself, or if it predates closing.
+    pub fn start_close(&mut self) -> bool {
+        self.closing = true;
+        self.stream_tick_notify.set();
+        self.send_aead.is_some() && !self.peer_closed && self.peer_version >= 11
+    }
+
+    /// Whether the peer acked the close started with [MultiplexState::start_close].
+    pub fn is_close_acked(&self) -> bool {
+        self.close_acked
+    }
+
+    /// Notified once the peer acks the close.
+    pub fn listen_close_acked(&self) -> EventListener {
+        self.close_event.listen()
+    }
+
+    /// Whether the peer closed the multiplex for good.
+    pub fn is_peer_closed(&self) -> bool {
+        self.peer_closed
+    }
+
+    /// Closes every stream with the given reason, and forgets about them.
+    pub fn close_streams(&mut self, reason: CloseReason) {
+        for (_, stream) in self.stream_tab.drain() {
+            stream.set_close_reason(reason);
+        }
+        self.tick_times.clear();
+        self.pings_sent.clear();
+    }
+
+    /// Sets when the send-side key is ratcheted, or stops ratcheting it with `None`.
+    pub fn set_rekey_policy(&mut self, policy: Option<RekeyPolicy>) {
+        self.rekey_policy = policy;
+        self.stream_tick_notify.set();
+    }
+
+    /// When the send-side key is next due to be ratcheted because of its age, if ever.
+    fn rekey_deadline(&self) -> Option<Instant> {
+        let policy = self.rekey_policy?;
+        if self.send_aead.is_none() || self.peer_version < 6 {
+            return None;
+        }
+        Some(self.send_rekeyed + policy.max_age)
+    }
+
+    /// Ratchets the send-side key once it has been used as much as the rekey policy allows, announcing the new key with a [Frame::Rekey].
+    fn maybe_rekey(&mut self, raw_callback: &mut impl FnMut(Frame)) {
+        let (Some(policy), Some(send_aead)) = (self.rekey_policy, self.send_aead.as_ref()) else {
+            return;
+        };
+        if self.peer_version < 6
+            || (send_aead.sealed_bytes() < policy.max_bytes
+                && runtime::elapsed(self.send_rekeyed) < policy.max_age)
+        {
+            return;
+        }
+        let send_aead = send_aead.ratchet();
+        log::debug!(
+            "send-side key ratcheted to generation {}",
+            send_aead.generation()
+        );
+        trace_lifecycle(
+            "SendKeyRatcheted",
+            "",
+            format_args!("generation {}", send_aead.generation()),
+        );
+        let (nonce, inner) = send_aead.encrypt_split(&StreamMessage::Empty.stdcode());
+        raw_callback(Frame::Rekey { nonce, inner });
+        self.send_aead = Some(send_aead);
+        self.send_rekeyed = runtime::now();
+    }
+
+    /// Sets the relative weight of a bandwidth-sharing group, if new streams can honour it.
+    pub fn set_group_weight(&mut self, group: &str, weight: f64) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            weight.is_finite() && weight > 0.0,
+            "group weights must be positive"
+        );
+        anyhow::ensure!(
+            weight == 1.0 || self.shared_congestion.is_none(),
+            "a shared congestion window cannot be weighted"
+        );
+        self.congestion.check_weight(weight)?;
+        self.group_weights.insert(group.to_owned(), weight);
+        self.groups_dirty = true;
+        self.stream_tick_notify.set();
+        Ok(())
+    }
+
+    /// Splits every group's weight evenly among its member streams.
+    fn reweigh_groups(&mut self) {
+        let mut members: AHashMap<&str, usize> = AHashMap::new();
+        for stream in self.stream_tab.values() {
+            if let Some(group) = stream.group() {
+                *members.entry(group).or_default() += 1;
+            }
+        }
+        let shares: AHashMap<String, f64> = members
+            .into_iter()
+            .map(|(group, count)| {
+                let weight = self.group_weights.get(group).copied().unwrap_or(1.0);
+                (group.to_owned(), weight / count as f64)
+            })
+            .collect();
+        for stream in self.stream_tab.values_mut() {
+            let share = stream
+                .group()
+                .and_then(|g| shares.get(g).copied())
+                .unwrap_or(1.0);
+            stream.set_weight(share);
+        }
+        self.groups_dirty = false;
+    }
+
+    /// Returns the loss statistics aggregated over every stream, past and present.
+    pub fn loss_stats(&self) -> LossStats {
+        let mut stats = self.retired_loss_stats.clone();
+        for stream in self.stream_tab.values() {
+            stats.merge(stream.loss_stats());
+        }
+        stats
+    }
+
+    /// Returns the totals of the statistics of all open streams.
+    pub fn stats(&self) -> MultiplexStats {
+        let mut stats = MultiplexStats::default();
+        for stream in self.stream_tab.values() {
+            stats.add(&stream.snapshot());
+        }
+        stats
+    }
+
+    /// Checks the invariants of every stream, and that no stream is left scheduled for ticking after it is gone.
+    #[cfg(all(test, feature = "soak"))]
+    pub fn check_invariants(&self) -> anyhow::Result<()> {
+        for stream in self.stream_tab.values() {
+            stream.check_invariants()?;
+        }
+        for stream_id in self.tick_times.keys() {
+            anyhow::ensure!(
+                self.stream_tab.contains_key(stream_id),
+                "stream {stream_id} is gone but still scheduled"
+            );
+        }
+        Ok(())
+    }
+
+    /// Records that a pipe was added. Before the handshake is done, this also makes the next tick send the hellos at once, rather than when they are next due, since those sent while there was no pipe went nowhere.
+    pub fn on_pipe_added(&mut self) {
+        self.setup.on_pipe_added();
+        if self.send_aead.is_none() {
+            self.stream_tick_notify.set();
+        }
+    }
+
+    /// Records that a stream was opened or accepted.
+    pub fn on_stream_ready(&mut self) {
+        self.setup.on_stream_ready();
+    }
+
+    /// Returns how long the multiplex took to get going.
+    pub fn setup_timings(&self) -> SetupTimings {
+        self.setup.snapshot()
+    }
+
+    /// Returns counts of the work done by ticking.
+    #[cfg(feature = "metrics")]
+    pub fn tick_stats(&self) -> TickStats {
+        self.tick_counters.snapshot()
+    }
+
+    /// Returns the rates of the work done by ticking, over recent windows.
+    #[cfg(feature = "metrics")]
+    pub fn tick_rates(&self) -> WindowRates<TickRates> {
+        self.tick_counters.rates()
+    }
+
+    /// Returns counts of the work done by ticking, and starts counting afresh.
+    #[cfg(feature = "metrics")]
+    pub fn reset_tick_stats(&self) -> TickStats {
+        self.tick_counters.reset()
+    }
+
+    /// Returns diagnostics about how evenly streams share bandwidth.
+    pub fn fairness_stats(&self) -> FairnessStats {
+        let shares: Vec<f64> = self
+            .stream_tab
+            .values()
+            .filter(|stream| stream.has_pending_data())
+            .map(|stream| stream.send_throughput() / stream.weight())
+            .collect();
+        FairnessStats::new(&shares, self.watchdog.events())
+    }
+
+    /// Sets how many ticks a stream with data to send may go without being ticked before it counts as starved.
+    pub fn set_starvation_threshold(&mut self, ticks: u64) {
+        self.watchdog.set_threshold(ticks);
+    }
+
+    /// Sets how far from the local clock the timestamp of an acceptable ClientHello may be.
+    pub fn set_max_hello_age(&mut self, max_age: Option<Duration>) {
+        self.max_hello_age = max_age;
+    }
+
+    /// Sets where randomness comes from, replacing the ephemeral keys so that they come from it too. Only meaningful before the handshake.
+    pub fn set_rng(&mut self, rng: MuxRng) {
+        self.local_esk_send = x25519_dalek::StaticSecret::new(rng.clone());
+        self.local_esk_recv = x25519_dalek::StaticSecret::new(rng.clone());
+        self.rng = rng;
+    }
+
+    /// Also accepts handshakes from peers that expect one of the given identities rather than `local_lsk`. Once the peer's first message shows which one it expects, that one becomes `local_lsk` and the others are forgotten.
+    pub fn set_extra_identities(&mut self, lsks: Vec<MuxSecret>) {
+        self.extra_lsks = lsks;
+    }
+
+    fn identities(&self) -> impl Iterator<Item = &MuxSecret> {
+        std::iter::once(&self.local_lsk).chain(self.extra_lsks.iter())
+    }
+
+    /// Sets the maximum segment size, propagating it to every active stream.
+    pub fn set_mss(&mut self, mss: usize) {
+        if mss != self.mss {
+            self.mss = mss;
+            for stream in self.stream_tab.values_mut() {
+                stream.set_mss(mss);
             }
         }
+    }
+
+    /// Has every stream resend what it has in flight right away, after traffic moved off a dead pipe.
+    pub fn on_failover(&mut self) {
+        let now = runtime::now();
+        for (stream_id, stream) in self.stream_tab.iter_mut() {
+            stream.on_failover();
+            self.tick_times.set(*stream_id, now);
+        }
+    }
+
+    /// Sets the kind of path that new streams should assume.
+    pub fn set_path_profile(&mut self, profile: Option<PathProfile>) {
+        self.path_profile = profile;
+    }
+
+    /// Returns how the tick loop should trade responsiveness for fewer wakeups.
+    pub fn power_profile(&self) -> PowerProfile {
+        self.power_profile
+    }
+
+    /// Sets how the tick loop should trade responsiveness for fewer wakeups, waking it up so that the change takes effect right away.
+    pub fn set_power_profile(&mut self, profile: PowerProfile) {
+        self.power_profile = profile;
+        self.stream_tick_notify.set();
+    }
+
+    /// Sets the RTT that new streams should assume until they measure one.
+    pub fn set_initial_rtt(&mut self, rtt: Option<Duration>) {
+        self.initial_rtt = rtt;
+    }
+
+    /// Sets which unreliable datagrams new streams drop instead of delivering.
+    pub fn set_urel_policy(&mut self, policy: UrelPolicy) {
+        self.urel_policy = policy;
+    }
+
+    /// Sets how long streams, new and existing, keep delivering what was written to them once dropped, and how long the multiplex does once it is dropped.
+    pub fn set_drop_linger(&mut self, linger: Option<Duration>) {
+        self.drop_linger = linger;
+        for stream in self.stream_tab.values_mut() {
+            stream.set_drop_linger(linger);
+        }
+    }
+
+    pub fn drop_linger(&self) -> Option<Duration> {
+        self.drop_linger
+    }
+
+    /// Sets how new streams are paced, and which are exempt.
+    pub fn set_pacing_policy(&mut self, policy: PacingPolicy) {
+        self.pacing_policy = policy;
+    }
+
+    /// Sets whether new streams adapt to spurious retransmissions.
+    pub fn set_eifel_response(&mut self, enabled: bool) {
+        self.eifel_response = enabled;
+    }
+
+    /// Sets whether new streams check retransmission timeouts for being spurious.
+    pub fn set_frto(&mut self, enabled: bool) {
+        self.frto = enabled;
+    }
+
+    /// Sets how many packets new streams may retransmit per round trip.
+    pub fn set_retransmit_burst(&mut self, burst: usize) {
+        self.retransmit_burst = burst;
+    }
+
+    /// Sets how many later packets new streams wait to see acked before retransmitting an unacked one.
+    pub fn set_fast_retransmit_threshold(&mut self, threshold: u64) {
+        self.fast_retransmit_threshold = threshold;
+    }
+
+    /// Sets whether new streams report the application's read rate to the other side.
+    pub fn set_read_rate_feedback(&mut self, enabled: bool) {
+        self.read_rate_feedback = enabled;
+    }
+
+    /// Sets how many bytes new streams may write before their segments are striped over several pipes.
+    pub fn set_single_path_below(&mut self, bytes: u64) {
+        self.single_path_below = bytes;
+    }
+
+    /// Sets the congestion control algorithm of new streams, if it can honour the weights of the groups.
+    pub fn set_congestion_control(&mut self, algo: CongestionAlgorithm) -> anyhow::Result<()> {
+        for weight in self.group_weights.values() {
+            algo.check_weight(*weight)?;
+        }
+        self.congestion = algo;
+        if self.shared_congestion.is_some() {
+            self.shared_congestion = Some(SharedCongestion::new(self.congestion.build()));
+        }
+        Ok(())
+    }
 
-        let insta = self.tick_times.peek().map(|(_, time)| time.0);
-        insta.unwrap_or_else(|| Instant::now() + Duration::from_secs(86400))
+    /// Sets whether new streams share one congestion controller, unless groups are weighted. Streams that already share one keep it.
+    pub fn set_shared_congestion(&mut self, enabled: bool) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            !enabled || self.group_weights.values().all(|weight| *weight == 1.0),
+            "a shared congestion window cannot be weighted"
+        );
+        if enabled != self.shared_congestion.is_some() {
+            self.shared_congestion =
+                enabled.then(|| SharedCongestion::new(self.congestion.build()));
+        }
+        Ok(())
+    }
+
+    /// Makes new streams count their segments against the congestion windows of the pipes they go out over.
+    pub fn set_routes(&mut self, routes: Arc<Routes>) {
+        self.routes = Some(routes);
+    }
+
+    /// Applies the settings shared by every new stream.
+    fn init_stream(&self, stream: &mut StreamState) {
+        stream.set_mss(self.mss);
+        stream.set_urel_policy(self.urel_policy);
+        stream.set_drop_linger(self.drop_linger);
+        stream.set_pacing_policy(self.pacing_policy);
+        stream.set_eifel_response(self.eifel_response);
+        stream.set_frto(self.frto);
+        stream.set_retransmit_burst(self.retransmit_burst);
+        stream.set_fast_retransmit_threshold(self.fast_retransmit_threshold);
+        stream.set_read_rate_feedback(self.read_rate_feedback);
+        stream.set_single_path_below(self.single_path_below);
+        stream.set_peer_version(self.peer_version);
+        match &self.shared_congestion {
+            Some(shared) => stream.share_congestion_control(shared),
+            None => stream.set_congestion_control(self.congestion.build()),
+        }
+        if let Some(seed) = PathSeed::new(self.path_profile, self.initial_rtt) {
+            stream.seed_path(seed);
+        }
+        if let Some(routes) = &self.routes {
+            stream.set_routes(routes.clone());
+        }
+    }
+
+    /// Returns the function a stream calls to be ticked. However often it is called between two ticks of the multiplex, the stream is queued and the tick loop woken only once.
+    fn tick_notifier(&self, stream_id: StreamId) -> impl Fn() + Clone + Send + Sync + 'static {
+        let stream_tick_notify = self.stream_tick_notify.clone();
+        let force_ticks = self.force_ticks.clone();
+        let tick_counters = self.tick_counters.clone();
+        let pending = Arc::new(AtomicBool::new(false));
+        move || {
+            let coalesced = pending.swap(true, Ordering::AcqRel);
+            tick_counters.on_notification(coalesced);
+            if !coalesced {
+                force_ticks.push((stream_id, pending.clone()));
+                stream_tick_notify.set();
+            }
+        }
     }
 
-    /// Starts the opening of a connection, returning a Stream in the pending state.
-    pub fn start_open_stream(&mut self, additional: &str) -> anyhow::Result<Stream> {
+    /// Starts the opening of a connection, returning a Stream in the pending state. With `early_data`, data written to the stream is sent without waiting for the other side to accept it.
+    pub fn start_open_stream(
+        &mut self,
+        additional: &str,
+        early_data: bool,
+    ) -> anyhow::Result<Stream> {
+        if self.going_away || self.closing {
+            anyhow::bail!("the multiplex is closing");
+        }
+        if self.peer_closed {
+            anyhow::bail!("the other side closed the multiplex");
+        }
+        if self.peer_going_away {
+            anyhow::bail!("the other side is closing the multiplex");
+        }
         for _ in 0..100 {
-            let stream_id: u16 = rand::thread_rng().gen();
+            // the dialing side opens even IDs and the other side odd ones, so that streams both sides open at the same time never collide
+            let stream_id = StreamId::for_opener(self.rng.u16(), self.initiator);
             if !self.stream_tab.contains_key(&stream_id) {
-                let stream_tick_notify = self.stream_tick_notify.clone();
-                let force_ticks = self.force_ticks.clone();
-                let (new_stream, handle) = StreamState::new_pending(
-                    move || {
-                        force_ticks.push(stream_id);
-                        stream_tick_notify.set();
-                    },
-                    stream_id,
-                    additional.to_owned(),
-                );
+                let tick_notify = self.tick_notifier(stream_id);
+                // streams opened from outside the multiplex's tasks still belong in its span
+                #[cfg(feature = "tracing")]
+                let _entered = self.span.enter();
+                let (mut new_stream, handle) =
+                    StreamState::new_pending(tick_notify.clone(), stream_id, additional.to_owned());
+                self.init_stream(&mut new_stream);
+                if early_data {
+                    new_stream.allow_early_data();
+                }
                 self.stream_tab.insert(stream_id, new_stream);
-                self.stream_tick_notify.set();
+                tick_notify();
                 return Ok(handle);
             }
         }
@@ -150,136 +864,850 @@ impl MultiplexState {
     }
 
     /// Processes an incoming message. If the message is rejected for whatever reason, an error is returned, but the state should be presumed to still be in a valid state.
+    ///
+    /// Newly accepted streams are passed to `accept_callback`, which returns false if the application cannot take any more; the stream is then refused.
     pub fn recv_msg(
         &mut self,
         msg: Frame,
         mut outgoing_callback: impl FnMut(Frame),
-        mut accept_callback: impl FnMut(Stream),
+        accept_callback: impl FnMut(Stream) -> bool,
     ) -> anyhow::Result<()> {
         match msg {
             Frame::ClientHello {
                 long_pk,
                 eph_pk,
-                version: _,
-                timestamp: _,
+                version,
+                timestamp,
             } => {
+                if self.peer_lpk.is_some_and(|pk| pk != long_pk) {
+                    self.drops.record(DropReason::Unauthenticated);
+                    anyhow::bail!("dropping clienthello from an unexpected key");
+                }
+                if let Some(max_age) = self.max_hello_age {
+                    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+                    if now.as_secs().abs_diff(timestamp) > max_age.as_secs() {
+                        self.drops.record(DropReason::Replayed);
+                        anyhow::bail!("dropping clienthello with stale timestamp {timestamp}");
+                    }
+                }
+                trace_lifecycle(
+                    "ClientHelloReceived",
+                    "",
+                    format_args!("version {version} from {}", hex::encode(long_pk.as_bytes())),
+                );
                 if self.peer_lpk.is_none() {
                     self.peer_lpk = Some(long_pk);
                 }
-                let recv_secret = triple_ecdh(
-                    &self.local_lsk.0,
-                    &self.local_esk_recv,
-                    &self.peer_lpk.unwrap().0,
-                    &eph_pk,
-                );
-                log::debug!("receive-side symmetric key registered: {:?}", recv_secret);
-                self.recv_aead = Some(NonObfsAead::new(recv_secret.as_bytes()));
-                let our_hello = Frame::ServerHello {
-                    long_pk: self.local_lsk.to_public(),
-                    eph_pk: (&self.local_esk_recv).into(),
-                };
-                outgoing_callback(our_hello);
+                let mut candidates: Vec<(MuxSecret, NonObfsAead)> = self
+                    .identities()
+                    .map(|lsk| {
+                        let recv_secret = triple_ecdh(
+                            &lsk.0,
+                            &self.local_esk_recv,
+                            &self.peer_lpk.unwrap().0,
+                            &eph_pk,
+                        );
+                        (lsk.clone(), NonObfsAead::new(recv_secret.as_bytes()))
+                    })
+                    .collect();
+                for (lsk, _) in candidates.iter() {
+                    trace_lifecycle(
+                        "ServerHelloSent",
+                        "",
+                        hex::encode(lsk.to_public().as_bytes()),
+                    );
+                    outgoing_callback(Frame::ServerHello {
+                        long_pk: lsk.to_public(),
+                        eph_pk: (&self.local_esk_recv).into(),
+                    });
+                }
+                if self.recv_keys.is_some() || !self.recv_candidates.is_empty() {
+                    // the peer's ephemeral key is the same for the whole session, so this is a duplicate, and must not undo any rekeying since. Nor may it change the version: hellos are not authenticated, so anyone could replay one
+                    return Ok(());
+                }
+                self.peer_version = version;
+                for stream in self.stream_tab.values_mut() {
+                    stream.set_peer_version(version);
+                }
+                if candidates.len() == 1 {
+                    log::debug!("receive-side symmetric key registered");
+                    trace_lifecycle("RecvKeyRegistered", "", "");
+                    self.recv_keys = candidates.pop().map(|(_, aead)| Opener::new(aead));
+                } else {
+                    log::debug!(
+                        "{} candidate receive-side keys registered",
+                        candidates.len()
+                    );
+                    self.recv_candidates = candidates;
+                }
                 Ok(())
             }
             Frame::ServerHello { long_pk, eph_pk } => {
+                if self.peer_lpk.is_some_and(|pk| pk != long_pk) {
+                    self.drops.record(DropReason::Unauthenticated);
+                    anyhow::bail!("dropping serverhello from an unexpected key");
+                }
+                trace_lifecycle("ServerHelloReceived", "", hex::encode(long_pk.as_bytes()));
                 if self.peer_lpk.is_none() {
                     self.peer_lpk = Some(long_pk);
                 }
-                let send_secret = triple_ecdh(
-                    &self.local_lsk.0,
-                    &self.local_esk_send,
-                    &self.peer_lpk.unwrap().0,
-                    &eph_pk,
-                );
-                log::debug!("send-side symmetric key registered: {:?}", send_secret);
-                self.send_aead = Some(NonObfsAead::new(send_secret.as_bytes()));
-                // we unblock the ticks because the ticker could be in the state where it's slowly retransmitting hellos
-                self.stream_tick_notify.set();
+                if !self.extra_lsks.is_empty() {
+                    // the send-side key depends on which identity the peer expects, which its first message tells us
+                    self.pending_serverhello = Some(eph_pk);
+                    return Ok(());
+                }
+                self.register_send_secret(eph_pk, &mut outgoing_callback);
                 Ok(())
             }
-            Frame::EncryptedMsg { inner } => {
-                let recv_aead = self
-                    .recv_aead
-                    .as_ref()
-                    .context("cannot decrypt messages without receive-side symmetric key")?;
-                let (nonce, inner) = recv_aead.decrypt(&inner)?;
-                if !self.replay_filter.add(nonce) {
-                    anyhow::bail!("replay filter caught nonce {nonce}");
+            Frame::EncryptedMsg { .. } | Frame::CompactMsg { .. } | Frame::Rekey { .. }
+                if !self.recv_candidates.is_empty() =>
+            {
+                let opened = self.open_resolving_identity(msg, &mut outgoing_callback);
+                self.recv_opened(opened, outgoing_callback, accept_callback)
+            }
+            Frame::EncryptedMsg { .. } | Frame::CompactMsg { .. } | Frame::Rekey { .. } => {
+                let opened = self
+                    .opener()
+                    .context("cannot decrypt messages without receive-side symmetric key")
+                    .and_then(|opener| opener.open(msg));
+                self.recv_opened(opened, outgoing_callback, accept_callback)
+            }
+        }
+    }
+
+    fn register_send_secret(
+        &mut self,
+        peer_eph_pk: x25519_dalek::PublicKey,
+        outgoing_callback: &mut impl FnMut(Frame),
+    ) {
+        let send_secret = triple_ecdh(
+            &self.local_lsk.0,
+            &self.local_esk_send,
+            &self.peer_lpk.unwrap().0,
+            &peer_eph_pk,
+        );
+        // a duplicate serverhello must not reset the nonce counter, or the other side's replay filter would drop what we send next
+        let send_secret_hash = blake3::hash(send_secret.as_bytes());
+        if self.send_secret_hash == Some(send_secret_hash) {
+            return;
+        }
+        log::debug!("send-side symmetric key registered");
+        trace_lifecycle("SendKeyRegistered", "", "");
+        let send_aead = NonObfsAead::new(send_secret.as_bytes());
+        // tells a peer with several identities which one we expect, without waiting for us to have anything to say
+        outgoing_callback(seal_msg(
+            &send_aead,
+            self.peer_version,
+            &StreamMessage::Empty,
+        ));
+        self.send_secret_hash = Some(send_secret_hash);
+        self.send_aead = Some(send_aead);
+        self.send_rekeyed = runtime::now();
+        self.setup.on_handshake();
+        // we unblock the ticks because the ticker could be in the state where it's slowly retransmitting hellos
+        self.stream_tick_notify.set();
+    }
+
+    /// Opens a message under whichever identity the peer expects, settling on that identity for the rest of the session.
+    fn open_resolving_identity(
+        &mut self,
+        msg: Frame,
+        outgoing_callback: &mut impl FnMut(Frame),
+    ) -> anyhow::Result<Opened> {
+        for i in 0..self.recv_candidates.len() {
+            let opener = Opener::new(self.recv_candidates[i].1.clone());
+            if let Ok(opened) = opener.open(msg.clone()) {
+                let (lsk, _) = self.recv_candidates.swap_remove(i);
+                log::debug!("peer expects identity {:?}", lsk.to_public());
+                trace_lifecycle(
+                    "IdentityResolved",
+                    "",
+                    hex::encode(lsk.to_public().as_bytes()),
+                );
+                self.recv_candidates.clear();
+                self.extra_lsks.clear();
+                self.local_lsk = lsk;
+                self.recv_keys = Some(opener);
+                if let Some(eph_pk) = self.pending_serverhello.take() {
+                    self.register_send_secret(eph_pk, outgoing_callback);
                 }
-                let inner: StreamMessage =
-                    stdcode::deserialize(&inner).context("could not deserialize message")?;
-                log::trace!("recv {:?}", inner);
-                trace_incoming_msg(&inner);
-                match &inner {
-                    StreamMessage::Reliable {
-                        kind: RelKind::Syn,
-                        stream_id,
-                        seqno: _,
-                        payload,
-                    } => {
-                        let stream_id = *stream_id;
-                        if let Some(stream) = self.stream_tab.get_mut(&stream_id) {
-                            stream.inject_incoming(inner);
-                        } else {
-                            let stream_tick_notify = self.stream_tick_notify.clone();
-                            let force_ticks = self.force_ticks.clone();
-                            // create a new stream in the right state. we don't need to do anything else
-                            let (mut stream, handle) = StreamState::new_established(
-                                move || {
-                                    force_ticks.push(stream_id);
-                                    stream_tick_notify.set();
-                                },
-                                stream_id,
-                                String::from_utf8_lossy(payload).to_string(),
-                            );
-
-                            stream.inject_incoming(inner); // this creates the syn-ack
-                            self.stream_tab.insert(stream_id, stream);
-                            accept_callback(handle);
-                        }
-                    }
-                    StreamMessage::Unreliable {
+                return Ok(opened);
+            }
+        }
+        anyhow::bail!("message opens under none of our identities")
+    }
+
+    /// Whether an encrypted frame was sealed by the peer and not seen before, spending its nonce in the replay filter if so. See [crate::Multiplex::claims].
+    pub fn claim(&mut self, frame: Frame) -> bool {
+        let Some(opener) = self.recv_keys.as_ref() else {
+            return false;
+        };
+        opener
+            .open(frame)
+            .is_ok_and(|opened| self.replay_filter.add(opened.nonce))
+    }
+
+    /// Processes an encrypted message that was already opened with [MultiplexState::opener], or that failed to open.
+    pub fn recv_opened(
+        &mut self,
+        opened: anyhow::Result<Opened>,
+        mut outgoing_callback: impl FnMut(Frame),
+        mut accept_callback: impl FnMut(Stream) -> bool,
+    ) -> anyhow::Result<()> {
+        let Opened {
+            nonce,
+            inner,
+            generation,
+        } = match opened {
+            Ok(opened) => opened,
+            Err(err) => {
+                self.drops.record(DropReason::Unauthenticated);
+                return Err(err);
+            }
+        };
+        if !self.replay_filter.add(nonce) {
+            self.drops.record(DropReason::Replayed);
+            anyhow::bail!("replay filter caught nonce {nonce}");
+        }
+        if let Some(keys) = self.recv_keys.as_mut() {
+            if generation > keys.current.generation() {
+                // whether or not its rekey frame made it here, the peer has moved on to its next key
+                keys.advance(nonce);
+                log::debug!("receive-side key ratcheted to generation {generation}");
+                trace_lifecycle(
+                    "RecvKeyRatcheted",
+                    "",
+                    format_args!("generation {generation}"),
+                );
+            }
+        }
+        let inner: StreamMessage = match stdcode::deserialize(&inner) {
+            Ok(inner) => inner,
+            Err(err) => {
+                self.drops.record(DropReason::Malformed);
+                return Err(err).context("could not deserialize message");
+            }
+        };
+        log::trace!("recv {:?}", inner);
+        trace_incoming_msg(&inner);
+        match &inner {
+            StreamMessage::Reliable {
+                kind: RelKind::Syn,
+                stream_id,
+                seqno: _,
+                payload,
+            } => {
+                let stream_id = *stream_id;
+                if let Some(stream) = self.stream_tab.get_mut(&stream_id) {
+                    stream.inject_incoming(inner);
+                } else if self.going_away || self.closing || self.peer_closed {
+                    log::debug!("refusing stream {stream_id}: closing the multiplex");
+                    outgoing_callback(self.rst_frame(stream_id, ResetCode::GoingAway)?);
+                } else {
+                    // create a new stream in the right state. we don't need to do anything else
+                    let (mut stream, handle) = StreamState::new_established(
+                        self.tick_notifier(stream_id),
                         stream_id,
-                        payload: _,
-                    } => {
-                        let stream = self
-                            .stream_tab
-                            .get_mut(stream_id)
-                            .context("dropping urel message with unknown stream id")?;
-                        stream.inject_incoming(inner);
+                        String::from_utf8_lossy(payload).to_string(),
+                    );
+                    self.init_stream(&mut stream);
+
+                    stream.inject_incoming(inner); // this creates the syn-ack
+                    self.stream_tab.insert(stream_id, stream);
+                    if !accept_callback(handle) {
+                        log::debug!("refusing stream {stream_id}: accept queue full");
+                        self.stream_tab.remove(&stream_id);
+                        outgoing_callback(self.rst_frame(stream_id, ResetCode::AcceptBacklogFull)?);
                     }
+                }
+            }
+            StreamMessage::Unreliable {
+                stream_id,
+                payload: _,
+            } => {
+                let stream = self
+                    .stream_tab
+                    .get_mut(stream_id)
+                    .context("dropping urel message with unknown stream id")?;
+                stream.inject_incoming(inner);
+            }
 
-                    StreamMessage::Reliable {
-                        kind,
-                        stream_id,
-                        seqno: _,
-                        payload: _,
-                    } => {
-                        if let Some(stream) = self.stream_tab.get_mut(stream_id) {
-                            stream.inject_incoming(inner);
-                        } else {
-                            // respond with a RST if the kind is not already an RST. This prevents infinite RST loops, but kills connections that the other side thinks exists but we know do not.
-                            if *kind != RelKind::Rst {
-                                let inner = StreamMessage::Reliable {
-                                    kind: RelKind::Rst,
-                                    stream_id: *stream_id,
-                                    seqno: 0,
-                                    payload: Bytes::new(),
-                                };
-                                let inner = self
-                                    .send_aead
-                                    .as_ref()
-                                    .context("cannot get send_aead to respond with RST")?
-                                    .encrypt(&inner.stdcode());
-                                outgoing_callback(Frame::EncryptedMsg { inner });
-                            }
-                        }
+            StreamMessage::Reliable {
+                kind,
+                stream_id,
+                seqno: _,
+                payload: _,
+            } => {
+                if let Some(stream) = self.stream_tab.get_mut(stream_id) {
+                    stream.inject_incoming(inner);
+                } else {
+                    // respond with a RST if the kind is not already an RST. This prevents infinite RST loops, but kills connections that the other side thinks exists but we know do not.
+                    // while closing, the Close tells the other side instead, and a RST overtaking it would close the stream for the wrong reason
+                    let close_tells = self.closing && self.peer_version >= 11;
+                    if *kind != RelKind::Rst && !close_tells {
+                        // peers that predate the code take it for an unspecified reset
+                        outgoing_callback(self.rst_frame(*stream_id, ResetCode::UnknownStream)?);
                     }
+                }
+            }
 
-                    StreamMessage::Empty => {}
+            StreamMessage::Datagram { payload } => self.datagrams.push_recv(payload.clone()),
+
+            StreamMessage::GoAway => {
+                log::debug!("the other side is closing the multiplex");
+                trace_lifecycle("GoAwayReceived", "", "");
+                self.peer_going_away = true;
+            }
+
+            StreamMessage::Ping { id } => {
+                outgoing_callback(self.seal_reply(&StreamMessage::Pong { id: *id })?);
+            }
+
+            StreamMessage::Pong { id } => {
+                if let Some((sent, pong)) = self.pings_sent.remove(id) {
+                    let _ = pong.try_send(runtime::elapsed(sent));
                 }
-                Ok(())
             }
+
+            StreamMessage::Close => {
+                // acked every time, in case an earlier ack was lost
+                outgoing_callback(self.seal_reply(&StreamMessage::CloseAck)?);
+                if !self.peer_closed {
+                    log::debug!("the other side closed the multiplex");
+                    trace_lifecycle("CloseReceived", "", "");
+                    self.peer_closed = true;
+                    self.close_streams(CloseReason::MultiplexClosedByPeer);
+                    self.close_event.notify(usize::MAX);
+                }
+            }
+
+            StreamMessage::CloseAck => {
+                if self.closing && !self.close_acked {
+                    trace_lifecycle("CloseAcked", "", "");
+                    self.close_acked = true;
+                    self.close_event.notify(usize::MAX);
+                }
+            }
+
+            StreamMessage::Empty => {}
+        }
+        Ok(())
+    }
+
+    /// Returns what seals messages from [MultiplexState::tick] for the peer, or `None` before the handshake is done.
+    pub fn sealer(&self) -> Option<Sealer> {
+        Some(Sealer {
+            send_aead: self.send_aead.clone()?,
+            peer_version: self.peer_version,
+        })
+    }
+
+    /// Returns what opens encrypted messages from the peer, to be passed on to [MultiplexState::recv_opened], or `None` before the handshake is done, or while it is not yet known which identity the peer expects.
+    pub fn opener(&self) -> Option<Opener> {
+        self.recv_keys.clone()
+    }
+
+    fn rst_frame(&self, stream_id: StreamId, code: ResetCode) -> anyhow::Result<Frame> {
+        self.seal_reply(&StreamMessage::Reliable {
+            kind: RelKind::Rst,
+            stream_id,
+            seqno: Seqno::ZERO,
+            payload: code.to_payload(),
+        })
+    }
+
+    /// Seals a message that answers an incoming one right away, rather than waiting for a tick.
+    fn seal_reply(&self, inner: &StreamMessage) -> anyhow::Result<Frame> {
+        let send_aead = self
+            .send_aead
+            .as_ref()
+            .context("cannot get send_aead to respond")?;
+        Ok(seal_msg(send_aead, self.peer_version, inner))
+    }
+}
+
+/// Seals messages for the peer. Cheap to clone, and usable on any thread, so that encryption need not happen while the state is locked.
+#[derive(Clone)]
+pub struct Sealer {
+    send_aead: NonObfsAead,
+    peer_version: u64,
+}
+
+impl Sealer {
+    pub fn seal(&self, msg: &StreamMessage) -> Frame {
+        seal_msg(&self.send_aead, self.peer_version, msg)
+    }
+}
+
+/// Opens encrypted messages from the peer. Like [Sealer], usable on any thread.
+#[derive(Clone)]
+pub struct Opener {
+    current: NonObfsAead,
+    // the first nonce opened with the current key
+    current_since: u64,
+    // the key before the current one, kept for a while for messages delayed past a rekey, and when it was replaced
+    previous: Option<(NonObfsAead, Instant)>,
+    // the key after the current one, so that the peer rekeying is noticed even if its rekey frame is lost
+    next: NonObfsAead,
+}
+
+/// A message opened by an [Opener].
+pub struct Opened {
+    pub nonce: u64,
+    pub inner: Bytes,
+    /// The generation of the key it was sealed with. See [NonObfsAead::generation].
+    pub generation: u64,
+}
+
+impl Opener {
+    fn new(recv_aead: NonObfsAead) -> Self {
+        Self {
+            next: recv_aead.ratchet(),
+            current: recv_aead,
+            current_since: 0,
+            previous: None,
+        }
+    }
+
+    pub fn open(&self, frame: Frame) -> anyhow::Result<Opened> {
+        match frame {
+            Frame::CompactMsg { nonce, inner } | Frame::Rekey { nonce, inner } => {
+                // nonces keep counting up across rekeys, so they tell which key a message was most likely sealed with
+                let candidates = if nonce < self.current_since {
+                    [
+                        self.previous.as_ref().map(|(aead, _)| aead),
+                        Some(&self.current),
+                    ]
+                } else {
+                    [Some(&self.current), Some(&self.next)]
+                };
+                for aead in candidates.into_iter().flatten() {
+                    if let Ok(inner) = aead.decrypt_split(nonce, &inner) {
+                        return Ok(Opened {
+                            nonce,
+                            inner,
+                            generation: aead.generation(),
+                        });
+                    }
+                }
+                Err(AeadError::DecryptionFailure.into())
+            }
+            // peers that only send these predate rekeying
+            Frame::EncryptedMsg { inner } => {
+                let (nonce, inner) = self.current.decrypt(&inner)?;
+                Ok(Opened {
+                    nonce,
+                    inner,
+                    generation: self.current.generation(),
+                })
+            }
+            _ => anyhow::bail!("not an encrypted message"),
         }
     }
+
+    /// Moves on to the next key, which the message with the given nonce was the first to be opened with.
+    fn advance(&mut self, nonce: u64) {
+        let next = self.next.ratchet();
+        let current = std::mem::replace(&mut self.current, std::mem::replace(&mut self.next, next));
+        self.previous = Some((current, runtime::now()));
+        self.current_since = nonce;
+    }
+
+    /// When the previous key is due to be forgotten, if there is one.
+    fn previous_deadline(&self, grace: Duration) -> Option<Instant> {
+        self.previous
+            .as_ref()
+            .map(|(_, replaced)| *replaced + grace)
+    }
+
+    /// Forgets the previous key once it was replaced longer than the given time ago.
+    fn forget_previous_after(&mut self, grace: Duration) {
+        if self
+            .previous_deadline(grace)
+            .is_some_and(|deadline| deadline <= runtime::now())
+        {
+            self.previous = None;
+        }
+    }
+}
+
+/// Encrypts a message into a frame, in the most compact format the peer understands.
+fn seal_msg(send_aead: &NonObfsAead, peer_version: u64, msg: &StreamMessage) -> Frame {
+    if peer_version >= 2 {
+        let (nonce, inner) = send_aead.encrypt_split(&msg.stdcode());
+        Frame::CompactMsg { nonce, inner }
+    } else {
+        Frame::EncryptedMsg {
+            inner: send_aead.encrypt(&msg.stdcode()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+    use smol::prelude::*;
+
+    use crate::{
+        frame::{Frame, PROTOCOL_VERSION},
+        sim::{sim_pipe_pair, SimLink},
+        utilities::runtime,
+        CloseReason, CongestionAlgorithm, Multiplex, MuxSecret, RekeyPolicy,
+    };
+
+    #[test]
+    fn test_rotated_identity() {
+        smol::block_on(async {
+            let old_sk = MuxSecret::generate();
+            let new_sk = MuxSecret::generate();
+            for (expected, server_opens) in [(&old_sk, false), (&new_sk, false), (&old_sk, true)] {
+                let server = Multiplex::new(new_sk.clone(), None);
+                server.set_extra_identities(vec![old_sk.clone()]);
+                let client = Multiplex::new(MuxSecret::generate(), Some(expected.to_public()));
+                let (client_pipe, server_pipe) = sim_pipe_pair(SimLink::default());
+                client.add_pipe(client_pipe);
+                server.add_pipe(server_pipe);
+
+                let (mut opened, mut accepted) = if server_opens {
+                    // the client has no streams yet, but still tells the server which identity it expects
+                    let opened = server.open_conn("").await.unwrap();
+                    (opened, client.accept_conn().await.unwrap())
+                } else {
+                    let opened = client.open_conn("").await.unwrap();
+                    (opened, server.accept_conn().await.unwrap())
+                };
+                opened.write_all(b"ping").await.unwrap();
+                opened.flush().await.unwrap();
+                let mut buf = [0u8; 4];
+                accepted.read_exact(&mut buf).await.unwrap();
+                accepted.write_all(b"pong").await.unwrap();
+                accepted.flush().await.unwrap();
+                opened.read_exact(&mut buf).await.unwrap();
+                assert_eq!(&buf, b"pong");
+                assert_eq!(server.local_pk(), expected.to_public());
+            }
+        })
+    }
+
+    #[test]
+    fn test_streams_from_both_sides() {
+        smol::block_on(async {
+            let server_sk = MuxSecret::generate();
+            let server = Multiplex::new(server_sk.clone(), None);
+            let client = Multiplex::new(MuxSecret::generate(), Some(server_sk.to_public()));
+            let (client_pipe, server_pipe) = sim_pipe_pair(SimLink::default());
+            client.add_pipe(client_pipe);
+            server.add_pipe(server_pipe);
+
+            // both sides open many streams at once, which must neither collide nor get mixed up
+            let mut opened = vec![];
+            for i in 0..50 {
+                opened.push(server.open_conn_early(&format!("push {i}")).unwrap());
+                opened.push(client.open_conn_early(&format!("pull {i}")).unwrap());
+            }
+            for stream in opened.iter() {
+                stream.wait_connected().await.unwrap();
+            }
+            let mut pushed = vec![];
+            let mut pulled = vec![];
+            for _ in 0..50 {
+                pushed.push(client.accept_conn().await.unwrap().label().to_owned());
+                pulled.push(server.accept_conn().await.unwrap().label().to_owned());
+            }
+            pushed.sort();
+            pulled.sort();
+            let mut expected_pushed: Vec<_> = (0..50).map(|i| format!("push {i}")).collect();
+            let mut expected_pulled: Vec<_> = (0..50).map(|i| format!("pull {i}")).collect();
+            expected_pushed.sort();
+            expected_pulled.sort();
+            assert_eq!(pushed, expected_pushed);
+            assert_eq!(pulled, expected_pulled);
+        })
+    }
+
+    #[test]
+    fn test_graceful_close() {
+        smol::block_on(async {
+            let server_sk = MuxSecret::generate();
+            let server = Multiplex::new(server_sk.clone(), None);
+            let client = Multiplex::new(MuxSecret::generate(), Some(server_sk.to_public()));
+            let (client_pipe, server_pipe) = sim_pipe_pair(SimLink {
+                delay: Duration::from_millis(20),
+                ..Default::default()
+            });
+            client.add_pipe(client_pipe);
+            server.add_pipe(server_pipe);
+
+            let mut opened = client.open_conn("").await.unwrap();
+            let mut accepted = server.accept_conn().await.unwrap();
+            let data = vec![7u8; 200_000];
+            opened.write_all(&data).await.unwrap();
+            // whatever was written still gets across
+            client
+                .graceful_close(Duration::from_secs(30))
+                .await
+                .unwrap();
+            let mut buf = vec![0u8; data.len()];
+            accepted.read_exact(&mut buf).await.unwrap();
+            assert_eq!(buf, data);
+
+            assert!(client.open_conn("").await.is_err());
+            assert!(client.accept_conn().await.is_err());
+            // the other side was told not to bother opening streams
+            assert!(server.open_conn("").await.is_err());
+            assert!(client.pipe_stats().is_empty());
+        })
+    }
+
+    #[test]
+    fn test_drop_linger() {
+        smol::block_on(async {
+            let server_sk = MuxSecret::generate();
+            let server = Multiplex::new(server_sk.clone(), None);
+            let client = Multiplex::new(MuxSecret::generate(), Some(server_sk.to_public()));
+            let (client_pipe, server_pipe) = sim_pipe_pair(SimLink {
+                delay: Duration::from_millis(20),
+                ..Default::default()
+            });
+            client.add_pipe(client_pipe);
+            server.add_pipe(server_pipe);
+
+            let mut opened = client.open_conn("").await.unwrap();
+            let mut accepted = server.accept_conn().await.unwrap();
+            let data = vec![7u8; 200_000];
+            opened.write_all(&data).await.unwrap();
+            // fire and forget: whatever was written still gets across, and then the stream closes
+            drop(opened);
+            drop(client);
+            let mut buf = vec![0u8; data.len()];
+            accepted.read_exact(&mut buf).await.unwrap();
+            assert_eq!(buf, data);
+            assert_eq!(accepted.read(&mut buf).await.unwrap_or(0), 0);
+            // and then the multiplex closes too
+            assert_eq!(
+                server.accept_conn().await.err().map(|e| e.kind()),
+                Some(std::io::ErrorKind::ConnectionAborted)
+            );
+        })
+    }
+
+    #[test]
+    fn test_shared_congestion() {
+        smol::block_on(async {
+            let server_sk = MuxSecret::generate();
+            let server = Multiplex::new(server_sk.clone(), None);
+            let client = Multiplex::new(MuxSecret::generate(), Some(server_sk.to_public()));
+            client.set_shared_congestion(true).unwrap();
+            let (client_pipe, server_pipe) = sim_pipe_pair(SimLink {
+                delay: Duration::from_millis(20),
+                loss: 0.01,
+                bandwidth: Some(2_000_000.0),
+                queue_limit: 100_000,
+                ..Default::default()
+            });
+            client.add_pipe(client_pipe);
+            server.add_pipe(server_pipe);
+
+            let data: Vec<u8> = (0..300_000u32).map(|i| i as u8).collect();
+            let mut transfers = vec![];
+            for _ in 0..4 {
+                let mut opened = client.open_conn("").await.unwrap();
+                let mut accepted = server.accept_conn().await.unwrap();
+                let data = data.clone();
+                transfers.push(runtime::spawn(async move {
+                    opened.write_all(&data).await.unwrap();
+                    let mut buf = vec![0u8; data.len()];
+                    accepted.read_exact(&mut buf).await.unwrap();
+                    assert_eq!(buf, data);
+                }));
+            }
+            // every stream gets through, however little of the one window the others leave it
+            for transfer in transfers {
+                transfer.await;
+            }
+        })
+    }
+
+    #[test]
+    fn test_ping() {
+        smol::block_on(async {
+            let server_sk = MuxSecret::generate();
+            let server = Multiplex::new(server_sk.clone(), None);
+            let client = Multiplex::new(MuxSecret::generate(), Some(server_sk.to_public()));
+            let (client_pipe, server_pipe) = sim_pipe_pair(SimLink {
+                delay: Duration::from_millis(50),
+                ..Default::default()
+            });
+            client.add_pipe(client_pipe);
+            server.add_pipe(server_pipe);
+
+            // sent once the handshake is done, so it does not count towards the RTT, though a loaded machine may add to it
+            let rtt = client.ping().await.unwrap();
+            assert!(
+                (Duration::from_millis(100)..Duration::from_secs(1)).contains(&rtt),
+                "{rtt:?}"
+            );
+            let rtt = server.ping().await.unwrap();
+            assert!(rtt >= Duration::from_millis(100), "{rtt:?}");
+        })
+    }
+
+    #[test]
+    fn test_close_handshake() {
+        smol::block_on(async {
+            let server_sk = MuxSecret::generate();
+            let server = Multiplex::new(server_sk.clone(), None);
+            let client = Multiplex::new(MuxSecret::generate(), Some(server_sk.to_public()));
+            let (client_pipe, server_pipe) = sim_pipe_pair(SimLink {
+                delay: Duration::from_millis(50),
+                ..Default::default()
+            });
+            client.add_pipe(client_pipe);
+            server.add_pipe(server_pipe);
+            let stream = client.open_conn("").await.unwrap();
+            let mut accepted = server.accept_conn().await.unwrap();
+
+            // acked within a round trip, and the other side knows why its stream closed
+            let start = Instant::now();
+            client.close().await.unwrap();
+            assert!(start.elapsed() < Duration::from_secs(1));
+            assert_eq!(stream.close_reason(), Some(CloseReason::MultiplexClosed));
+            assert_eq!(accepted.read(&mut [0u8; 10]).await.unwrap_or(0), 0);
+            assert_eq!(
+                accepted.close_reason(),
+                Some(CloseReason::MultiplexClosedByPeer)
+            );
+            assert_eq!(
+                server.accept_conn().await.err().map(|e| e.kind()),
+                Some(std::io::ErrorKind::ConnectionAborted)
+            );
+            assert!(server.open_conn("").await.is_err());
+        })
+    }
+
+    #[test]
+    fn test_replayed_hello() {
+        smol::block_on(async {
+            let server_sk = MuxSecret::generate();
+            let client_sk = MuxSecret::generate();
+            let server = Multiplex::new(server_sk.clone(), None);
+            let client = Multiplex::new(client_sk.clone(), Some(server_sk.to_public()));
+            let (client_pipe, server_pipe) = sim_pipe_pair(SimLink::default());
+            client.add_pipe(client_pipe);
+            server.add_pipe(server_pipe);
+            client.open_conn("").await.unwrap();
+            server.accept_conn().await.unwrap();
+
+            // the client's long-term key is public, so anyone can make up a hello naming it
+            let mut state = server.state.lock();
+            assert_eq!(state.peer_version, PROTOCOL_VERSION);
+            state
+                .recv_msg(
+                    Frame::ClientHello {
+                        long_pk: client_sk.to_public(),
+                        eph_pk: (&x25519_dalek::StaticSecret::new(rand::thread_rng())).into(),
+                        version: 1,
+                        timestamp: SystemTime::now()
+                            .duration_since(UNIX_EPOCH)
+                            .unwrap()
+                            .as_secs(),
+                    },
+                    |_| {},
+                    |_| true,
+                )
+                .unwrap();
+            assert_eq!(state.peer_version, PROTOCOL_VERSION);
+        })
+    }
+
+    #[test]
+    fn test_rekey() {
+        smol::block_on(async {
+            let server_sk = MuxSecret::generate();
+            let server = Multiplex::new(server_sk.clone(), None);
+            let client = Multiplex::new(MuxSecret::generate(), Some(server_sk.to_public()));
+            let policy = RekeyPolicy {
+                max_bytes: 10_000,
+                ..Default::default()
+            };
+            server.set_rekey_policy(Some(policy));
+            client.set_rekey_policy(Some(policy));
+            // some rekey frames get lost, which the other side must get over
+            let (client_pipe, server_pipe) = sim_pipe_pair(SimLink {
+                loss: 0.05,
+                ..Default::default()
+            });
+            client.add_pipe(client_pipe);
+            server.add_pipe(server_pipe);
+
+            let opened = client.open_conn("").await.unwrap();
+            let accepted = server.accept_conn().await.unwrap();
+            let data: Vec<u8> = (0..200_000u32).map(|i| i as u8).collect();
+            for (mut from, mut to) in [
+                (opened.clone(), accepted.clone()),
+                (accepted.clone(), opened.clone()),
+            ] {
+                from.write_all(&data).await.unwrap();
+                from.flush().await.unwrap();
+                let mut buf = vec![0u8; data.len()];
+                to.read_exact(&mut buf).await.unwrap();
+                assert_eq!(buf, data);
+            }
+            for mux in [&client, &server] {
+                let state = mux.state.lock();
+                assert!(state.send_aead.as_ref().unwrap().generation() > 10);
+                assert!(state.recv_keys.as_ref().unwrap().current.generation() > 10);
+            }
+        })
+    }
+
+    #[test]
+    fn test_accept_backlog() {
+        smol::block_on(async {
+            let server_sk = MuxSecret::generate();
+            let server = Multiplex::new(server_sk.clone(), None);
+            server.set_accept_backlog(2);
+            let client = Multiplex::new(MuxSecret::generate(), Some(server_sk.to_public()));
+            let (client_pipe, server_pipe) = sim_pipe_pair(SimLink::default());
+            client.add_pipe(client_pipe);
+            server.add_pipe(server_pipe);
+
+            // nobody accepts, so the backlog fills up and the stream after it is refused
+            client.open_conn("first").await.unwrap();
+            client.open_conn("second").await.unwrap();
+            let err = client.open_conn("third").await.err().unwrap();
+            assert_eq!(err.kind(), std::io::ErrorKind::ConnectionRefused);
+            assert!(err.to_string().contains("accept queue is full"), "{err}");
+
+            // accepting makes room again
+            assert_eq!(server.accept_conn().await.unwrap().label(), "first");
+            client.open_conn("fourth").await.unwrap();
+            assert_eq!(server.accept_conn().await.unwrap().label(), "second");
+            assert_eq!(server.accept_conn().await.unwrap().label(), "fourth");
+        })
+    }
+
+    #[test]
+    fn weights_are_rejected_where_they_cannot_be_honoured() {
+        smol::block_on(async {
+            let mux = Multiplex::new(MuxSecret::generate(), None);
+            assert!(mux.set_group_weight("bulk", 0.0).is_err());
+            mux.set_congestion_control(CongestionAlgorithm::Bbr)
+                .unwrap();
+            assert!(mux.set_group_weight("bulk", 2.0).is_err());
+            mux.set_group_weight("bulk", 1.0).unwrap();
+
+            mux.set_congestion_control(CongestionAlgorithm::Cubic)
+                .unwrap();
+            mux.set_group_weight("bulk", 2.0).unwrap();
+            assert!(mux
+                .set_congestion_control(CongestionAlgorithm::Bbr)
+                .is_err());
+            assert!(mux.set_shared_congestion(true).is_err());
+            mux.set_group_weight("bulk", 1.0).unwrap();
+            mux.set_shared_congestion(true).unwrap();
+            assert!(mux.set_group_weight("bulk", 2.0).is_err());
+        })
+    }
 }
@@ -0,0 +1,52 @@
+use std::time::Duration;
+
+use super::StreamStats;
+
+/// A snapshot of the state of all the streams open in a [crate::Multiplex], returned by [crate::Multiplex::stats]. Streams that have closed no longer count.
+#[derive(Clone, Debug, Default)]
+pub struct MultiplexStats {
+    /// Streams currently open.
+    pub streams: usize,
+    /// The sum of the streams' congestion windows, in packets.
+    pub cwnd: f64,
+    /// Packets sent but not yet acknowledged, over all streams.
+    pub inflight: usize,
+    /// Data packets sent for the first time, over all streams.
+    pub data_packets_sent: u64,
+    /// Data packets sent again because they were thought lost, over all streams.
+    pub retransmissions: u64,
+    /// The fraction of all data packets sent that were retransmissions.
+    pub retransmission_ratio: f64,
+    /// The sum of the streams' delivery rates, in packets per second. See [StreamStats::delivery_rate].
+    pub delivery_rate: f64,
+    /// The lowest RTT measured on any stream, or `None` before the first measurement.
+    pub min_rtt: Option<Duration>,
+    /// Bytes received and waiting to be read by the application, over all streams.
+    pub read_queue_bytes: usize,
+    /// Bytes written by the application and waiting to be sent, over all streams.
+    pub write_queue_bytes: usize,
+}
+
+impl MultiplexStats {
+    /// Adds a stream's statistics to the totals.
+    pub(crate) fn add(&mut self, stream: &StreamStats) {
+        self.streams += 1;
+        self.cwnd += stream.cwnd;
+        self.inflight += stream.inflight;
+        self.data_packets_sent += stream.data_packets_sent;
+        self.retransmissions += stream.retransmissions;
+        let sent = self.data_packets_sent + self.retransmissions;
+        self.retransmission_ratio = if sent == 0 {
+            0.0
+        } else {
+            self.retransmissions as f64 / sent as f64
+        };
+        self.delivery_rate += stream.delivery_rate;
+        self.min_rtt = match (self.min_rtt, stream.min_rtt) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        };
+        self.read_queue_bytes += stream.read_queue_bytes;
+        self.write_queue_bytes += stream.write_queue_bytes;
+    }
+}
@@ -0,0 +1,251 @@
+use std::time::{Duration, Instant};
+
+use crate::utilities::runtime;
+
+use super::{
+    constants::DEFAULT_FAST_RETRANSMIT_THRESHOLD,
+    stream::{AckEvent, CongestionAlgorithm, CongestionControl, LossStats},
+};
+
+/// The RTT that paths are paced by before any of their segments is acked.
+const INITIAL_RTT: Duration = Duration::from_millis(100);
+
+/// The smoothing factor of the path RTT, as in TCP.
+const SRTT_ALPHA: f64 = 0.125;
+
+/// How many segments a path may send at once after being idle.
+const MAX_BURST: f64 = 8.0;
+
+/// How many times the one-way delay of the fastest pipe a segment may take over a slower pipe, rather than wait for room on the fastest: waiting takes about a round trip for the window to open, and then the one-way delay.
+const ECF_FACTOR: u32 = 3;
+
+/// Which of the pipes with the given one-way delays a segment should go over rather than wait for room on the fastest of them, as the ECF scheduler decides: those whose delay is within [ECF_FACTOR] times that of the fastest. A segment sent over a pipe much slower than that would arrive after segments sent later over the fastest pipe, and hold them up at the receiver. Pipes whose delay is unknown always qualify.
+pub fn earliest_arrivals(delays: &[Option<Duration>]) -> Vec<bool> {
+    let fastest = delays.iter().flatten().min().copied();
+    delays
+        .iter()
+        .map(|delay| match (delay, fastest) {
+            (Some(delay), Some(fastest)) => *delay <= fastest * ECF_FACTOR,
+            _ => true,
+        })
+        .collect()
+}
+
+/// The congestion window and pacing of one pipe, which the policies that stripe segments over several pipes send against, as MPTCP gives each subflow its own.
+///
+/// A stream's own window describes the one path all its segments take, which under striping is none of them: it would grow with the acks of the fast path and shrink with the losses of the slow one. Instead, every data segment is counted against the pipe it went out over until it is acked or found lost, so that each pipe gets only as many segments as its own window and pacing rate allow.
+pub struct PathCongestion {
+    cc: Box<dyn CongestionControl>,
+    inflight: usize,
+    srtt: Option<Duration>,
+    min_rtt: Option<Duration>,
+    latest_rtt: Option<Duration>,
+    // losses until then belong to the recovery episode that already shrank the window
+    recovery_until: Option<Instant>,
+    // segments sent over the path so far, and the count when the latest one acked was sent
+    sent: u64,
+    acked: u64,
+    tokens: f64,
+    refilled: Instant,
+    loss: LossStats,
+}
+
+impl Default for PathCongestion {
+    fn default() -> Self {
+        Self {
+            cc: CongestionAlgorithm::default().build(),
+            inflight: 0,
+            srtt: None,
+            min_rtt: None,
+            latest_rtt: None,
+            recovery_until: None,
+            sent: 0,
+            acked: 0,
+            tokens: MAX_BURST,
+            refilled: runtime::now(),
+            loss: LossStats::default(),
+        }
+    }
+}
+
+impl PathCongestion {
+    pub fn cwnd(&self) -> f64 {
+        self.cc.cwnd()
+    }
+
+    pub fn srtt(&self) -> Option<Duration> {
+        self.srtt
+    }
+
+    pub fn min_rtt(&self) -> Option<Duration> {
+        self.min_rtt
+    }
+
+    /// Histograms of the losses of the segments sent over the path, each counted against the pipe it last went out over.
+    pub fn loss_stats(&self) -> &LossStats {
+        &self.loss
+    }
+
+    /// Segments sent over the path that are neither acked nor found lost.
+    pub fn inflight(&self) -> usize {
+        self.inflight
+    }
+
+    /// How many segments per second the path is paced at.
+    pub fn pacing_rate(&self) -> f64 {
+        self.cc
+            .pacing_rate(self.min_rtt.unwrap_or(INITIAL_RTT))
+            .max(1.0)
+    }
+
+    /// Whether both the window and the pacing of the path allow another segment now.
+    pub fn has_room(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        (self.inflight as f64) < self.cc.cwnd() && self.tokens >= 1.0
+    }
+
+    /// Counts a segment sent over the path, returning its place among all segments sent over it.
+    pub fn on_sent(&mut self, now: Instant) -> u64 {
+        self.refill(now);
+        self.inflight += 1;
+        self.tokens = (self.tokens - 1.0).max(0.0);
+        self.sent += 1;
+        self.sent
+    }
+
+    /// Handles the ack of the segment that was the given one sent over the path, with its RTT unless it was retransmitted, which makes the RTT ambiguous.
+    pub fn on_acked(&mut self, serial: u64, rtt: Option<Duration>, now: Instant) {
+        self.inflight = self.inflight.saturating_sub(1);
+        self.acked = self.acked.max(serial);
+        self.loss.record(false);
+        if let Some(rtt) = rtt {
+            self.latest_rtt = Some(rtt);
+            self.min_rtt = Some(self.min_rtt.map_or(rtt, |min_rtt| min_rtt.min(rtt)));
+            self.srtt = Some(match self.srtt {
+                Some(srtt) => srtt.mul_f64(1.0 - SRTT_ALPHA) + rtt.mul_f64(SRTT_ALPHA),
+                None => rtt,
+            });
+        }
+        let min_rtt = self.min_rtt.unwrap_or(INITIAL_RTT);
+        self.cc.on_ack(&AckEvent {
+            now,
+            acked: 1,
+            inflight: self.inflight,
+            min_rtt,
+            srtt: self.srtt.unwrap_or(min_rtt),
+            latest_rtt: self.latest_rtt,
+            delivery_rate: self.pacing_rate(),
+        });
+    }
+
+    /// Handles the stream of a segment sent over the path retransmitting it, with when and as which segment of the path it was sent. The window shrinks, once per round trip's worth of losses, if the path lost it: segments sent after it over the same path were acked, or it timed out. A segment that streams took for lost only because segments over faster paths overtook it leaves the window alone.
+    pub fn on_lost(&mut self, serial: u64, sent: Instant, now: Instant) {
+        self.inflight = self.inflight.saturating_sub(1);
+        self.loss.record(true);
+        let overtaken = self.overtaken(serial);
+        let timed_out = now.saturating_duration_since(sent) > 2 * self.srtt.unwrap_or(INITIAL_RTT);
+        if !overtaken && !timed_out {
+            return;
+        }
+        if self.recovery_until.is_none_or(|until| now >= until) {
+            self.cc.on_loss(now);
+            self.recovery_until = Some(now + self.srtt.unwrap_or(INITIAL_RTT));
+        }
+    }
+
+    /// Whether enough segments sent over the path after the given one were acked for it to count as lost, as in fast retransmit.
+    pub fn overtaken(&self, serial: u64) -> bool {
+        serial + DEFAULT_FAST_RETRANSMIT_THRESHOLD < self.acked
+    }
+
+    /// Forgets a segment sent over the path that will never be acked, such as one of a stream that closed.
+    pub fn on_forgotten(&mut self) {
+        self.inflight = self.inflight.saturating_sub(1);
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.refilled);
+        self.refilled = now;
+        self.tokens = (self.tokens + elapsed.as_secs_f64() * self.pacing_rate()).min(MAX_BURST);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn window_and_pacing_limit_the_path() {
+        let mut path = PathCongestion::default();
+        let now = Instant::now();
+        let mut sent = 0;
+        while path.has_room(now) {
+            path.on_sent(now);
+            sent += 1;
+        }
+        // the initial window of the default algorithm
+        assert_eq!(sent, path.cwnd() as usize);
+        assert_eq!(path.inflight(), sent);
+
+        // acks open the window, but pacing only lets more out as time passes
+        for serial in 1..=sent as u64 {
+            path.on_acked(serial, Some(Duration::from_millis(50)), now);
+        }
+        let mut burst = 0;
+        while path.has_room(now) {
+            path.on_sent(now);
+            burst += 1;
+        }
+        assert!(burst < path.cwnd() as usize);
+        assert!(path.has_room(now + Duration::from_millis(50)));
+    }
+
+    #[test]
+    fn one_window_reduction_per_round_trip() {
+        let mut path = PathCongestion::default();
+        let now = Instant::now();
+        for _ in 0..10 {
+            path.on_sent(now);
+        }
+        path.on_acked(10, Some(Duration::from_millis(50)), now);
+        let cwnd = path.cwnd();
+        path.on_lost(1, now, now);
+        let reduced = path.cwnd();
+        assert!(reduced < cwnd);
+        // the rest of the burst was lost along with it
+        path.on_lost(2, now, now + Duration::from_millis(10));
+        assert_eq!(path.cwnd(), reduced);
+        // the next round trip, a timeout
+        path.on_lost(9, now, now + Duration::from_millis(200));
+        assert!(path.cwnd() < reduced);
+        assert_eq!(path.inflight(), 6);
+        let loss = path.loss_stats();
+        assert_eq!((loss.delivered, loss.lost), (1, 3));
+    }
+
+    #[test]
+    fn slow_pipes_wait_for_the_fastest() {
+        let ms = Duration::from_millis;
+        assert_eq!(
+            earliest_arrivals(&[Some(ms(10)), Some(ms(25)), None, Some(ms(80))]),
+            [true, true, true, false]
+        );
+        assert_eq!(earliest_arrivals(&[None, None]), [true, true]);
+    }
+
+    #[test]
+    fn overtaken_by_other_paths() {
+        let mut path = PathCongestion::default();
+        let now = Instant::now();
+        for _ in 0..4 {
+            path.on_sent(now);
+        }
+        path.on_acked(1, Some(Duration::from_millis(50)), now);
+        // streams retransmit the rest because segments over other paths were acked, well before this path times out
+        let cwnd = path.cwnd();
+        path.on_lost(2, now, now + Duration::from_millis(20));
+        path.on_lost(3, now, now + Duration::from_millis(20));
+        assert_eq!(path.cwnd(), cwnd);
+        assert_eq!(path.inflight(), 1);
+    }
+}
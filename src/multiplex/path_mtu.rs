@@ -0,0 +1,240 @@
+use std::{
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+
+use bytes::Bytes;
+use event_listener::Event;
+use smol::channel::Receiver;
+
+use crate::utilities::runtime::{self, TimeoutExt};
+
+use super::{constants::BASE_PLPMTU, pipe_stats::PipeCounters, rng::MuxRng};
+
+/// The smallest datagram size searched for, in case even [BASE_PLPMTU] does not get through.
+const MIN_PLPMTU: usize = 512;
+/// The largest datagram size searched for, that of an Ethernet MTU after IPv4 and UDP headers.
+const MAX_PLPMTU: usize = 1472;
+/// The search stops once it has narrowed the path MTU down to this many bytes.
+const SEARCH_PRECISION: usize = 16;
+/// How many probes of a size must go unanswered before the size is taken not to get through, rather than the probes to have been lost.
+const MAX_PROBES: usize = 3;
+/// How often the path MTU found is confirmed, to catch paths that start dropping datagrams of that size.
+const CONFIRM_INTERVAL: Duration = Duration::from_secs(30);
+/// How often the search is started over, to catch paths that carry larger datagrams than before.
+const RAISE_INTERVAL: Duration = Duration::from_secs(600);
+
+/// How often a search waiting for the pipe to carry an authenticated message checks again.
+const AUTHENTICATED_POLL: Duration = Duration::from_millis(100);
+
+const PROBE_MAGIC: &[u8] = b"!!mtup!!";
+const ACK_MAGIC: &[u8] = b"!!mtua!!";
+
+/// Whether path MTU discovery is on, shared by the searches of all pipes of a pool.
+#[derive(Default)]
+pub(crate) struct PathMtuSwitch {
+    enabled: AtomicBool,
+    event: Event,
+}
+
+impl PathMtuSwitch {
+    pub fn set_enabled(&self, enabled: bool) {
+        self.enabled.store(enabled, Ordering::Relaxed);
+        self.event.notify(usize::MAX);
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    async fn wait_enabled(&self) {
+        loop {
+            let listener = self.event.listen();
+            if self.is_enabled() {
+                return;
+            }
+            listener.await;
+        }
+    }
+}
+
+/// A probe that, with `overhead` more bytes added on its way to the pipe, makes a datagram of `size` bytes. The padding is random, so that it compresses no better than anything else.
+pub(crate) fn probe(size: usize, overhead: usize, rng: &MuxRng) -> Bytes {
+    let len = size.saturating_sub(overhead).max(PROBE_MAGIC.len() + 2);
+    let mut pkt = vec![0u8; len];
+    pkt[..PROBE_MAGIC.len()].copy_from_slice(PROBE_MAGIC);
+    pkt[PROBE_MAGIC.len()..][..2].copy_from_slice(&(size as u16).to_le_bytes());
+    rng.fill(&mut pkt[PROBE_MAGIC.len() + 2..]);
+    pkt.into()
+}
+
+/// If the datagram is a probe, returns the answer to it. Only probes over pipes that carried an authenticated message are answered, so that nobody else learns there is a session here.
+pub(crate) fn answer_probe(pkt: &[u8]) -> Option<Bytes> {
+    let size = pkt.strip_prefix(PROBE_MAGIC)?.get(..2)?;
+    Some([ACK_MAGIC, size].concat().into())
+}
+
+/// If the datagram answers a probe, returns the size of the datagram the probe made.
+pub(crate) fn parse_ack(pkt: &[u8]) -> Option<usize> {
+    let size = pkt.strip_prefix(ACK_MAGIC)?;
+    Some(u16::from_le_bytes(size.try_into().ok()?) as usize)
+}
+
+/// Finds the largest datagrams that make it across a pipe while path MTU discovery is on, DPLPMTUD-style: probes padded to various sizes are sent with `send_probe`, and the sizes answered, as passed to `acks`, are taken to get through. The result goes in the pipe's counters.
+///
+/// The search waits for the pipe to carry an authenticated message, since the other side answers no probes before then.
+pub(crate) async fn discover_path_mtu(
+    switch: &PathMtuSwitch,
+    counters: &PipeCounters,
+    acks: Receiver<usize>,
+    send_probe: impl Fn(usize),
+) {
+    loop {
+        switch.wait_enabled().await;
+        while !counters.is_authenticated() {
+            runtime::Timer::after(AUTHENTICATED_POLL).await;
+        }
+        let mtu = search(counters, &acks, &send_probe).await;
+        log::debug!("path MTU is {mtu}");
+        counters.set_path_mtu(Some(mtu));
+        let searched = runtime::now();
+        loop {
+            runtime::Timer::after(CONFIRM_INTERVAL).await;
+            if !switch.is_enabled() {
+                counters.set_path_mtu(None);
+                break;
+            }
+            if !probe_size(mtu, counters, &acks, &send_probe).await {
+                // a black hole: datagrams of the size found no longer make it, so fall back to the base size while searching again
+                log::warn!("datagrams of {mtu} bytes no longer get through, searching again");
+                counters.set_path_mtu(None);
+                break;
+            }
+            if runtime::elapsed(searched) >= RAISE_INTERVAL {
+                break;
+            }
+        }
+    }
+}
+
+/// Searches for the largest size that gets through, starting with [BASE_PLPMTU].
+async fn search(
+    counters: &PipeCounters,
+    acks: &Receiver<usize>,
+    send_probe: impl Fn(usize),
+) -> usize {
+    let (mut low, mut high) = (MIN_PLPMTU, MAX_PLPMTU);
+    let mut size = BASE_PLPMTU;
+    while high - low > SEARCH_PRECISION {
+        if probe_size(size, counters, acks, &send_probe).await {
+            low = size;
+        } else {
+            high = size - 1;
+        }
+        size = (low + high).div_ceil(2);
+    }
+    low
+}
+
+/// Probes whether datagrams of the given size get through, trying a few times in case probes are lost.
+async fn probe_size(
+    size: usize,
+    counters: &PipeCounters,
+    acks: &Receiver<usize>,
+    send_probe: impl Fn(usize),
+) -> bool {
+    // answers to earlier probes that arrived too late mean nothing now
+    while acks.try_recv().is_ok() {}
+    let timeout = counters
+        .smoothed_rtt()
+        .map_or(Duration::from_secs(1), |srtt| {
+            (srtt * 3).max(Duration::from_millis(100))
+        });
+    for _ in 0..MAX_PROBES {
+        send_probe(size);
+        let answered = async {
+            while let Ok(acked) = acks.recv().await {
+                if acked == size {
+                    return true;
+                }
+            }
+            false
+        };
+        if answered.timeout(timeout).await == Some(true) {
+            return true;
+        }
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use smol::prelude::*;
+
+    use crate::{
+        multiplex::constants::FRAME_OVERHEAD,
+        sim::{sim_pipe_pair, SimLink},
+        utilities::runtime::{self, TimeoutExt},
+        Multiplex, MuxRng, MuxSecret, Pipe,
+    };
+
+    #[test]
+    fn finds_path_mtu() {
+        smol::block_on(async {
+            let server_sk = MuxSecret::generate();
+            let server = Multiplex::new(server_sk.clone(), None);
+            let client = Multiplex::new(MuxSecret::generate(), Some(server_sk.to_public()));
+            client.set_path_mtu_discovery(true);
+            let (client_pipe, server_pipe) = sim_pipe_pair(SimLink {
+                delay: Duration::from_millis(10),
+                mtu: Some(1000),
+                ..Default::default()
+            });
+            client.add_pipe(client_pipe);
+            server.add_pipe(server_pipe);
+
+            // the search starts once the session's messages went over the pipe
+            let mut opened = client.open_conn("").await.unwrap();
+            let mut accepted = server.accept_conn().await.unwrap();
+            opened.write_all(b"hello").await.unwrap();
+            let mut buf = [0u8; 5];
+            accepted.read_exact(&mut buf).await.unwrap();
+
+            let path_mtu = async {
+                loop {
+                    if let Some(mtu) = client.pipe_stats()[0].path_mtu {
+                        return mtu;
+                    }
+                    runtime::Timer::after(Duration::from_millis(100)).await;
+                }
+            }
+            .await;
+            assert!((1000 - super::SEARCH_PRECISION..=1000).contains(&path_mtu));
+            assert_eq!(client.mss(), path_mtu - FRAME_OVERHEAD);
+
+            // segments of the new size must make it across
+            let data = vec![7u8; 100_000];
+            opened.write_all(&data).await.unwrap();
+            opened.flush().await.unwrap();
+            let mut buf = vec![0u8; data.len()];
+            accepted.read_exact(&mut buf).await.unwrap();
+            assert_eq!(buf, data);
+        })
+    }
+
+    #[test]
+    fn unauthenticated_probes_go_unanswered() {
+        smol::block_on(async {
+            let server = Multiplex::new(MuxSecret::generate(), None);
+            let (scanner, server_pipe) = sim_pipe_pair(SimLink::default());
+            server.add_pipe(server_pipe);
+            scanner.send(super::probe(1000, 0, &MuxRng::default()));
+            // the server's own hello may come over the pipe, but no answer
+            while let Some(Ok(pkt)) = scanner.recv().timeout(Duration::from_millis(500)).await {
+                assert_eq!(super::parse_ack(&pkt), None);
+            }
+        })
+    }
+}
@@ -0,0 +1,89 @@
+use std::{str::FromStr, time::Duration};
+
+use thiserror::Error;
+
+/// A rough description of the path between the two ends of a multiplex, used to pick the congestion-control parameters of new streams before they have measured anything themselves.
+///
+/// Without a profile, streams assume an RTT of one second and start with a small congestion window, which is conservative on fast paths and can cause spurious retransmissions on very slow ones.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PathProfile {
+    /// A local network: sub-millisecond to a few milliseconds of RTT, little jitter.
+    Lan,
+    /// An ordinary Internet path.
+    Wan,
+    /// A geostationary satellite link: long RTT, large bandwidth-delay product.
+    Satellite,
+    /// A mobile network: moderate RTT with a lot of jitter.
+    Cellular,
+}
+
+impl PathProfile {
+    /// The RTT assumed for this kind of path until one is measured.
+    pub fn typical_rtt(&self) -> Duration {
+        match self {
+            PathProfile::Lan => Duration::from_millis(2),
+            PathProfile::Wan => Duration::from_millis(80),
+            PathProfile::Satellite => Duration::from_millis(600),
+            PathProfile::Cellular => Duration::from_millis(150),
+        }
+    }
+
+    /// The initial congestion window, in segments.
+    fn initial_cwnd(&self) -> f64 {
+        match self {
+            PathProfile::Lan => 16.0,
+            PathProfile::Wan => 10.0,
+            PathProfile::Satellite => 32.0,
+            PathProfile::Cellular => 10.0,
+        }
+    }
+
+    /// The RTT variation assumed initially, relative to the RTT.
+    fn jitter_ratio(&self) -> f64 {
+        match self {
+            PathProfile::Lan | PathProfile::Wan => 0.5,
+            PathProfile::Satellite => 0.25,
+            PathProfile::Cellular => 1.0,
+        }
+    }
+}
+
+impl FromStr for PathProfile {
+    type Err = UnknownPathProfile;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "lan" => Ok(PathProfile::Lan),
+            "wan" => Ok(PathProfile::Wan),
+            "satellite" => Ok(PathProfile::Satellite),
+            "cellular" => Ok(PathProfile::Cellular),
+            _ => Err(UnknownPathProfile(s.to_owned())),
+        }
+    }
+}
+
+/// Returned when parsing a name that is not one of "lan", "wan", "satellite" and "cellular".
+#[derive(Error, Debug)]
+#[error("unknown path profile {0:?}")]
+pub struct UnknownPathProfile(String);
+
+/// Initial values for the congestion-control state of a new stream.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct PathSeed {
+    pub rtt: Duration,
+    pub rtt_var: Duration,
+    pub cwnd: Option<f64>,
+}
+
+impl PathSeed {
+    /// Combines a profile and an RTT hint, the hint taking precedence over the profile's typical RTT. Returns None if neither is given.
+    pub fn new(profile: Option<PathProfile>, rtt_hint: Option<Duration>) -> Option<Self> {
+        let rtt = rtt_hint.or_else(|| profile.map(|p| p.typical_rtt()))?;
+        let jitter_ratio = profile.map(|p| p.jitter_ratio()).unwrap_or(0.5);
+        Some(Self {
+            rtt,
+            rtt_var: rtt.mul_f64(jitter_ratio),
+            cwnd: profile.map(|p| p.initial_cwnd()),
+        })
+    }
+}
@@ -1,13 +1,17 @@
 use std::{
     collections::VecDeque,
     convert::Infallible,
-    sync::Arc,
-    time::{Duration, Instant},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant, SystemTime},
 };
 
+use async_trait::async_trait;
 use bytes::Bytes;
 
-use event_listener::Event;
+use event_listener::{Event, EventListener};
 
 use futures_util::{stream::FuturesUnordered, StreamExt};
 use parking_lot::{Mutex, RwLock};
@@ -15,56 +19,367 @@ use parking_lot::{Mutex, RwLock};
 use smol::{
     channel::{Receiver, Sender},
     future::FutureExt,
-    Task,
 };
-use smol_timeout::TimeoutExt;
-use smolscale::immortal::Immortal;
 
-use crate::Pipe;
+#[cfg(feature = "obfuscation")]
+use crate::crypt::{BridgeCookie, COOKIE_LEN};
+use crate::{
+    frame::{Seqno, StreamId},
+    utilities::runtime::{self, Immortal, Task, TimeoutExt},
+    DialTimings, Pipe,
+};
+
+#[cfg(feature = "multipath")]
+use std::sync::atomic::AtomicI64;
+
+#[cfg(feature = "multipath")]
+use super::{bonding, path_congestion::earliest_arrivals};
+#[cfg(feature = "obfuscation")]
+use super::{
+    conn_id::{ConnIdMode, ConnIdState, CONN_ID_LEN},
+    drop_stats::DropReason,
+};
+use super::{
+    constants::{FRAME_OVERHEAD, MSS},
+    drop_stats::DropCounters,
+    path_mtu::{self, PathMtuSwitch},
+    pipe_stats::{PipeCounters, PipeStats},
+    rng::MuxRng,
+    routes::Routes,
+    trace::trace_lifecycle,
+};
+
+/// Controls when the multiplex moves outgoing traffic from one pipe to another, based on periodic RTT probes of every pipe.
+///
+/// Only the side that opened the connection picks pipes this way; the other side always replies on the pipe it last received from.
+#[derive(Clone, Copy, Debug)]
+pub struct PipeSwitchPolicy {
+    /// How often all pipes are probed.
+    pub probe_interval: Duration,
+    /// The fraction by which another pipe's RTT must be lower than that of the current pipe for it to be considered better. For example, 0.2 only switches to pipes with at least 20% lower RTT.
+    pub min_improvement: f64,
+    /// How long another pipe must stay better before traffic is moved to it. While a pipe is waiting to qualify, probes are repeated every `hold_time`, or every `probe_interval` if that is shorter.
+    pub hold_time: Duration,
+}
+
+impl Default for PipeSwitchPolicy {
+    fn default() -> Self {
+        Self {
+            probe_interval: Duration::from_secs(60),
+            min_improvement: 0.0,
+            hold_time: Duration::ZERO,
+        }
+    }
+}
+
+/// Controls how quickly the multiplex gives up on a pipe that stopped working, set with [crate::Multiplex::set_failover_policy].
+///
+/// A pipe that nothing has been received from for `probe_interval` is probed, and one that stays silent for `timeout` is considered dead: no more traffic is scheduled on it, and if it carried traffic, everything in flight is retransmitted at once over the remaining pipes rather than waiting out retransmission timeouts. A dead pipe comes back as soon as anything is received from it again.
+///
+/// Like [PipeSwitchPolicy], this only applies to the side that opened the connection, and the probes keep idle pipes busy with a datagram and its answer every `probe_interval`.
+#[derive(Clone, Copy, Debug)]
+pub struct FailoverPolicy {
+    /// How long a pipe may stay silent before it is probed.
+    pub probe_interval: Duration,
+    /// How long a pipe may stay silent before it is considered dead.
+    pub timeout: Duration,
+}
+
+impl Default for FailoverPolicy {
+    fn default() -> Self {
+        Self {
+            probe_interval: Duration::from_secs(1),
+            timeout: Duration::from_secs(3),
+        }
+    }
+}
+
+/// How the multiplex spreads outgoing datagrams over its pipes, going by the RTTs that the periodic probes of [PipeSwitchPolicy] measure.
+///
+/// Like [PipeSwitchPolicy], this mostly applies to the side that opened the connection. Until a probe has been answered, everything goes over the first pipe added.
+///
+/// [MultipathPolicy::WeightedRoundRobin] and [MultipathPolicy::Bonded] stripe the segments of streams over several pipes, so each pipe also keeps a congestion window and pacing rate of its own, shown in [crate::PipeStats::cwnd], and carries only as many data segments as these allow. Losses are then told by what is acked over the same pipe, so that segments over a fast pipe overtaking those over a slow one are not taken for losses.
+///
+/// A data segment goes over a slower pipe only if it would still arrive about as soon as by waiting for room on the fastest one, as in the ECF scheduler, so that the receiver gets segments roughly in order instead of holding fast data back behind slow data. Streams can stay off the slower pipes altogether with [crate::StreamOptions::single_path], or while they are short with [crate::Multiplex::set_single_path_below].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MultipathPolicy {
+    /// Everything goes over the one pipe with the lowest RTT, moving to another as [PipeSwitchPolicy] says.
+    #[default]
+    LowestRtt,
+    #[cfg(feature = "multipath")]
+    /// Datagrams take turns among the pipes that answered the latest probe, each pipe getting a share inversely proportional to its RTT. This adds up the capacity of several paths, at the cost of reordering when their RTTs differ a lot.
+    WeightedRoundRobin,
+    #[cfg(feature = "multipath")]
+    /// Every datagram goes over the two pipes with the lowest RTTs, so that either path can fail or lose packets without any cost. This doubles the traffic, and the receiving multiplex drops the copy that arrives second as a replay, so copies show up in [crate::DropStats::replayed]. A multiplex that only answers, such as one on the server side, sends over the pipe it last heard from and, if it also uses this policy, the one it heard from before that.
+    Redundant,
+    #[cfg(feature = "multipath")]
+    /// Segments are striped over all pipes in proportion to how many bytes per second each is measured to carry, adding up the bandwidth of links that differ a lot, such as DSL and LTE. Ten times a second, each pipe is asked how much arrived over it; a pipe that loses datagrams or whose RTT grows is full and carries what it delivers, while one that does neither is given more. Each datagram goes over the pipe it would arrive first over, so that the pipe with the lowest delay takes everything until it is full and segments arrive in about the order they were sent. [crate::PipeStats::bonding] shows what each pipe achieves.
+    ///
+    /// Unlike the other policies, this applies to whichever side sets it, including one that only answers. Pipes whose other side does not answer, such as those to peers that do not support bonding, stop getting traffic after two seconds, and with no pipes left, datagrams go wherever they would otherwise.
+    Bonded,
+}
+
+/// Whether a captured packet was sent or received.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CaptureDirection {
+    Outgoing,
+    Incoming,
+}
+
+/// A raw datagram as handed to or received from a [Pipe], passed to the hook set with [crate::Multiplex::set_capture_hook].
+///
+/// Apart from handshake frames and pipe-level pings, the contents are ciphertext. If a bridge secret is set, the data includes the trailing cookie, and if connection IDs are enabled, the leading connection ID.
+#[derive(Debug)]
+pub struct CapturedPacket<'a> {
+    pub time: SystemTime,
+    pub direction: CaptureDirection,
+    pub protocol: &'a str,
+    pub peer_addr: String,
+    pub data: &'a [u8],
+}
+
+pub type CaptureHook = Arc<dyn Fn(&CapturedPacket<'_>) + Send + Sync + 'static>;
+
+/// A change of the address that the other side of a [Pipe] is heard from, such as a NAT rebinding a UDP pipe to another port, passed to the hook set with [crate::Multiplex::set_address_change_hook].
+#[derive(Clone, Debug)]
+pub struct AddressChange {
+    pub time: SystemTime,
+    pub protocol: String,
+    pub old_addr: String,
+    pub new_addr: String,
+}
+
+pub type AddressChangeHook = Arc<dyn Fn(&AddressChange) + Send + Sync + 'static>;
+
+/// Things that every datagram passing through a pipe goes through, shared by the whole pool.
+struct PipeHooks {
+    capture: RwLock<Option<CaptureHook>>,
+    addr_change: RwLock<Option<AddressChangeHook>>,
+    // whether a pipe whose address changed carries nothing but probes until one is answered
+    revalidate: AtomicBool,
+    // whether probes go unanswered on a pipe until it carried an authenticated message
+    silent: AtomicBool,
+    #[cfg(feature = "obfuscation")]
+    cookie: RwLock<Option<BridgeCookie>>,
+    #[cfg(feature = "obfuscation")]
+    conn_id: RwLock<Option<ConnIdState>>,
+    rng: RwLock<MuxRng>,
+    // only datagrams without a valid cookie or connection ID are dropped here
+    #[cfg_attr(not(feature = "obfuscation"), allow(dead_code))]
+    drops: Arc<DropCounters>,
+}
+
+impl PipeHooks {
+    /// Sends a datagram down a pipe, adding the cookie and connection ID if needed.
+    fn transmit(&self, pipe: &dyn Pipe, pkt: Bytes) {
+        #[cfg(feature = "obfuscation")]
+        let pkt = match self.cookie.read().as_ref() {
+            Some(cookie) => cookie.seal(&pkt),
+            None => pkt,
+        };
+        #[cfg(feature = "obfuscation")]
+        let pkt = match self.conn_id.read().as_ref() {
+            Some(conn_id) => conn_id.prefix(&pkt, &self.rng.read()),
+            None => pkt,
+        };
+        self.capture(CaptureDirection::Outgoing, pipe, &pkt);
+        pipe.send(pkt);
+    }
+
+    /// Processes a datagram that came out of a pipe, returning None if it should be silently dropped.
+    fn receive(&self, pipe: &dyn Pipe, pkt: Bytes) -> Option<Bytes> {
+        self.capture(CaptureDirection::Incoming, pipe, &pkt);
+        #[cfg(feature = "obfuscation")]
+        let pkt = match self.conn_id.read().as_ref() {
+            Some(conn_id) => {
+                let stripped = conn_id.strip(&pkt);
+                if stripped.is_none() {
+                    self.drops.record(DropReason::Malformed);
+                }
+                stripped?
+            }
+            None => pkt,
+        };
+        #[cfg(feature = "obfuscation")]
+        if let Some(cookie) = self.cookie.read().as_ref() {
+            let opened = cookie.open(&pkt);
+            if opened.is_none() {
+                self.drops.record(DropReason::BadCookie);
+            }
+            return opened;
+        }
+        Some(pkt)
+    }
+
+    /// How many bytes the cookie and connection ID add to each datagram.
+    #[cfg(feature = "obfuscation")]
+    fn overhead(&self) -> usize {
+        let cookie = self.cookie.read().is_some() as usize * COOKIE_LEN;
+        let conn_id = self.conn_id.read().is_some() as usize * CONN_ID_LEN;
+        cookie + conn_id
+    }
+
+    #[cfg(not(feature = "obfuscation"))]
+    fn overhead(&self) -> usize {
+        0
+    }
+
+    fn capture(&self, direction: CaptureDirection, pipe: &dyn Pipe, data: &[u8]) {
+        if let Some(hook) = self.capture.read().as_ref() {
+            hook(&CapturedPacket {
+                time: SystemTime::now(),
+                direction,
+                protocol: pipe.protocol(),
+                peer_addr: pipe.peer_addr(),
+                data,
+            })
+        }
+    }
+}
+
+/// A pipe that counts the datagrams going through it.
+struct CountedPipe {
+    inner: Arc<dyn Pipe>,
+    counters: Arc<PipeCounters>,
+}
+
+#[async_trait]
+impl Pipe for CountedPipe {
+    fn send(&self, to_send: Bytes) {
+        self.counters.on_send(to_send.len());
+        self.inner.send(to_send)
+    }
+
+    async fn recv(&self) -> std::io::Result<Bytes> {
+        let pkt = self.inner.recv().await?;
+        self.counters.on_recv(pkt.len());
+        Ok(pkt)
+    }
+
+    fn protocol(&self) -> &str {
+        self.inner.protocol()
+    }
+
+    fn peer_metadata(&self) -> &str {
+        self.inner.peer_metadata()
+    }
+
+    fn peer_addr(&self) -> String {
+        self.inner.peer_addr()
+    }
+
+    fn dial_timings(&self) -> Option<DialTimings> {
+        self.inner.dial_timings()
+    }
+}
+
+/// What the health checks share with the rest of the pool.
+#[derive(Default)]
+struct Failover {
+    policy: RwLock<Option<FailoverPolicy>>,
+    // how many times traffic had to move off a dead pipe
+    count: AtomicU64,
+    event: Event,
+}
+
+/// A datagram for the session, with the pipe it came from and that pipe's counters.
+type Received = (Bytes, Arc<dyn Pipe>, Arc<PipeCounters>);
 
 #[derive(Clone)]
 struct SinglePipe {
     pipe: Arc<dyn Pipe>,
+    counters: Arc<PipeCounters>,
+    // whether a health probe is waiting for an answer
+    health_probe: Arc<AtomicBool>,
+    // for weighted round-robin, how far this pipe is owed datagrams
+    #[cfg(feature = "multipath")]
+    credit: Arc<AtomicI64>,
     ping_notify: Arc<Event>,
-    _assoc_task: Arc<Task<()>>,
+    hooks: Arc<PipeHooks>,
+    // ends once the pipe fails to receive
+    assoc_task: Arc<Task<()>>,
+    _path_mtu_task: Arc<Task<()>>,
 }
 
 impl SinglePipe {
     /// Creates a new pipe manager.
-    fn new(pipe: Arc<dyn Pipe>, send_incoming: Sender<(Bytes, Arc<dyn Pipe>)>) -> Self {
+    fn new(
+        pipe: Arc<dyn Pipe>,
+        send_incoming: Sender<Received>,
+        hooks: Arc<PipeHooks>,
+        path_mtu: Arc<PathMtuSwitch>,
+    ) -> Self {
         let ping_notify = Arc::new(Event::new());
+        let (send_mtu_ack, recv_mtu_ack) = smol::channel::bounded(16);
+        let counters = Arc::new(PipeCounters::default());
+        let pipe: Arc<dyn Pipe> = Arc::new(CountedPipe {
+            inner: pipe,
+            counters: counters.clone(),
+        });
 
-        let _assoc_task = smolscale::spawn(pipe_associated_task(
+        let assoc_task = runtime::spawn(pipe_associated_task(
             ping_notify.clone(),
             pipe.clone(),
             send_incoming,
+            hooks.clone(),
+            counters.clone(),
+            send_mtu_ack,
         ));
+        let _path_mtu_task = runtime::spawn({
+            let pipe = pipe.clone();
+            let hooks = hooks.clone();
+            let counters = counters.clone();
+            async move {
+                path_mtu::discover_path_mtu(&path_mtu, &counters, recv_mtu_ack, |size| {
+                    let probe = path_mtu::probe(size, hooks.overhead(), &hooks.rng.read());
+                    hooks.transmit(&pipe, probe)
+                })
+                .await
+            }
+        });
         Self {
-            pipe: Arc::new(pipe),
+            pipe,
+            counters,
+            health_probe: Default::default(),
+            #[cfg(feature = "multipath")]
+            credit: Default::default(),
             ping_notify,
-            _assoc_task: _assoc_task.into(),
+            hooks,
+            assoc_task: assoc_task.into(),
+            _path_mtu_task: _path_mtu_task.into(),
         }
     }
+
+    /// Whether the pipe failed to receive, which it never recovers from.
+    fn is_closed(&self) -> bool {
+        self.assoc_task.is_finished()
+    }
+
     /// Pings the other end, returning only when a response is received.
     async fn measure_ping(&self) -> Duration {
-        let start = Instant::now();
+        let start = runtime::now();
         let evlisten = self.ping_notify.listen();
-        let start_time = Instant::now();
+        let start_time = runtime::now();
         let pipe = self.pipe.clone();
+        let hooks = self.hooks.clone();
+        let counters = self.counters.clone();
         async move {
             evlisten.await;
-            start_time.elapsed()
+            runtime::elapsed(start_time)
         }
         .race(async move {
             let mut wait_millis = 1000;
             loop {
-                pipe.send(Bytes::from_static(b"!!ping!!"));
-                smol::Timer::after(Duration::from_millis(wait_millis)).await;
+                hooks.transmit(&pipe, Bytes::from_static(b"!!ping!!"));
+                counters.on_ping_sent();
+                runtime::Timer::after(Duration::from_millis(wait_millis)).await;
                 wait_millis = fastrand::u64(wait_millis..=(wait_millis * 2)).min(100000)
             }
         })
         .await;
-        start.elapsed()
+        let rtt = runtime::elapsed(start);
+        self.counters.on_probe_answered(rtt);
+        rtt
     }
 }
 
@@ -72,66 +387,254 @@ impl SinglePipe {
 pub struct PipePool {
     pipes: Arc<RwLock<VecDeque<SinglePipe>>>,
     size_limit: usize,
-    send_incoming: Sender<(Bytes, Arc<dyn Pipe>)>,
-    recv_incoming: Receiver<(Bytes, Arc<dyn Pipe>)>,
+    send_incoming: Sender<Received>,
+    recv_incoming: Receiver<Received>,
     selected_send_pipe: Arc<Mutex<Option<Arc<dyn Pipe>>>>,
     last_recv_pipe: Mutex<Option<Arc<dyn Pipe>>>,
+    // the pipe received from before the last one, if different
+    prev_recv_pipe: Mutex<Option<Arc<dyn Pipe>>>,
 
     last_significant_recv_time: Arc<RwLock<Instant>>,
 
     naive_send: bool,
+    heard_from_peer: AtomicBool,
+    mss: AtomicUsize,
+    path_mtu: Arc<PathMtuSwitch>,
+    hooks: Arc<PipeHooks>,
+    switch_policy: Arc<RwLock<PipeSwitchPolicy>>,
+    probing: Arc<AtomicBool>,
+    multipath_policy: Arc<RwLock<MultipathPolicy>>,
+    failover: Arc<Failover>,
+    // makes each weighted round-robin or bonded pick see the credits or schedule left by the last one
+    #[cfg(feature = "multipath")]
+    wrr_lock: Mutex<()>,
+    routes: Arc<Routes>,
 
     _stats_gatherer: Immortal,
+    _health_checker: Immortal,
+    #[cfg(feature = "multipath")]
+    _bond_prober: Immortal,
 }
 
 async fn stats_gatherer_loop(
     last_recv_time: Arc<RwLock<Instant>>,
     selected_send_pipe: Arc<Mutex<Option<Arc<dyn Pipe>>>>,
     pipes: Arc<RwLock<VecDeque<SinglePipe>>>,
+    switch_policy: Arc<RwLock<PipeSwitchPolicy>>,
+    probing: Arc<AtomicBool>,
 ) -> Infallible {
-    smol::Timer::after(Duration::from_secs(5)).await;
+    runtime::Timer::after(Duration::from_secs(5)).await;
+    // the pipe that has been better than the selected one, and since when
+    let mut candidate: Option<(Arc<dyn Pipe>, Instant)> = None;
     loop {
+        if !probing.load(Ordering::Relaxed) {
+            candidate = None;
+            let probe_interval = switch_policy.read().probe_interval;
+            runtime::Timer::after(probe_interval).await;
+            continue;
+        }
         // wait until we're chill
-        while last_recv_time.read().elapsed() < Duration::from_secs(1) {
+        while runtime::elapsed(*last_recv_time.read()) < Duration::from_secs(1) {
             log::warn!("waiting for chillness before pinging");
-            smol::Timer::after(Duration::from_secs(1)).await;
+            runtime::Timer::after(Duration::from_secs(1)).await;
+        }
+        let policy = *switch_policy.read();
+        let selected = selected_send_pipe.lock().clone();
+        let probe_start = runtime::now();
+        let probed: Vec<SinglePipe> = pipes.read().iter().cloned().collect();
+        let mut ping_gatherer = FuturesUnordered::new();
+        for pipe in probed.iter() {
+            let pipe = pipe.clone();
+            ping_gatherer.push(async move {
+                let ping = pipe.measure_ping().await;
+                (pipe, ping)
+            })
         }
         let measure = async {
-            let mut ping_gatherer = FuturesUnordered::new();
-            {
-                let pipes = pipes.read();
-                for pipe in pipes.iter() {
-                    let pipe = pipe.clone();
-                    ping_gatherer.push(async move {
-                        let ping = pipe.measure_ping().await;
-                        (pipe, ping)
-                    })
+            let (best, ping) = ping_gatherer.next().await?;
+            let is_selected = |pipe: &SinglePipe| {
+                selected.as_ref().map(|s| s.peer_addr()) == Some(pipe.pipe.peer_addr())
+            };
+            if is_selected(&best) {
+                return Some((best, ping, false));
+            }
+            // the best pipe is significantly better unless the selected one answers within the threshold
+            let threshold = ping.div_f64((1.0 - policy.min_improvement).max(f64::EPSILON));
+            let selected_answered = async {
+                while let Some((pipe, selected_ping)) = ping_gatherer.next().await {
+                    if is_selected(&pipe) {
+                        return Some(selected_ping);
+                    }
+                }
+                None
+            }
+            .timeout(threshold.saturating_sub(ping))
+            .await
+            .flatten();
+            let significant = match selected_answered {
+                Some(selected_ping) => {
+                    ping.as_secs_f64()
+                        < selected_ping.as_secs_f64() * (1.0 - policy.min_improvement)
                 }
+                None => true,
+            };
+            Some((best, ping, significant))
+        };
+        let mut next_probe = policy.probe_interval;
+        let measured = measure.timeout(Duration::from_secs(30)).await;
+        // the other pipes' answers only update their RTTs, which the other multipath policies go by
+        async { while ping_gatherer.next().await.is_some() {} }
+            .timeout(Duration::from_secs(5))
+            .await;
+        drop(ping_gatherer);
+        for pipe in probed {
+            pipe.counters.on_probe_unanswered(probe_start);
+        }
+        match measured {
+            Some(Some((best, ping, true))) => {
+                let since = match &candidate {
+                    Some((pipe, since)) if pipe.peer_addr() == best.pipe.peer_addr() => *since,
+                    _ => runtime::now(),
+                };
+                if runtime::elapsed(since) >= policy.hold_time {
+                    log::warn!(
+                        "picked best pipe {}/{} with ping {:?}",
+                        best.pipe.protocol(),
+                        best.pipe.peer_addr(),
+                        ping
+                    );
+                    trace_lifecycle(
+                        "PipeSelected",
+                        &pipe_name(&*best.pipe),
+                        format_args!("best ping {ping:?}"),
+                    );
+                    *selected_send_pipe.lock() = Some(best.pipe.clone());
+                    candidate = None;
+                } else {
+                    log::debug!(
+                        "pipe {}/{} with ping {:?} is better, waiting for it to stay better",
+                        best.pipe.protocol(),
+                        best.pipe.peer_addr(),
+                        ping
+                    );
+                    candidate = Some((best.pipe.clone(), since));
+                    next_probe = next_probe.min(policy.hold_time);
+                }
+            }
+            Some(_) => candidate = None,
+            None => log::warn!("pinging all pipes timed out!"),
+        }
+        runtime::Timer::after(next_probe).await;
+    }
+}
+
+/// Probes pipes that went quiet, marks the ones that stay silent as dead, and moves traffic off them.
+async fn health_loop(
+    selected_send_pipe: Arc<Mutex<Option<Arc<dyn Pipe>>>>,
+    pipes: Arc<RwLock<VecDeque<SinglePipe>>>,
+    multipath_policy: Arc<RwLock<MultipathPolicy>>,
+    failover: Arc<Failover>,
+) -> Infallible {
+    loop {
+        let Some(policy) = *failover.policy.read() else {
+            runtime::Timer::after(Duration::from_secs(1)).await;
+            continue;
+        };
+        let checked: Vec<SinglePipe> = pipes.read().iter().cloned().collect();
+        let selected = selected_send_pipe.lock().clone();
+        let mut in_use_died = false;
+        for pipe in checked.iter() {
+            let silence = pipe.counters.silence();
+            let dead = silence >= policy.timeout;
+            if pipe.counters.set_dead(dead) {
+                log::warn!(
+                    "pipe {}/{} is {} after {:?} of silence",
+                    pipe.pipe.protocol(),
+                    pipe.pipe.peer_addr(),
+                    if dead { "dead" } else { "alive again" },
+                    silence
+                );
+                trace_lifecycle(
+                    if dead { "PipeDead" } else { "PipeAlive" },
+                    &pipe_name(&*pipe.pipe),
+                    format_args!("after {silence:?} of silence"),
+                );
+                // under the other policies, every pipe with an RTT may have carried traffic
+                let in_use = *multipath_policy.read() != MultipathPolicy::LowestRtt
+                    || selected
+                        .as_ref()
+                        .is_some_and(|selected| Arc::ptr_eq(selected, &pipe.pipe));
+                in_use_died |= dead && in_use;
+            }
+            if silence >= policy.probe_interval && !pipe.health_probe.swap(true, Ordering::Relaxed)
+            {
+                let pipe = pipe.clone();
+                runtime::spawn(async move {
+                    pipe.measure_ping().timeout(policy.timeout).await;
+                    pipe.health_probe.store(false, Ordering::Relaxed);
+                })
+                .detach();
             }
-            if let Some((best, ping)) = ping_gatherer.next().await {
+        }
+        // a selected pipe that was removed from the pool is as good as dead
+        let selected_alive = selected.as_ref().is_some_and(|selected| {
+            checked
+                .iter()
+                .any(|p| Arc::ptr_eq(&p.pipe, selected) && !p.counters.is_dead())
+        });
+        if !selected_alive {
+            let replacement = checked
+                .iter()
+                .filter(|p| p.counters.is_usable())
+                .min_by_key(|p| p.counters.rtt().unwrap_or(Duration::MAX));
+            if let Some(replacement) = replacement {
                 log::warn!(
-                    "picked best pipe {}/{} with ping {:?}",
-                    best.pipe.protocol(),
-                    best.pipe.peer_addr(),
-                    ping
+                    "failing over to pipe {}/{}",
+                    replacement.pipe.protocol(),
+                    replacement.pipe.peer_addr()
                 );
-                *selected_send_pipe.lock() = Some(best.pipe.clone());
+                trace_lifecycle("PipeSelected", &pipe_name(&*replacement.pipe), "failover");
+                *selected_send_pipe.lock() = Some(replacement.pipe.clone());
+                in_use_died |= selected.is_some();
             }
-        };
-        if measure.timeout(Duration::from_secs(30)).await.is_none() {
-            log::warn!("pinging all pipes timed out!")
         }
-        smol::Timer::after(Duration::from_secs(60)).await;
+        if in_use_died {
+            failover.count.fetch_add(1, Ordering::Relaxed);
+            failover.event.notify(usize::MAX);
+        }
+        runtime::Timer::after(policy.probe_interval.min(policy.timeout) / 2).await;
+    }
+}
+
+/// Asks every pipe how much it delivered while pipes are bonded.
+#[cfg(feature = "multipath")]
+async fn bond_loop(
+    pipes: Arc<RwLock<VecDeque<SinglePipe>>>,
+    multipath_policy: Arc<RwLock<MultipathPolicy>>,
+) -> Infallible {
+    loop {
+        runtime::Timer::after(bonding::REPORT_INTERVAL).await;
+        if *multipath_policy.read() != MultipathPolicy::Bonded {
+            continue;
+        }
+        for pipe in pipes.read().iter() {
+            let request = pipe.counters.bond_request();
+            pipe.hooks.transmit(&pipe.pipe, request);
+        }
     }
 }
 
 impl PipePool {
     /// Creates a new instance of PipePool that reads bts from up_recv and sends them down the "best" pipe available and sends pkts from all pipes to send_incoming
-    pub fn new(size_limit: usize, naive_send: bool) -> Self {
+    pub fn new(size_limit: usize, naive_send: bool, drops: Arc<DropCounters>) -> Self {
         let (send_incoming, recv_incoming) = smol::channel::bounded(1);
         let pipes = Arc::new(RwLock::new(VecDeque::new()));
         let selected_send_pipe: Arc<Mutex<Option<Arc<dyn Pipe>>>> = Default::default();
-        let last_significant_recv_time = Arc::new(RwLock::new(Instant::now()));
+        let last_significant_recv_time = Arc::new(RwLock::new(runtime::now()));
+        let switch_policy: Arc<RwLock<PipeSwitchPolicy>> = Default::default();
+        let probing = Arc::new(AtomicBool::new(true));
+        let multipath_policy: Arc<RwLock<MultipathPolicy>> = Default::default();
+        let failover: Arc<Failover> = Default::default();
         Self {
             pipes: pipes.clone(),
             size_limit,
@@ -140,18 +643,55 @@ impl PipePool {
             recv_incoming,
             selected_send_pipe: selected_send_pipe.clone(),
             last_recv_pipe: Default::default(),
+            prev_recv_pipe: Default::default(),
             naive_send,
+            heard_from_peer: AtomicBool::new(false),
+            mss: AtomicUsize::new(MSS),
+            path_mtu: Default::default(),
+            hooks: Arc::new(PipeHooks {
+                capture: Default::default(),
+                addr_change: Default::default(),
+                revalidate: Default::default(),
+                silent: Default::default(),
+                #[cfg(feature = "obfuscation")]
+                cookie: Default::default(),
+                #[cfg(feature = "obfuscation")]
+                conn_id: Default::default(),
+                rng: Default::default(),
+                drops,
+            }),
+            switch_policy: switch_policy.clone(),
+            probing: probing.clone(),
+            multipath_policy: multipath_policy.clone(),
+            failover: failover.clone(),
+            #[cfg(feature = "multipath")]
+            wrr_lock: Mutex::new(()),
+            routes: Default::default(),
             last_significant_recv_time: last_significant_recv_time.clone(),
 
             _stats_gatherer: if naive_send {
-                Immortal::spawn(smol::future::pending())
+                runtime::spawn(smol::future::pending())
             } else {
-                Immortal::spawn(stats_gatherer_loop(
+                runtime::spawn(stats_gatherer_loop(
                     last_significant_recv_time,
+                    selected_send_pipe.clone(),
+                    pipes.clone(),
+                    switch_policy,
+                    probing,
+                ))
+            },
+            _health_checker: if naive_send {
+                runtime::spawn(smol::future::pending())
+            } else {
+                runtime::spawn(health_loop(
                     selected_send_pipe,
-                    pipes,
+                    pipes.clone(),
+                    multipath_policy.clone(),
+                    failover,
                 ))
             },
+            #[cfg(feature = "multipath")]
+            _bond_prober: runtime::spawn(bond_loop(pipes, multipath_policy)),
         }
     }
 
@@ -160,9 +700,28 @@ impl PipePool {
         self.pipes.read().iter().map(|s| s.pipe.clone()).collect()
     }
 
+    /// How many pipes are still open, leaving out those that failed to receive.
+    pub fn open_pipes(&self) -> usize {
+        self.pipes.read().iter().filter(|p| !p.is_closed()).count()
+    }
+
     /// Retain only the pipes the fit this criterion.
     pub fn retain(&self, mut f: impl FnMut(&dyn Pipe) -> bool) {
-        self.pipes.write().retain(|p| f(&p.pipe))
+        let mut pipes = self.pipes.write();
+        pipes.retain(|p| f(&p.pipe));
+        self.sync_routes(&pipes);
+    }
+
+    /// Drops every pipe, so that nothing more is sent or received until pipes are added again.
+    pub fn close_all(&self) {
+        let closed = std::mem::take(&mut *self.pipes.write());
+        self.routes.set_pipes(vec![]);
+        *self.selected_send_pipe.lock() = None;
+        *self.last_recv_pipe.lock() = None;
+        *self.prev_recv_pipe.lock() = None;
+        for single in closed {
+            trace_lifecycle("PipeClosed", &pipe_name(&*single.pipe), "multiplex closed");
+        }
     }
 
     /// Obtains the pipe last used for sending.
@@ -177,24 +736,209 @@ impl PipePool {
         pipe.clone()
     }
 
+    /// Returns the maximum segment size that streams should currently use. With path MTU discovery on, that is whatever fills the smallest datagrams found to get across any live pipe, once they are found.
+    pub fn mss(&self) -> usize {
+        if self.path_mtu.is_enabled() {
+            let path_mtu = self
+                .pipes
+                .read()
+                .iter()
+                .filter(|p| !p.counters.is_dead())
+                .filter_map(|p| p.counters.path_mtu())
+                .min();
+            if let Some(path_mtu) = path_mtu {
+                return path_mtu
+                    .saturating_sub(FRAME_OVERHEAD + self.hooks.overhead())
+                    .max(1);
+            }
+        }
+        self.mss.load(Ordering::Relaxed)
+    }
+
+    /// Enables or disables searching for the largest datagrams that get across each pipe.
+    pub fn set_path_mtu_discovery(&self, enabled: bool) {
+        self.path_mtu.set_enabled(enabled);
+        if !enabled {
+            for pipe in self.pipes.read().iter() {
+                pipe.counters.set_path_mtu(None);
+            }
+        }
+    }
+
+    /// Changes the maximum segment size. Active streams pick up the new value on the next tick.
+    pub fn set_mss(&self, mss: usize) {
+        let mss = mss.max(1);
+        let old = self.mss.swap(mss, Ordering::Relaxed);
+        if old != mss {
+            log::debug!("pipe pool mss changed {old} => {mss}");
+        }
+    }
+
+    /// Sets a hook that sees every datagram sent or received through any pipe, or removes it with `None`.
+    pub fn set_capture_hook(&self, hook: Option<CaptureHook>) {
+        *self.hooks.capture.write() = hook;
+    }
+
+    /// Sets a hook that is called whenever the other side of a pipe is heard from at another address, or removes it with `None`.
+    pub fn set_address_change_hook(&self, hook: Option<AddressChangeHook>) {
+        *self.hooks.addr_change.write() = hook;
+    }
+
+    /// Sets whether a pipe whose address changed carries nothing but probes until one is answered from the new address.
+    pub fn set_revalidate_on_address_change(&self, enabled: bool) {
+        self.hooks.revalidate.store(enabled, Ordering::Relaxed);
+        if !enabled {
+            for pipe in self.pipes.read().iter() {
+                pipe.counters.set_validated(true);
+            }
+        }
+    }
+
+    /// Sets whether liveness probes go unanswered on a pipe until it carried a message authenticated by the session.
+    pub fn set_silent_until_authenticated(&self, enabled: bool) {
+        self.hooks.silent.store(enabled, Ordering::Relaxed);
+    }
+
+    /// Requires a cookie derived from the given bridge secret on every datagram, silently dropping datagrams without one; `None` turns this off. Both sides must use the same secret.
+    #[cfg(feature = "obfuscation")]
+    pub fn set_bridge_secret(&self, secret: Option<&[u8]>) {
+        *self.hooks.cookie.write() = secret.map(BridgeCookie::new);
+    }
+
+    /// Enables or disables connection-ID prefixes on every datagram.
+    #[cfg(feature = "obfuscation")]
+    pub fn set_conn_id(&self, mode: Option<ConnIdMode>) {
+        *self.hooks.conn_id.write() =
+            mode.map(|mode| ConnIdState::new(mode, &self.hooks.rng.read()));
+    }
+
+    /// Sets where the nonces of connection IDs come from.
+    pub fn set_rng(&self, rng: MuxRng) {
+        *self.hooks.rng.write() = rng;
+    }
+
+    /// Changes when outgoing traffic is moved from one pipe to another. Takes effect from the next round of probes.
+    pub fn set_switch_policy(&self, policy: PipeSwitchPolicy) {
+        *self.switch_policy.write() = policy;
+    }
+
+    /// Changes how outgoing datagrams are spread over the pipes. Takes effect immediately.
+    pub fn set_multipath_policy(&self, policy: MultipathPolicy) {
+        *self.multipath_policy.write() = policy;
+        #[cfg(feature = "multipath")]
+        self.routes.set_striping(matches!(
+            policy,
+            MultipathPolicy::WeightedRoundRobin | MultipathPolicy::Bonded
+        ));
+    }
+
+    /// Where the data segments in flight went out over, which streams report their acks and losses to.
+    pub fn routes(&self) -> Arc<Routes> {
+        self.routes.clone()
+    }
+
+    fn sync_routes(&self, pipes: &VecDeque<SinglePipe>) {
+        self.routes
+            .set_pipes(pipes.iter().map(|p| p.counters.clone()).collect());
+    }
+
+    /// Enables or disables detecting dead pipes and moving traffic off them.
+    pub fn set_failover_policy(&self, policy: Option<FailoverPolicy>) {
+        *self.failover.policy.write() = policy;
+        if policy.is_none() {
+            for pipe in self.pipes.read().iter() {
+                pipe.counters.set_dead(false);
+            }
+        }
+    }
+
+    /// Returns how many times traffic moved off a dead pipe, so that whatever was in flight over it can be sent again.
+    pub fn failovers(&self) -> u64 {
+        self.failover.count.load(Ordering::Relaxed)
+    }
+
+    /// Listens for the next time traffic moves off a dead pipe.
+    pub fn listen_failover(&self) -> EventListener {
+        self.failover.event.listen()
+    }
+
+    /// Returns statistics of every pipe in the pool.
+    pub fn pipe_stats(&self) -> Vec<PipeStats> {
+        #[cfg_attr(not(feature = "multipath"), allow(unused_mut))]
+        let mut stats: Vec<PipeStats> = self
+            .pipes
+            .read()
+            .iter()
+            .map(|p| p.counters.snapshot(p.pipe.as_ref()))
+            .collect();
+        #[cfg(feature = "multipath")]
+        self.share_bonding(&mut stats);
+        stats
+    }
+
+    /// Fills in the share of what all bonded pipes delivered that each delivered.
+    #[cfg(feature = "multipath")]
+    fn share_bonding(&self, stats: &mut [PipeStats]) {
+        let delivered: f64 = stats
+            .iter()
+            .filter_map(|s| s.bonding)
+            .map(|b| b.delivery_rate)
+            .sum();
+        if delivered > 0.0 {
+            for bonding in stats.iter_mut().filter_map(|s| s.bonding.as_mut()) {
+                bonding.share = bonding.delivery_rate / delivered;
+            }
+        }
+    }
+
+    /// Sets whether pipes are probed to find the fastest one. When not, traffic stays on the selected pipe. Takes effect from the next round of probes.
+    pub fn set_probing(&self, enabled: bool) {
+        self.probing.store(enabled, Ordering::Relaxed);
+    }
+
     /// Adds a Pipe to the PipePool, deleting the oldest pipe if there are too many Pipes in the PipePool.
     pub fn add_pipe(&self, pipe: impl Pipe) {
         let mut pipes = self.pipes.write();
-        let pipe: Arc<dyn Pipe> = Arc::new(pipe);
-        pipes.push_back(SinglePipe::new(pipe.clone(), self.send_incoming.clone()));
+        // closed pipes make room before any open one is evicted
+        pipes.retain(|p| {
+            let closed = p.is_closed();
+            if closed {
+                trace_lifecycle("PipeEvicted", &pipe_name(&*p.pipe), "closed");
+            }
+            !closed
+        });
+        let single = SinglePipe::new(
+            Arc::new(pipe),
+            self.send_incoming.clone(),
+            self.hooks.clone(),
+            self.path_mtu.clone(),
+        );
+        // what goes through the pool's own references to the pipe is counted too
+        let pipe = single.pipe.clone();
+        trace_lifecycle("PipeAdded", &pipe_name(&*pipe), "");
+        pipes.push_back(single);
         if pipes.len() > self.size_limit {
             let front = pipes.pop_front();
             if let Some(front) = front {
+                let mut evicted = Some(front.clone());
                 let selected = self.selected_send_pipe.lock().clone();
                 if let Some(selected) = selected {
                     if selected.peer_addr() == front.pipe.peer_addr() {
-                        pipes.pop_front();
+                        evicted = pipes.pop_front();
                         pipes.push_back(front);
                     }
                 }
+                if let Some(evicted) = evicted {
+                    trace_lifecycle(
+                        "PipeEvicted",
+                        &pipe_name(&*evicted.pipe),
+                        format_args!("over the limit of {} pipes", self.size_limit),
+                    );
+                }
             }
         }
         log::debug!("{} pipes in the mux", pipes.len());
+        self.sync_routes(&pipes);
 
         {
             let mut p = self.last_recv_pipe.lock();
@@ -205,58 +949,752 @@ impl PipePool {
         {
             let mut p = self.selected_send_pipe.lock();
             if p.is_none() {
+                trace_lifecycle("PipeSelected", &pipe_name(&*pipe), "first pipe");
                 *p = Some(pipe);
             }
         }
     }
 
     pub async fn send(&self, pkt: Bytes) {
+        self.send_segment(pkt, None).await
+    }
+
+    /// Sends a datagram that carries the data segment with the given stream ID and seqno, if any. The segment is counted against the congestion window of the pipe it goes out over, and pieces of a segment all go over the pipe the first one took.
+    pub async fn send_segment(&self, pkt: Bytes, segment: Option<(StreamId, Seqno)>) {
+        // A responder that requires cookies stays completely silent until the other side has proven that it knows the bridge secret.
+        #[cfg(feature = "obfuscation")]
+        if self.naive_send
+            && !self.heard_from_peer.load(Ordering::Relaxed)
+            && self.hooks.cookie.read().is_some()
+        {
+            return;
+        }
+        #[cfg(feature = "multipath")]
+        let single_path =
+            segment.is_some_and(|(stream_id, _)| self.routes.is_single_path(stream_id));
         // If naive_send is true, we simply use the packet that we last *received* traffic from.
         // That pipe is *probably* alive, and if not the client will be opening a new one soon.
         if self.naive_send {
-            if let Some(pipe) = self.last_recv_pipe() {
-                pipe.send(pkt);
+            #[cfg(feature = "multipath")]
+            if *self.multipath_policy.read() == MultipathPolicy::Bonded && !single_path {
+                if let Some(pipe) = self.bonded_pick(pkt.len(), segment.is_some()) {
+                    self.transmit(&pipe, pkt, segment);
+                    return;
+                }
+            }
+            if let Some(pipe) = self.last_recv_pipe.lock().clone() {
+                // under the redundant policy, the pipe heard from before that gets a copy too
+                #[cfg(feature = "multipath")]
+                if *self.multipath_policy.read() == MultipathPolicy::Redundant {
+                    if let Some(prev) = self.prev_recv_pipe.lock().clone() {
+                        if self.is_validated(&prev) {
+                            self.hooks.transmit(&prev, pkt.clone());
+                        }
+                    }
+                }
+                if self.is_validated(&pipe) {
+                    self.transmit(&pipe, pkt, segment);
+                }
                 return;
             }
         }
 
+        #[cfg(feature = "multipath")]
+        let policy = match *self.multipath_policy.read() {
+            MultipathPolicy::WeightedRoundRobin | MultipathPolicy::Bonded if single_path => {
+                MultipathPolicy::LowestRtt
+            }
+            policy => policy,
+        };
+        #[cfg(not(feature = "multipath"))]
+        let policy = *self.multipath_policy.read();
+        if let Some(pipe) = segment.and_then(|segment| self.pipe_of(segment)) {
+            self.transmit(&pipe, pkt, segment);
+            return;
+        }
+        match policy {
+            MultipathPolicy::LowestRtt => {}
+            #[cfg(feature = "multipath")]
+            MultipathPolicy::WeightedRoundRobin => {
+                if let Some(pipe) = self.weighted_pick(segment.is_some()) {
+                    self.transmit(&pipe, pkt, segment);
+                    return;
+                }
+            }
+            #[cfg(feature = "multipath")]
+            MultipathPolicy::Bonded => {
+                if let Some(pipe) = self.bonded_pick(pkt.len(), segment.is_some()) {
+                    self.transmit(&pipe, pkt, segment);
+                    return;
+                }
+            }
+            #[cfg(feature = "multipath")]
+            MultipathPolicy::Redundant => {
+                let fastest = self.fastest_pipes(2);
+                if !fastest.is_empty() {
+                    for pipe in fastest {
+                        self.transmit(&pipe, pkt.clone(), segment);
+                    }
+                    return;
+                }
+            }
+        }
         let bb = self.selected_send_pipe.lock().as_ref().cloned();
-        if let Some(last) = bb {
-            last.send(pkt);
+        if let Some(last) = bb.filter(|last| self.is_validated(last)) {
+            self.transmit(&last, pkt, segment);
         }
     }
 
-    pub async fn recv(&self) -> anyhow::Result<Bytes> {
-        let (ret, pipe) = self.recv_incoming.recv().await?;
-        *self.last_recv_pipe.lock() = Some(pipe);
+    /// Sends a datagram down a pipe of the pool, counting the data segment it carries, if any, against the pipe.
+    fn transmit(&self, pipe: &Arc<dyn Pipe>, pkt: Bytes, segment: Option<(StreamId, Seqno)>) {
+        if let Some((stream_id, seqno)) = segment {
+            let counters = self
+                .pipes
+                .read()
+                .iter()
+                .find(|p| Arc::ptr_eq(&p.pipe, pipe))
+                .map(|p| p.counters.clone());
+            if let Some(counters) = counters {
+                self.routes.on_sent(stream_id, seqno, &counters);
+            }
+        }
+        self.hooks.transmit(pipe, pkt);
+    }
+
+    /// The pipe that an earlier piece of a segment went out over, as long as it may still carry traffic.
+    fn pipe_of(&self, (stream_id, seqno): (StreamId, Seqno)) -> Option<Arc<dyn Pipe>> {
+        let counters = self.routes.pipe_of(stream_id, seqno)?;
+        self.pipes
+            .read()
+            .iter()
+            .find(|p| Arc::ptr_eq(&p.counters, &counters) && p.counters.is_usable())
+            .map(|p| p.pipe.clone())
+    }
+
+    /// Whether traffic may go over a pipe, which it may not while the pipe waits to be validated after its address changed.
+    fn is_validated(&self, pipe: &Arc<dyn Pipe>) -> bool {
+        self.pipes
+            .read()
+            .iter()
+            .find(|p| Arc::ptr_eq(&p.pipe, pipe))
+            .is_none_or(|p| p.counters.is_validated())
+    }
+
+    #[cfg(feature = "multipath")]
+    /// Picks the next pipe by smooth weighted round-robin among the live pipes with a known RTT, weighing each by the inverse of its RTT. A data segment only goes to pipes with room for it, if any has.
+    fn weighted_pick(&self, segment: bool) -> Option<Arc<dyn Pipe>> {
+        let pipes = self.pipes.read();
+        let _guard = self.wrr_lock.lock();
+        let mut total = 0;
+        let mut best: Option<(&SinglePipe, i64)> = None;
+        let candidates = pipes
+            .iter()
+            .filter(|p| p.counters.is_usable())
+            .filter_map(|p| Some((p, p.counters.rtt()?)))
+            .collect();
+        for (pipe, rtt) in with_room(candidates, segment, |(p, _)| p) {
+            let weight = (1_000_000_000 / rtt.as_micros().max(1)) as i64;
+            total += weight;
+            let credit = pipe.credit.fetch_add(weight, Ordering::Relaxed) + weight;
+            if best.is_none_or(|(_, best_credit)| credit > best_credit) {
+                best = Some((pipe, credit));
+            }
+        }
+        let (best, _) = best?;
+        best.credit.fetch_sub(total, Ordering::Relaxed);
+        Some(best.pipe.clone())
+    }
+
+    #[cfg(feature = "multipath")]
+    /// Picks the live pipe, among those that answer bonding requests, that a datagram of `len` bytes would arrive first over. See [bonding::BondEstimator::arrival]. A data segment only goes to pipes with room for it, if any has.
+    fn bonded_pick(&self, len: usize, segment: bool) -> Option<Arc<dyn Pipe>> {
+        let pipes = self.pipes.read();
+        let _guard = self.wrr_lock.lock();
+        let now = runtime::now();
+        let candidates = pipes
+            .iter()
+            .filter(|p| p.counters.is_usable())
+            .map(|p| (p, p.counters.bond()))
+            .filter(|(_, bond)| bond.is_reporting())
+            .collect();
+        let (pipe, mut bond) = with_room(candidates, segment, |(p, _)| p)
+            .into_iter()
+            .min_by_key(|(_, bond)| bond.arrival(len, now))?;
+        bond.schedule(len, now);
+        Some(pipe.pipe.clone())
+    }
+
+    #[cfg(feature = "multipath")]
+    /// Returns up to `count` live pipes with known RTTs, the fastest first.
+    fn fastest_pipes(&self, count: usize) -> Vec<Arc<dyn Pipe>> {
+        let mut pipes: Vec<(Duration, Arc<dyn Pipe>)> = self
+            .pipes
+            .read()
+            .iter()
+            .filter(|p| p.counters.is_usable())
+            .filter_map(|p| Some((p.counters.rtt()?, p.pipe.clone())))
+            .collect();
+        pipes.sort_unstable_by_key(|(rtt, _)| *rtt);
+        pipes.into_iter().take(count).map(|(_, p)| p).collect()
+    }
+
+    /// Receives a datagram for the session, with the counters of the pipe it came from, so that the pipe can be marked authenticated once the datagram turns out to be.
+    pub async fn recv(&self) -> anyhow::Result<(Bytes, Arc<PipeCounters>)> {
+        let (ret, pipe, counters) = self.recv_incoming.recv().await?;
+        self.heard_from_peer.store(true, Ordering::Relaxed);
+        let mut last = self.last_recv_pipe.lock();
+        if let Some(last) = last.as_ref().filter(|last| !Arc::ptr_eq(last, &pipe)) {
+            *self.prev_recv_pipe.lock() = Some(last.clone());
+            if self.naive_send {
+                trace_lifecycle("PipeSelected", &pipe_name(&*pipe), "last heard from");
+            }
+        }
+        *last = Some(pipe);
+        drop(last);
         // on average, we update the recv time every 100 KB of reads
         if fastrand::f64() < 0.01 * (ret.len() as f64 / 1000.0) {
-            *self.last_significant_recv_time.write() = Instant::now();
+            *self.last_significant_recv_time.write() = runtime::now();
         }
-        Ok(ret)
+        Ok((ret, counters))
+    }
+}
+
+#[cfg(feature = "multipath")]
+/// Narrows down the pipes that a datagram may go over to those with room in their congestion window and pacing for another data segment, if it carries one and any of them has room. Pipes so much slower than the fastest that the segment would arrive later than waiting for room there are left out too. See [earliest_arrivals].
+fn with_room<'a, T>(
+    candidates: Vec<T>,
+    segment: bool,
+    pipe: impl Fn(&T) -> &'a SinglePipe,
+) -> Vec<T> {
+    if !segment {
+        return candidates;
+    }
+    let now = runtime::now();
+    let delays: Vec<_> = candidates
+        .iter()
+        .map(|c| pipe(c).counters.one_way_delay())
+        .collect();
+    let roomy: Vec<bool> = candidates
+        .iter()
+        .zip(earliest_arrivals(&delays))
+        .map(|(c, in_time)| in_time && pipe(c).counters.path().has_room(now))
+        .collect();
+    if !roomy.contains(&true) {
+        return candidates;
     }
+    candidates
+        .into_iter()
+        .zip(roomy)
+        .filter_map(|(c, roomy)| roomy.then_some(c))
+        .collect()
 }
 
 async fn pipe_associated_task(
     ping_notify: Arc<Event>,
     pipe: Arc<dyn Pipe>,
-    send_incoming: Sender<(Bytes, Arc<dyn Pipe>)>,
+    send_incoming: Sender<Received>,
+    hooks: Arc<PipeHooks>,
+    counters: Arc<PipeCounters>,
+    send_mtu_ack: Sender<usize>,
 ) {
     loop {
         let pkt = pipe.recv().await;
         if let Ok(pkt) = pkt {
+            let pkt = if let Some(pkt) = hooks.receive(&pipe, pkt) {
+                pkt
+            } else {
+                log::trace!("dropping datagram with a bad cookie");
+                continue;
+            };
+            if let Some(old_addr) = counters.on_peer_addr(&pipe.peer_addr()) {
+                on_addr_change(&pipe, &hooks, &counters, old_addr);
+            }
             // these are invalid messages anyway
             if pkt[..] == b"!!ping!!"[..] {
-                // in this case, we just reflect back a pong
-
-                pipe.send(Bytes::from_static(b"!!pong!!"));
+                // in this case, we just reflect back a pong, unless that would tell anyone who asks that a session is here
+                if !hooks.silent.load(Ordering::Relaxed) || counters.is_authenticated() {
+                    hooks.transmit(&pipe, Bytes::from_static(b"!!pong!!"));
+                }
             } else if pkt[..] == b"!!pong!!"[..] {
-                ping_notify.notify(1);
-            } else {
-                let _ = send_incoming.send((pkt, pipe.clone())).await;
+                counters.on_pong_received();
+                if !counters.is_validated() {
+                    log::debug!("pipe {} validated", pipe_name(&*pipe));
+                    counters.set_validated(true);
+                }
+                // health probes and the periodic ones may be waiting at the same time
+                ping_notify.notify(usize::MAX);
+            } else if let Some(answer) = path_mtu::answer_probe(&pkt) {
+                if counters.is_authenticated() {
+                    hooks.transmit(&pipe, answer);
+                }
+            } else if let Some(size) = path_mtu::parse_ack(&pkt) {
+                let _ = send_mtu_ack.try_send(size);
+            } else if !handle_bonding(&pkt, &pipe, &hooks, &counters) {
+                let _ = send_incoming
+                    .send((pkt, pipe.clone(), counters.clone()))
+                    .await;
             }
         } else {
+            trace_lifecycle("PipeClosed", &pipe_name(&*pipe), "");
             return;
         }
     }
 }
+
+/// Answers a bonding request or takes in a report, returning whether the datagram was one. Requests are only answered over pipes that carried an authenticated message, so that nobody else learns there is a session here.
+#[cfg(feature = "multipath")]
+fn handle_bonding(
+    pkt: &Bytes,
+    pipe: &Arc<dyn Pipe>,
+    hooks: &PipeHooks,
+    counters: &PipeCounters,
+) -> bool {
+    if let Some(report) = bonding::answer_request(pkt, || counters.recv_bytes()) {
+        if counters.is_authenticated() {
+            hooks.transmit(pipe, report);
+        }
+    } else if let Some(report) = bonding::parse_report(pkt) {
+        counters.on_bond_report(report);
+    } else {
+        return false;
+    }
+    true
+}
+
+#[cfg(not(feature = "multipath"))]
+fn handle_bonding(
+    _pkt: &Bytes,
+    _pipe: &Arc<dyn Pipe>,
+    _hooks: &PipeHooks,
+    _counters: &PipeCounters,
+) -> bool {
+    false
+}
+
+/// Tells whoever wants to know that the other side of a pipe was heard from at another address and, if the pool revalidates pipes, keeps traffic off the pipe and probes it until a probe is answered from there.
+fn on_addr_change(
+    pipe: &Arc<dyn Pipe>,
+    hooks: &Arc<PipeHooks>,
+    counters: &Arc<PipeCounters>,
+    old_addr: String,
+) {
+    let new_addr = pipe.peer_addr();
+    log::warn!("pipe {}/{old_addr} moved to {new_addr}", pipe.protocol());
+    trace_lifecycle(
+        "PipeAddressChanged",
+        &pipe_name(&**pipe),
+        format_args!("from {old_addr}"),
+    );
+    if let Some(hook) = hooks.addr_change.read().as_ref() {
+        hook(&AddressChange {
+            time: SystemTime::now(),
+            protocol: pipe.protocol().to_owned(),
+            old_addr,
+            new_addr,
+        })
+    }
+    if !hooks.revalidate.load(Ordering::Relaxed) || !counters.is_validated() {
+        return;
+    }
+    counters.set_validated(false);
+    let pipe = Arc::downgrade(pipe);
+    let hooks = hooks.clone();
+    let counters = counters.clone();
+    runtime::spawn(async move {
+        let mut wait = Duration::from_millis(200);
+        while !counters.is_validated() {
+            // stops once the pipe is gone
+            let Some(pipe) = pipe.upgrade() else {
+                return;
+            };
+            hooks.transmit(&pipe, Bytes::from_static(b"!!ping!!"));
+            counters.on_ping_sent();
+            drop(pipe);
+            runtime::Timer::after(wait).await;
+            wait = (wait * 2).min(Duration::from_secs(10));
+        }
+    })
+    .detach();
+}
+
+/// How pipes are named in traces.
+fn pipe_name(pipe: &dyn Pipe) -> String {
+    format!("{}/{}", pipe.protocol(), pipe.peer_addr())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use async_trait::async_trait;
+    use bytes::Bytes;
+    use parking_lot::Mutex;
+    use smol::prelude::*;
+
+    use crate::{
+        sim::{sim_pipe_pair, SimLink, SimPipe},
+        utilities::runtime::{self, TimeoutExt},
+        FailoverPolicy, Multiplex, MuxSecret, Pipe,
+    };
+    #[cfg(feature = "multipath")]
+    use crate::{MultipathPolicy, StreamOptions};
+
+    /// A simulated pipe whose other side can be made to show up at another address.
+    struct RebindingPipe {
+        inner: SimPipe,
+        addr: Arc<Mutex<String>>,
+    }
+
+    #[async_trait]
+    impl Pipe for RebindingPipe {
+        fn send(&self, to_send: Bytes) {
+            self.inner.send(to_send)
+        }
+
+        async fn recv(&self) -> std::io::Result<Bytes> {
+            self.inner.recv().await
+        }
+
+        fn protocol(&self) -> &str {
+            "sim"
+        }
+
+        fn peer_metadata(&self) -> &str {
+            ""
+        }
+
+        fn peer_addr(&self) -> String {
+            self.addr.lock().clone()
+        }
+    }
+
+    #[test]
+    fn address_change() {
+        smol::block_on(async {
+            let server_sk = MuxSecret::generate();
+            let server = Multiplex::new(server_sk.clone(), None);
+            let client = Multiplex::new(MuxSecret::generate(), Some(server_sk.to_public()));
+            let (client_pipe, server_pipe) = sim_pipe_pair(SimLink {
+                delay: Duration::from_millis(20),
+                ..Default::default()
+            });
+            let addr = Arc::new(Mutex::new("10.0.0.1:1000".to_owned()));
+            client.add_pipe(client_pipe);
+            server.add_pipe(RebindingPipe {
+                inner: server_pipe,
+                addr: addr.clone(),
+            });
+            let (send_change, recv_change) = smol::channel::unbounded();
+            server.set_address_change_hook(Some(Arc::new(move |change| {
+                let _ = send_change.try_send(change.clone());
+            })));
+            server.set_revalidate_on_address_change(true);
+
+            let mut stream = client.open_conn("").await.unwrap();
+            let mut accepted = server.accept_conn().await.unwrap();
+            stream.write_all(b"before").await.unwrap();
+            let mut buf = [0u8; 6];
+            accepted.read_exact(&mut buf).await.unwrap();
+
+            // the client's NAT rebinds
+            *addr.lock() = "10.0.0.1:2000".to_owned();
+            stream.write_all(b"after!").await.unwrap();
+            let change = recv_change.recv().await.unwrap();
+            assert_eq!(change.old_addr, "10.0.0.1:1000");
+            assert_eq!(change.new_addr, "10.0.0.1:2000");
+            // the server answers once the client answered its probe from the new address
+            accepted.read_exact(&mut buf).await.unwrap();
+            accepted.write_all(b"reply!").await.unwrap();
+            stream.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"reply!");
+            let stats = &server.pipe_stats()[0];
+            assert_eq!(stats.addr_changes, 1);
+            assert!(stats.validated);
+        })
+    }
+
+    /// A simulated pipe that can be cut off, losing everything both ways.
+    struct CuttablePipe {
+        inner: SimPipe,
+        cut: Arc<AtomicBool>,
+    }
+
+    #[async_trait]
+    impl Pipe for CuttablePipe {
+        fn send(&self, to_send: Bytes) {
+            if !self.cut.load(Ordering::Relaxed) {
+                self.inner.send(to_send)
+            }
+        }
+
+        async fn recv(&self) -> std::io::Result<Bytes> {
+            loop {
+                let pkt = self.inner.recv().await?;
+                if !self.cut.load(Ordering::Relaxed) {
+                    return Ok(pkt);
+                }
+            }
+        }
+
+        fn protocol(&self) -> &str {
+            "sim"
+        }
+
+        fn peer_metadata(&self) -> &str {
+            ""
+        }
+
+        fn peer_addr(&self) -> String {
+            "cuttable".into()
+        }
+    }
+
+    #[test]
+    fn traffic_moves_off_dead_pipes() {
+        smol::block_on(async {
+            let server_sk = MuxSecret::generate();
+            let server = Multiplex::new(server_sk.clone(), None);
+            let client = Multiplex::new(MuxSecret::generate(), Some(server_sk.to_public()));
+            client.set_failover_policy(Some(FailoverPolicy {
+                probe_interval: Duration::from_millis(100),
+                timeout: Duration::from_millis(500),
+            }));
+            let link = SimLink {
+                delay: Duration::from_millis(20),
+                ..Default::default()
+            };
+            let cut = Arc::new(AtomicBool::new(false));
+            let (client_pipe, server_pipe) = sim_pipe_pair(link);
+            // everything goes over the first pipe until it dies
+            client.add_pipe(CuttablePipe {
+                inner: client_pipe,
+                cut: cut.clone(),
+            });
+            server.add_pipe(server_pipe);
+            let (client_pipe, server_pipe) = sim_pipe_pair(link);
+            client.add_pipe(client_pipe);
+            server.add_pipe(server_pipe);
+
+            let mut stream = client.open_conn("").await.unwrap();
+            let mut accepted = server.accept_conn().await.unwrap();
+            let mut buf = [0u8; 6];
+            stream.write_all(b"before").await.unwrap();
+            accepted.read_exact(&mut buf).await.unwrap();
+
+            // what was lost with the pipe arrives over the other one long before retransmission timeouts would pile up
+            cut.store(true, Ordering::Relaxed);
+            stream.write_all(b"after!").await.unwrap();
+            accepted
+                .read_exact(&mut buf)
+                .timeout(Duration::from_secs(3))
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(&buf, b"after!");
+            let alive: Vec<bool> = client.pipe_stats().iter().map(|s| s.alive).collect();
+            assert_eq!(alive, [false, true]);
+
+            // and the pipe comes back once a later probe is answered
+            cut.store(false, Ordering::Relaxed);
+            async {
+                while !client.pipe_stats()[0].alive {
+                    runtime::Timer::after(Duration::from_millis(50)).await;
+                }
+            }
+            .timeout(Duration::from_secs(2))
+            .await
+            .unwrap();
+        })
+    }
+
+    #[test]
+    fn silent_pipes_only_answer_the_session() {
+        smol::block_on(async {
+            let server_sk = MuxSecret::generate();
+            let server = Multiplex::new(server_sk.clone(), None);
+            server.set_silent_until_authenticated(true);
+            let client = Multiplex::new(MuxSecret::generate(), Some(server_sk.to_public()));
+            client.set_failover_policy(Some(FailoverPolicy {
+                probe_interval: Duration::from_millis(100),
+                timeout: Duration::from_secs(5),
+            }));
+            let (client_pipe, server_pipe) = sim_pipe_pair(SimLink::default());
+            client.add_pipe(client_pipe);
+            server.add_pipe(server_pipe);
+            let (scanner, server_pipe) = sim_pipe_pair(SimLink::default());
+            server.add_pipe(server_pipe);
+
+            // whoever does not hold the session keys hears nothing
+            scanner.send(Bytes::from_static(b"!!ping!!"));
+            assert!(scanner
+                .recv()
+                .timeout(Duration::from_millis(500))
+                .await
+                .is_none());
+
+            // while the client's health probes are answered once its messages came over the pipe
+            let mut stream = client.open_conn("").await.unwrap();
+            let mut accepted = server.accept_conn().await.unwrap();
+            stream.write_all(b"hello").await.unwrap();
+            let mut buf = [0u8; 5];
+            accepted.read_exact(&mut buf).await.unwrap();
+            runtime::Timer::after(Duration::from_millis(500)).await;
+            let stats = &client.pipe_stats()[0];
+            assert!(stats.rtt.is_some(), "{stats:?}");
+            assert!(stats.alive);
+        })
+    }
+
+    #[cfg(feature = "multipath")]
+    #[test]
+    fn unauthenticated_bonding_requests_go_unanswered() {
+        smol::block_on(async {
+            let server = Multiplex::new(MuxSecret::generate(), None);
+            let (scanner, server_pipe) = sim_pipe_pair(SimLink::default());
+            server.add_pipe(server_pipe);
+            scanner.send(super::bonding::request(0, 0));
+            // the server's own hello may come over the pipe, but no report
+            while let Some(Ok(pkt)) = scanner.recv().timeout(Duration::from_millis(500)).await {
+                assert!(super::bonding::parse_report(&pkt).is_none());
+            }
+        })
+    }
+
+    #[cfg(feature = "multipath")]
+    #[test]
+    fn striped_pipes_keep_their_own_windows() {
+        let clean = SimLink {
+            delay: Duration::from_millis(15),
+            bandwidth: Some(1_000_000.0),
+            ..Default::default()
+        };
+        let lossy = SimLink { loss: 0.2, ..clean };
+        smol::block_on(async {
+            let server_sk = MuxSecret::generate();
+            let server = Multiplex::new(server_sk.clone(), None);
+            let client = Multiplex::new(MuxSecret::generate(), Some(server_sk.to_public()));
+            client.set_multipath_policy(MultipathPolicy::Bonded);
+            for link in [clean, lossy] {
+                let (client_pipe, server_pipe) = sim_pipe_pair(link);
+                client.add_pipe(client_pipe);
+                server.add_pipe(server_pipe);
+            }
+
+            let mut stream = client.open_conn("").await.unwrap();
+            let mut incoming = server.accept_conn().await.unwrap();
+            let _send = runtime::spawn(async move {
+                let chunk = vec![0u8; 65536];
+                while stream.write_all(&chunk).await.is_ok() {}
+            });
+            let _recv = runtime::spawn(async move {
+                let mut buf = vec![0u8; 65536];
+                while incoming.read(&mut buf).await.is_ok() {}
+            });
+            runtime::Timer::after(Duration::from_secs(3)).await;
+
+            let stats = client.pipe_stats();
+            assert!(
+                stats.iter().all(|s| s.sent_packets > 100),
+                "not striped: {stats:?}"
+            );
+            // losses on one path shrink only that path's window
+            assert!(
+                stats[1].cwnd < stats[0].cwnd,
+                "lossy window {} not below clean window {}: {stats:?}",
+                stats[1].cwnd,
+                stats[0].cwnd
+            );
+            // and its losses are counted against it alone
+            assert!(
+                stats[1].loss.loss_rate() > 0.1 && stats[0].loss.loss_rate() < 0.05,
+                "losses not told apart: {stats:?}"
+            );
+        })
+    }
+
+    #[cfg(feature = "multipath")]
+    #[test]
+    fn single_path_streams_are_not_striped() {
+        let link = SimLink {
+            delay: Duration::from_millis(15),
+            bandwidth: Some(2_000_000.0),
+            ..Default::default()
+        };
+        smol::block_on(async {
+            let server_sk = MuxSecret::generate();
+            let server = Multiplex::new(server_sk.clone(), None);
+            let client = Multiplex::new(MuxSecret::generate(), Some(server_sk.to_public()));
+            client.set_multipath_policy(MultipathPolicy::Bonded);
+            for _ in 0..2 {
+                let (client_pipe, server_pipe) = sim_pipe_pair(link);
+                client.add_pipe(client_pipe);
+                server.add_pipe(server_pipe);
+            }
+
+            let mut stream = client
+                .open_conn_with_options(
+                    "",
+                    StreamOptions {
+                        single_path: true,
+                        ..Default::default()
+                    },
+                )
+                .await
+                .unwrap();
+            let mut incoming = server.accept_conn().await.unwrap();
+            let data = vec![0u8; 1_000_000];
+            stream.write_all(&data).await.unwrap();
+            let mut buf = vec![0u8; data.len()];
+            incoming.read_exact(&mut buf).await.unwrap();
+
+            // everything went over the first pipe added, which is the lowest-RTT one until probes find another, and the other only carried bonding requests
+            let stats = client.pipe_stats();
+            assert!(stats[0].sent_bytes > 1_000_000, "{stats:?}");
+            assert!(stats[1].sent_bytes < 50_000, "{stats:?}");
+        })
+    }
+
+    #[cfg(feature = "multipath")]
+    #[test]
+    fn short_streams_are_not_striped() {
+        let link = SimLink {
+            delay: Duration::from_millis(15),
+            bandwidth: Some(2_000_000.0),
+            ..Default::default()
+        };
+        smol::block_on(async {
+            let server_sk = MuxSecret::generate();
+            let server = Multiplex::new(server_sk.clone(), None);
+            let client = Multiplex::new(MuxSecret::generate(), Some(server_sk.to_public()));
+            client.set_multipath_policy(MultipathPolicy::Bonded);
+            client.set_single_path_below(200_000);
+            for _ in 0..2 {
+                let (client_pipe, server_pipe) = sim_pipe_pair(link);
+                client.add_pipe(client_pipe);
+                server.add_pipe(server_pipe);
+            }
+
+            let mut stream = client.open_conn("").await.unwrap();
+            let mut incoming = server.accept_conn().await.unwrap();
+            let mut buf = vec![0u8; 150_000];
+            stream.write_all(&buf).await.unwrap();
+            incoming.read_exact(&mut buf).await.unwrap();
+            let stats = client.pipe_stats();
+            assert!(stats[0].sent_bytes > 150_000, "{stats:?}");
+            assert!(stats[1].sent_bytes < 20_000, "{stats:?}");
+
+            // past the threshold, the stream is striped like any other
+            let mut buf = vec![0u8; 1_000_000];
+            stream.write_all(&buf).await.unwrap();
+            incoming.read_exact(&mut buf).await.unwrap();
+            let stats = client.pipe_stats();
+            assert!(stats[1].sent_bytes > 200_000, "{stats:?}");
+        })
+    }
+}
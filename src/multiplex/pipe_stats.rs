@@ -0,0 +1,317 @@
+use std::{
+    sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+    time::{Duration, Instant},
+};
+
+use parking_lot::{Mutex, MutexGuard};
+
+#[cfg(feature = "multipath")]
+use super::bonding::{BondEstimator, BondingStats, Report};
+use super::{
+    path_congestion::PathCongestion,
+    stream::{throughput::ThroughputEstimator, LossStats},
+};
+use crate::{utilities::runtime, DialTimings, Pipe};
+
+/// The weight of each new probe RTT in the smoothed RTT, as in TCP.
+const SRTT_ALPHA: f64 = 0.125;
+
+/// Statistics of one pipe of a [crate::Multiplex], as returned by [crate::Multiplex::pipe_stats].
+///
+/// RTTs and probe loss come from the probes that the side that opened the connection sends; on the other side, they stay empty.
+#[derive(Clone, Debug)]
+pub struct PipeStats {
+    pub protocol: String,
+    pub peer_addr: String,
+    /// Round-trip time measured by the latest probe, or `None` if the pipe has not answered one yet, or did not answer the last one.
+    pub rtt: Option<Duration>,
+    /// Moving average of the RTTs of all probes answered so far, or `None` before the first.
+    pub smoothed_rtt: Option<Duration>,
+    /// The fraction of probes sent over the pipe that were not answered, over its whole lifetime. Probes still waiting for an answer count as unanswered.
+    pub probe_loss: f64,
+    /// Datagrams and bytes handed to the pipe, including probes.
+    pub sent_packets: u64,
+    pub sent_bytes: u64,
+    /// Datagrams and bytes received from the pipe, including probes and datagrams that were then dropped.
+    pub recv_packets: u64,
+    pub recv_bytes: u64,
+    /// Bytes per second handed to and received from the pipe, averaged over the recent periods when traffic was flowing.
+    pub send_throughput: f64,
+    pub recv_throughput: f64,
+    /// When a datagram was last handed to the pipe, if ever.
+    pub last_sent: Option<Instant>,
+    /// When a datagram was last received from the pipe, if ever.
+    pub last_recv: Option<Instant>,
+    /// How long dialing the pipe took, step by step. See [Pipe::dial_timings].
+    pub dial_timings: Option<DialTimings>,
+    /// The largest datagrams found to get across the pipe, or `None` unless path MTU discovery found them. See [crate::Multiplex::set_path_mtu_discovery].
+    pub path_mtu: Option<usize>,
+    /// What the pipe achieves under [crate::MultipathPolicy::Bonded], or `None` unless this side bonds its pipes and the other side has reported on this one.
+    #[cfg(feature = "multipath")]
+    pub bonding: Option<BondingStats>,
+    /// Whether the pipe is considered alive. With [crate::Multiplex::set_failover_policy], a pipe that stays silent for the configured timeout is considered dead until it is heard from again; without it, pipes are always considered alive.
+    pub alive: bool,
+    /// How many times the address of the other side, as [Pipe::peer_addr] gives it, was seen to change, e.g. because a NAT rebound a UDP pipe's port. See [crate::Multiplex::set_address_change_hook].
+    pub addr_changes: u64,
+    /// Whether the pipe may carry traffic. With [crate::Multiplex::set_revalidate_on_address_change], a pipe whose address changed carries nothing but probes until one is answered from the new address.
+    pub validated: bool,
+    /// The congestion window of the pipe, in segments. Under [crate::MultipathPolicy::WeightedRoundRobin] and [crate::MultipathPolicy::Bonded], each pipe only carries as many data segments as its own window allows.
+    pub cwnd: f64,
+    /// Data segments sent over the pipe that are neither acked nor found lost.
+    pub inflight: usize,
+    /// Histograms of the losses of stream data sent over the pipe. A segment that a stream retransmits counts as lost on the pipe it last went out over, and one that is acked as delivered by the pipe that carried it.
+    pub loss: LossStats,
+}
+
+/// The traffic in one direction of a pipe.
+#[derive(Default)]
+struct Traffic {
+    packets: u64,
+    bytes: u64,
+    last: Option<Instant>,
+    throughput: ThroughputEstimator,
+}
+
+impl Traffic {
+    fn record(&mut self, len: usize) {
+        let now = runtime::now();
+        self.packets += 1;
+        self.bytes += len as u64;
+        self.last = Some(now);
+        self.throughput.on_delivered(len as u64, now);
+    }
+}
+
+pub(crate) struct PipeCounters {
+    added: Instant,
+    // the latest probe's RTT, and when it was answered
+    rtt: Mutex<Option<(Duration, Instant)>>,
+    srtt: Mutex<Option<Duration>>,
+    pings: AtomicU64,
+    pongs: AtomicU64,
+    // 0 if unknown
+    path_mtu: AtomicUsize,
+    #[cfg(feature = "multipath")]
+    bond: Mutex<BondEstimator>,
+    path: Mutex<PathCongestion>,
+    dead: AtomicBool,
+    // the latest address the other side was seen at
+    addr: Mutex<Option<String>>,
+    addr_changes: AtomicU64,
+    unvalidated: AtomicBool,
+    // whether a message that passed decryption and the replay filter came over the pipe
+    authenticated: AtomicBool,
+    sent: Mutex<Traffic>,
+    received: Mutex<Traffic>,
+}
+
+impl Default for PipeCounters {
+    fn default() -> Self {
+        Self {
+            added: runtime::now(),
+            rtt: Default::default(),
+            srtt: Default::default(),
+            pings: Default::default(),
+            pongs: Default::default(),
+            path_mtu: Default::default(),
+            #[cfg(feature = "multipath")]
+            bond: Default::default(),
+            path: Default::default(),
+            dead: Default::default(),
+            addr: Default::default(),
+            addr_changes: Default::default(),
+            unvalidated: Default::default(),
+            authenticated: Default::default(),
+            sent: Default::default(),
+            received: Default::default(),
+        }
+    }
+}
+
+impl PipeCounters {
+    pub fn on_send(&self, len: usize) {
+        self.sent.lock().record(len);
+    }
+
+    pub fn on_recv(&self, len: usize) {
+        self.received.lock().record(len);
+    }
+
+    /// How long nothing has been received, or since the pipe was added if nothing ever was.
+    pub fn silence(&self) -> Duration {
+        runtime::elapsed(self.received.lock().last.unwrap_or(self.added))
+    }
+
+    pub fn is_dead(&self) -> bool {
+        self.dead.load(Ordering::Relaxed)
+    }
+
+    /// Marks the pipe dead or alive, returning whether that changed anything. A dead pipe's RTT is forgotten, so that the multipath policies leave it alone.
+    pub fn set_dead(&self, dead: bool) -> bool {
+        if dead {
+            *self.rtt.lock() = None;
+        }
+        self.dead.swap(dead, Ordering::Relaxed) != dead
+    }
+
+    /// Whether traffic may be scheduled on the pipe: it is neither dead nor waiting to be validated.
+    pub fn is_usable(&self) -> bool {
+        !self.is_dead() && !self.unvalidated.load(Ordering::Relaxed)
+    }
+
+    /// Notes the address the other side was just heard from, returning the one before if it changed.
+    pub fn on_peer_addr(&self, addr: &str) -> Option<String> {
+        let mut last = self.addr.lock();
+        match last.as_mut() {
+            Some(last) if *last != addr => {
+                self.addr_changes.fetch_add(1, Ordering::Relaxed);
+                Some(std::mem::replace(last, addr.to_owned()))
+            }
+            Some(_) => None,
+            None => {
+                *last = Some(addr.to_owned());
+                None
+            }
+        }
+    }
+
+    pub fn set_validated(&self, validated: bool) {
+        self.unvalidated.store(!validated, Ordering::Relaxed);
+    }
+
+    pub fn is_validated(&self) -> bool {
+        !self.unvalidated.load(Ordering::Relaxed)
+    }
+
+    /// Notes that a message authenticated by the session came over the pipe.
+    pub fn set_authenticated(&self) {
+        self.authenticated.store(true, Ordering::Relaxed);
+    }
+
+    /// Whether the pipe carried a message authenticated by the session, and not just whatever anyone can send.
+    pub fn is_authenticated(&self) -> bool {
+        self.authenticated.load(Ordering::Relaxed)
+    }
+
+    pub fn on_ping_sent(&self) {
+        self.pings.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn on_pong_received(&self) {
+        self.pongs.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn on_probe_answered(&self, rtt: Duration) {
+        *self.rtt.lock() = Some((rtt, runtime::now()));
+        let mut srtt = self.srtt.lock();
+        *srtt = Some(match *srtt {
+            Some(srtt) => srtt.mul_f64(1.0 - SRTT_ALPHA) + rtt.mul_f64(SRTT_ALPHA),
+            None => rtt,
+        });
+    }
+
+    /// Forgets the RTT unless a probe was answered since the given time.
+    pub fn on_probe_unanswered(&self, since: Instant) {
+        let mut rtt = self.rtt.lock();
+        if rtt.is_some_and(|(_, answered)| answered < since) {
+            *rtt = None;
+        }
+    }
+
+    pub fn rtt(&self) -> Option<Duration> {
+        self.rtt.lock().map(|(rtt, _)| rtt)
+    }
+
+    pub fn smoothed_rtt(&self) -> Option<Duration> {
+        *self.srtt.lock()
+    }
+
+    pub fn set_path_mtu(&self, mtu: Option<usize>) {
+        self.path_mtu.store(mtu.unwrap_or(0), Ordering::Relaxed);
+    }
+
+    pub fn path_mtu(&self) -> Option<usize> {
+        Some(self.path_mtu.load(Ordering::Relaxed)).filter(|mtu| *mtu > 0)
+    }
+
+    #[cfg(feature = "multipath")]
+    /// Asks the other side how much it has received, for bonding.
+    pub fn bond_request(&self) -> bytes::Bytes {
+        self.bond.lock().on_request_sent();
+        super::bonding::request(
+            self.sent.lock().bytes,
+            runtime::elapsed(self.added).as_micros() as u64,
+        )
+    }
+
+    #[cfg(feature = "multipath")]
+    pub fn recv_bytes(&self) -> u64 {
+        self.received.lock().bytes
+    }
+
+    #[cfg(feature = "multipath")]
+    pub fn on_bond_report(&self, report: Report) {
+        let rtt =
+            runtime::elapsed(self.added).saturating_sub(Duration::from_micros(report.sent_micros));
+        self.bond.lock().on_report(report, rtt, runtime::now());
+    }
+
+    #[cfg(feature = "multipath")]
+    /// The pipe's share of bonding, for scheduling datagrams over it.
+    pub fn bond(&self) -> MutexGuard<'_, BondEstimator> {
+        self.bond.lock()
+    }
+
+    /// How long a segment sent over the pipe now should take to arrive: half the RTT of the segments acked over it, taking the lowest RTT while nothing is in flight, so that a pipe whose queue has drained is not judged by the delay it had. Probes stand in until a segment is acked, and `None` before those are answered too.
+    pub fn one_way_delay(&self) -> Option<Duration> {
+        let path = self.path();
+        let rtt = if path.inflight() == 0 {
+            path.min_rtt()
+        } else {
+            path.srtt()
+        };
+        rtt.or_else(|| self.smoothed_rtt()).map(|rtt| rtt / 2)
+    }
+
+    /// The congestion state of the pipe, which data segments sent over it are counted against.
+    pub fn path(&self) -> MutexGuard<'_, PathCongestion> {
+        self.path.lock()
+    }
+
+    pub fn snapshot(&self, pipe: &dyn Pipe) -> PipeStats {
+        let pings = self.pings.load(Ordering::Relaxed);
+        let pongs = self.pongs.load(Ordering::Relaxed);
+        let sent = self.sent.lock();
+        let received = self.received.lock();
+        let path = self.path();
+        PipeStats {
+            protocol: pipe.protocol().to_owned(),
+            peer_addr: pipe.peer_addr(),
+            rtt: self.rtt(),
+            smoothed_rtt: self.smoothed_rtt(),
+            probe_loss: if pings == 0 {
+                0.0
+            } else {
+                1.0 - (pongs.min(pings) as f64 / pings as f64)
+            },
+            sent_packets: sent.packets,
+            sent_bytes: sent.bytes,
+            recv_packets: received.packets,
+            recv_bytes: received.bytes,
+            send_throughput: sent.throughput.estimate(),
+            recv_throughput: received.throughput.estimate(),
+            last_sent: sent.last,
+            last_recv: received.last,
+            dial_timings: pipe.dial_timings(),
+            path_mtu: self.path_mtu(),
+            #[cfg(feature = "multipath")]
+            bonding: self.bond.lock().stats(),
+            alive: !self.is_dead(),
+            addr_changes: self.addr_changes.load(Ordering::Relaxed),
+            validated: self.is_validated(),
+            cwnd: path.cwnd(),
+            inflight: path.inflight(),
+            loss: path.loss_stats().clone(),
+        }
+    }
+}
@@ -0,0 +1,50 @@
+use std::time::{Duration, Instant};
+
+use once_cell::sync::Lazy;
+
+/// How long acks are held back, at most, so that several go out together.
+const ACK_DELAY: Duration = Duration::from_millis(10);
+const LOW_POWER_ACK_DELAY: Duration = Duration::from_millis(50);
+/// In low-power mode, timers that are not due soon fire on multiples of this, so that wakeups of different timers and multiplexes coincide.
+const LOW_POWER_TIMER_GRANULARITY: Duration = Duration::from_secs(5);
+
+// timers are rounded relative to this, so that all multiplexes in the process round them the same way
+static EPOCH: Lazy<Instant> = Lazy::new(Instant::now);
+
+/// How a multiplex trades responsiveness for fewer wakeups and transmissions, which matters mostly for battery-powered devices. Can be switched at any time with [crate::Multiplex::set_power_profile].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PowerProfile {
+    /// Acks go out within 10 ms, timers fire when they are due, and pipes are probed regularly to find the fastest one.
+    #[default]
+    Normal,
+    /// Acks are batched over up to 50 ms, timers that are not due within a few seconds, such as those of idle streams, are coalesced to 5-second boundaries, and pipes are not probed, so traffic stays on the current pipe even if another one gets faster. Throughput and latency suffer somewhat in exchange.
+    LowPower,
+}
+
+impl PowerProfile {
+    /// How long the multiplex waits after a tick before the next one, which batches up the acks generated in between.
+    pub(crate) fn ack_delay(&self) -> Duration {
+        match self {
+            PowerProfile::Normal => ACK_DELAY,
+            PowerProfile::LowPower => LOW_POWER_ACK_DELAY,
+        }
+    }
+
+    /// Whether pipes are probed to find the fastest one.
+    pub(crate) fn probes_pipes(&self) -> bool {
+        *self == PowerProfile::Normal
+    }
+
+    /// Postpones a timer that is not due soon to the next multiple of the timer granularity, if this profile coalesces timers.
+    pub(crate) fn coalesce(&self, deadline: Instant, now: Instant) -> Instant {
+        match self {
+            PowerProfile::LowPower if deadline >= now + LOW_POWER_TIMER_GRANULARITY => {
+                let granularity = LOW_POWER_TIMER_GRANULARITY.as_nanos();
+                let since_epoch = deadline.saturating_duration_since(*EPOCH).as_nanos();
+                let rounded = since_epoch.div_ceil(granularity) * granularity;
+                *EPOCH + Duration::from_nanos(rounded as u64)
+            }
+            _ => deadline,
+        }
+    }
+}
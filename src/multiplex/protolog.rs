@@ -0,0 +1,134 @@
+use std::{
+    sync::atomic::{AtomicU32, AtomicU64, Ordering},
+    time::Instant,
+};
+
+use once_cell::sync::Lazy;
+
+/// The `log` target that protocol events are logged under, so that they can be turned on and off apart from everything else.
+pub(crate) const TARGET: &str = "sosistab2::proto";
+
+/// How many times per second each kind of event is logged, at most, unless [set_protolog_rate_limit] says otherwise.
+const DEFAULT_RATE_LIMIT: u32 = 10;
+
+/// The per-packet events of the stream protocol that are counted, and logged at the debug level under the `sosistab2::proto` target, when the `protolog` feature is on. Without it, they cost nothing at all.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ProtoEvent {
+    /// A data packet was sent for the first time.
+    Send,
+    /// A packet was retransmitted because its timeout expired.
+    Retransmit,
+    /// A timed-out packet was retransmitted alone, to find out whether the timeout was spurious.
+    FrtoProbe,
+    /// A packet was taken to be lost because enough packets sent after it were acked.
+    FastRetransmit,
+    /// A stream started recovering from a loss.
+    Recovery,
+}
+
+impl ProtoEvent {
+    pub const ALL: [ProtoEvent; 5] = [
+        ProtoEvent::Send,
+        ProtoEvent::Retransmit,
+        ProtoEvent::FrtoProbe,
+        ProtoEvent::FastRetransmit,
+        ProtoEvent::Recovery,
+    ];
+}
+
+/// How often a [ProtoEvent] happened in this process, as returned by [protolog_counts].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ProtoEventCounts {
+    pub occurred: u64,
+    pub logged: u64,
+    /// Occurrences that were not logged because the rate limit was reached, while logging was on.
+    pub suppressed: u64,
+}
+
+struct Slot {
+    occurred: AtomicU64,
+    logged: AtomicU64,
+    suppressed: AtomicU64,
+    // the second since EPOCH that `in_window` counts log lines of
+    window: AtomicU64,
+    in_window: AtomicU32,
+}
+
+impl Slot {
+    const fn new() -> Self {
+        Self {
+            occurred: AtomicU64::new(0),
+            logged: AtomicU64::new(0),
+            suppressed: AtomicU64::new(0),
+            window: AtomicU64::new(0),
+            in_window: AtomicU32::new(0),
+        }
+    }
+
+    /// Whether another log line fits within the limit for the given second. Lines that lose a race at the turn of a second may be counted against either.
+    fn allow(&self, second: u64, limit: u32) -> bool {
+        let window = self.window.load(Ordering::Relaxed);
+        if window != second
+            && self
+                .window
+                .compare_exchange(window, second, Ordering::Relaxed, Ordering::Relaxed)
+                .is_ok()
+        {
+            self.in_window.store(0, Ordering::Relaxed);
+        }
+        if self.in_window.fetch_add(1, Ordering::Relaxed) < limit {
+            self.logged.fetch_add(1, Ordering::Relaxed);
+            true
+        } else {
+            self.suppressed.fetch_add(1, Ordering::Relaxed);
+            false
+        }
+    }
+}
+
+static SLOTS: [Slot; ProtoEvent::ALL.len()] = [const { Slot::new() }; ProtoEvent::ALL.len()];
+static RATE_LIMIT: AtomicU32 = AtomicU32::new(DEFAULT_RATE_LIMIT);
+static EPOCH: Lazy<Instant> = Lazy::new(Instant::now);
+
+/// Sets how many times per second each kind of [ProtoEvent] is logged, at most. Occurrences past that are only counted. Defaults to 10.
+pub fn set_protolog_rate_limit(per_second: u32) {
+    RATE_LIMIT.store(per_second, Ordering::Relaxed);
+}
+
+/// Returns how often the given event happened so far in this process, and how often it was logged.
+pub fn protolog_counts(event: ProtoEvent) -> ProtoEventCounts {
+    let slot = &SLOTS[event as usize];
+    ProtoEventCounts {
+        occurred: slot.occurred.load(Ordering::Relaxed),
+        logged: slot.logged.load(Ordering::Relaxed),
+        suppressed: slot.suppressed.load(Ordering::Relaxed),
+    }
+}
+
+/// Counts an occurrence of the event, returning whether it should be logged. Only called through [crate::multiplex::trace::proto_event].
+pub(crate) fn record(event: ProtoEvent) -> bool {
+    let slot = &SLOTS[event as usize];
+    slot.occurred.fetch_add(1, Ordering::Relaxed);
+    if !log::log_enabled!(target: TARGET, log::Level::Debug) {
+        return false;
+    }
+    slot.allow(
+        EPOCH.elapsed().as_secs(),
+        RATE_LIMIT.load(Ordering::Relaxed),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Slot;
+
+    #[test]
+    fn rate_limited_per_second() {
+        let slot = Slot::new();
+        let allowed = (0..5).filter(|_| slot.allow(3, 2)).count();
+        assert_eq!(allowed, 2);
+        assert!(slot.allow(4, 2));
+        assert_eq!(slot.suppressed.into_inner(), 3);
+        assert_eq!(slot.logged.into_inner(), 3);
+    }
+}
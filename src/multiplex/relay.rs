@@ -0,0 +1,653 @@
+use std::{
+    collections::VecDeque,
+    net::Shutdown,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use ahash::AHashMap;
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures_util::Future;
+use parking_lot::Mutex;
+use smol::{lock::Semaphore, net::TcpStream, prelude::*};
+
+use crate::{
+    frame::Frame,
+    utilities::runtime::{self, TimeoutExt},
+    DialTimings, Multiplex, MuxSecret, Pipe, PipeListener, Stream,
+};
+
+/// A relayed multiplex that carries no streams for this long is dropped.
+const SESSION_IDLE: Duration = Duration::from_secs(300);
+/// How many sessions [serve_relay] relays at once. Pipes starting further sessions are dropped.
+const MAX_SESSIONS: usize = 1024;
+/// How many upstream multiplexes [serve_relay] dials at once. Further sessions wait for one of these dials to finish.
+const MAX_DIALS: usize = 16;
+/// How many new pipes [serve_relay] waits on at once to learn which session they belong to. Further pipes are dropped.
+const MAX_SORTING: usize = 256;
+/// How long, and for how many datagrams, a new pipe may go without showing which session it belongs to before it is dropped.
+const SORT_TIMEOUT: Duration = Duration::from_secs(10);
+const SORT_DATAGRAMS: usize = 16;
+/// How many open pipes [serve_relay] lets a session have. Further pipes are dropped, rather than pushing out the oldest.
+const MAX_SESSION_PIPES: usize = 8;
+/// How many sessions' keys [serve_relay] tries sealed messages of new pipes against per second. Pipes beyond that are dropped.
+const MAX_TRIALS: usize = 65536;
+/// How much is read at once when copying.
+const COPY_BUF: usize = 65536;
+
+/// Relays two streams into each other until either of them closes, then closes both.
+///
+/// Backpressure carries across: when one leg falls behind, the sender on the other leg is paused with [Stream::pause_reading] instead of data piling up in the relay. A leg only falls behind if its far end stops acknowledging data, though, and far ends acknowledge whatever fits in their buffers; for backpressure all the way from a slowly reading application, that end should use [crate::Multiplex::set_read_rate_feedback].
+pub async fn relay_streams(a: Stream, b: Stream) -> std::io::Result<()> {
+    let result = splice(a.clone(), b.clone())
+        .race(splice(b.clone(), a.clone()))
+        .await;
+    a.clone().shutdown().await;
+    b.clone().shutdown().await;
+    result.map(|_| ())
+}
+
+/// Copies data between a stream and a TCP connection in both directions, returning how many bytes went from the stream to the TCP connection and how many the other way.
+///
+/// Unlike a pair of plain copies, this passes on how each side finishes. When the stream ends, the TCP connection's write half is shut down, so the TCP peer sees a clean end of data. When the TCP peer shuts down its write half, the stream is closed for writing with [Stream::close_write], so the other side of the stream sees a clean end of data too, while data keeps coming the other way. Returns once both directions are finished, or the stream closes entirely, then closes it.
+///
+/// Backpressure carries across both ways: a TCP peer that stops reading pauses the sender on the stream, as in [relay_streams], and a stream that cannot take more stops reads from TCP.
+pub async fn copy_bidirectional(stream: Stream, tcp: TcpStream) -> std::io::Result<(u64, u64)> {
+    // the copy from TCP never finishes by itself, so its progress is kept outside of it
+    let from_tcp = AtomicU64::new(0);
+    let to_tcp = async {
+        let copied = splice(stream.clone(), tcp.clone()).await?;
+        tcp.shutdown(Shutdown::Write)?;
+        std::io::Result::Ok(copied)
+    };
+    let from_tcp_done = async {
+        copy_counted(tcp.clone(), stream.clone(), &from_tcp).await?;
+        stream.close_write();
+        stream.wait_until_acked(None).await
+    }
+    .or(async {
+        // nothing more goes anywhere once the other side closed the stream entirely
+        stream.wait_closed().await;
+        Ok(())
+    });
+    let result = futures_util::future::try_join(to_tcp, from_tcp_done).await;
+    stream.clone().shutdown().await;
+    Ok((result?.0, from_tcp.load(Ordering::Relaxed)))
+}
+
+/// Copies from a stream until it ends, returning how many bytes were copied.
+async fn splice(mut from: Stream, mut to: impl AsyncWrite + Unpin) -> std::io::Result<u64> {
+    let mut buf = vec![0u8; COPY_BUF];
+    let mut copied = 0;
+    loop {
+        let n = from.read(&mut buf).await?;
+        if n == 0 {
+            return Ok(copied);
+        }
+        let mut write = to.write_all(&buf[..n]);
+        match smol::future::poll_once(&mut write).await {
+            Some(written) => written?,
+            // if the other leg cannot take more right now, stop the sender on this one until it can, rather than buffering what it sends
+            None => {
+                from.pause_reading();
+                let written = write.await;
+                from.resume_reading();
+                written?;
+            }
+        }
+        copied += n as u64;
+    }
+}
+
+/// Copies into a stream until `from` ends, counting the bytes copied as it goes.
+async fn copy_counted(
+    mut from: impl AsyncRead + Unpin,
+    mut to: Stream,
+    copied: &AtomicU64,
+) -> std::io::Result<()> {
+    let mut buf = vec![0u8; COPY_BUF];
+    loop {
+        let n = from.read(&mut buf).await?;
+        if n == 0 {
+            return Ok(());
+        }
+        // waits while the stream's write buffer is full
+        to.write_all(&buf[..n]).await?;
+        copied.fetch_add(n as u64, Ordering::Relaxed);
+    }
+}
+
+/// Forwards every stream the other side of `downstream` opens to `upstream`: each one is relayed with [relay_streams] into a stream with the same label opened on `upstream`.
+///
+/// Runs until `downstream` fails. Dropping the returned future stops relaying, including streams already being relayed.
+pub async fn relay_multiplex(downstream: &Multiplex, upstream: &Multiplex) -> std::io::Result<()> {
+    relay_session(downstream, upstream, None).await
+}
+
+/// Relays a multiplex, giving up once no streams were relayed for `idle`, if given.
+async fn relay_session(
+    downstream: &Multiplex,
+    upstream: &Multiplex,
+    idle: Option<Duration>,
+) -> std::io::Result<()> {
+    let mut tasks: Vec<runtime::Task<()>> = vec![];
+    loop {
+        let accepted = match idle {
+            Some(idle) => downstream.accept_conn().timeout(idle).await,
+            None => Some(downstream.accept_conn().await),
+        };
+        tasks.retain(|task| !task.is_finished());
+        let stream = match accepted {
+            Some(Ok(stream)) => stream,
+            Some(Err(err)) => return Err(err),
+            None => {
+                if tasks.is_empty() {
+                    return Ok(());
+                }
+                continue;
+            }
+        };
+        // data the client already sent goes out right behind the opening handshake, instead of waiting a round trip for it
+        let upstream_stream = upstream.open_conn_early(stream.label())?;
+        let label = stream.label().to_owned();
+        tasks.push(runtime::spawn(async move {
+            if let Err(err) = relay_streams(stream, upstream_stream).await {
+                log::debug!("relaying stream {:?} failed: {:?}", label, err);
+            }
+        }));
+    }
+}
+
+/// Runs a relay node: accepts pipes from `listener`, groups them into multiplexes, and relays each multiplex with [relay_multiplex] to its own upstream multiplex, obtained by calling `connect_upstream`.
+///
+/// Pipes are grouped by what the client sends over them, not by their [crate::Pipe::peer_metadata], which anyone could copy to push their pipes into another client's session. A pipe whose first message is a client hello starts the session of that hello's ephemeral key, which is new for every session. Since anyone who saw a hello can send it again, a pipe only joins a session that already exists with an encrypted message that opens under that session's keys, which only its client can seal, and that the session's replay filter has not seen before; the message is spent on that, and not delivered. Pipes that show neither within ten seconds are dropped.
+///
+/// At most 1024 sessions are relayed at once, and at most 16 upstream multiplexes are dialed at once; pipes starting sessions beyond that are dropped, and sessions beyond that wait for their dial. Each session takes at most 8 open pipes, so that pipes joining it never push out the ones it has, and sealed messages are tried against at most 65536 sessions' keys per second in all, so that made-up messages cost a bounded amount of work. A multiplex that carries no streams for five minutes is dropped, along with its upstream. Returns only when the listener fails.
+pub async fn serve_relay<L, F, Fut>(
+    listener: L,
+    local_sk: MuxSecret,
+    connect_upstream: F,
+) -> std::io::Result<()>
+where
+    L: PipeListener,
+    F: Fn() -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = std::io::Result<Multiplex>> + Send + 'static,
+{
+    let connect_upstream = Arc::new(connect_upstream);
+    let sessions: Arc<Mutex<AHashMap<[u8; 32], Arc<Multiplex>>>> = Default::default();
+    let dials = Arc::new(Semaphore::new(MAX_DIALS));
+    let sorting = Arc::new(Semaphore::new(MAX_SORTING));
+    let trials = Arc::new(TrialBudget::default());
+    let mut tasks: Vec<runtime::Task<()>> = vec![];
+    loop {
+        let pipe = listener.accept_pipe().await?;
+        tasks.retain(|task| !task.is_finished());
+        let Some(sorting) = sorting.try_acquire_arc() else {
+            log::debug!(
+                "dropping pipe from {}: too many pipes are being sorted",
+                pipe.peer_addr()
+            );
+            continue;
+        };
+        let local_sk = local_sk.clone();
+        let sessions = sessions.clone();
+        let dials = dials.clone();
+        let trials = trials.clone();
+        let connect_upstream = connect_upstream.clone();
+        tasks.push(runtime::spawn(async move {
+            let peer_addr = pipe.peer_addr();
+            let mut sorter = Sorter::new(pipe);
+            let Some(joined) = join_session(&mut sorter, &sessions, &trials, local_sk)
+                .timeout(SORT_TIMEOUT)
+                .await
+            else {
+                log::debug!("dropping pipe from {peer_addr}: it did not show its session in time");
+                return;
+            };
+            drop(sorting);
+            let (key, downstream) = match joined {
+                Ok(Joined::Existing(downstream)) => {
+                    // checked and added under the lock, so that pipes joining at once cannot go over the limit together
+                    let _sessions = sessions.lock();
+                    if downstream.open_pipes() >= MAX_SESSION_PIPES {
+                        log::debug!("dropping pipe from {peer_addr}: its session has enough pipes");
+                    } else {
+                        downstream.add_pipe(sorter.into_pipe());
+                    }
+                    return;
+                }
+                Ok(Joined::New(key, downstream)) => {
+                    downstream.add_pipe(sorter.into_pipe());
+                    (key, downstream)
+                }
+                Err(err) => {
+                    log::debug!("dropping pipe from {peer_addr}: {:?}", err);
+                    return;
+                }
+            };
+
+            let dial = dials.acquire_arc().await;
+            let upstream = connect_upstream().await;
+            drop(dial);
+            match upstream {
+                Ok(upstream) => {
+                    if let Err(err) =
+                        relay_session(&downstream, &upstream, Some(SESSION_IDLE)).await
+                    {
+                        log::debug!("relayed session {} failed: {:?}", hex::encode(key), err);
+                    }
+                }
+                Err(err) => log::warn!(
+                    "could not connect upstream for session {}: {:?}",
+                    hex::encode(key),
+                    err
+                ),
+            }
+            sessions.lock().remove(&key);
+        }));
+    }
+}
+
+/// The session a new pipe was sorted into.
+enum Joined {
+    /// A session that its hello started, under the hello's ephemeral key.
+    New([u8; 32], Arc<Multiplex>),
+    /// A session that was already relayed, and claimed a sealed message of the pipe.
+    Existing(Arc<Multiplex>),
+}
+
+/// Reads from a new pipe until it starts a session or shows that it belongs to one.
+async fn join_session(
+    sorter: &mut Sorter,
+    sessions: &Mutex<AHashMap<[u8; 32], Arc<Multiplex>>>,
+    trials: &TrialBudget,
+    local_sk: MuxSecret,
+) -> std::io::Result<Joined> {
+    loop {
+        match sorter.next_session().await? {
+            PipeSession::Hello(key) => {
+                let mut sessions = sessions.lock();
+                // a hello says nothing about who sent it, so joining the session it names takes a sealed message
+                if sessions.contains_key(&key) {
+                    continue;
+                }
+                if sessions.len() >= MAX_SESSIONS {
+                    return Err(std::io::Error::other("too many sessions"));
+                }
+                log::debug!("relaying new session {}", hex::encode(key));
+                let downstream = Arc::new(Multiplex::new(local_sk, None));
+                sessions.insert(key, downstream.clone());
+                return Ok(Joined::New(key, downstream));
+            }
+            PipeSession::Sealed(frame) => {
+                let candidates: Vec<Arc<Multiplex>> = sessions.lock().values().cloned().collect();
+                if !trials.take(candidates.len()) {
+                    return Err(std::io::Error::other("too many sealed messages to try"));
+                }
+                return candidates
+                    .into_iter()
+                    .find(|session| session.claims(frame.clone()))
+                    .map(Joined::Existing)
+                    .ok_or_else(|| {
+                        std::io::Error::new(std::io::ErrorKind::NotFound, "no session of ours")
+                    });
+            }
+        }
+    }
+}
+
+/// How many sessions' keys sealed messages may still be tried against this second, across all pipes.
+struct TrialBudget(Mutex<(Instant, usize)>);
+
+impl Default for TrialBudget {
+    fn default() -> Self {
+        Self(Mutex::new((runtime::now(), MAX_TRIALS)))
+    }
+}
+
+impl TrialBudget {
+    /// Takes this many trials from the budget, returning whether there were enough.
+    fn take(&self, trials: usize) -> bool {
+        let mut budget = self.0.lock();
+        let now = runtime::now();
+        if now.saturating_duration_since(budget.0) >= Duration::from_secs(1) {
+            *budget = (now, MAX_TRIALS);
+        }
+        match budget.1.checked_sub(trials) {
+            Some(left) => {
+                budget.1 = left;
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// Which session a new pipe belongs to, as a message sent over it shows.
+enum PipeSession {
+    /// A client hello, with the ephemeral key of the session it opens.
+    Hello([u8; 32]),
+    /// An encrypted message of a session that is already open.
+    Sealed(Frame),
+}
+
+/// Reads the datagrams of a new pipe until one shows which session it belongs to, skipping probes and the like, and keeps them for the session to receive.
+struct Sorter {
+    pipe: Arc<dyn Pipe>,
+    read: VecDeque<Bytes>,
+    // how many more datagrams may be read
+    left: usize,
+}
+
+impl Sorter {
+    fn new(pipe: Arc<dyn Pipe>) -> Self {
+        Self {
+            pipe,
+            read: VecDeque::new(),
+            left: SORT_DATAGRAMS,
+        }
+    }
+
+    /// Reads until a datagram shows a session. A sealed message is not kept, since claiming the pipe for a session spends it.
+    async fn next_session(&mut self) -> std::io::Result<PipeSession> {
+        while self.left > 0 {
+            self.left -= 1;
+            let pkt = self.pipe.recv().await?;
+            // clients send nothing else over a pipe until it answers their probes, as the pipe pool would
+            if pkt[..] == b"!!ping!!"[..] {
+                self.pipe.send(Bytes::from_static(b"!!pong!!"));
+                continue;
+            }
+            match stdcode::deserialize::<Frame>(&pkt) {
+                Ok(Frame::ClientHello { eph_pk, .. }) => {
+                    self.read.push_back(pkt);
+                    return Ok(PipeSession::Hello(eph_pk.to_bytes()));
+                }
+                Ok(
+                    frame @ (Frame::EncryptedMsg { .. }
+                    | Frame::CompactMsg { .. }
+                    | Frame::Rekey { .. }),
+                ) => return Ok(PipeSession::Sealed(frame)),
+                _ => self.read.push_back(pkt),
+            }
+        }
+        Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "no message showing the session",
+        ))
+    }
+
+    fn into_pipe(self) -> SortedPipe {
+        SortedPipe {
+            pipe: self.pipe,
+            read: Mutex::new(self.read),
+        }
+    }
+}
+
+/// A pipe that was sorted into a session, handing out the datagrams read while sorting it before any others.
+struct SortedPipe {
+    pipe: Arc<dyn Pipe>,
+    read: Mutex<VecDeque<Bytes>>,
+}
+
+#[async_trait]
+impl Pipe for SortedPipe {
+    fn send(&self, to_send: Bytes) {
+        self.pipe.send(to_send)
+    }
+
+    async fn recv(&self) -> std::io::Result<Bytes> {
+        let read = self.read.lock().pop_front();
+        match read {
+            Some(pkt) => Ok(pkt),
+            None => self.pipe.recv().await,
+        }
+    }
+
+    fn protocol(&self) -> &str {
+        self.pipe.protocol()
+    }
+
+    fn peer_metadata(&self) -> &str {
+        self.pipe.peer_metadata()
+    }
+
+    fn peer_addr(&self) -> String {
+        self.pipe.peer_addr()
+    }
+
+    fn dial_timings(&self) -> Option<DialTimings> {
+        self.pipe.dial_timings()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicUsize;
+
+    use smol::channel::Receiver;
+
+    use super::*;
+    use crate::sim::{sim_pipe_pair, SimLink, SimPipe};
+
+    /// Hands the relay the server ends of simulated pipes, all with the same empty metadata.
+    struct SimListener(Receiver<SimPipe>);
+
+    #[async_trait]
+    impl PipeListener for SimListener {
+        async fn accept_pipe(&self) -> std::io::Result<Arc<dyn Pipe>> {
+            let pipe = self
+                .0
+                .recv()
+                .await
+                .map_err(|_| std::io::Error::from(std::io::ErrorKind::BrokenPipe))?;
+            Ok(Arc::new(pipe))
+        }
+    }
+
+    /// A multiplex to an echo server, as the relay dials upstream.
+    fn echo_upstream() -> Multiplex {
+        let server_sk = MuxSecret::generate();
+        let server = Multiplex::new(server_sk.clone(), None);
+        let client = Multiplex::new(MuxSecret::generate(), Some(server_sk.to_public()));
+        let (client_pipe, server_pipe) = sim_pipe_pair(SimLink {
+            delay: Duration::from_millis(5),
+            ..Default::default()
+        });
+        client.add_pipe(client_pipe);
+        server.add_pipe(server_pipe);
+        client.add_drop_friend(runtime::spawn(async move {
+            while let Ok(stream) = server.accept_conn().await {
+                let (reader, writer) = (stream.clone(), stream);
+                runtime::spawn(async move {
+                    let _ = smol::io::copy(reader, writer).await;
+                })
+                .detach();
+            }
+        }));
+        client
+    }
+
+    async fn echo(client: &Multiplex, msg: &[u8]) -> Vec<u8> {
+        let mut stream = client.open_conn("echo").await.unwrap();
+        stream.write_all(msg).await.unwrap();
+        let mut buf = vec![0; msg.len()];
+        stream.read_exact(&mut buf).await.unwrap();
+        buf
+    }
+
+    #[test]
+    fn sessions_follow_the_handshake_not_the_metadata() {
+        smol::block_on(async {
+            let relay_sk = MuxSecret::generate();
+            let (send_pipes, recv_pipes) = smol::channel::unbounded();
+            let dialed = Arc::new(AtomicUsize::new(0));
+            let _relay = runtime::spawn(serve_relay(SimListener(recv_pipes), relay_sk.clone(), {
+                let dialed = dialed.clone();
+                move || {
+                    dialed.fetch_add(1, Ordering::Relaxed);
+                    async { Ok(echo_upstream()) }
+                }
+            }));
+            let link = SimLink {
+                delay: Duration::from_millis(5),
+                ..Default::default()
+            };
+            let connect = |client: &Multiplex| {
+                let (client_pipe, relay_pipe) = sim_pipe_pair(link);
+                client.add_pipe(client_pipe);
+                send_pipes.try_send(relay_pipe).unwrap();
+            };
+
+            // two clients whose pipes carry the same metadata still get a session each
+            let alice = Multiplex::new(MuxSecret::generate(), Some(relay_sk.to_public()));
+            let bob = Multiplex::new(MuxSecret::generate(), Some(relay_sk.to_public()));
+            connect(&alice);
+            connect(&bob);
+            assert_eq!(echo(&alice, b"alice").await, b"alice");
+            assert_eq!(echo(&bob, b"bob").await, b"bob");
+
+            // a later pipe joins the session whose keys its messages are sealed with
+            alice.retain(|_| false);
+            connect(&alice);
+            assert_eq!(echo(&alice, b"again").await, b"again");
+            assert_eq!(dialed.load(Ordering::Relaxed), 2);
+        })
+    }
+
+    /// A writer that fails every write at once.
+    struct Broken;
+
+    impl AsyncWrite for Broken {
+        fn poll_write(
+            self: std::pin::Pin<&mut Self>,
+            _: &mut std::task::Context<'_>,
+            _: &[u8],
+        ) -> std::task::Poll<std::io::Result<usize>> {
+            std::task::Poll::Ready(Err(std::io::ErrorKind::ConnectionReset.into()))
+        }
+
+        fn poll_flush(
+            self: std::pin::Pin<&mut Self>,
+            _: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            std::task::Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(
+            self: std::pin::Pin<&mut Self>,
+            _: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            std::task::Poll::Ready(Ok(()))
+        }
+    }
+
+    #[test]
+    fn splice_fails_with_the_write() {
+        smol::block_on(async {
+            let client = echo_upstream();
+            let mut stream = client.open_conn("").await.unwrap();
+            stream.write_all(b"hello").await.unwrap();
+            // the echo comes back, and cannot be written anywhere
+            let err = splice(stream, Broken).await.unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::ConnectionReset);
+        })
+    }
+
+    #[test]
+    fn copy_bidirectional_passes_on_half_closes() {
+        smol::block_on(async {
+            let server_sk = MuxSecret::generate();
+            let server = Multiplex::new(server_sk.clone(), None);
+            let client = Multiplex::new(MuxSecret::generate(), Some(server_sk.to_public()));
+            let (client_pipe, server_pipe) = sim_pipe_pair(SimLink::default());
+            client.add_pipe(client_pipe);
+            server.add_pipe(server_pipe);
+            let listener = smol::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let mut tcp_peer = TcpStream::connect(listener.local_addr().unwrap())
+                .await
+                .unwrap();
+            let (tcp, _) = listener.accept().await.unwrap();
+            let mut opened = client.open_conn("").await.unwrap();
+            let accepted = server.accept_conn().await.unwrap();
+            let copy = runtime::spawn(copy_bidirectional(accepted, tcp));
+
+            // the TCP peer finishing its request ends the stream, while the response still gets back
+            tcp_peer.write_all(b"request").await.unwrap();
+            tcp_peer.shutdown(Shutdown::Write).unwrap();
+            let mut request = vec![];
+            opened.read_to_end(&mut request).await.unwrap();
+            assert_eq!(request, b"request");
+            opened.write_all(b"the response").await.unwrap();
+            opened.close_write();
+            let mut response = vec![];
+            tcp_peer.read_to_end(&mut response).await.unwrap();
+            assert_eq!(response, b"the response");
+            assert_eq!(copy.await.unwrap(), (12, 7));
+        })
+    }
+
+    #[test]
+    fn replayed_messages_do_not_join_sessions() {
+        smol::block_on(async {
+            let relay_sk = MuxSecret::generate();
+            let (send_pipes, recv_pipes) = smol::channel::unbounded();
+            let _relay = runtime::spawn(serve_relay(
+                SimListener(recv_pipes),
+                relay_sk.clone(),
+                || async { Ok(echo_upstream()) },
+            ));
+            let link = SimLink {
+                delay: Duration::from_millis(5),
+                ..Default::default()
+            };
+            let alice = Multiplex::new(MuxSecret::generate(), Some(relay_sk.to_public()));
+            let sent: Arc<Mutex<Vec<Bytes>>> = Default::default();
+            alice.set_capture_hook(Some({
+                let sent = sent.clone();
+                Arc::new(move |pkt: &crate::CapturedPacket<'_>| {
+                    if pkt.direction == crate::CaptureDirection::Outgoing {
+                        sent.lock().push(Bytes::copy_from_slice(pkt.data));
+                    }
+                })
+            }));
+            let (client_pipe, relay_pipe) = sim_pipe_pair(link);
+            alice.add_pipe(client_pipe);
+            send_pipes.try_send(relay_pipe).unwrap();
+            assert_eq!(echo(&alice, b"alice").await, b"alice");
+
+            // whoever saw alice's hello and messages can send them again, over pipes of their own
+            let sent = sent.lock().clone();
+            let find = |is: fn(&Frame) -> bool| {
+                sent.iter()
+                    .find(|pkt| stdcode::deserialize(pkt).is_ok_and(|frame| is(&frame)))
+                    .unwrap()
+                    .clone()
+            };
+            let hello = find(|frame| matches!(frame, Frame::ClientHello { .. }));
+            let sealed = find(|frame| {
+                matches!(frame, Frame::EncryptedMsg { .. } | Frame::CompactMsg { .. })
+            });
+            for replayed in [
+                vec![hello.clone()],
+                vec![sealed.clone()],
+                vec![hello, sealed],
+            ] {
+                let (attacker, relay_pipe) = sim_pipe_pair(link);
+                send_pipes.try_send(relay_pipe).unwrap();
+                for pkt in replayed {
+                    attacker.send(pkt);
+                }
+                // a pipe that joined the session would hear from it, if only its probes, while one that is dropped hears nothing
+                let heard = attacker.recv().timeout(Duration::from_secs(2)).await;
+                assert!(!matches!(heard, Some(Ok(_))), "{heard:?}");
+            }
+            assert_eq!(echo(&alice, b"still").await, b"still");
+        })
+    }
+}
@@ -0,0 +1,102 @@
+use std::time::Duration;
+
+use ahash::{AHashMap, AHashSet};
+use bytes::Bytes;
+use smol::prelude::*;
+
+use super::trace_file::TraceRecord;
+use crate::{
+    sim::{sim_pipe_pair, SimLink},
+    utilities::runtime,
+    Multiplex, MuxSecret,
+};
+
+/// The outcome of replaying a trace.
+#[derive(Clone, Debug)]
+pub struct ReplayReport {
+    /// Total bytes of stream data replayed.
+    pub bytes: u64,
+    /// Time between the first and the last data segment of the original capture.
+    pub trace_duration: Duration,
+    /// Time it took the replay to deliver every byte to the receiving side.
+    pub replay_duration: Duration,
+}
+
+/// Replays the data segments of an outgoing trace through a pair of multiplexes connected by a simulated link.
+///
+/// Every traced stream is reopened on the simulated pair, and the first transmission of every data segment is written into it at its original time, with its traced payload if there is one and zeros otherwise. Retransmissions are left to the current code, so comparing `replay_duration` against `trace_duration` shows how the current code copes with the captured workload.
+pub async fn replay_trace(records: &[TraceRecord], link: SimLink) -> std::io::Result<ReplayReport> {
+    let mut seen = AHashSet::new();
+    let data: Vec<&TraceRecord> = records
+        .iter()
+        .filter(|r| r.kind == "Data" && seen.insert((r.stream_id, r.seqno)))
+        .collect();
+    let first_ms = data.iter().map(|r| r.time_ms).fold(f64::INFINITY, f64::min);
+    let last_ms = data.iter().map(|r| r.time_ms).fold(first_ms, f64::max);
+    let mut schedules: AHashMap<u16, Vec<(Duration, Bytes)>> = AHashMap::new();
+    for r in data {
+        schedules.entry(r.stream_id).or_default().push((
+            Duration::from_secs_f64((r.time_ms - first_ms) / 1000.0),
+            r.payload
+                .clone()
+                .unwrap_or_else(|| vec![0u8; r.payload_len].into()),
+        ));
+    }
+
+    let server_sk = MuxSecret::generate();
+    let server = Multiplex::new(server_sk.clone(), None);
+    let client = Multiplex::new(MuxSecret::generate(), Some(server_sk.to_public()));
+    let (client_pipe, server_pipe) = sim_pipe_pair(link);
+    client.add_pipe(client_pipe);
+    server.add_pipe(server_pipe);
+
+    let mut streams = vec![];
+    for (stream_id, schedule) in schedules {
+        streams.push((client.open_conn(&stream_id.to_string()).await?, schedule));
+    }
+
+    let start = runtime::now();
+    let mut readers = vec![];
+    let mut writers = vec![];
+    let mut bytes = 0;
+    for (mut stream, schedule) in streams {
+        let mut incoming = server.accept_conn().await?;
+        let expected: usize = schedule.iter().map(|(_, payload)| payload.len()).sum();
+        bytes += expected as u64;
+        readers.push(runtime::spawn(async move {
+            let mut buf = vec![0u8; 65536];
+            let mut remaining = expected;
+            while remaining > 0 {
+                let n = incoming.read(&mut buf).await?;
+                if n == 0 {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::UnexpectedEof,
+                        "replayed stream closed early",
+                    ));
+                }
+                remaining = remaining.saturating_sub(n);
+            }
+            Ok::<_, std::io::Error>(())
+        }));
+        writers.push(runtime::spawn(async move {
+            for (offset, payload) in schedule {
+                runtime::Timer::at(start + offset).await;
+                stream.write_all(&payload).await?;
+            }
+            Ok::<_, std::io::Error>(stream)
+        }));
+    }
+    // keep the writing halves alive until everything has been read
+    let mut written = vec![];
+    for writer in writers {
+        written.push(writer.await?);
+    }
+    for reader in readers {
+        reader.await?;
+    }
+    Ok(ReplayReport {
+        bytes,
+        trace_duration: Duration::from_secs_f64((last_ms - first_ms).max(0.0) / 1000.0),
+        replay_duration: runtime::elapsed(start),
+    })
+}
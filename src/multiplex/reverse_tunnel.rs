@@ -0,0 +1,252 @@
+use std::{io::ErrorKind, net::SocketAddr};
+
+use ahash::AHashMap;
+use smol::{
+    net::{TcpListener, TcpStream},
+    prelude::*,
+};
+
+use crate::{copy_bidirectional, utilities::runtime, Multiplex, Stream};
+
+/// Label prefix of the streams a client registers a service with.
+const EXPOSE_PREFIX: &str = "expose ";
+/// Label prefix of the streams a server carries a connection to a service over.
+const TUNNEL_PREFIX: &str = "tunnel ";
+
+/// Exposes local TCP services through the server on the other side of `mux`, which must run [serve_reverse_tunnels]. This lets a client behind a NAT or firewall offer services that nobody could otherwise connect to.
+///
+/// Each service is registered under its name, and every connection the server gets for it is carried back over a stream of its own and connected to the service's address. Registrations last until this returns, which is when `mux` fails or the server drops one of them; it fails at once if the server turns down a service.
+///
+/// This accepts every stream the server opens over `mux`, so `mux` should not be used to accept streams for anything else. Dropping the returned future unregisters the services and closes the connections being carried.
+pub async fn expose_tcp(mux: &Multiplex, services: &[(&str, SocketAddr)]) -> std::io::Result<()> {
+    let mut registrations = vec![];
+    for (name, _) in services {
+        let mut registration = mux.open_conn(&format!("{EXPOSE_PREFIX}{name}")).await?;
+        // the server confirms with a single byte, or closes the stream if it turns the service down
+        let mut confirmation = [0u8; 1];
+        if registration.read(&mut confirmation).await? == 0 {
+            return Err(std::io::Error::new(
+                ErrorKind::ConnectionRefused,
+                format!("server turned down exposing {name:?}"),
+            ));
+        }
+        log::debug!("exposed {name:?} through the server");
+        registrations.push(registration);
+    }
+    let services: AHashMap<String, SocketAddr> = services
+        .iter()
+        .map(|(name, addr)| (name.to_string(), *addr))
+        .collect();
+
+    let serve = async {
+        let mut tunnels: Vec<runtime::Task<()>> = vec![];
+        loop {
+            let stream = mux.accept_conn().await?;
+            tunnels.retain(|task| !task.is_finished());
+            let Some(addr) = stream
+                .label()
+                .strip_prefix(TUNNEL_PREFIX)
+                .and_then(|name| services.get(name))
+                .copied()
+            else {
+                log::debug!(
+                    "closing stream {:?}, which is for no service",
+                    stream.label()
+                );
+                continue;
+            };
+            tunnels.push(runtime::spawn(async move {
+                let result = async {
+                    let tcp = TcpStream::connect(addr).await?;
+                    copy_bidirectional(stream, tcp).await
+                };
+                if let Err(err) = result.await {
+                    log::debug!("tunnel to {addr} failed: {:?}", err);
+                }
+            }));
+        }
+    };
+    let dropped = futures_util::future::select_all(
+        registrations
+            .into_iter()
+            .map(|registration| Box::pin(wait_closed(registration))),
+    );
+    serve
+        .race(async {
+            dropped.await;
+            Err(std::io::Error::new(
+                ErrorKind::ConnectionAborted,
+                "server dropped an exposed service",
+            ))
+        })
+        .await
+}
+
+/// Serves the client on the other side of `mux` that exposes TCP services with [expose_tcp]. For every service it registers, `bind` gives the address to listen on for connections to it, or `None` to turn it down, and each connection is carried to the client over a stream of its own.
+///
+/// Listening for a service stops once the client drops it. Runs until `mux` fails, and accepts every stream the client opens, so `mux` should not be used to accept streams for anything else. Dropping the returned future stops serving, including connections already being carried.
+pub async fn serve_reverse_tunnels(
+    mux: &Multiplex,
+    bind: impl Fn(&str) -> Option<SocketAddr>,
+) -> std::io::Result<()> {
+    // the listeners hand their connections over, since only this can open streams over the multiplex
+    let (send_conn, recv_conn) = smol::channel::unbounded::<(String, TcpStream, SocketAddr)>();
+    let register = async {
+        let mut listeners: Vec<runtime::Task<()>> = vec![];
+        loop {
+            let mut registration = mux.accept_conn().await?;
+            listeners.retain(|task| !task.is_finished());
+            let Some(name) = registration
+                .label()
+                .strip_prefix(EXPOSE_PREFIX)
+                .map(str::to_owned)
+            else {
+                log::debug!(
+                    "closing stream {:?}, which registers no service",
+                    registration.label()
+                );
+                continue;
+            };
+            let Some(addr) = bind(&name) else {
+                log::debug!("turning down exposing {name:?}");
+                continue;
+            };
+            let listener = match TcpListener::bind(addr).await {
+                Ok(listener) => listener,
+                Err(err) => {
+                    log::warn!("could not listen on {addr} for {name:?}: {:?}", err);
+                    continue;
+                }
+            };
+            registration.write_all(&[1]).await?;
+            registration.flush().await?;
+            log::debug!("listening on {addr} for {name:?}");
+
+            let send_conn = send_conn.clone();
+            listeners.push(runtime::spawn(async move {
+                let listen = async {
+                    loop {
+                        let (tcp, peer) = listener.accept().await?;
+                        if send_conn.send((name.clone(), tcp, peer)).await.is_err() {
+                            return std::io::Result::Ok(());
+                        }
+                    }
+                };
+                let result = listen
+                    .race(async {
+                        wait_closed(registration).await;
+                        Ok(())
+                    })
+                    .await;
+                if let Err(err) = result {
+                    log::debug!("listening for {name:?} failed: {:?}", err);
+                }
+            }));
+        }
+    };
+    let carry = async {
+        let mut tunnels: Vec<runtime::Task<()>> = vec![];
+        while let Ok((name, tcp, peer)) = recv_conn.recv().await {
+            tunnels.retain(|task| !task.is_finished());
+            // whatever the connection sends right away goes out behind the opening handshake
+            let stream = mux.open_conn_early(&format!("{TUNNEL_PREFIX}{name}"))?;
+            tunnels.push(runtime::spawn(async move {
+                if let Err(err) = copy_bidirectional(stream, tcp).await {
+                    log::debug!("tunnel from {peer} failed: {:?}", err);
+                }
+            }));
+        }
+        Ok(())
+    };
+    register.race(carry).await
+}
+
+/// Waits until the other side closes a stream that carries nothing else.
+async fn wait_closed(mut stream: Stream) {
+    let mut buf = [0u8; 64];
+    while matches!(stream.read(&mut buf).await, Ok(n) if n > 0) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::{
+        sim::{sim_pipe_pair, SimLink},
+        utilities::runtime::TimeoutExt,
+        MuxSecret,
+    };
+
+    /// A client and server multiplex connected to each other.
+    fn connected() -> (Multiplex, Multiplex) {
+        let server_sk = MuxSecret::generate();
+        let server = Multiplex::new(server_sk.clone(), None);
+        let client = Multiplex::new(MuxSecret::generate(), Some(server_sk.to_public()));
+        let (client_pipe, server_pipe) = sim_pipe_pair(SimLink::default());
+        client.add_pipe(client_pipe);
+        server.add_pipe(server_pipe);
+        (client, server)
+    }
+
+    #[test]
+    fn connections_reach_the_exposed_service() {
+        smol::block_on(async {
+            let (client, server) = connected();
+            // the service behind the client echoes whatever it gets
+            let service = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let service_addr = service.local_addr().unwrap();
+            let _service = runtime::spawn(async move {
+                while let Ok((tcp, _)) = service.accept().await {
+                    runtime::spawn(async move {
+                        let _ = smol::io::copy(tcp.clone(), tcp).await;
+                    })
+                    .detach();
+                }
+            });
+            let public_addr = std::net::TcpListener::bind("127.0.0.1:0")
+                .unwrap()
+                .local_addr()
+                .unwrap();
+            let _server = runtime::spawn(async move {
+                let _ =
+                    serve_reverse_tunnels(&server, |name| (name == "echo").then_some(public_addr))
+                        .await;
+            });
+            let _client = runtime::spawn(async move {
+                let _ = expose_tcp(&client, &[("echo", service_addr)]).await;
+            });
+
+            // the server listens once the client registered the service
+            let mut tcp = async {
+                loop {
+                    if let Ok(tcp) = TcpStream::connect(public_addr).await {
+                        return tcp;
+                    }
+                    runtime::Timer::after(Duration::from_millis(10)).await;
+                }
+            }
+            .timeout(Duration::from_secs(5))
+            .await
+            .unwrap();
+            tcp.write_all(b"hello").await.unwrap();
+            let mut buf = [0u8; 5];
+            tcp.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"hello");
+        })
+    }
+
+    #[test]
+    fn turned_down_services_fail() {
+        smol::block_on(async {
+            let (client, server) = connected();
+            let _server = runtime::spawn(async move {
+                let _ = serve_reverse_tunnels(&server, |_| None).await;
+            });
+            let err = expose_tcp(&client, &[("echo", "127.0.0.1:1".parse().unwrap())])
+                .await
+                .unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::ConnectionRefused);
+        })
+    }
+}
@@ -0,0 +1,76 @@
+use std::sync::Arc;
+
+use parking_lot::Mutex;
+use rand::RngCore;
+use rand_chacha::{
+    rand_core::{CryptoRng, OsRng, SeedableRng},
+    ChaCha20Rng,
+};
+
+type FillFn = dyn Fn(&mut [u8]) + Send + Sync + 'static;
+
+/// Where a multiplex gets its randomness from: the ephemeral keys of its handshakes, the IDs of the streams it opens, the nonces of its connection IDs, and the padding of its path MTU probes. Set with [crate::Multiplex::set_rng]; by default, this is the operating system's RNG.
+///
+/// AEAD nonces need no randomness, since they count up from zero under keys that are fresh for every session.
+#[derive(Clone)]
+pub struct MuxRng(Arc<FillFn>);
+
+impl MuxRng {
+    /// The operating system's RNG.
+    pub fn os() -> Self {
+        Self::from_fn(|dest| OsRng.fill_bytes(dest))
+    }
+
+    /// A ChaCha20 stream generated from the given seed, so that the same seed gives the same keys and stream IDs every time. Meant for reproducible simulations; a seed that anyone else knows gives away the session keys.
+    pub fn seeded(seed: [u8; 32]) -> Self {
+        let rng = Mutex::new(ChaCha20Rng::from_seed(seed));
+        Self::from_fn(move |dest| rng.lock().fill_bytes(dest))
+    }
+
+    /// Random bytes from the given function, which fills the whole buffer it is given, e.g. from a certified DRBG. It must be cryptographically secure, since the session keys come from it.
+    pub fn from_fn(fill: impl Fn(&mut [u8]) + Send + Sync + 'static) -> Self {
+        Self(Arc::new(fill))
+    }
+
+    pub(crate) fn bytes<const N: usize>(&self) -> [u8; N] {
+        let mut buf = [0u8; N];
+        (self.0)(&mut buf);
+        buf
+    }
+
+    pub(crate) fn fill(&self, dest: &mut [u8]) {
+        (self.0)(dest)
+    }
+
+    pub(crate) fn u16(&self) -> u16 {
+        u16::from_le_bytes(self.bytes())
+    }
+}
+
+impl Default for MuxRng {
+    fn default() -> Self {
+        Self::os()
+    }
+}
+
+// lets key generation draw from this
+impl RngCore for MuxRng {
+    fn next_u32(&mut self) -> u32 {
+        u32::from_le_bytes(self.bytes())
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        u64::from_le_bytes(self.bytes())
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        (self.0)(dest)
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand::Error> {
+        (self.0)(dest);
+        Ok(())
+    }
+}
+
+impl CryptoRng for MuxRng {}
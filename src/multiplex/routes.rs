@@ -0,0 +1,233 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Instant,
+};
+
+use ahash::{AHashMap, AHashSet};
+use parking_lot::{Mutex, RwLock};
+
+use crate::{
+    frame::{Seqno, StreamId},
+    utilities::runtime,
+};
+
+use super::{path_congestion::earliest_arrivals, pipe_stats::PipeCounters};
+
+/// Which pipe every data segment in flight went out over, so that its ack or loss reaches the congestion state of that pipe. See [super::path_congestion::PathCongestion].
+///
+/// The pipe pool records where segments go, and the streams report what became of them.
+#[derive(Default)]
+pub struct Routes {
+    // the pipes of the pool, for streams to ask whether any has room
+    pipes: RwLock<Vec<Arc<PipeCounters>>>,
+    segments: Mutex<AHashMap<(StreamId, Seqno), Route>>,
+    striping: AtomicBool,
+    // the streams whose segments all go over the pipe with the lowest RTT
+    single_path: RwLock<AHashSet<StreamId>>,
+}
+
+struct Route {
+    pipe: Arc<PipeCounters>,
+    // which segment sent over the pipe it was, and when
+    serial: u64,
+    sent: Instant,
+}
+
+impl Routes {
+    /// Replaces the pipes that segments may go over.
+    pub fn set_pipes(&self, pipes: Vec<Arc<PipeCounters>>) {
+        *self.pipes.write() = pipes;
+    }
+
+    #[cfg(feature = "multipath")]
+    /// Sets whether segments are striped over several pipes, in which case the congestion state of each pipe, rather than that of each stream, decides how much streams may send.
+    pub fn set_striping(&self, striping: bool) {
+        self.striping.store(striping, Ordering::Relaxed);
+    }
+
+    pub fn is_striping(&self) -> bool {
+        self.striping.load(Ordering::Relaxed)
+    }
+
+    /// Whether the segments of a stream all go over the pipe with the lowest RTT, rather than being striped. See [crate::StreamOptions::single_path].
+    #[cfg(feature = "multipath")]
+    pub fn is_single_path(&self, stream_id: StreamId) -> bool {
+        self.single_path.read().contains(&stream_id)
+    }
+
+    /// The pipe that a segment still in flight went out over, for sending the rest of its pieces the same way.
+    pub fn pipe_of(&self, stream_id: StreamId, seqno: Seqno) -> Option<Arc<PipeCounters>> {
+        self.segments
+            .lock()
+            .get(&(stream_id, seqno))
+            .map(|route| route.pipe.clone())
+    }
+
+    /// Records that a segment went out over a pipe, unless a piece of it already did.
+    pub fn on_sent(&self, stream_id: StreamId, seqno: Seqno, pipe: &Arc<PipeCounters>) {
+        let now = runtime::now();
+        let mut segments = self.segments.lock();
+        if segments.contains_key(&(stream_id, seqno)) {
+            return;
+        }
+        let serial = pipe.path().on_sent(now);
+        segments.insert(
+            (stream_id, seqno),
+            Route {
+                pipe: pipe.clone(),
+                serial,
+                sent: now,
+            },
+        );
+    }
+
+    /// Whether a segment in flight was overtaken by enough segments acked over the same pipe to count as lost. See [super::path_congestion::PathCongestion::overtaken].
+    pub fn overtaken(&self, stream_id: StreamId, seqno: Seqno) -> bool {
+        self.segments
+            .lock()
+            .get(&(stream_id, seqno))
+            .is_some_and(|route| route.pipe.path().overtaken(route.serial))
+    }
+
+    /// Reports the ack of a segment to the pipe it went out over, with an RTT sample unless it was retransmitted.
+    pub fn on_acked(&self, stream_id: StreamId, seqno: Seqno, retransmitted: bool) {
+        if let Some(route) = self.segments.lock().remove(&(stream_id, seqno)) {
+            let now = runtime::now();
+            let rtt = (!retransmitted).then(|| now.saturating_duration_since(route.sent));
+            route.pipe.path().on_acked(route.serial, rtt, now);
+        }
+    }
+
+    /// Reports a segment being retransmitted to the pipe it last went out over. The retransmission is recorded anew once it is sent.
+    pub fn on_lost(&self, stream_id: StreamId, seqno: Seqno) {
+        if let Some(route) = self.segments.lock().remove(&(stream_id, seqno)) {
+            route
+                .pipe
+                .path()
+                .on_lost(route.serial, route.sent, runtime::now());
+        }
+    }
+
+    /// Forgets the segments of a stream that is gone.
+    pub fn forget_stream(&self, stream_id: StreamId) {
+        self.single_path.write().remove(&stream_id);
+        self.segments.lock().retain(|(id, _), route| {
+            if *id == stream_id {
+                route.pipe.path().on_forgotten();
+            }
+            *id != stream_id
+        });
+    }
+
+    /// Whether any pipe that may carry traffic has room for another segment, and is not so much slower than the fastest that the segment should rather wait for room there. See [earliest_arrivals].
+    pub fn has_room(&self, now: Instant) -> bool {
+        let pipes = self.pipes.read();
+        let usable: Vec<&Arc<PipeCounters>> =
+            pipes.iter().filter(|pipe| pipe.is_usable()).collect();
+        let delays: Vec<_> = usable.iter().map(|pipe| pipe.one_way_delay()).collect();
+        usable
+            .iter()
+            .zip(earliest_arrivals(&delays))
+            .any(|(pipe, in_time)| in_time && pipe.path().has_room(now))
+    }
+}
+
+/// The routes of one stream's segments, which the stream's [super::stream::stream_state::StreamState] reports acks and losses to, and which forgets them once the stream is gone.
+pub struct StreamRoutes {
+    routes: Arc<Routes>,
+    stream_id: StreamId,
+    single_path: bool,
+}
+
+impl StreamRoutes {
+    pub fn new(routes: Arc<Routes>, stream_id: StreamId) -> Self {
+        Self {
+            routes,
+            stream_id,
+            single_path: false,
+        }
+    }
+
+    /// Sets whether the stream's segments all go over the pipe with the lowest RTT, even while the multiplex stripes segments.
+    pub fn set_single_path(&mut self, single_path: bool) {
+        if single_path != self.single_path {
+            self.single_path = single_path;
+            let mut streams = self.routes.single_path.write();
+            if single_path {
+                streams.insert(self.stream_id);
+            } else {
+                streams.remove(&self.stream_id);
+            }
+        }
+    }
+
+    /// Whether the stream's segments are striped over several pipes.
+    pub fn is_striping(&self) -> bool {
+        self.routes.is_striping() && !self.single_path
+    }
+
+    /// Whether any pipe has room for another of the stream's segments.
+    pub fn has_room(&self, now: Instant) -> bool {
+        self.routes.has_room(now)
+    }
+
+    pub fn overtaken(&self, seqno: Seqno) -> bool {
+        self.routes.overtaken(self.stream_id, seqno)
+    }
+
+    pub fn on_acked(&self, seqno: Seqno, retransmitted: bool) {
+        self.routes.on_acked(self.stream_id, seqno, retransmitted)
+    }
+
+    pub fn on_lost(&self, seqno: Seqno) {
+        self.routes.on_lost(self.stream_id, seqno)
+    }
+}
+
+impl Drop for StreamRoutes {
+    fn drop(&mut self) {
+        self.routes.forget_stream(self.stream_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn acks_and_losses_reach_the_pipe() {
+        let routes = Arc::new(Routes::default());
+        let pipes = vec![
+            Arc::new(PipeCounters::default()),
+            Arc::new(PipeCounters::default()),
+        ];
+        routes.set_pipes(pipes.clone());
+        let stream = StreamRoutes::new(routes.clone(), StreamId(2));
+        for seqno in 0..4 {
+            routes.on_sent(StreamId(2), Seqno(seqno), &pipes[seqno as usize % 2]);
+        }
+        // a second piece of the same segment is not counted again
+        routes.on_sent(StreamId(2), Seqno(0), &pipes[0]);
+        assert_eq!(pipes[0].path().inflight(), 2);
+        assert!(Arc::ptr_eq(
+            &routes.pipe_of(StreamId(2), Seqno(1)).unwrap(),
+            &pipes[1]
+        ));
+
+        stream.on_lost(Seqno(1));
+        assert_eq!(pipes[1].path().inflight(), 1);
+        assert!(routes.pipe_of(StreamId(2), Seqno(1)).is_none());
+        stream.on_acked(Seqno(0), false);
+        assert_eq!(pipes[0].path().inflight(), 1);
+        assert!(routes.pipe_of(StreamId(2), Seqno(0)).is_none());
+
+        // what is left is forgotten along with the stream
+        drop(stream);
+        assert_eq!(pipes[0].path().inflight(), 0);
+        assert_eq!(pipes[1].path().inflight(), 0);
+        assert!(routes.has_room(Instant::now()));
+    }
+}
@@ -0,0 +1,297 @@
+use std::sync::{
+    atomic::{AtomicU64, AtomicUsize, Ordering},
+    Arc,
+};
+
+use ahash::AHashMap;
+use bytes::Bytes;
+use futures_util::Future;
+use parking_lot::Mutex;
+use smol::{
+    channel::{Receiver, Sender},
+    lock::Semaphore,
+    prelude::*,
+};
+
+use crate::{utilities::runtime, Stream};
+
+/// Requests or responses larger than this are refused.
+const MAX_FRAME_LEN: usize = 16 * 1024 * 1024;
+
+/// How many requests [serve_rpc] handles at once on one stream. Further requests are not read until one of these is answered.
+const MAX_CONCURRENT_REQUESTS: usize = 64;
+
+/// How many whole frames may wait to be written to a stream.
+const WRITE_QUEUE: usize = 64;
+
+type Pending = Arc<Mutex<AHashMap<u64, Sender<Bytes>>>>;
+
+/// A pooled request/response channel, multiplexing many short logical requests over a small set of persistent streams. Created by [crate::Multiplex::rpc_channel].
+///
+/// This avoids paying a stream-opening round trip per request. The other side must serve the streams with [serve_rpc].
+///
+/// Calls may be cancelled by dropping them at any point, e.g. on a timeout, without disturbing the other calls sharing their stream.
+pub struct RpcChannel {
+    lanes: Vec<RpcLane>,
+    next_lane: AtomicUsize,
+    next_id: AtomicU64,
+}
+
+struct RpcLane {
+    // whole request frames, which the writer task writes one after another, so that a cancelled call never leaves half a frame on the stream
+    frames: Sender<Vec<u8>>,
+    pending: Pending,
+    _reader: runtime::Task<()>,
+    _writer: runtime::Task<()>,
+}
+
+impl RpcChannel {
+    pub(crate) fn new(streams: Vec<Stream>) -> Self {
+        let lanes = streams
+            .into_iter()
+            .map(|stream| {
+                let pending: Pending = Default::default();
+                let (frames, recv_frames) = smol::channel::bounded(WRITE_QUEUE);
+                let _reader =
+                    runtime::spawn(lane_reader(stream.clone(), frames.clone(), pending.clone()));
+                let _writer = runtime::spawn(lane_writer(stream, recv_frames, pending.clone()));
+                RpcLane {
+                    frames,
+                    pending,
+                    _reader,
+                    _writer,
+                }
+            })
+            .collect();
+        Self {
+            lanes,
+            next_lane: AtomicUsize::new(0),
+            next_id: AtomicU64::new(0),
+        }
+    }
+
+    /// Sends a request and waits for its response.
+    pub async fn call(&self, request: &[u8]) -> std::io::Result<Bytes> {
+        let lane = &self.lanes[self.next_lane.fetch_add(1, Ordering::Relaxed) % self.lanes.len()];
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let frame = encode_frame(id, request)?;
+        let (send_resp, recv_resp) = smol::channel::bounded(1);
+        lane.pending.lock().insert(id, send_resp);
+        let _pending = PendingGuard {
+            pending: &lane.pending,
+            id,
+        };
+        let closed = || {
+            std::io::Error::new(
+                std::io::ErrorKind::BrokenPipe,
+                "rpc stream closed before the response arrived",
+            )
+        };
+        lane.frames.send(frame).await.map_err(|_| closed())?;
+        recv_resp.recv().await.map_err(|_| closed())
+    }
+}
+
+/// Forgets a call once it returns or is dropped, so that a late response is discarded.
+struct PendingGuard<'a> {
+    pending: &'a Pending,
+    id: u64,
+}
+
+impl Drop for PendingGuard<'_> {
+    fn drop(&mut self) {
+        self.pending.lock().remove(&self.id);
+    }
+}
+
+async fn lane_reader(mut stream: Stream, frames: Sender<Vec<u8>>, pending: Pending) {
+    while let Ok((id, response)) = read_frame(&mut stream).await {
+        if let Some(send_resp) = pending.lock().remove(&id) {
+            let _ = send_resp.try_send(response);
+        }
+    }
+    // closing the channel first, a call either fails to send its request or is already pending when every pending call is failed
+    frames.close();
+    pending.lock().clear();
+}
+
+async fn lane_writer(stream: Stream, frames: Receiver<Vec<u8>>, pending: Pending) {
+    let _ = write_frames(stream, frames.clone()).await;
+    frames.close();
+    pending.lock().clear();
+}
+
+/// Serves requests arriving on a stream opened by the other side's [RpcChannel], answering each with the given handler. Up to 64 requests are handled concurrently, so responses may be sent out of order; further requests wait to be read until one of those is answered.
+///
+/// Returns when the stream is closed. Requests still being handled then are cancelled, as they are when the returned future is dropped.
+pub async fn serve_rpc<F, Fut>(stream: Stream, handler: F) -> std::io::Result<()>
+where
+    F: Fn(Bytes) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Bytes> + Send + 'static,
+{
+    let handler = Arc::new(handler);
+    let (frames, recv_frames) = smol::channel::bounded(WRITE_QUEUE);
+    let _writer = runtime::spawn(write_frames(stream.clone(), recv_frames));
+    let permits = Arc::new(Semaphore::new(MAX_CONCURRENT_REQUESTS));
+    let mut handlers: Vec<runtime::Task<()>> = vec![];
+    let mut read_half = stream;
+    loop {
+        let permit = permits.acquire_arc().await;
+        let (id, request) = read_frame(&mut read_half).await?;
+        handlers.retain(|task| !task.is_finished());
+        let handler = handler.clone();
+        let frames = frames.clone();
+        handlers.push(runtime::spawn(async move {
+            let response = handler(request).await;
+            match encode_frame(id, &response) {
+                Ok(frame) => {
+                    let _ = frames.send(frame).await;
+                }
+                Err(err) => log::debug!("could not send rpc response {id}: {:?}", err),
+            }
+            drop(permit);
+        }));
+    }
+}
+
+/// Writes whole frames to a stream as they come, until the channel closes or writing fails.
+async fn write_frames(mut stream: Stream, frames: Receiver<Vec<u8>>) -> std::io::Result<()> {
+    while let Ok(frame) = frames.recv().await {
+        stream.write_all(&frame).await?;
+    }
+    Ok(())
+}
+
+fn encode_frame(id: u64, body: &[u8]) -> std::io::Result<Vec<u8>> {
+    if body.len() > MAX_FRAME_LEN {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "rpc frame too large",
+        ));
+    }
+    let mut frame = Vec::with_capacity(body.len() + 12);
+    frame.extend_from_slice(&id.to_le_bytes());
+    frame.extend_from_slice(&(body.len() as u32).to_le_bytes());
+    frame.extend_from_slice(body);
+    Ok(frame)
+}
+
+async fn read_frame(stream: &mut Stream) -> std::io::Result<(u64, Bytes)> {
+    let mut header = [0u8; 12];
+    stream.read_exact(&mut header).await?;
+    let id = u64::from_le_bytes(header[..8].try_into().unwrap());
+    let len = u32::from_le_bytes(header[8..].try_into().unwrap()) as usize;
+    if len > MAX_FRAME_LEN {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "rpc frame too large",
+        ));
+    }
+    let mut body = vec![0u8; len];
+    stream.read_exact(&mut body).await?;
+    Ok((id, body.into()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use futures_util::future::join_all;
+
+    use super::*;
+    use crate::{
+        sim::{sim_pipe_pair, SimLink},
+        Multiplex, MuxSecret,
+    };
+
+    /// A multiplex pair whose server side serves the streams of an [RpcChannel] of the given width with the handler.
+    async fn rpc_pair<F, Fut>(width: usize, handler: F) -> (Multiplex, Multiplex, RpcChannel)
+    where
+        F: Fn(Bytes) -> Fut + Clone + Send + Sync + 'static,
+        Fut: Future<Output = Bytes> + Send + 'static,
+    {
+        let server_sk = MuxSecret::generate();
+        let server = Multiplex::new(server_sk.clone(), None);
+        let client = Multiplex::new(MuxSecret::generate(), Some(server_sk.to_public()));
+        let (client_pipe, server_pipe) = sim_pipe_pair(SimLink {
+            delay: Duration::from_millis(5),
+            ..Default::default()
+        });
+        client.add_pipe(client_pipe);
+        server.add_pipe(server_pipe);
+        let channel = client.rpc_channel("rpc", width).await.unwrap();
+        for _ in 0..width {
+            let stream = server.accept_conn().await.unwrap();
+            runtime::spawn(serve_rpc(stream, handler.clone())).detach();
+        }
+        (server, client, channel)
+    }
+
+    #[test]
+    fn responses_reach_their_callers() {
+        smol::block_on(async {
+            // the earlier requests take longer, so their responses come last
+            let (_server, _client, channel) = rpc_pair(1, |request: Bytes| async move {
+                runtime::Timer::after(Duration::from_millis(10 * (10 - request[0] as u64))).await;
+                request
+            })
+            .await;
+            let requests: Vec<[u8; 1]> = (0..10u8).map(|i| [i]).collect();
+            let responses = join_all(requests.iter().map(|request| channel.call(request))).await;
+            for (request, response) in requests.iter().zip(responses) {
+                assert_eq!(&response.unwrap()[..], request);
+            }
+            assert!(channel.lanes[0].pending.lock().is_empty());
+        })
+    }
+
+    #[test]
+    fn cancelled_calls_leave_the_lane_usable() {
+        smol::block_on(async {
+            let (_server, _client, channel) = rpc_pair(1, |request: Bytes| async move {
+                if request.len() > 1000 {
+                    runtime::Timer::after(Duration::from_secs(1)).await;
+                }
+                request
+            })
+            .await;
+            // dropped while its large request is still being written, and again while waiting for the response
+            for wait in [Duration::ZERO, Duration::from_millis(200)] {
+                let cancelled = channel
+                    .call(&[1; 100_000])
+                    .or(async {
+                        runtime::Timer::after(wait).await;
+                        Err(std::io::ErrorKind::TimedOut.into())
+                    })
+                    .await;
+                assert!(cancelled.is_err());
+                assert!(channel.lanes[0].pending.lock().is_empty());
+            }
+            assert_eq!(&channel.call(b"hi").await.unwrap()[..], b"hi");
+        })
+    }
+
+    #[test]
+    fn oversized_frames_are_refused() {
+        smol::block_on(async {
+            let (server, client, channel) =
+                rpc_pair(1, |request: Bytes| async move { request }).await;
+            let err = channel.call(&vec![0; MAX_FRAME_LEN + 1]).await.unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+            assert_eq!(channel.call(&[0; 100]).await.unwrap().len(), 100);
+
+            // a peer claiming a larger frame is cut off before anything is allocated for it
+            let mut stream = client.open_conn("rpc").await.unwrap();
+            let served = runtime::spawn(serve_rpc(
+                server.accept_conn().await.unwrap(),
+                |request: Bytes| async move { request },
+            ));
+            let mut header = vec![];
+            header.extend_from_slice(&0u64.to_le_bytes());
+            header.extend_from_slice(&(MAX_FRAME_LEN as u32 + 1).to_le_bytes());
+            stream.write_all(&header).await.unwrap();
+            let err = served.await.unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        })
+    }
+}
@@ -0,0 +1,136 @@
+use std::collections::VecDeque;
+
+use ahash::AHashMap;
+
+use super::stream::{RelKind, StreamMessage};
+use crate::frame::StreamId;
+
+/// Interleaves the data packets that streams send in one tick of the multiplex by weighted round robin, so that a stream sending a little does not wait behind a whole window of a bulk stream.
+///
+/// Each round, every stream sends up to its priority in data packets. Other messages, such as acks, go out right away, unless data of the same stream is already waiting, in which case they stay behind it.
+#[derive(Default)]
+pub struct DataScheduler {
+    // streams in the order they first queued something, with their priority
+    queues: Vec<(usize, VecDeque<StreamMessage>)>,
+    // where each stream's queue is, until the next drain
+    positions: AHashMap<StreamId, usize>,
+}
+
+impl DataScheduler {
+    /// Queues a message of a stream, or passes it to `send` right away if it need not wait.
+    pub fn push(
+        &mut self,
+        stream_id: StreamId,
+        priority: usize,
+        msg: StreamMessage,
+        send: impl FnOnce(StreamMessage),
+    ) {
+        match self.positions.get(&stream_id) {
+            Some(&position) => self.queues[position].1.push_back(msg),
+            None if is_data(&msg) => {
+                self.positions.insert(stream_id, self.queues.len());
+                self.queues.push((priority.max(1), VecDeque::from([msg])));
+            }
+            None => send(msg),
+        }
+    }
+
+    /// Sends everything queued, interleaved by priority.
+    pub fn drain(&mut self, mut send: impl FnMut(StreamMessage)) {
+        while !self.queues.is_empty() {
+            for (priority, queue) in self.queues.iter_mut() {
+                let mut quantum = *priority;
+                while quantum > 0 {
+                    let Some(msg) = queue.pop_front() else {
+                        break;
+                    };
+                    if is_data(&msg) {
+                        quantum -= 1;
+                    }
+                    send(msg);
+                }
+                // whatever follows the last data packet need not wait for the next round
+                while queue.front().is_some_and(|msg| !is_data(msg)) {
+                    send(queue.pop_front().unwrap());
+                }
+            }
+            self.queues.retain(|(_, queue)| !queue.is_empty());
+        }
+        self.positions.clear();
+    }
+}
+
+fn is_data(msg: &StreamMessage) -> bool {
+    matches!(
+        msg,
+        StreamMessage::Reliable {
+            kind: RelKind::Data,
+            ..
+        }
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frame::Seqno;
+
+    fn data(stream_id: u16, seqno: u64) -> StreamMessage {
+        StreamMessage::Reliable {
+            kind: RelKind::Data,
+            stream_id: StreamId(stream_id),
+            seqno: Seqno(seqno),
+            payload: Default::default(),
+        }
+    }
+
+    #[test]
+    fn interleaves_by_priority() {
+        let mut scheduler = DataScheduler::default();
+        let mut sent = vec![];
+        for seqno in 0..6 {
+            scheduler.push(StreamId(1), 1, data(1, seqno), |msg| sent.push(msg));
+        }
+        for seqno in 0..4 {
+            scheduler.push(StreamId(2), 2, data(2, seqno), |msg| sent.push(msg));
+        }
+        let fin = StreamMessage::Reliable {
+            kind: RelKind::Fin,
+            stream_id: StreamId(2),
+            seqno: Seqno(4),
+            payload: Default::default(),
+        };
+        scheduler.push(StreamId(2), 2, fin, |msg| sent.push(msg));
+        scheduler.push(StreamId(3), 1, StreamMessage::Empty, |msg| sent.push(msg));
+        assert_eq!(sent.len(), 1);
+        scheduler.drain(|msg| sent.push(msg));
+        let order: Vec<(u16, u64, RelKind)> = sent[1..]
+            .iter()
+            .map(|msg| match msg {
+                StreamMessage::Reliable {
+                    kind,
+                    stream_id,
+                    seqno,
+                    ..
+                } => (stream_id.0, seqno.0, *kind),
+                _ => panic!("unexpected message"),
+            })
+            .collect();
+        assert_eq!(
+            order,
+            vec![
+                (1, 0, RelKind::Data),
+                (2, 0, RelKind::Data),
+                (2, 1, RelKind::Data),
+                (1, 1, RelKind::Data),
+                (2, 2, RelKind::Data),
+                (2, 3, RelKind::Data),
+                (2, 4, RelKind::Fin),
+                (1, 2, RelKind::Data),
+                (1, 3, RelKind::Data),
+                (1, 4, RelKind::Data),
+                (1, 5, RelKind::Data),
+            ]
+        );
+    }
+}
@@ -0,0 +1,43 @@
+use std::time::{Duration, Instant};
+
+/// How long a [crate::Multiplex] took to get going, as returned by [crate::Multiplex::setup_timings], counted from when its first pipe was added. Together with the [crate::DialTimings] of its pipes, this breaks down where the time to set up a connection goes.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SetupTimings {
+    /// Until the handshake was done and this side could encrypt messages to the other, or `None` if it is not done yet.
+    pub handshake: Option<Duration>,
+    /// Until the first stream opened or accepted through the multiplex was connected, or `None` if none was yet.
+    pub first_stream: Option<Duration>,
+}
+
+/// When each step of setting up a multiplex happened.
+#[derive(Default)]
+pub(crate) struct SetupClock {
+    first_pipe: Option<Instant>,
+    handshake: Option<Instant>,
+    first_stream: Option<Instant>,
+}
+
+impl SetupClock {
+    pub fn on_pipe_added(&mut self) {
+        self.first_pipe.get_or_insert_with(Instant::now);
+    }
+
+    pub fn on_handshake(&mut self) {
+        self.handshake.get_or_insert_with(Instant::now);
+    }
+
+    pub fn on_stream_ready(&mut self) {
+        self.first_stream.get_or_insert_with(Instant::now);
+    }
+
+    pub fn snapshot(&self) -> SetupTimings {
+        let since_first_pipe = |at: Option<Instant>| {
+            let first_pipe = self.first_pipe?;
+            Some(at?.saturating_duration_since(first_pipe))
+        };
+        SetupTimings {
+            handshake: since_first_pipe(self.handshake),
+            first_stream: since_first_pipe(self.first_stream),
+        }
+    }
+}
@@ -0,0 +1,237 @@
+//! A soak test, which keeps a pair of multiplexes busy over lossy simulated links for a long time, opening and closing streams all along, and keeps checking that nothing leaks, nothing grows without bound and nothing gets corrupted. Slow leaks and rare races take far longer to show up than the other tests run.
+//!
+//! Only built with the `soak` feature. It runs on a Tokio runtime whose clock is paused, so time only passes when every task is waiting, and then jumps straight to the next timer. The simulated links, the timers of the multiplexes and `runtime::now` all follow that clock, so the test runs for `SOSISTAB_SOAK_SECS` seconds of simulated time, two hours by default, in however long the work takes:
+//!
+//! ```text
+//! SOSISTAB_SOAK_SECS=86400 cargo test --release --features soak soak
+//! ```
+
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use smol::prelude::*;
+
+use crate::{
+    multiplex::constants::{DEFAULT_READ_BUFFER, DEFAULT_WRITE_BUFFER},
+    sim::{sim_pipe_pair, SimLink},
+    utilities::runtime,
+    Multiplex, MuxSecret, Stream,
+};
+
+/// How many exchanges run at once, in each direction.
+const CONCURRENCY: usize = 4;
+/// The most any exchange sends.
+const MAX_EXCHANGE: usize = 2_000_000;
+/// The largest single write.
+const MAX_WRITE: usize = 65536;
+/// How often the invariants are checked.
+const CHECK_INTERVAL: Duration = Duration::from_millis(100);
+/// How often progress is reported, in simulated time.
+const REPORT_INTERVAL: Duration = Duration::from_secs(600);
+
+/// How long the test runs, in simulated time.
+fn soak_duration() -> Duration {
+    let secs = std::env::var("SOSISTAB_SOAK_SECS")
+        .ok()
+        .and_then(|secs| secs.parse().ok())
+        .unwrap_or(7200);
+    Duration::from_secs(secs)
+}
+
+/// Sends a random amount of random data over a newly opened stream, and checks that the other side hashed it the same.
+async fn exchange(mut stream: Stream, seed: u64) -> usize {
+    let rng = fastrand::Rng::with_seed(seed);
+    let len = rng.usize(..=MAX_EXCHANGE);
+    let mut hasher = blake3::Hasher::new();
+    let mut sent = 0;
+    while sent < len {
+        let chunk: Vec<u8> = (0..rng.usize(1..=MAX_WRITE).min(len - sent))
+            .map(|_| rng.u8(..))
+            .collect();
+        hasher.update(&chunk);
+        stream.write_all(&chunk).await.expect("write failed");
+        sent += chunk.len();
+    }
+    stream.close_write();
+    let mut echoed = vec![];
+    stream
+        .read_to_end(&mut echoed)
+        .await
+        .expect("reading the hash failed");
+    assert_eq!(
+        echoed,
+        hasher.finalize().as_bytes(),
+        "{len} bytes sent with seed {seed} arrived corrupted"
+    );
+    len
+}
+
+/// Answers an exchange with the hash of everything received.
+async fn answer(mut stream: Stream) {
+    let mut hasher = blake3::Hasher::new();
+    let mut buf = vec![0u8; MAX_WRITE];
+    loop {
+        let n = stream.read(&mut buf).await.expect("read failed");
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    stream
+        .write_all(hasher.finalize().as_bytes())
+        .await
+        .expect("writing the hash failed");
+    stream.close_write();
+    // dropped once the hash is acked, so the stream closes cleanly
+    let _ = stream.wait_until_acked(None).await;
+}
+
+/// Checks the invariants of both sides, and that what they buffer stays within what the streams allow.
+fn check(muxes: &[&Multiplex]) {
+    for mux in muxes {
+        if let Err(err) = mux.check_invariants() {
+            panic!("invariant violated: {err:?}");
+        }
+        let stats = mux.stats();
+        // both directions' exchanges, and streams that are still closing
+        assert!(
+            stats.streams <= 4 * CONCURRENCY,
+            "{} streams open",
+            stats.streams
+        );
+        assert!(
+            stats.write_queue_bytes <= stats.streams * (DEFAULT_WRITE_BUFFER + MAX_WRITE),
+            "{} bytes waiting to be sent",
+            stats.write_queue_bytes
+        );
+        assert!(
+            stats.read_queue_bytes <= stats.streams * DEFAULT_READ_BUFFER,
+            "{} bytes waiting to be read",
+            stats.read_queue_bytes
+        );
+    }
+}
+
+#[test]
+fn soak() {
+    let duration = soak_duration();
+    // a single thread, so that the clock can tell when everything is waiting
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .enable_time()
+        .start_paused(true)
+        .build()
+        .unwrap();
+    let real_start = Instant::now();
+    rt.block_on(async {
+        let server_sk = MuxSecret::generate();
+        let server = Arc::new(Multiplex::new(server_sk.clone(), None));
+        let client = Arc::new(Multiplex::new(
+            MuxSecret::generate(),
+            Some(server_sk.to_public()),
+        ));
+        for link in [
+            SimLink {
+                delay: Duration::from_millis(30),
+                loss: 0.02,
+                bandwidth: Some(2_000_000.0),
+                queue_limit: 100_000,
+                ..Default::default()
+            },
+            SimLink {
+                delay: Duration::from_millis(80),
+                loss: 0.05,
+                bandwidth: Some(1_000_000.0),
+                queue_limit: 50_000,
+                ..Default::default()
+            },
+        ] {
+            let (client_pipe, server_pipe) = sim_pipe_pair(link);
+            client.add_pipe(client_pipe);
+            server.add_pipe(server_pipe);
+        }
+
+        let answerers: Vec<_> = [&client, &server]
+            .into_iter()
+            .map(|mux| {
+                let mux = mux.clone();
+                runtime::spawn(async move {
+                    while let Ok(stream) = mux.accept_conn().await {
+                        runtime::spawn(answer(stream)).detach();
+                    }
+                })
+            })
+            .collect();
+
+        let deadline = runtime::now() + duration;
+        let exchanges = Arc::new(AtomicU64::new(0));
+        let bytes = Arc::new(AtomicU64::new(0));
+        // both sides open streams, so that both halves of the stream IDs get used
+        let openers: Vec<_> = (0..2 * CONCURRENCY)
+            .map(|i| {
+                let mux = if i % 2 == 0 { &client } else { &server }.clone();
+                let exchanges = exchanges.clone();
+                let bytes = bytes.clone();
+                runtime::spawn(async move {
+                    let mut seed = i as u64;
+                    while runtime::now() < deadline {
+                        let stream = mux.open_conn("soak").await.expect("open failed");
+                        bytes.fetch_add(exchange(stream, seed).await as u64, Ordering::Relaxed);
+                        exchanges.fetch_add(1, Ordering::Relaxed);
+                        seed += (2 * CONCURRENCY) as u64;
+                    }
+                })
+            })
+            .collect();
+
+        let started = runtime::now();
+        let mut next_report = started + REPORT_INTERVAL;
+        let mut openers = futures_util::future::join_all(openers);
+        loop {
+            let finished = async {
+                (&mut openers).await;
+                true
+            }
+            .or(async {
+                runtime::Timer::after(CHECK_INTERVAL).await;
+                false
+            })
+            .await;
+            check(&[&client, &server]);
+            if runtime::now() >= next_report {
+                next_report += REPORT_INTERVAL;
+                eprintln!(
+                    "soak: {:?} simulated in {:?}, {} exchanges, {} bytes, client {:?}, server {:?}",
+                    runtime::elapsed(started),
+                    real_start.elapsed(),
+                    exchanges.load(Ordering::Relaxed),
+                    bytes.load(Ordering::Relaxed),
+                    client.stats(),
+                    server.stats()
+                );
+            }
+            if finished {
+                break;
+            }
+        }
+        assert!(exchanges.load(Ordering::Relaxed) > 0);
+
+        // every stream goes away once both sides are done with it
+        let settled = runtime::now() + Duration::from_secs(30);
+        while client.stats().streams + server.stats().streams > 0 {
+            assert!(
+                runtime::now() < settled,
+                "streams left over: client {:?}, server {:?}",
+                client.stats(),
+                server.stats()
+            );
+            runtime::Timer::after(CHECK_INTERVAL).await;
+        }
+        check(&[&client, &server]);
+        drop(answerers);
+    })
+}
@@ -4,6 +4,7 @@ use parking_lot::Mutex;
 use recycle_box::{coerce_box, RecycleBox};
 use serde::{Deserialize, Serialize};
 use smol::prelude::*;
+use thiserror::Error;
 
 use std::{
     collections::VecDeque,
@@ -12,18 +13,38 @@ use std::{
     sync::Arc,
     task::Context,
     task::Poll,
+    time::{Duration, Instant},
 };
 
-use crate::frame::Seqno;
+use crate::{
+    frame::{Seqno, StreamId},
+    multiplex::constants::{DEFAULT_READ_BUFFER, DEFAULT_WRITE_BUFFER},
+    utilities::runtime,
+};
 
+mod congestion;
+mod datagrams;
+mod fragment;
 mod inflight;
-mod reorderer;
+mod sack;
+mod stats;
 pub mod stream_state;
+pub(crate) mod throughput;
+
+pub(crate) use congestion::SharedCongestion;
+pub use congestion::{
+    AckEvent, Bbr, Bic, CongestionAlgorithm, CongestionControl, Cubic, Highspeed, Ledbat,
+};
+pub use datagrams::Datagrams;
+#[cfg(feature = "bench")]
+pub use inflight::SeqnoRing;
+pub use inflight::{LossStats, LOSS_BUCKETS};
+pub use stats::StreamStats;
 
 #[deprecated]
 pub type MuxStream = Stream;
 
-/// [Stream] represents a reliable stream, multiplexed over a [Multiplex]. It implements [AsyncRead], [AsyncWrite], and [Clone], making using it very similar to using a TcpStream.
+/// [Stream] represents a reliable stream, multiplexed over a [Multiplex]. It implements [AsyncRead], [AsyncWrite], and [Clone], making using it very similar to using a TcpStream. With the `tokio` feature, it implements Tokio's `AsyncRead` and `AsyncWrite` too, so that it can be handed to Tokio-based libraries directly.
 pub struct Stream {
     // forces the multiplex to tick immediately
     tick_notify: Arc<dyn Fn() + Send + Sync + 'static>,
@@ -44,7 +65,15 @@ impl Drop for Stream {
     fn drop(&mut self) {
         if let Some(_nfo) = Arc::get_mut(&mut self.label) {
             // this means we're the last one!
-            self.queues.lock().closed = true;
+            let mut queues = self.queues.lock();
+            match queues.drop_linger {
+                // the multiplex closes the stream once whatever was written is delivered
+                Some(linger) if !queues.closed => {
+                    queues.linger_until = Some(runtime::now() + linger)
+                }
+                _ => queues.close(CloseReason::LocalShutdown),
+            }
+            drop(queues);
             (self.tick_notify)();
         }
     }
@@ -77,20 +106,30 @@ impl Stream {
         }
     }
 
-    /// Waits until this Stream is fully connected.
+    /// Waits until this Stream is fully connected. Fails if the other side refused the stream.
     pub async fn wait_connected(&self) -> std::io::Result<()> {
         self.local_notify
             .wait_until(|| {
                 log::trace!("waiting until connected...");
-                if self.queues.lock().connected {
+                let queues = self.queues.lock();
+                if queues.connected {
                     log::trace!("connected now");
-                    Some(())
+                    Some(Ok(()))
+                } else if queues.closed {
+                    let reason = match queues.reset_code {
+                        Some(ResetCode::AcceptBacklogFull) => "the other side's accept queue is full",
+                        Some(ResetCode::GoingAway) => "the other side is closing the multiplex",
+                        _ => "the other side refused the stream",
+                    };
+                    Some(Err(std::io::Error::new(
+                        std::io::ErrorKind::ConnectionRefused,
+                        reason,
+                    )))
                 } else {
                     None
                 }
             })
-            .await;
-        Ok(())
+            .await
     }
 
     /// Returns the label attached to the stream.
@@ -103,38 +142,183 @@ impl Stream {
         self.label()
     }
 
-    /// Shuts down the stream, causing future read and write operations to fail.
-    pub async fn shutdown(&mut self) {
-        self.queues.lock().closed = true;
-        (self.tick_notify)();
+    /// Sets a low-water mark for reads: readers are only woken up once at least this many bytes are buffered, or the stream is closed. Defaults to 1, and 0 counts as 1.
+    ///
+    /// High-throughput readers can raise this to get fewer, larger reads. Since data is held back until the mark is reached, it should not exceed the size of the smallest message the application waits for. A mark above [StreamOptions::read_buffer] counts as that size, since the other side never sends more than fits the read buffer. Applies to all clones of this stream.
+    pub fn set_read_buffer_min(&self, bytes: usize) {
+        self.queues.lock().read_buffer_min = bytes.max(1);
         self.local_notify.notify_all();
     }
 
-    /// Sends an unreliable datagram.
-    pub async fn send_urel(&self, dgram: Bytes) -> std::io::Result<()> {
-        self.queues.lock().send_urel.push_back(dgram);
+    /// Sets the buffer sizes and priority of this stream, applying to all its clones. Shrinking a buffer does not discard what is already in it.
+    pub fn set_options(&self, options: StreamOptions) {
+        self.queues.lock().options = options;
         (self.tick_notify)();
-        Ok(())
+        self.local_notify.notify_all();
     }
 
-    /// Receives an unreliable datagram.
-    pub async fn recv_urel(&self) -> std::io::Result<Bytes> {
+    /// Returns the buffer sizes and priority of this stream.
+    pub fn options(&self) -> StreamOptions {
+        self.queues.lock().options
+    }
+
+    /// Returns a snapshot of statistics about this stream, such as its congestion window, how much it retransmits, and how much reordering it sees. These are updated whenever the stream's state advances, so they may lag slightly; the queue sizes are always current.
+    pub fn stats(&self) -> StreamStats {
+        self.queues.lock().stats()
+    }
+
+    /// Returns how many bytes have been written to this stream so far, through this handle or any of its clones.
+    pub fn bytes_written(&self) -> u64 {
+        self.queues.lock().written_bytes
+    }
+
+    /// Returns how many of the bytes written so far the other side has acknowledged. Acknowledged bytes have reached the other side's buffers, though not necessarily its application.
+    pub fn bytes_acked(&self) -> u64 {
+        self.queues.lock().acked_bytes
+    }
+
+    /// Waits until the other side has acknowledged the first `upto` bytes ever written to this stream, or, with `None`, everything written so far. Offsets can be obtained from [Stream::bytes_written] after a write.
+    ///
+    /// Writing only hands data to a buffer, so this is what tells a sender that its data has actually arrived. Fails if the stream closes before then.
+    pub async fn wait_until_acked(&self, upto: Option<u64>) -> std::io::Result<()> {
+        let upto = upto.unwrap_or_else(|| self.bytes_written());
         self.local_notify
             .wait_until(|| {
-                let mut queues = self.queues.lock();
-                if let Some(front) = queues.recv_urel.pop_front() {
-                    Some(Ok(front))
+                let queues = self.queues.lock();
+                if queues.acked_bytes >= upto {
+                    Some(Ok(()))
                 } else if queues.closed {
-                    Some(Err(std::io::Error::new(
-                        std::io::ErrorKind::BrokenPipe,
-                        "broken pipe",
-                    )))
+                    Some(Err(queues.closed_error()))
                 } else {
                     None
                 }
             })
             .await
     }
+
+    /// Returns the estimated throughput of this stream in each direction, in bytes per second, as a pair of (sending, receiving). Each is a moving average of the rate at which data was acknowledged by, or delivered from, the other side, taken only over the periods when data was flowing, so idle periods don't drag it down.
+    ///
+    /// This is meant for applications that adapt what they send to the available bandwidth, such as adaptive-bitrate video.
+    pub fn estimated_throughput(&self) -> (f64, f64) {
+        let queues = self.queues.lock();
+        (queues.stats.send_throughput, queues.stats.recv_throughput)
+    }
+
+    /// Puts this stream into a named bandwidth-sharing group, or takes it out of any group with `None`. See [crate::Multiplex::set_group_weight].
+    pub fn set_group(&self, group: Option<&str>) {
+        self.queues.lock().group = group.map(|g| g.to_owned());
+        (self.tick_notify)();
+    }
+
+    /// Switches this stream to a different congestion control algorithm, overriding the one chosen with [crate::Multiplex::set_congestion_control]. The new algorithm starts from the current congestion window, and leaves the stream unweighted if it cannot honour the weight of its bandwidth-sharing group.
+    pub fn set_congestion_control(&self, algo: CongestionAlgorithm) {
+        self.queues.lock().congestion = Some(algo);
+        (self.tick_notify)();
+    }
+
+    /// Asks the other side to stop sending data on this stream until [Stream::resume_reading] is called. Data already in flight is still delivered.
+    ///
+    /// Unlike simply not reading, which lets buffers fill up until the stream stalls, this stops the sender promptly, which is useful for proxies relaying backpressure hop by hop. Peers that predate this feature ignore the request. Applies to all clones of this stream.
+    pub fn pause_reading(&self) {
+        self.queues.lock().read_paused = true;
+        (self.tick_notify)();
+    }
+
+    /// Lets the other side resume sending after [Stream::pause_reading].
+    pub fn resume_reading(&self) {
+        self.queues.lock().read_paused = false;
+        (self.tick_notify)();
+    }
+
+    /// Finishes writing to the stream while still reading from it, like shutting down the write half of a TCP connection. Once everything written so far is acknowledged, the other side's reads return end of file when they have read all of it; further writes fail. Closing the stream as an [AsyncWrite] does the same. Applies to all clones of this stream.
+    ///
+    /// Peers that predate half-closing get the whole stream closed instead, once everything written is acknowledged.
+    pub fn close_write(&self) {
+        self.queues.lock().write_closed = true;
+        (self.tick_notify)();
+        self.local_notify.notify_all();
+    }
+
+    /// Waits until the stream is closed entirely, by either side.
+    pub(crate) async fn wait_closed(&self) {
+        self.local_notify
+            .wait_until(|| self.queues.lock().closed.then_some(()))
+            .await
+    }
+
+    /// Shuts down the stream, causing future read and write operations to fail.
+    pub async fn shutdown(&mut self) {
+        self.queues.lock().close(CloseReason::LocalShutdown);
+        (self.tick_notify)();
+        self.local_notify.notify_all();
+    }
+
+    /// Returns why the stream closed, or `None` if it is still open. This is shared by all clones, so any of them can tell why the stream died, whichever one closed it; operations that fail because the stream closed also fail with an error wrapping the reason.
+    pub fn close_reason(&self) -> Option<CloseReason> {
+        let queues = self.queues.lock();
+        if queues.closed {
+            queues.close_reason
+        } else {
+            None
+        }
+    }
+
+    /// Sends an unreliable datagram.
+    pub async fn send_urel(&self, dgram: Bytes) -> std::io::Result<()> {
+        self.push_urel(dgram);
+        Ok(())
+    }
+
+    pub(crate) fn push_urel(&self, dgram: Bytes) {
+        let mut queues = self.queues.lock();
+        let policy = queues.urel_policy;
+        if let Some(limit) = policy.send_queue_limit {
+            if queues.send_urel.len() >= limit {
+                queues.urel_dropped_queue_full += 1;
+                match policy.send_overflow {
+                    UrelOverflow::DropOldest if limit > 0 => {
+                        queues.send_urel.pop_front();
+                    }
+                    _ => return,
+                }
+            }
+        }
+        queues.send_urel.push_back(dgram);
+        drop(queues);
+        (self.tick_notify)();
+    }
+
+    /// Receives an unreliable datagram.
+    pub async fn recv_urel(&self) -> std::io::Result<Bytes> {
+        self.recv_urel_owned().await
+    }
+
+    /// Returns the unreliable datagrams of this stream as a [futures_util::Stream] of received datagrams that is also a [futures_util::Sink] for datagrams to send. See [Datagrams].
+    pub fn datagrams(&self) -> Datagrams {
+        Datagrams::new(self.clone())
+    }
+
+    /// Like [Stream::recv_urel], but the future does not borrow the stream.
+    fn recv_urel_owned(&self) -> impl Future<Output = std::io::Result<Bytes>> + Send + 'static {
+        let local_notify = self.local_notify.clone();
+        let queues = self.queues.clone();
+        async move {
+            local_notify
+                .wait_until(|| {
+                    let mut queues = queues.lock();
+                    if queues.closed && queues.urel_policy.drop_after_close {
+                        Some(Err(queues.closed_error()))
+                    } else if let Some(front) = queues.recv_urel.pop_front() {
+                        Some(Ok(front))
+                    } else if queues.closed {
+                        Some(Err(queues.closed_error()))
+                    } else {
+                        None
+                    }
+                })
+                .await
+        }
+    }
 }
 
 impl Clone for Stream {
@@ -171,7 +355,7 @@ impl AsyncRead for Stream {
                             if inner.read_stream.capacity() > inner.read_stream.len() * 2 {
                                 inner.read_stream.shrink_to_fit();
                             }
-                            if !inner.read_stream.is_empty() || inner.closed {
+                            if inner.read_ready() || inner.closed || inner.read_eof {
                                 Some(())
                             } else {
                                 None
@@ -187,7 +371,11 @@ impl AsyncRead for Stream {
                 self.read_ready_resolved = true;
                 self.read_ready_future = Some(read_future);
                 let mut queues = self.queues.lock();
-                let n = queues.read_stream.read(buf);
+                let n = match queues.violation_error() {
+                    // a stream reset over a violation did not end cleanly, so reading to its end is an error
+                    Some(err) if queues.read_stream.is_empty() && !buf.is_empty() => Err(err),
+                    _ => queues.read_stream.read(buf),
+                };
                 (self.tick_notify)();
 
                 Poll::Ready(n)
@@ -212,7 +400,7 @@ impl AsyncWrite for Stream {
         if self.write_ready_resolved {
             let write_ready = self.local_notify.clone();
             let inner = self.queues.clone();
-            // this waits until there's less than 100 KB waiting to be written. this produces the right backpressure
+            // this waits until there's not more than the write buffer size waiting to be written. this produces the right backpressure
             write_future = RecycleBox::into_pin(coerce_box!(RecycleBox::recycle_pinned(
                 write_future,
                 async move {
@@ -222,7 +410,9 @@ impl AsyncWrite for Stream {
                             if inner.write_stream.capacity() > inner.write_stream.len() * 2 {
                                 inner.write_stream.shrink_to_fit();
                             }
-                            if inner.write_stream.len() <= 100_000 {
+                            if inner.write_stream.len() <= inner.options.write_buffer
+                                || inner.write_closed
+                            {
                                 Some(())
                             } else {
                                 None
@@ -237,7 +427,20 @@ impl AsyncWrite for Stream {
             Poll::Ready(()) => {
                 self.write_ready_resolved = true;
                 self.write_ready_future = Some(write_future);
-                let n = self.queues.lock().write_stream.write(buf);
+                let n = {
+                    let mut queues = self.queues.lock();
+                    if queues.write_closed {
+                        return Poll::Ready(Err(std::io::Error::new(
+                            std::io::ErrorKind::BrokenPipe,
+                            "stream closed for writing",
+                        )));
+                    }
+                    let n = queues.write_stream.write(buf);
+                    if let Ok(n) = n {
+                        queues.written_bytes += n as u64;
+                    }
+                    n
+                };
                 (self.tick_notify)();
 
                 Poll::Ready(n)
@@ -250,9 +453,9 @@ impl AsyncWrite for Stream {
         }
     }
 
+    /// Only closes the write half, see [Stream::close_write].
     fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
-        self.queues.lock().closed = true;
-        (self.tick_notify)();
+        self.close_write();
         Poll::Ready(Ok(()))
     }
 
@@ -261,6 +464,117 @@ impl AsyncWrite for Stream {
     }
 }
 
+#[cfg(feature = "tokio")]
+impl tokio::io::AsyncRead for Stream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let n = std::task::ready!(AsyncRead::poll_read(self, cx, buf.initialize_unfilled()))?;
+        buf.advance(n);
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl tokio::io::AsyncWrite for Stream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        AsyncWrite::poll_write(self, cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        AsyncWrite::poll_flush(self, cx)
+    }
+
+    /// Only closes the write half, see [Stream::close_write].
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        AsyncWrite::poll_close(self, cx)
+    }
+}
+
+/// Buffer sizes of a stream, which bound how much memory it uses, and its priority. Set when opening a stream with [crate::Multiplex::open_conn_with_options], or at any time with [Stream::set_options].
+#[derive(Clone, Copy, Debug)]
+pub struct StreamOptions {
+    /// Writes wait while more than this many bytes are waiting to be sent. Defaults to 100 kB.
+    pub write_buffer: usize,
+    /// How many received bytes may wait to be read. The other side is told how much room is left, and stops sending once it runs out, until the application reads more. Peers that predate this do not stop, so once more than this many bytes are waiting, further data is not accepted, and they have to retransmit it later. Defaults to 10 MB.
+    pub read_buffer: usize,
+    /// How many received unreliable datagrams may wait for [Stream::recv_urel]; beyond that, the oldest one is dropped. `None`, the default, means no limit. The queue of datagrams waiting to be sent is limited by [UrelPolicy::send_queue_limit].
+    pub urel_recv_queue_limit: Option<usize>,
+    /// How many data packets the stream may send, whenever several streams of the same multiplex have data ready at once, for every one sent by a stream of priority 1. Interactive streams sharing a multiplex with bulk transfers can be given a higher priority so that their data does not wait behind a whole window of bulk data. This only orders what goes out; how much each stream may have in flight is still up to its congestion control, see [crate::Multiplex::set_group_weight]. Defaults to 1, and 0 counts as 1.
+    pub priority: u32,
+    /// Marks the stream as latency-sensitive, e.g. one carrying DNS or interactive traffic, with the queueing delay it can tolerate. While any such stream is open, the other streams of the multiplex keep no more data in flight than drains within a round trip plus the smallest budget among them, so that a large download does not fill the queues along the path; and the data of latency-sensitive streams goes out ahead of theirs. This costs bulk streams some throughput on paths whose RTT varies a lot. Defaults to `None`.
+    pub latency_budget: Option<Duration>,
+    /// Keeps the data of the stream on the one pipe with the lowest RTT, as under [crate::MultipathPolicy::LowestRtt], even while the multiplex stripes the data of other streams over several pipes with [crate::MultipathPolicy::WeightedRoundRobin] or [crate::MultipathPolicy::Bonded]. Striping adds up the bandwidth of the pipes, but data over a slow pipe arrives late and holds back whatever follows it, which an interactive stream should not have to wait for. Defaults to `false`.
+    pub single_path: bool,
+}
+
+impl Default for StreamOptions {
+    fn default() -> Self {
+        Self {
+            write_buffer: DEFAULT_WRITE_BUFFER,
+            read_buffer: DEFAULT_READ_BUFFER,
+            urel_recv_queue_limit: None,
+            priority: 1,
+            latency_budget: None,
+            single_path: false,
+        }
+    }
+}
+
+/// Which unreliable datagrams a stream drops instead of delivering, so that applications need not cope with datagrams showing up around the edges of the stream's lifetime, or with stale datagrams that queued up while they could not be sent. Drops are counted in [StreamStats]. By default, nothing is dropped.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct UrelPolicy {
+    /// Drop datagrams that arrive on an opening stream before the other side has accepted it, rather than delivering them once it has.
+    pub drop_before_established: bool,
+    /// Drop datagrams that arrive after the stream was closed, as well as any still waiting to be received when it closes, rather than letting [Stream::recv_urel] return them before failing.
+    pub drop_after_close: bool,
+    /// How many datagrams may wait to be sent, e.g. while the stream is still being opened, before [UrelPolicy::send_overflow] decides which to drop. `None` means no limit.
+    pub send_queue_limit: Option<usize>,
+    /// Which datagram to drop when the send queue is full.
+    pub send_overflow: UrelOverflow,
+}
+
+/// How streams are paced, and which are exempt from pacing, and so send as much as their congestion window allows at once instead of spreading it out over a round trip. Pacing keeps bulk streams from overflowing the queues along the path, but a short request or response gains nothing from it, and waits for the pacing rate, which starts out low, before its last bytes go out. By default, every stream is paced at a congestion window per round trip.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PacingPolicy {
+    /// Streams are exempt until this many bytes have been written to them, so that streams that never get this large are never paced. 0 exempts none.
+    pub exempt_below: u64,
+    /// Latency-sensitive streams, those with a [StreamOptions::latency_budget], are exempt however much is written to them.
+    pub exempt_latency_sensitive: bool,
+    /// The rate that paced streams send at.
+    pub mode: PacingMode,
+    /// How many packets a paced stream may save up to send back to back, as in a token bucket, so that a stream whose packets are due faster than its timers can fire still keeps to its pacing rate on average, e.g. on a fast LAN. `None`, the default, carries nothing over from one packet to the next: each time the stream is ticked, it may send as many whole packets as became due since it last sent one, and whatever fraction of a packet was due besides is lost, which holds back streams whose packets are due faster than its timers fire.
+    pub max_burst: Option<usize>,
+}
+
+/// The rate that paced streams send at, see [PacingPolicy::mode].
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum PacingMode {
+    /// Nothing is paced, as if every stream were exempt.
+    Off,
+    /// A congestion window per round trip, as the congestion controller says, or less if the reader on the other side is slower.
+    #[default]
+    Cwnd,
+    /// A fixed number of bytes per second, whatever the congestion controller says. The congestion window still limits how much may be in flight.
+    FixedRate(f64),
+}
+
+/// Which datagram to drop when more unreliable datagrams are sent than [UrelPolicy::send_queue_limit] allows to wait.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum UrelOverflow {
+    /// Drop the oldest waiting datagram, so that what gets sent is as fresh as possible. Suits real-time traffic.
+    #[default]
+    DropOldest,
+    /// Drop the datagram being sent, keeping those already waiting.
+    DropNewest,
+}
+
 #[derive(Default)]
 /// The "go-between" between MuxStream and StreamState
 struct StreamQueues {
@@ -272,28 +586,140 @@ struct StreamQueues {
     recv_urel: VecDeque<Bytes>,
     /// Unreliable datagrams to be sent to the other end
     send_urel: VecDeque<Bytes>,
+    /// Readers are only woken once this many bytes are waiting to be read, see [StreamQueues::read_ready]
+    read_buffer_min: usize,
+    /// Buffer sizes set through the handle
+    options: StreamOptions,
+    /// Bandwidth-sharing group set through the handle
+    group: Option<String>,
+    /// Congestion control algorithm chosen through the handle, not yet picked up by the StreamState
+    congestion: Option<CongestionAlgorithm>,
+    /// Whether the reader asked the other side to stop sending
+    read_paused: bool,
+    /// Why the other side reset the stream, if it did
+    reset_code: Option<ResetCode>,
+    /// What the other side did wrong, if this side reset the stream because of it
+    violation: Option<ProtocolViolation>,
+    /// Why the stream closed, as first found out by either the handle or the StreamState
+    close_reason: Option<CloseReason>,
+    /// Statistics published by the StreamState
+    stats: StreamStats,
+    urel_policy: UrelPolicy,
+    /// Unreliable datagrams dropped because too many were waiting to be sent
+    urel_dropped_queue_full: u64,
+    /// Bytes ever written through the handle
+    written_bytes: u64,
+    /// Bytes, from the start of the stream, that the other side acknowledged
+    acked_bytes: u64,
+    /// Whether the handle finished writing
+    write_closed: bool,
+    /// How long the stream keeps delivering what was written to it once the last handle is dropped, or `None` to close it right away
+    drop_linger: Option<Duration>,
+    /// Until when the stream, whose last handle was dropped, keeps delivering what was written to it
+    linger_until: Option<Instant>,
+    /// Whether the other side finished writing, and everything it wrote was delivered
+    read_eof: bool,
     connected: bool,
     closed: bool,
 }
 
+impl StreamQueues {
+    fn new() -> Self {
+        Self {
+            read_buffer_min: 1,
+            ..Default::default()
+        }
+    }
+
+    /// Whether enough is waiting to be read for readers to be woken: the low-water mark, or a full read buffer if that is smaller, as the other side sends no more than that.
+    fn read_ready(&self) -> bool {
+        let min = self.read_buffer_min.min(self.options.read_buffer).max(1);
+        self.read_stream.len() >= min
+    }
+
+    /// Closes the stream, unless it already closed for another reason.
+    fn close(&mut self, reason: CloseReason) {
+        self.set_close_reason(reason);
+        self.closed = true;
+    }
+
+    /// Records why the stream is closing, unless a reason was already recorded.
+    fn set_close_reason(&mut self, reason: CloseReason) {
+        self.close_reason.get_or_insert(reason);
+    }
+
+    /// The error that operations on the closed stream fail with.
+    fn closed_error(&self) -> std::io::Error {
+        match self.close_reason {
+            Some(reason) => std::io::Error::new(std::io::ErrorKind::BrokenPipe, reason),
+            None => std::io::Error::new(std::io::ErrorKind::BrokenPipe, "broken pipe"),
+        }
+    }
+
+    /// The statistics published by the StreamState, together with the ones kept here.
+    fn stats(&self) -> StreamStats {
+        StreamStats {
+            urel_dropped_queue_full: self.urel_dropped_queue_full,
+            read_queue_bytes: self.read_stream.len(),
+            write_queue_bytes: self.write_stream.len(),
+            ..self.stats.clone()
+        }
+    }
+
+    /// The error reads fail with once everything is read, if the stream was reset over a protocol violation by either side.
+    fn violation_error(&self) -> Option<std::io::Error> {
+        if !self.closed {
+            return None;
+        }
+        if let Some(violation) = self.violation {
+            return Some(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                violation,
+            ));
+        }
+        (self.reset_code == Some(ResetCode::ProtocolViolation)).then(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::ConnectionReset,
+                "the other side reset the stream over a protocol violation",
+            )
+        })
+    }
+}
+
 /// A stream-related message.
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum StreamMessage {
     Reliable {
         kind: RelKind,
-        stream_id: u16,
+        stream_id: StreamId,
         seqno: Seqno,
         payload: Bytes,
     },
     Unreliable {
-        stream_id: u16,
+        stream_id: StreamId,
         payload: Bytes,
     },
     Empty,
+    /// An unreliable datagram of the multiplex itself, sent with [crate::Multiplex::send_datagram]. Only sent to peers whose hello advertises version 7 or later.
+    Datagram {
+        payload: Bytes,
+    },
+    /// Tells the other side that the sender is closing the multiplex with [crate::Multiplex::graceful_close], and so accepts no more streams. Only sent to peers whose hello advertises version 9 or later.
+    GoAway,
+    /// Asks the other side to answer with a [StreamMessage::Pong] carrying the same ID right away, to measure the RTT with [crate::Multiplex::ping]. Only sent to peers whose hello advertises version 10 or later.
+    Ping {
+        id: u64,
+    },
+    Pong {
+        id: u64,
+    },
+    /// Tells the other side that the sender is done with the multiplex, through [crate::Multiplex::close] or by dropping it, so that it releases everything right away rather than waiting for the multiplex to time out. Repeated until answered with a [StreamMessage::CloseAck]. Only sent to peers whose hello advertises version 11 or later.
+    Close,
+    CloseAck,
 }
 
 impl StreamMessage {
-    pub fn seqno(&self) -> u64 {
+    pub fn seqno(&self) -> Seqno {
         match self {
             StreamMessage::Reliable {
                 kind: _,
@@ -301,7 +727,121 @@ impl StreamMessage {
                 seqno,
                 payload: _,
             } => *seqno,
-            _ => 0,
+            _ => Seqno::ZERO,
+        }
+    }
+
+    /// The stream and seqno of the data segment that the message carries, or a piece of, if any.
+    pub(crate) fn segment(&self) -> Option<(StreamId, Seqno)> {
+        match self {
+            StreamMessage::Reliable {
+                kind: RelKind::Data | RelKind::DataFrag,
+                stream_id,
+                seqno,
+                payload: _,
+            } => Some((*stream_id, *seqno)),
+            _ => None,
+        }
+    }
+}
+
+/// Why a stream was reset, carried as the first byte of the payload of a [RelKind::Rst]. Peers that predate reset codes send an empty payload.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub(crate) enum ResetCode {
+    Unspecified,
+    /// The stream was refused because the application is not accepting streams fast enough.
+    AcceptBacklogFull,
+    /// The side sending the reset got a message for the stream that no correct implementation sends.
+    ProtocolViolation,
+    /// The stream was refused because the side sending the reset is closing the multiplex.
+    GoingAway,
+    /// The side sending the reset got a message for a stream it does not know, such as data that overtook the SYN. Peers that predate this send [ResetCode::Unspecified].
+    UnknownStream,
+}
+
+impl ResetCode {
+    pub fn from_payload(payload: &[u8]) -> Self {
+        match payload.first() {
+            Some(1) => Self::AcceptBacklogFull,
+            Some(2) => Self::ProtocolViolation,
+            Some(3) => Self::GoingAway,
+            Some(4) => Self::UnknownStream,
+            _ => Self::Unspecified,
+        }
+    }
+
+    pub fn to_payload(self) -> Bytes {
+        match self {
+            Self::Unspecified => Bytes::new(),
+            Self::AcceptBacklogFull => Bytes::from_static(&[1]),
+            Self::ProtocolViolation => Bytes::from_static(&[2]),
+            Self::GoingAway => Bytes::from_static(&[3]),
+            Self::UnknownStream => Bytes::from_static(&[4]),
+        }
+    }
+}
+
+/// A message from the other side of a stream that no correct implementation sends, such as one from a buggy or malicious peer. Only the stream it was for is reset; once everything received before is read, reads fail with an [std::io::ErrorKind::InvalidData] error wrapping this.
+#[derive(Error, Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ProtocolViolation {
+    /// An acknowledgement for data that was never sent.
+    #[error("acknowledgement for data that was never sent")]
+    AckBeyondSent,
+    /// An acknowledgement whose payload could not be decoded.
+    #[error("malformed acknowledgement")]
+    MalformedAck,
+    /// A window update whose payload is neither empty nor a stream offset.
+    #[error("malformed window update")]
+    MalformedWindowUpdate,
+    /// A piece of a segment that does not fit the segment it claims to be part of.
+    #[error("malformed segment fragment")]
+    MalformedFragment,
+}
+
+/// Why a [Stream] closed, as returned by [Stream::close_reason].
+#[derive(Error, Copy, Clone, Debug, Eq, PartialEq)]
+pub enum CloseReason {
+    /// This side closed the stream, through [Stream::shutdown] or by dropping it, or by finishing writing with [Stream::close_write] while the other side predates half-closing.
+    #[error("stream closed by this side")]
+    LocalShutdown,
+    /// The other side closed the stream.
+    #[error("stream closed by the other side")]
+    PeerClosed,
+    /// The other side reset the stream without saying why, e.g. because it no longer knew about it.
+    #[error("stream reset by the other side")]
+    PeerReset,
+    /// The other side refused the stream when it was being opened.
+    #[error("the other side refused the stream")]
+    Refused,
+    /// The other side refused the stream because its application is not accepting streams fast enough.
+    #[error("the other side's accept queue is full")]
+    AcceptBacklogFull,
+    /// The other side refused the stream because it is closing the multiplex, see [crate::Multiplex::graceful_close].
+    #[error("the other side is closing the multiplex")]
+    PeerGoingAway,
+    /// This side reset the stream because the other side violated the protocol.
+    #[error("stream reset over a protocol violation by the other side: {0}")]
+    ProtocolViolation(ProtocolViolation),
+    /// The other side reset the stream because it thought this side violated the protocol.
+    #[error("the other side reset the stream over a protocol violation")]
+    PeerProtocolViolation,
+    /// The [crate::Multiplex] carrying the stream shut down.
+    #[error("the multiplex carrying the stream closed")]
+    MultiplexClosed,
+    /// The other side closed the [crate::Multiplex] carrying the stream, see [crate::Multiplex::close].
+    #[error("the other side closed the multiplex carrying the stream")]
+    MultiplexClosedByPeer,
+}
+
+impl CloseReason {
+    /// The reason for a reset from the other side, sent with the given code.
+    pub(crate) fn from_reset(code: ResetCode, established: bool) -> Self {
+        match code {
+            ResetCode::AcceptBacklogFull => Self::AcceptBacklogFull,
+            ResetCode::ProtocolViolation => Self::PeerProtocolViolation,
+            ResetCode::GoingAway => Self::PeerGoingAway,
+            ResetCode::Unspecified | ResetCode::UnknownStream if established => Self::PeerReset,
+            ResetCode::Unspecified | ResetCode::UnknownStream => Self::Refused,
         }
     }
 }
@@ -313,6 +853,23 @@ pub enum RelKind {
     Data,
     DataAck,
     Fin,
+    /// Acknowledges a [RelKind::Eof]
     FinAck,
     Rst,
+    /// Asks the other side to stop sending data
+    Pause,
+    /// Lets the other side send data again
+    Resume,
+    /// Tells the other side how fast the application reads the stream, in bytes per second, so that it does not send faster; zero lifts the limit
+    ReadRate,
+    /// Like [RelKind::DataAck], but with the selective acks encoded as ranges
+    DataAckRanges,
+    /// Like [RelKind::DataAckRanges], but in a fixed layout that is written and read without serde
+    DataAckCompact,
+    /// Tells the other side up to which stream offset it may send data, as a little-endian `u64`; with an empty payload, asks the other side for its window
+    WindowUpdate,
+    /// Tells the other side that this side writes nothing after the data packets before the seqno, while it keeps reading; repeated until answered with a [RelKind::FinAck]
+    Eof,
+    /// A piece of a [RelKind::Data] segment that is retransmitted after the MSS shrank below its size; the other side puts the pieces back together before acking the seqno
+    DataFrag,
 }
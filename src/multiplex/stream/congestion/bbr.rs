@@ -0,0 +1,362 @@
+use std::time::{Duration, Instant};
+
+use crate::utilities::runtime;
+
+use super::{AckEvent, CongestionControl};
+
+// 2/ln(2), the smallest gain that can double the sending rate every round trip
+const STARTUP_GAIN: f64 = 2.885;
+const CWND_GAIN: f64 = 2.0;
+// the pacing gains of the bandwidth probing cycle, one round trip each
+const PROBE_BW_GAINS: [f64; 8] = [1.25, 0.75, 1.0, 1.0, 1.0, 1.0, 1.0, 1.0];
+// how many round trips a bandwidth sample counts towards the estimate
+const BW_WINDOW_ROUNDS: u64 = 10;
+// how long a minimum RTT sample counts before probing for a new one
+const MIN_RTT_WINDOW: Duration = Duration::from_secs(10);
+const PROBE_RTT_DURATION: Duration = Duration::from_millis(200);
+// how much of the in-flight bound is kept when packets are lost
+const LOSS_BETA: f64 = 0.7;
+const MIN_CWND: f64 = 4.0;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Mode {
+    /// Doubling the sending rate every round trip until the bandwidth stops growing.
+    Startup,
+    /// Draining the queue built up during startup.
+    Drain,
+    /// Cycling around the estimated bandwidth, index into [PROBE_BW_GAINS] and when the phase started.
+    ProbeBw { phase: usize, since: Instant },
+    /// Sending with a tiny window to measure the minimum RTT without our own queue.
+    ProbeRtt { until: Instant },
+}
+
+/// BBR congestion control, loosely following BBRv2: the sending rate comes from measured bottleneck bandwidth and minimum RTT instead of from losses, so random loss does not collapse throughput. Loss only bounds how much may be in flight, and that bound is probed upwards again every cycle.
+///
+/// Stream weights other than 1 are rejected: the window follows the measured bandwidth rather than a growth rate, so scaling it makes the heavier stream take ever more of the bottleneck instead of a proportional share.
+pub struct Bbr {
+    mode: Mode,
+    cwnd: f64,
+
+    // bottleneck bandwidth in packets per second, and the round when it was measured
+    btl_bw: f64,
+    btl_bw_round: u64,
+    min_rtt: Option<Duration>,
+    min_rtt_time: Instant,
+
+    // round trip counting: a round ends once everything in flight at its start is delivered
+    delivered: u64,
+    round: u64,
+    next_round_delivered: u64,
+
+    // startup ends when the bandwidth stops growing by a quarter for three rounds
+    full_bw: f64,
+    full_bw_rounds: u64,
+
+    // the loss-derived bound on packets in flight, and how much it grows by at the next probe
+    inflight_hi: f64,
+    probe_up: f64,
+    pre_loss: Option<(f64, f64)>,
+}
+
+impl Default for Bbr {
+    fn default() -> Self {
+        Self {
+            mode: Mode::Startup,
+            cwnd: MIN_CWND,
+            btl_bw: 0.0,
+            btl_bw_round: 0,
+            min_rtt: None,
+            min_rtt_time: runtime::now(),
+            delivered: 0,
+            round: 0,
+            next_round_delivered: 0,
+            full_bw: 0.0,
+            full_bw_rounds: 0,
+            inflight_hi: f64::INFINITY,
+            probe_up: 1.0,
+            pre_loss: None,
+        }
+    }
+}
+
+impl Bbr {
+    fn bdp(&self) -> f64 {
+        self.btl_bw * self.min_rtt.unwrap_or_default().as_secs_f64()
+    }
+
+    fn pacing_gain(&self) -> f64 {
+        match self.mode {
+            Mode::Startup => STARTUP_GAIN,
+            Mode::Drain => 1.0 / STARTUP_GAIN,
+            Mode::ProbeBw { phase, .. } => PROBE_BW_GAINS[phase],
+            Mode::ProbeRtt { .. } => 1.0,
+        }
+    }
+
+    fn enter_probe_bw(&mut self, now: Instant) {
+        self.mode = Mode::ProbeBw {
+            // start cruising rather than probing, since startup just found the limit
+            phase: 2,
+            since: now,
+        };
+    }
+
+    /// Advances the probing cycle, each phase lasting a round trip. Probing up lifts the in-flight bound, by more the longer probes have gone without loss.
+    fn advance_cycle(&mut self, now: Instant, inflight: usize) {
+        let Mode::ProbeBw { phase, since } = self.mode else {
+            return;
+        };
+        let min_rtt = self.min_rtt.unwrap_or_default();
+        let elapsed = now.saturating_duration_since(since) >= min_rtt;
+        let next = match PROBE_BW_GAINS[phase] {
+            // keep probing until the extra data is actually in flight, or as much as the bound allows
+            gain if gain > 1.0 => {
+                elapsed && (inflight as f64 >= self.bdp() * gain || self.cwnd >= self.inflight_hi)
+            }
+            // stop draining once the queue is gone
+            gain if gain < 1.0 => elapsed || inflight as f64 <= self.bdp(),
+            _ => elapsed,
+        };
+        if next {
+            let phase = (phase + 1) % PROBE_BW_GAINS.len();
+            if PROBE_BW_GAINS[phase] > 1.0 && self.inflight_hi.is_finite() {
+                self.inflight_hi += self.probe_up;
+                self.probe_up = (self.probe_up * 2.0).min(self.inflight_hi);
+            }
+            self.mode = Mode::ProbeBw { phase, since: now };
+        }
+    }
+
+    fn check_full_bw(&mut self) {
+        if self.btl_bw >= self.full_bw * 1.25 {
+            self.full_bw = self.btl_bw;
+            self.full_bw_rounds = 0;
+        } else {
+            self.full_bw_rounds += 1;
+        }
+    }
+}
+
+impl CongestionControl for Bbr {
+    fn cwnd(&self) -> f64 {
+        self.cwnd
+    }
+
+    fn pacing_rate(&self, min_rtt: Duration) -> f64 {
+        if self.btl_bw > 0.0 {
+            self.pacing_gain() * self.btl_bw
+        } else {
+            // no bandwidth sample yet
+            self.pacing_gain() * self.cwnd / min_rtt.as_secs_f64()
+        }
+    }
+
+    fn on_ack(&mut self, ack: &AckEvent) {
+        let now = ack.now;
+        self.delivered += ack.acked as u64;
+        let round_start = self.delivered >= self.next_round_delivered;
+        if round_start {
+            self.round += 1;
+            self.next_round_delivered = self.delivered + ack.inflight as u64;
+        }
+
+        if ack.delivery_rate >= self.btl_bw || self.round - self.btl_bw_round > BW_WINDOW_ROUNDS {
+            self.btl_bw = ack.delivery_rate;
+            self.btl_bw_round = self.round;
+        }
+        let rtt = ack.latest_rtt.unwrap_or(ack.min_rtt);
+        let min_rtt_expired = now.saturating_duration_since(self.min_rtt_time) > MIN_RTT_WINDOW;
+        if self.min_rtt.is_none_or(|min_rtt| rtt <= min_rtt) {
+            self.min_rtt = Some(rtt);
+            self.min_rtt_time = now;
+        }
+
+        match self.mode {
+            Mode::Startup => {
+                if round_start {
+                    self.check_full_bw();
+                    if self.full_bw_rounds >= 3 {
+                        self.mode = Mode::Drain;
+                    }
+                }
+            }
+            Mode::Drain => {
+                if ack.inflight as f64 <= self.bdp() {
+                    self.enter_probe_bw(now);
+                }
+            }
+            Mode::ProbeBw { .. } => self.advance_cycle(now, ack.inflight),
+            Mode::ProbeRtt { until } => {
+                if now >= until && round_start {
+                    self.min_rtt_time = now;
+                    self.enter_probe_bw(now);
+                }
+            }
+        }
+        if min_rtt_expired && !matches!(self.mode, Mode::ProbeRtt { .. } | Mode::Startup) {
+            self.mode = Mode::ProbeRtt {
+                until: now + PROBE_RTT_DURATION.max(self.min_rtt.unwrap_or_default()),
+            };
+            // the next sample, taken with the queue drained, becomes the new minimum
+            self.min_rtt = None;
+        }
+
+        // grow by at most what was acked, like slow start, up to a few BDPs
+        let gain = if self.mode == Mode::Startup {
+            STARTUP_GAIN
+        } else {
+            CWND_GAIN
+        };
+        let target = if self.bdp() > 0.0 {
+            gain * self.bdp()
+        } else {
+            f64::INFINITY
+        };
+        self.cwnd = match self.mode {
+            Mode::ProbeRtt { .. } => MIN_CWND,
+            _ => (self.cwnd + ack.acked as f64).min(target),
+        }
+        .min(self.inflight_hi)
+        .max(MIN_CWND);
+    }
+
+    fn on_loss(&mut self, _now: Instant) {
+        if self.mode == Mode::Startup {
+            // with random loss, reacting now would end startup long before the bandwidth is found; startup ends anyway once the bandwidth stops growing
+            return;
+        }
+        self.pre_loss = Some((self.cwnd, self.inflight_hi));
+        // never bound the window below what the measured path can hold, so that random loss does not throttle a stream that is not causing it
+        self.inflight_hi = (self.cwnd * LOSS_BETA).max(self.bdp()).max(MIN_CWND);
+        self.probe_up = 1.0;
+        self.cwnd = self.cwnd.min(self.inflight_hi);
+    }
+
+    fn on_spurious_loss(&mut self) {
+        if let Some((cwnd, inflight_hi)) = self.pre_loss.take() {
+            self.cwnd = self.cwnd.max(cwnd);
+            self.inflight_hi = self.inflight_hi.max(inflight_hi);
+        }
+    }
+
+    fn set_cwnd(&mut self, cwnd: f64) {
+        self.cwnd = cwnd.max(MIN_CWND);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RTT: Duration = Duration::from_millis(50);
+    // packets per second through the bottleneck, for a BDP of 50 packets
+    const BOTTLENECK: f64 = 1000.0;
+
+    fn ack(now: Instant, inflight: usize, delivery_rate: f64) -> AckEvent {
+        AckEvent {
+            now,
+            acked: 1,
+            inflight,
+            min_rtt: RTT,
+            srtt: RTT,
+            latest_rtt: Some(RTT),
+            delivery_rate,
+        }
+    }
+
+    /// Sends a window's worth over the bottleneck and acks it, a round trip later.
+    fn round(bbr: &mut Bbr, now: &mut Instant) {
+        *now += RTT;
+        let window = bbr.cwnd() as usize;
+        let rate = (window as f64 / RTT.as_secs_f64()).min(BOTTLENECK);
+        for acked in 1..=window {
+            bbr.on_ack(&ack(*now, window - acked, rate));
+        }
+    }
+
+    #[test]
+    fn startup_drain_and_probe_bw() {
+        let mut bbr = Bbr::default();
+        let mut now = Instant::now();
+        let mut rounds = 0;
+        while bbr.mode == Mode::Startup {
+            round(&mut bbr, &mut now);
+            rounds += 1;
+            assert!(rounds < 20, "startup never ended");
+        }
+        // the window doubled or so every round until the bandwidth stopped growing
+        assert_eq!(bbr.mode, Mode::Drain);
+        assert_eq!(bbr.btl_bw, BOTTLENECK);
+        assert_eq!(bbr.bdp(), 50.0);
+        assert!(bbr.cwnd() <= STARTUP_GAIN * bbr.bdp());
+        assert!(bbr.pacing_rate(RTT) < BOTTLENECK);
+
+        // the queue drains away, and the cycle starts cruising at the bandwidth
+        bbr.on_ack(&ack(now, 40, BOTTLENECK));
+        assert!(matches!(bbr.mode, Mode::ProbeBw { phase: 2, .. }));
+        assert_eq!(bbr.pacing_rate(RTT), BOTTLENECK);
+        assert_eq!(bbr.cwnd(), CWND_GAIN * bbr.bdp());
+
+        // each round trip moves the cycle on, round to probing up, draining what probing queued, and cruising again
+        let mut gains = vec![];
+        for _ in 0..PROBE_BW_GAINS.len() {
+            now += RTT;
+            bbr.on_ack(&ack(now, 63, BOTTLENECK));
+            gains.push(bbr.pacing_rate(RTT) / BOTTLENECK);
+        }
+        assert_eq!(gains, [1.0, 1.0, 1.0, 1.0, 1.0, 1.25, 0.75, 1.0]);
+    }
+
+    #[test]
+    fn probe_rtt_after_min_rtt_expires() {
+        let mut bbr = Bbr::default();
+        let mut now = Instant::now();
+        while bbr.mode == Mode::Startup {
+            round(&mut bbr, &mut now);
+        }
+        bbr.on_ack(&ack(now, 40, BOTTLENECK));
+
+        // no lower RTT for longer than the window, so the queue is drained to measure it afresh
+        now += MIN_RTT_WINDOW + RTT;
+        bbr.on_ack(&ack(now, 40, BOTTLENECK));
+        assert!(matches!(bbr.mode, Mode::ProbeRtt { .. }));
+        assert_eq!(bbr.cwnd(), MIN_CWND);
+        // until what was in flight is delivered, and for at least a while
+        let entered = now;
+        while matches!(bbr.mode, Mode::ProbeRtt { .. }) {
+            round(&mut bbr, &mut now);
+            assert!(
+                now - entered < MIN_RTT_WINDOW,
+                "probing the RTT never ended"
+            );
+        }
+        assert!(now - entered >= PROBE_RTT_DURATION);
+        assert!(matches!(bbr.mode, Mode::ProbeBw { .. }));
+        assert_eq!(bbr.min_rtt, Some(RTT));
+    }
+
+    #[test]
+    fn losses_bound_inflight_only_after_startup() {
+        let mut bbr = Bbr::default();
+        let mut now = Instant::now();
+        round(&mut bbr, &mut now);
+        let cwnd = bbr.cwnd();
+        bbr.on_loss(now);
+        assert_eq!(bbr.cwnd(), cwnd);
+        assert!(bbr.inflight_hi.is_infinite());
+
+        while bbr.mode == Mode::Startup {
+            round(&mut bbr, &mut now);
+        }
+        bbr.on_ack(&ack(now, 40, BOTTLENECK));
+        let cwnd = bbr.cwnd();
+        bbr.on_loss(now);
+        assert_eq!(bbr.cwnd(), cwnd * LOSS_BETA);
+        // but never below what the path holds
+        bbr.on_loss(now);
+        assert_eq!(bbr.cwnd(), bbr.bdp());
+        // a loss that turns out spurious gives the window back
+        bbr.on_spurious_loss();
+        assert_eq!(bbr.cwnd(), cwnd * LOSS_BETA);
+    }
+}
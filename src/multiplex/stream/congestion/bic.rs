@@ -0,0 +1,73 @@
+use std::time::Instant;
+
+use super::{AckEvent, CongestionControl};
+
+/// Binary increase congestion control: the window grows quickly towards where the last loss happened, slowly around it, and quickly again past it.
+pub struct Bic {
+    cwnd: f64,
+    ssthresh: f64,
+    weight: f64,
+    // the window and threshold before the last loss, for undoing it
+    pre_loss: Option<(f64, f64)>,
+}
+
+impl Default for Bic {
+    fn default() -> Self {
+        Self {
+            cwnd: 4.0,
+            ssthresh: 0.0,
+            weight: 1.0,
+            pre_loss: None,
+        }
+    }
+}
+
+impl CongestionControl for Bic {
+    fn cwnd(&self) -> f64 {
+        self.cwnd
+    }
+
+    fn on_ack(&mut self, ack: &AckEvent) {
+        for _ in 0..ack.acked {
+            // the binary search runs on the window per unit of weight, as if the stream were that many streams
+            let (cwnd, ssthresh) = (self.cwnd / self.weight, self.ssthresh / self.weight);
+            let bic_inc = if cwnd < ssthresh {
+                (ssthresh - cwnd) / 2.0
+            } else {
+                cwnd - ssthresh
+            }
+            .clamp(1.0, 50.0)
+            .min(cwnd);
+            self.cwnd += bic_inc * self.weight / self.cwnd;
+        }
+    }
+
+    fn on_loss(&mut self, _now: Instant) {
+        self.pre_loss = Some((self.cwnd, self.ssthresh));
+        let beta = 0.15;
+        if self.cwnd < self.ssthresh {
+            self.ssthresh = self.cwnd * (2.0 - beta) / 2.0;
+        } else {
+            self.ssthresh = self.cwnd;
+        }
+
+        self.cwnd *= 1.0 - beta;
+        self.cwnd = self.cwnd.max(1.0);
+    }
+
+    fn on_spurious_loss(&mut self) {
+        if let Some((cwnd, ssthresh)) = self.pre_loss.take() {
+            self.cwnd = self.cwnd.max(cwnd);
+            self.ssthresh = ssthresh;
+        }
+    }
+
+    fn set_weight(&mut self, weight: f64) -> anyhow::Result<()> {
+        self.weight = weight;
+        Ok(())
+    }
+
+    fn set_cwnd(&mut self, cwnd: f64) {
+        self.cwnd = cwnd.max(1.0);
+    }
+}
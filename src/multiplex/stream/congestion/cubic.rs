@@ -0,0 +1,98 @@
+use std::time::Instant;
+
+use super::{AckEvent, CongestionControl};
+
+const C: f64 = 0.4;
+const BETA: f64 = 0.7;
+
+/// CUBIC congestion control (RFC 8312): after a loss, the window follows a cubic function of the time since, plateauing around the window where the loss happened before probing beyond it.
+pub struct Cubic {
+    cwnd: f64,
+    ssthresh: f64,
+    // the window before the last reduction, and when the current growth epoch started
+    w_max: f64,
+    k: f64,
+    epoch_start: Option<Instant>,
+    // what Reno would have by now, which CUBIC never falls behind
+    w_est: f64,
+    weight: f64,
+    pre_loss: Option<(f64, f64, f64)>,
+}
+
+impl Default for Cubic {
+    fn default() -> Self {
+        Self {
+            cwnd: 4.0,
+            ssthresh: f64::INFINITY,
+            w_max: 0.0,
+            k: 0.0,
+            epoch_start: None,
+            w_est: 0.0,
+            weight: 1.0,
+            pre_loss: None,
+        }
+    }
+}
+
+impl CongestionControl for Cubic {
+    fn cwnd(&self) -> f64 {
+        self.cwnd
+    }
+
+    fn on_ack(&mut self, ack: &AckEvent) {
+        let acked = ack.acked as f64;
+        if self.cwnd < self.ssthresh {
+            // slow start
+            self.cwnd += acked * self.weight;
+            return;
+        }
+        // the cubic runs on the window per unit of weight, as if the stream were that many streams
+        let epoch_start = *self.epoch_start.get_or_insert_with(|| {
+            self.k = ((self.w_max - self.cwnd).max(0.0) / self.weight / C).cbrt();
+            self.w_max = self.w_max.max(self.cwnd);
+            self.w_est = self.cwnd;
+            ack.now
+        });
+        let t = (ack.now.saturating_duration_since(epoch_start) + ack.min_rtt).as_secs_f64();
+        let target = self.weight * C * (t - self.k).powi(3) + self.w_max;
+        self.w_est += 3.0 * (1.0 - BETA) / (1.0 + BETA) * acked * self.weight / self.cwnd;
+        let increase = if target > self.cwnd {
+            (target - self.cwnd) / self.cwnd
+        } else {
+            0.01 * self.weight / self.cwnd
+        };
+        self.cwnd += increase * acked;
+        self.cwnd = self.cwnd.max(self.w_est);
+    }
+
+    fn on_loss(&mut self, _now: Instant) {
+        self.pre_loss = Some((self.cwnd, self.ssthresh, self.w_max));
+        self.epoch_start = None;
+        // fast convergence: give up some bandwidth to newer flows when losses come sooner than last time
+        self.w_max = if self.cwnd < self.w_max {
+            self.cwnd * (1.0 + BETA) / 2.0
+        } else {
+            self.cwnd
+        };
+        self.ssthresh = (self.cwnd * BETA).max(1.0);
+        self.cwnd = self.ssthresh;
+    }
+
+    fn on_spurious_loss(&mut self) {
+        if let Some((cwnd, ssthresh, w_max)) = self.pre_loss.take() {
+            self.cwnd = self.cwnd.max(cwnd);
+            self.ssthresh = ssthresh;
+            self.w_max = w_max;
+            self.epoch_start = None;
+        }
+    }
+
+    fn set_weight(&mut self, weight: f64) -> anyhow::Result<()> {
+        self.weight = weight;
+        Ok(())
+    }
+
+    fn set_cwnd(&mut self, cwnd: f64) {
+        self.cwnd = cwnd.max(1.0);
+    }
+}
@@ -0,0 +1,90 @@
+use std::time::Instant;
+
+use super::{AckEvent, CongestionControl};
+
+// below this window, HighSpeed TCP behaves exactly like Reno
+const LOW_WINDOW: f64 = 38.0;
+const HIGH_WINDOW: f64 = 83000.0;
+const HIGH_DECREASE: f64 = 0.1;
+
+/// HighSpeed TCP (RFC 3649): Reno, except that large windows grow faster and shrink less on loss, so that long fat paths can be filled.
+pub struct Highspeed {
+    cwnd: f64,
+    ssthresh: f64,
+    weight: f64,
+    pre_loss: Option<(f64, f64)>,
+}
+
+impl Default for Highspeed {
+    fn default() -> Self {
+        Self {
+            cwnd: 4.0,
+            ssthresh: f64::INFINITY,
+            weight: 1.0,
+            pre_loss: None,
+        }
+    }
+}
+
+impl Highspeed {
+    /// The fraction of the window given up on loss.
+    fn decrease(w: f64) -> f64 {
+        if w <= LOW_WINDOW {
+            0.5
+        } else {
+            (HIGH_DECREASE - 0.5) * (w.ln() - LOW_WINDOW.ln())
+                / (HIGH_WINDOW.ln() - LOW_WINDOW.ln())
+                + 0.5
+        }
+    }
+
+    /// How many packets the window grows by per round trip.
+    fn increase(w: f64) -> f64 {
+        if w <= LOW_WINDOW {
+            1.0
+        } else {
+            let b = Self::decrease(w);
+            let p = 0.078 / w.powf(1.2);
+            w * w * p * 2.0 * b / (2.0 - b)
+        }
+    }
+}
+
+impl CongestionControl for Highspeed {
+    fn cwnd(&self) -> f64 {
+        self.cwnd
+    }
+
+    fn on_ack(&mut self, ack: &AckEvent) {
+        for _ in 0..ack.acked {
+            if self.cwnd < self.ssthresh {
+                self.cwnd += self.weight;
+            } else {
+                // grows and shrinks like `weight` streams each with their share of the window
+                self.cwnd += Self::increase(self.cwnd / self.weight) * self.weight / self.cwnd;
+            }
+        }
+    }
+
+    fn on_loss(&mut self, _now: Instant) {
+        self.pre_loss = Some((self.cwnd, self.ssthresh));
+        self.cwnd = (self.cwnd * (1.0 - Self::decrease(self.cwnd / self.weight))).max(1.0);
+        self.ssthresh = self.cwnd;
+    }
+
+    fn on_spurious_loss(&mut self) {
+        if let Some((cwnd, ssthresh)) = self.pre_loss.take() {
+            self.cwnd = self.cwnd.max(cwnd);
+            self.ssthresh = ssthresh;
+        }
+    }
+
+    fn set_weight(&mut self, weight: f64) -> anyhow::Result<()> {
+        self.weight = weight;
+        Ok(())
+    }
+
+    fn set_cwnd(&mut self, cwnd: f64) {
+        self.cwnd = cwnd.max(1.0);
+    }
+}
@@ -0,0 +1,233 @@
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+use super::{AckEvent, CongestionControl};
+
+// the queuing delay aimed for; RFC 6817 allows up to 100 ms, but a lower target gets out of the way sooner
+const DEFAULT_TARGET: Duration = Duration::from_millis(60);
+// base delay is the minimum over this many one-minute buckets
+const BASE_HISTORY: usize = 10;
+const BASE_BUCKET: Duration = Duration::from_secs(60);
+// current delay is the minimum of this many recent samples, to filter out noise
+const CURRENT_FILTER: usize = 4;
+const MIN_CWND: f64 = 2.0;
+
+/// A LEDBAT-style (RFC 6817) scavenger: it measures queuing delay as the RTT above the lowest seen recently, grows while that is below a target and shrinks as it goes above, so it uses spare capacity but quickly gets out of the way of other traffic building queues on the same path.
+///
+/// Meant for bulk background transfers that should not slow down interactive ones.
+pub struct Ledbat {
+    cwnd: f64,
+    // grow exponentially at first, until queuing delay shows up or a packet is lost
+    slow_start: bool,
+    target: Duration,
+    weight: f64,
+    // per-minute minimum RTTs, newest last, and when the newest bucket started
+    base_delays: VecDeque<Duration>,
+    bucket_start: Option<Instant>,
+    current_delays: VecDeque<Duration>,
+    pre_loss: Option<f64>,
+}
+
+impl Default for Ledbat {
+    fn default() -> Self {
+        Self::with_target(DEFAULT_TARGET)
+    }
+}
+
+impl Ledbat {
+    /// Creates a LEDBAT controller aiming for the given queuing delay instead of the default of 60 ms. Lower targets yield to other traffic sooner.
+    pub fn with_target(target: Duration) -> Self {
+        Self {
+            cwnd: MIN_CWND,
+            slow_start: true,
+            target: target.max(Duration::from_millis(1)),
+            weight: 1.0,
+            base_delays: VecDeque::new(),
+            bucket_start: None,
+            current_delays: VecDeque::new(),
+            pre_loss: None,
+        }
+    }
+
+    fn update_base_delay(&mut self, now: Instant, rtt: Duration) {
+        match self.bucket_start {
+            Some(start) if now.saturating_duration_since(start) < BASE_BUCKET => {
+                let newest = self.base_delays.back_mut().expect("bucket started");
+                *newest = (*newest).min(rtt);
+            }
+            _ => {
+                self.bucket_start = Some(now);
+                self.base_delays.push_back(rtt);
+                if self.base_delays.len() > BASE_HISTORY {
+                    self.base_delays.pop_front();
+                }
+            }
+        }
+    }
+
+    fn queuing_delay(&self) -> Duration {
+        let base = self.base_delays.iter().min().copied().unwrap_or_default();
+        let current = self
+            .current_delays
+            .iter()
+            .min()
+            .copied()
+            .unwrap_or_default();
+        current.saturating_sub(base)
+    }
+}
+
+impl CongestionControl for Ledbat {
+    fn cwnd(&self) -> f64 {
+        self.cwnd
+    }
+
+    fn on_ack(&mut self, ack: &AckEvent) {
+        let Some(rtt) = ack.latest_rtt else {
+            return;
+        };
+        self.update_base_delay(ack.now, rtt);
+        self.current_delays.push_back(rtt);
+        if self.current_delays.len() > CURRENT_FILTER {
+            self.current_delays.pop_front();
+        }
+
+        let queuing_delay = self.queuing_delay();
+        let acked = ack.acked as f64;
+        if self.slow_start && queuing_delay < self.target / 2 {
+            self.cwnd += self.weight * acked;
+        } else {
+            self.slow_start = false;
+            let off_target = (self.target.as_secs_f64() - queuing_delay.as_secs_f64())
+                / self.target.as_secs_f64();
+            self.cwnd += self.weight * off_target * acked / self.cwnd;
+        }
+        self.cwnd = self.cwnd.max(MIN_CWND);
+    }
+
+    fn on_loss(&mut self, _now: Instant) {
+        self.pre_loss = Some(self.cwnd);
+        self.slow_start = false;
+        self.cwnd = (self.cwnd / 2.0).max(MIN_CWND);
+    }
+
+    fn on_spurious_loss(&mut self) {
+        if let Some(cwnd) = self.pre_loss.take() {
+            self.cwnd = self.cwnd.max(cwnd);
+        }
+    }
+
+    fn set_weight(&mut self, weight: f64) -> anyhow::Result<()> {
+        self.weight = weight;
+        Ok(())
+    }
+
+    fn set_cwnd(&mut self, cwnd: f64) {
+        self.cwnd = cwnd.max(MIN_CWND);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BASE_RTT: Duration = Duration::from_millis(50);
+
+    fn ack(now: Instant, rtt: Duration) -> AckEvent {
+        AckEvent {
+            now,
+            acked: 1,
+            inflight: 0,
+            min_rtt: BASE_RTT,
+            srtt: rtt,
+            latest_rtt: Some(rtt),
+            delivery_rate: 0.0,
+        }
+    }
+
+    /// How much the window grows over ten acks, all showing the given queuing delay, after slow start.
+    fn growth(queuing_delay: Duration) -> f64 {
+        let mut ledbat = Ledbat::default();
+        let now = Instant::now();
+        ledbat.on_ack(&ack(now, BASE_RTT));
+        ledbat.on_loss(now);
+        // once only samples with that delay are left in the filter
+        for _ in 0..CURRENT_FILTER {
+            ledbat.on_ack(&ack(now, BASE_RTT + queuing_delay));
+        }
+        ledbat.set_cwnd(20.0);
+        for _ in 0..10 {
+            ledbat.on_ack(&ack(now, BASE_RTT + queuing_delay));
+        }
+        ledbat.cwnd() - 20.0
+    }
+
+    #[test]
+    fn backs_off_as_queuing_delay_nears_the_target() {
+        let ms = Duration::from_millis;
+        let growths: Vec<f64> = [0, 20, 40, 60, 80, 120]
+            .into_iter()
+            .map(|delay| growth(ms(delay)))
+            .collect();
+        // the closer to the target, the slower the growth, and past it the window shrinks
+        assert!(
+            growths.windows(2).all(|pair| pair[0] > pair[1]),
+            "{growths:?}"
+        );
+        assert!(growths[2] > 0.0);
+        assert!(growths[3].abs() < 0.1);
+        assert!(growths[4] < 0.0);
+    }
+
+    #[test]
+    fn settles_at_the_target() {
+        // a bottleneck of 1000 packets per second, whose queue holds whatever exceeds the BDP of 50 packets
+        let mut ledbat = Ledbat::default();
+        let mut now = Instant::now();
+        for _ in 0..20_000 {
+            now += Duration::from_millis(1);
+            let queued = (ledbat.cwnd() - 50.0).max(0.0);
+            ledbat.on_ack(&ack(
+                now,
+                BASE_RTT + Duration::from_secs_f64(queued / 1000.0),
+            ));
+        }
+        assert!(!ledbat.slow_start);
+        let queuing_delay = ledbat.queuing_delay();
+        assert!(
+            queuing_delay > DEFAULT_TARGET * 3 / 4 && queuing_delay <= DEFAULT_TARGET * 5 / 4,
+            "{queuing_delay:?}"
+        );
+    }
+
+    #[test]
+    fn slow_start_ends_at_half_the_target() {
+        let mut ledbat = Ledbat::default();
+        let now = Instant::now();
+        for _ in 0..CURRENT_FILTER {
+            ledbat.on_ack(&ack(now, BASE_RTT));
+        }
+        assert!(ledbat.slow_start);
+        assert_eq!(ledbat.cwnd(), MIN_CWND + CURRENT_FILTER as f64);
+        // a single late sample is filtered out as noise
+        ledbat.on_ack(&ack(now, BASE_RTT + DEFAULT_TARGET));
+        assert!(ledbat.slow_start);
+        for _ in 0..CURRENT_FILTER {
+            ledbat.on_ack(&ack(now, BASE_RTT + DEFAULT_TARGET / 2));
+        }
+        assert!(!ledbat.slow_start);
+    }
+
+    #[test]
+    fn losses_halve_the_window() {
+        let mut ledbat = Ledbat::default();
+        ledbat.set_cwnd(40.0);
+        ledbat.on_loss(Instant::now());
+        assert_eq!(ledbat.cwnd(), 20.0);
+        assert!(!ledbat.slow_start);
+        ledbat.on_spurious_loss();
+        assert_eq!(ledbat.cwnd(), 40.0);
+    }
+}
@@ -0,0 +1,197 @@
+use std::{
+    fmt::Debug,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+mod bbr;
+mod bic;
+mod cubic;
+mod highspeed;
+mod ledbat;
+mod shared;
+
+pub use bbr::Bbr;
+pub use bic::Bic;
+pub use cubic::Cubic;
+pub use highspeed::Highspeed;
+pub use ledbat::Ledbat;
+pub(crate) use shared::SharedCongestion;
+
+/// What a congestion controller learns from an ack that acknowledged new data.
+#[derive(Clone, Copy, Debug)]
+pub struct AckEvent {
+    /// When the ack was processed.
+    pub now: Instant,
+    /// Packets newly acknowledged by the ack.
+    pub acked: usize,
+    /// Packets still in flight after the ack.
+    pub inflight: usize,
+    /// The lowest round-trip time seen recently.
+    pub min_rtt: Duration,
+    /// The smoothed round-trip time.
+    pub srtt: Duration,
+    /// The latest round-trip time sample, taken from the last acked packet that was not retransmitted.
+    pub latest_rtt: Option<Duration>,
+    /// The estimated delivery rate of the path, in packets per second.
+    pub delivery_rate: f64,
+}
+
+/// A congestion control algorithm, deciding how many packets a stream may have in flight and how fast it sends them.
+///
+/// Windows are counted in packets. A stream calls [CongestionControl::on_loss] at most once per recovery episode, when it first finds packets lost, rather than once per lost packet.
+pub trait CongestionControl: Send + 'static {
+    /// How many packets may be in flight.
+    fn cwnd(&self) -> f64;
+
+    /// How fast to send, in packets per second. Defaults to sending a whole window per minimum RTT.
+    fn pacing_rate(&self, min_rtt: Duration) -> f64 {
+        self.cwnd() / min_rtt.as_secs_f64()
+    }
+
+    /// Called for every ack that acknowledges new data.
+    fn on_ack(&mut self, ack: &AckEvent);
+
+    /// Called when packets are found lost, at the start of a recovery episode.
+    fn on_loss(&mut self, now: Instant);
+
+    /// Called when the last recovery episode turns out to have been caused only by spurious retransmissions, so that the reaction to it can be undone.
+    fn on_spurious_loss(&mut self) {}
+
+    /// Sets the weight of the stream relative to other streams sharing a bottleneck; a stream with weight `w` should get about `w` times the share of a normal one. Algorithms that cannot honour weights, which is the default, fail for any weight but 1.
+    fn set_weight(&mut self, weight: f64) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            weight == 1.0,
+            "congestion control algorithm cannot weigh streams"
+        );
+        Ok(())
+    }
+
+    /// Replaces the congestion window, e.g. with one guessed from what is known about the path, or the window of the algorithm being replaced.
+    fn set_cwnd(&mut self, cwnd: f64);
+}
+
+/// Which congestion control algorithm streams use.
+#[derive(Clone, Default)]
+pub enum CongestionAlgorithm {
+    /// Binary increase congestion control, the long-standing default.
+    #[default]
+    Bic,
+    /// CUBIC (RFC 8312).
+    Cubic,
+    /// HighSpeed TCP (RFC 3649).
+    Highspeed,
+    /// BBR, which paces at the measured bottleneck bandwidth and holds up much better than loss-based algorithms on lossy links.
+    Bbr,
+    /// A LEDBAT-style scavenger that backs off as soon as queuing delay builds up, for background transfers that should yield to everything else. See [Ledbat].
+    Ledbat,
+    /// An algorithm supplied by the application, built anew for every stream.
+    Custom(Arc<dyn Fn() -> Box<dyn CongestionControl> + Send + Sync + 'static>),
+}
+
+impl CongestionAlgorithm {
+    /// Uses a custom algorithm, calling the given function to build one for every stream.
+    pub fn custom(build: impl Fn() -> Box<dyn CongestionControl> + Send + Sync + 'static) -> Self {
+        Self::Custom(Arc::new(build))
+    }
+
+    /// Builds a controller for a new stream.
+    pub(crate) fn build(&self) -> Box<dyn CongestionControl> {
+        match self {
+            Self::Bic => Box::<Bic>::default(),
+            Self::Cubic => Box::<Cubic>::default(),
+            Self::Highspeed => Box::<Highspeed>::default(),
+            Self::Bbr => Box::<Bbr>::default(),
+            Self::Ledbat => Box::<Ledbat>::default(),
+            Self::Custom(build) => build(),
+        }
+    }
+
+    /// Fails if streams using this algorithm cannot be given the weight.
+    pub(crate) fn check_weight(&self, weight: f64) -> anyhow::Result<()> {
+        self.build().set_weight(weight)
+    }
+}
+
+impl Debug for CongestionAlgorithm {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Bic => write!(f, "Bic"),
+            Self::Cubic => write!(f, "Cubic"),
+            Self::Highspeed => write!(f, "Highspeed"),
+            Self::Bbr => write!(f, "Bbr"),
+            Self::Ledbat => write!(f, "Ledbat"),
+            Self::Custom(_) => write!(f, "Custom"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RTT: Duration = Duration::from_millis(50);
+    // packets in flight that fill the bottleneck, and that overflow its queue
+    const BDP: f64 = 100.0;
+    const CAPACITY: f64 = 200.0;
+
+    /// How many times the average window of a stream with the given weight is that of an unweighted one, both sending over the same bottleneck for a few thousand round trips and seeing the same losses whenever their windows overflow it.
+    fn weighted_share(algo: &CongestionAlgorithm, weight: f64) -> f64 {
+        let mut heavy = algo.build();
+        heavy.set_weight(weight).unwrap();
+        let mut light = algo.build();
+        let mut now = Instant::now();
+        let (mut heavy_sum, mut light_sum) = (0.0, 0.0);
+        for round in 0..3000 {
+            let total = heavy.cwnd() + light.cwnd();
+            let rtt = RTT.mul_f64((total / BDP).max(1.0));
+            now += rtt;
+            if total > CAPACITY {
+                heavy.on_loss(now);
+                light.on_loss(now);
+                continue;
+            }
+            // after the windows have settled
+            if round >= 1000 {
+                heavy_sum += heavy.cwnd();
+                light_sum += light.cwnd();
+            }
+            for cc in [&mut heavy, &mut light] {
+                let window = cc.cwnd() as usize;
+                for acked in 1..=window {
+                    cc.on_ack(&AckEvent {
+                        now,
+                        acked: 1,
+                        inflight: window - acked,
+                        min_rtt: RTT,
+                        srtt: rtt,
+                        latest_rtt: Some(rtt),
+                        delivery_rate: total.min(BDP) / RTT.as_secs_f64(),
+                    });
+                }
+            }
+        }
+        heavy_sum / light_sum
+    }
+
+    #[test]
+    fn weighted_streams_get_proportional_shares() {
+        for algo in [
+            CongestionAlgorithm::Bic,
+            CongestionAlgorithm::Cubic,
+            CongestionAlgorithm::Highspeed,
+            CongestionAlgorithm::Ledbat,
+        ] {
+            for weight in [0.5, 1.0, 2.0, 3.0] {
+                let share = weighted_share(&algo, weight);
+                assert!(
+                    (share / weight - 1.0).abs() < 0.1,
+                    "{algo:?} with weight {weight} got {share} times the share"
+                );
+            }
+        }
+        // BBR cannot be weighted
+        assert!(Bbr::default().set_weight(2.0).is_err());
+        assert!(Bbr::default().set_weight(1.0).is_ok());
+    }
+}
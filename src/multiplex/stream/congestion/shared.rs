@@ -0,0 +1,199 @@
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use ahash::AHashMap;
+use parking_lot::Mutex;
+
+use crate::frame::StreamId;
+
+use super::{AckEvent, CongestionControl};
+
+/// One congestion controller and pacer shared by several streams, so that they are together as aggressive on the bottleneck as a single stream, like the streams of HTTP/2 over one TCP connection, instead of each growing a window of its own. See [crate::Multiplex::set_shared_congestion].
+///
+/// Each member gets whatever the others leave of the shared window, but never less than an equal share among the members that have data in flight or waiting, so that a bulk stream cannot starve the rest. Members pace at the shared rate in proportion to their share of the window, and cannot be weighted.
+pub(crate) struct SharedCongestion {
+    inner: Mutex<Shared>,
+}
+
+struct Shared {
+    cc: Box<dyn CongestionControl>,
+    members: AHashMap<StreamId, Member>,
+    // until the first ack or loss, the window may still be seeded
+    fresh: bool,
+    // losses that members find before this belong to the same recovery episode, which backs off only once
+    recovery_until: Option<Instant>,
+    srtt: Duration,
+}
+
+#[derive(Default)]
+struct Member {
+    inflight: usize,
+    active: bool,
+    delivery_rate: f64,
+}
+
+impl Shared {
+    fn others_inflight(&self, id: StreamId) -> usize {
+        self.members
+            .iter()
+            .filter(|(member, _)| **member != id)
+            .map(|(_, member)| member.inflight)
+            .sum()
+    }
+
+    fn share(&self, id: StreamId) -> f64 {
+        let total = self.cc.cwnd();
+        let active = self
+            .members
+            .iter()
+            .filter(|(member, state)| state.active || **member == id)
+            .count();
+        (total - self.others_inflight(id) as f64).max(total / active as f64)
+    }
+}
+
+impl SharedCongestion {
+    pub fn new(cc: Box<dyn CongestionControl>) -> Arc<Self> {
+        Arc::new(Self {
+            inner: Mutex::new(Shared {
+                cc,
+                members: AHashMap::new(),
+                fresh: true,
+                recovery_until: None,
+                srtt: Duration::ZERO,
+            }),
+        })
+    }
+
+    /// Adds a stream, returning the controller it should use.
+    pub fn join(self: &Arc<Self>, id: StreamId) -> Box<dyn CongestionControl> {
+        self.inner.lock().members.insert(id, Member::default());
+        Box::new(SharedMember {
+            shared: self.clone(),
+            id,
+        })
+    }
+
+    /// Tells the other members how much a stream has in flight, and whether it has anything to send.
+    pub fn report(&self, id: StreamId, inflight: usize, active: bool) {
+        if let Some(member) = self.inner.lock().members.get_mut(&id) {
+            member.inflight = inflight;
+            member.active = active;
+        }
+    }
+}
+
+/// A stream's view of a [SharedCongestion]. The stream leaves it when this is dropped.
+struct SharedMember {
+    shared: Arc<SharedCongestion>,
+    id: StreamId,
+}
+
+impl Drop for SharedMember {
+    fn drop(&mut self) {
+        self.shared.inner.lock().members.remove(&self.id);
+    }
+}
+
+impl CongestionControl for SharedMember {
+    fn cwnd(&self) -> f64 {
+        self.shared.inner.lock().share(self.id)
+    }
+
+    fn pacing_rate(&self, min_rtt: Duration) -> f64 {
+        let shared = self.shared.inner.lock();
+        shared.cc.pacing_rate(min_rtt) * shared.share(self.id) / shared.cc.cwnd()
+    }
+
+    fn on_ack(&mut self, ack: &AckEvent) {
+        let mut shared = self.shared.inner.lock();
+        shared.fresh = false;
+        shared.srtt = ack.srtt;
+        if let Some(member) = shared.members.get_mut(&self.id) {
+            member.inflight = ack.inflight;
+            member.delivery_rate = ack.delivery_rate;
+        }
+        let ack = AckEvent {
+            inflight: shared.members.values().map(|member| member.inflight).sum(),
+            delivery_rate: shared
+                .members
+                .values()
+                .map(|member| member.delivery_rate)
+                .sum(),
+            ..*ack
+        };
+        shared.cc.on_ack(&ack);
+    }
+
+    fn on_loss(&mut self, now: Instant) {
+        let mut shared = self.shared.inner.lock();
+        shared.fresh = false;
+        if shared.recovery_until.is_some_and(|until| now < until) {
+            return;
+        }
+        shared.recovery_until = Some(now + shared.srtt);
+        shared.cc.on_loss(now);
+    }
+
+    fn on_spurious_loss(&mut self) {
+        self.shared.inner.lock().cc.on_spurious_loss();
+    }
+
+    fn set_cwnd(&mut self, cwnd: f64) {
+        let mut shared = self.shared.inner.lock();
+        if shared.fresh {
+            shared.cc.set_cwnd(cwnd);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::SharedCongestion;
+    use crate::{
+        frame::StreamId,
+        multiplex::stream::congestion::{AckEvent, Bic, CongestionControl},
+    };
+
+    #[test]
+    fn members_share_one_window() {
+        let mut bic = Bic::default();
+        bic.set_cwnd(20.0);
+        let shared = SharedCongestion::new(Box::new(bic));
+        let mut a = shared.join(StreamId(1));
+        let mut b = shared.join(StreamId(2));
+        // a alone has data, so it may use the whole window
+        shared.report(StreamId(1), 15, true);
+        assert_eq!(a.cwnd(), 20.0);
+        // but once b has data too, b gets half of it however much a has in flight, and a no more than b leaves
+        shared.report(StreamId(2), 0, true);
+        assert_eq!(b.cwnd(), 10.0);
+        shared.report(StreamId(2), 10, true);
+        assert_eq!(a.cwnd(), 10.0);
+
+        // both find the same loss, which backs off only once
+        let ack = AckEvent {
+            now: Instant::now(),
+            acked: 1,
+            inflight: 10,
+            min_rtt: Duration::from_millis(50),
+            srtt: Duration::from_millis(50),
+            latest_rtt: None,
+            delivery_rate: 100.0,
+        };
+        a.on_ack(&ack);
+        let before = shared.inner.lock().cc.cwnd();
+        a.on_loss(Instant::now());
+        b.on_loss(Instant::now());
+        let after = shared.inner.lock().cc.cwnd();
+        assert!((after - before * 0.85).abs() < 1e-9, "{before} -> {after}");
+
+        drop(b);
+        shared.report(StreamId(1), 0, true);
+        assert_eq!(a.cwnd(), after);
+    }
+}
@@ -0,0 +1,82 @@
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use bytes::Bytes;
+use futures_util::{Sink, Stream as FuturesStream};
+use smol::prelude::*;
+
+use super::Stream;
+
+/// The unreliable datagrams of a [Stream], as returned by [Stream::datagrams], for use with stream and sink combinators instead of looping over [Stream::recv_urel].
+///
+/// As a [futures_util::Stream], it yields the datagrams received, ending once [Stream::recv_urel] would fail because the stream closed. As a [futures_util::Sink], it sends datagrams with [Stream::send_urel], which never waits, so the sink is always ready; datagrams beyond the queue limit are dropped as the [crate::UrelPolicy] says. Closing the sink does not close the stream.
+///
+/// Like a clone of the stream, this keeps the stream open until it is dropped.
+pub struct Datagrams {
+    stream: Stream,
+    recv_future: Option<Pin<Box<dyn Future<Output = std::io::Result<Bytes>> + Send + 'static>>>,
+    ended: bool,
+}
+
+impl Datagrams {
+    pub(super) fn new(stream: Stream) -> Self {
+        Self {
+            stream,
+            recv_future: None,
+            ended: false,
+        }
+    }
+
+    /// Returns the stream these datagrams belong to.
+    pub fn get_ref(&self) -> &Stream {
+        &self.stream
+    }
+}
+
+impl FuturesStream for Datagrams {
+    type Item = Bytes;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if self.ended {
+            return Poll::Ready(None);
+        }
+        let mut recv_future = match self.recv_future.take() {
+            Some(future) => future,
+            None => Box::pin(self.stream.recv_urel_owned()),
+        };
+        match recv_future.poll(cx) {
+            Poll::Ready(Ok(dgram)) => Poll::Ready(Some(dgram)),
+            Poll::Ready(Err(_)) => {
+                self.ended = true;
+                Poll::Ready(None)
+            }
+            Poll::Pending => {
+                self.recv_future = Some(recv_future);
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl Sink<Bytes> for Datagrams {
+    type Error = std::io::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: Bytes) -> Result<(), Self::Error> {
+        self.stream.push_urel(item);
+        Ok(())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+}
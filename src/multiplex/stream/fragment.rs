@@ -0,0 +1,145 @@
+use ahash::AHashMap;
+use bytes::{BufMut, Bytes, BytesMut};
+
+use crate::frame::Seqno;
+
+/// Bytes in front of the data of every [super::RelKind::DataFrag] payload: the offset of the data within its segment and the length of the whole segment, as little-endian `u32`s.
+pub const FRAG_HEADER: usize = 8;
+
+/// The largest segment a fragment may claim to be part of, which bounds what the receiver buffers per segment.
+const MAX_SEGMENT: usize = 65536;
+
+/// How many segments may be partly received at once. Fragments of further segments are dropped, and retransmitted later.
+const MAX_PARTIAL: usize = 64;
+
+/// Splits the payload of a segment that is larger than `mss` into [super::RelKind::DataFrag] payloads that each fit it.
+pub fn split(payload: &Bytes, mss: usize) -> impl Iterator<Item = Bytes> + '_ {
+    let chunk = mss.saturating_sub(FRAG_HEADER).max(1);
+    (0..payload.len()).step_by(chunk).map(move |offset| {
+        let end = (offset + chunk).min(payload.len());
+        let mut frag = BytesMut::with_capacity(FRAG_HEADER + end - offset);
+        frag.put_u32_le(offset as u32);
+        frag.put_u32_le(payload.len() as u32);
+        frag.put_slice(&payload[offset..end]);
+        frag.freeze()
+    })
+}
+
+/// A fragment whose header is missing, or that does not fit the segment it claims to be part of.
+#[derive(Debug)]
+pub struct MalformedFragment;
+
+/// Puts segments that were split by [split] back together.
+#[derive(Default)]
+pub struct Reassembler {
+    partial: AHashMap<Seqno, Partial>,
+}
+
+struct Partial {
+    data: Vec<u8>,
+    // the byte ranges received so far, sorted and merged
+    received: Vec<(usize, usize)>,
+}
+
+impl Reassembler {
+    /// Adds a fragment of the segment with the given seqno. Returns the whole segment once every byte of it has arrived.
+    pub fn insert(
+        &mut self,
+        seqno: Seqno,
+        payload: &[u8],
+    ) -> Result<Option<Bytes>, MalformedFragment> {
+        if payload.len() < FRAG_HEADER {
+            return Err(MalformedFragment);
+        }
+        let offset = u32::from_le_bytes(payload[0..4].try_into().unwrap()) as usize;
+        let total = u32::from_le_bytes(payload[4..8].try_into().unwrap()) as usize;
+        let data = &payload[FRAG_HEADER..];
+        if total == 0 || total > MAX_SEGMENT || offset + data.len() > total {
+            return Err(MalformedFragment);
+        }
+        if !self.partial.contains_key(&seqno) && self.partial.len() >= MAX_PARTIAL {
+            return Ok(None);
+        }
+        let partial = self.partial.entry(seqno).or_insert_with(|| Partial {
+            data: vec![0; total],
+            received: vec![],
+        });
+        if partial.data.len() != total {
+            return Err(MalformedFragment);
+        }
+        partial.data[offset..offset + data.len()].copy_from_slice(data);
+        partial.received.push((offset, offset + data.len()));
+        partial.received.sort_unstable();
+        let mut merged: Vec<(usize, usize)> = Vec::with_capacity(partial.received.len());
+        for (start, end) in partial.received.drain(..) {
+            match merged.last_mut() {
+                Some((_, last_end)) if start <= *last_end => *last_end = (*last_end).max(end),
+                _ => merged.push((start, end)),
+            }
+        }
+        partial.received = merged;
+        if partial.received == [(0, total)] {
+            let partial = self.partial.remove(&seqno).unwrap();
+            return Ok(Some(partial.data.into()));
+        }
+        Ok(None)
+    }
+
+    /// Forgets the segments before `seqno`, which were delivered some other way.
+    pub fn forget_before(&mut self, seqno: Seqno) {
+        self.partial
+            .retain(|partial_seqno, _| *partial_seqno >= seqno);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split_and_reassemble() {
+        let payload: Bytes = (0..3000u32).map(|i| i as u8).collect::<Vec<u8>>().into();
+        let mut frags: Vec<Bytes> = split(&payload, 1000).collect();
+        assert_eq!(frags.len(), 4);
+        assert!(frags.iter().all(|frag| frag.len() <= 1000));
+        // in any order, and with a fragment cut at another size repeated
+        frags.reverse();
+        frags.extend(split(&payload, 700).take(2));
+        let mut reassembler = Reassembler::default();
+        let mut whole = None;
+        for frag in frags.iter() {
+            if let Some(segment) = reassembler.insert(Seqno(5), frag).unwrap() {
+                assert!(whole.is_none());
+                whole = Some(segment);
+            }
+        }
+        assert_eq!(whole.unwrap(), payload);
+        assert_eq!(reassembler.partial.len(), 1);
+        reassembler.forget_before(Seqno(6));
+        assert!(reassembler.partial.is_empty());
+    }
+
+    #[test]
+    fn malformed_fragments() {
+        let mut reassembler = Reassembler::default();
+        assert!(reassembler.insert(Seqno(0), &[1, 2, 3]).is_err());
+        let mut beyond = BytesMut::new();
+        beyond.put_u32_le(90);
+        beyond.put_u32_le(100);
+        beyond.put_slice(&[0; 20]);
+        assert!(reassembler.insert(Seqno(0), &beyond).is_err());
+        let mut huge = BytesMut::new();
+        huge.put_u32_le(0);
+        huge.put_u32_le(u32::MAX);
+        assert!(reassembler.insert(Seqno(0), &huge).is_err());
+        // a fragment that disagrees on how long the segment is
+        let payload = Bytes::from(vec![1; 100]);
+        let first = split(&payload, 50).next().unwrap();
+        assert!(reassembler.insert(Seqno(1), &first).unwrap().is_none());
+        let mut other = BytesMut::new();
+        other.put_u32_le(50);
+        other.put_u32_le(200);
+        other.put_slice(&[0; 10]);
+        assert!(reassembler.insert(Seqno(1), &other).is_err());
+    }
+}
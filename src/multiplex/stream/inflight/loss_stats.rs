@@ -0,0 +1,69 @@
+/// Number of power-of-two buckets in each loss histogram.
+pub const LOSS_BUCKETS: usize = 16;
+
+/// Histograms describing the pattern of packet loss, useful for telling apart random loss (e.g. wireless) from bursty, policer-induced loss.
+///
+/// Both histograms use power-of-two buckets: bucket `i` counts runs whose length in packets is within `[2^i, 2^(i+1))`, with the last bucket absorbing everything longer.
+#[derive(Clone, Debug, Default)]
+pub struct LossStats {
+    /// Lengths of runs of consecutive lost packets.
+    pub burst_lengths: [u64; LOSS_BUCKETS],
+    /// Lengths of runs of consecutive delivered packets between two losses.
+    pub loss_gaps: [u64; LOSS_BUCKETS],
+    /// Total packets delivered on the first try.
+    pub delivered: u64,
+    /// Total packets that needed at least one retransmission.
+    pub lost: u64,
+
+    run_lost: bool,
+    run_len: u64,
+    seen_loss: bool,
+}
+
+impl LossStats {
+    /// Records the fate of the next acknowledged packet.
+    pub(crate) fn record(&mut self, lost: bool) {
+        if self.run_len > 0 && lost != self.run_lost {
+            if self.run_lost {
+                self.burst_lengths[bucket(self.run_len)] += 1;
+            } else if self.seen_loss {
+                self.loss_gaps[bucket(self.run_len)] += 1;
+            }
+            self.run_len = 0;
+        }
+        if lost {
+            self.lost += 1;
+            self.seen_loss = true;
+        } else {
+            self.delivered += 1;
+        }
+        self.run_lost = lost;
+        self.run_len += 1;
+    }
+
+    /// Adds the finished runs and totals of another set of statistics into this one.
+    pub(crate) fn merge(&mut self, other: &LossStats) {
+        for (a, b) in self.burst_lengths.iter_mut().zip(other.burst_lengths.iter()) {
+            *a += b;
+        }
+        for (a, b) in self.loss_gaps.iter_mut().zip(other.loss_gaps.iter()) {
+            *a += b;
+        }
+        self.delivered += other.delivered;
+        self.lost += other.lost;
+    }
+
+    /// Fraction of packets that were lost at least once.
+    pub fn loss_rate(&self) -> f64 {
+        let total = self.delivered + self.lost;
+        if total == 0 {
+            0.0
+        } else {
+            self.lost as f64 / total as f64
+        }
+    }
+}
+
+fn bucket(run_len: u64) -> usize {
+    ((63 - run_len.leading_zeros()) as usize).min(LOSS_BUCKETS - 1)
+}
@@ -1,21 +1,39 @@
 use std::{
-    collections::{btree_map::Entry, BTreeMap},
+    collections::BTreeMap,
     time::{Duration, Instant},
 };
 
-use crate::frame::Seqno;
+use crate::{
+    frame::Seqno,
+    utilities::{runtime, timer_wheel::TimerWheel},
+};
 
 use self::rtt_calc::{BwCalculator, RttCalculator};
 
 use super::StreamMessage;
+use crate::multiplex::{
+    constants::DEFAULT_FAST_RETRANSMIT_THRESHOLD, routes::StreamRoutes, trace::proto_event,
+};
 
+mod loss_stats;
 mod rtt_calc;
+mod seqno_ring;
+
+pub use loss_stats::{LossStats, LOSS_BUCKETS};
+pub use seqno_ring::SeqnoRing;
+
+/// How many acked retransmissions to remember in case the receiver reports them as duplicates
+const MAX_RECENT_RETRANS: usize = 1024;
 
 #[derive(Debug, Clone)]
 /// An element of Inflight.
 pub struct InflightEntry {
     send_time: Instant,
+    last_send_time: Instant,
     retrans: u64,
+    // whether acks showed this packet to be lost, as opposed to it timing out
+    marked_lost: bool,
+    spurious_known: bool,
     payload: StreamMessage,
 
     retrans_time: Instant,
@@ -24,14 +42,41 @@ pub struct InflightEntry {
 
 /// A data structure that tracks in-flight packets.
 pub struct Inflight {
-    segments: BTreeMap<Seqno, InflightEntry>,
-    rtos: BTreeMap<Instant, Vec<Seqno>>,
+    segments: SeqnoRing<InflightEntry>,
+    rtos: TimerWheel<Seqno>,
 
     rtt: RttCalculator,
+    // the latest RTT sample, taken from the last acked packet that was not retransmitted
+    latest_rtt: Option<Duration>,
     bw: BwCalculator,
+    loss: LossStats,
 
     sent: u64,
     retrans: u64,
+    // retransmitted packets acked recently, with their original and last send times, in case the receiver reports a duplicate
+    recent_retrans: BTreeMap<Seqno, (Instant, Instant)>,
+    // when the retransmissions found to be spurious were sent
+    spurious_retrans: Vec<Instant>,
+    spurious: u64,
+    eifel_response: bool,
+    // an unacked packet is lost once a packet sent more than this many after it is acked
+    fast_retransmit_threshold: u64,
+    frto: Option<FrtoProbe>,
+    ack_serial: u64,
+    // the pipes that packets went out over, if the multiplex keeps track of them
+    routes: Option<StreamRoutes>,
+}
+
+/// A packet retransmitted after a timeout while we wait for acks to show whether the timeout was spurious.
+///
+/// As in F-RTO (RFC 5682), the timeout was spurious if the first ack after it acks the packet, and a later ack acks packets sent before the timeout that were not retransmitted. A retransmission filling a hole would have had them all acked at once, while an ack that does not cover the timed-out packet means it really was lost.
+struct FrtoProbe {
+    seqno: Seqno,
+    since: Instant,
+    // the last ack before the probe was sent, and the ack that acked the probe
+    start_ack: u64,
+    probe_acked_by: Option<u64>,
+    originals_acked: bool,
 }
 
 impl Inflight {
@@ -39,12 +84,22 @@ impl Inflight {
     pub fn new() -> Self {
         Inflight {
             segments: Default::default(),
-            rtos: Default::default(),
+            rtos: TimerWheel::new(),
             rtt: Default::default(),
+            latest_rtt: None,
             bw: Default::default(),
+            loss: Default::default(),
 
             sent: 0,
             retrans: 0,
+            recent_retrans: BTreeMap::new(),
+            spurious_retrans: vec![],
+            spurious: 0,
+            eifel_response: false,
+            fast_retransmit_threshold: DEFAULT_FAST_RETRANSMIT_THRESHOLD,
+            frto: None,
+            ack_serial: 0,
+            routes: None,
         }
     }
 
@@ -54,67 +109,132 @@ impl Inflight {
     }
 
     pub fn lost_at(&self, now: Instant) -> usize {
-        self.rtos
-            .iter()
-            .take_while(|(&retrans_time, _)| retrans_time <= now)
-            .map(|(_, seqnos)| seqnos.len())
-            .sum()
+        self.rtos.due(now).count()
+    }
+
+    /// The lowest sequence number that is still in flight
+    pub fn first_unacked(&self) -> Option<Seqno> {
+        self.segments.first()
     }
 
     /// Mark all inflight packets less than a certain sequence number as acknowledged.
     pub fn mark_acked_lt(&mut self, seqno: Seqno) -> usize {
-        let mut to_remove = vec![];
-        for (k, _) in self.segments.iter() {
-            if *k < seqno {
-                to_remove.push(*k);
-            } else {
-                // we can rely on iteration order
-                break;
-            }
-        }
-        let mut sum = 0;
-        for seqno in to_remove {
-            if self.mark_acked(seqno) {
-                sum += 1;
-            }
+        self.mark_acked_range(Seqno::ZERO, seqno)
+    }
+
+    /// Marks every inflight packet from `start` up to, but not including, `end` as acknowledged. Returns how many there actually were.
+    pub fn mark_acked_range(&mut self, start: Seqno, end: Seqno) -> usize {
+        if start >= end {
+            return 0;
         }
+        let acked: Vec<Seqno> = self.segments.seqnos_in(start, end).collect();
+        let sum = acked
+            .into_iter()
+            .filter(|seqno| self.remove_acked(*seqno))
+            .count();
+        self.detect_fast_retransmit(end - 1);
         sum
     }
 
-    /// Marks a particular inflight packet as acknowledged. Returns whether or not there was actually such an inflight packet.
-    pub fn mark_acked(&mut self, acked_seqno: Seqno) -> bool {
+    /// Handles the selective acks carried by an ack, returning how many packets they newly acknowledge.
+    pub fn on_selective_acks(
+        &mut self,
+        duplicates: impl Iterator<Item = Seqno>,
+        ranges: impl Iterator<Item = (Seqno, Seqno)>,
+    ) -> usize {
+        // these are already covered by the cumulative ack, so they report duplicates
+        for seqno in duplicates {
+            self.on_duplicate_reported(seqno);
+        }
+        ranges
+            .map(|(start, end)| self.mark_acked_range(start, end))
+            .sum()
+    }
+
+    /// Marks packets sent well before an acknowledged one, and not acknowledged themselves, as lost. Packets striped over several pipes overtake each other all the time, so among them, only packets acked over the same pipe show one lost.
+    fn detect_fast_retransmit(&mut self, acked_seqno: Seqno) {
         let mut to_remove = vec![];
-        let now_rto = Instant::now();
+        let now_rto = runtime::now();
+        let striped = self.routes.as_ref().filter(|routes| routes.is_striping());
         for (seqno, entry) in self.segments.iter_mut() {
-            if acked_seqno > seqno + 5 && entry.retrans == 0 && entry.retrans_time > now_rto {
-                log::debug!(
-                    "fast retransmit triggered, acked_seqno = {acked_seqno}; seqno = {seqno}"
-                );
+            let overtaken = match striped {
+                Some(_) if seqno >= acked_seqno => break,
+                Some(routes) => routes.overtaken(seqno),
+                None => acked_seqno > seqno + self.fast_retransmit_threshold,
+            };
+            if !overtaken {
+                if striped.is_none() {
+                    break;
+                }
+                continue;
+            }
+            // packets already found lost, or retransmitted, stay as they are, but do not hide the ones behind them
+            if entry.retrans == 0 && entry.retrans_time > now_rto {
+                proto_event!(FastRetransmit, seqno = seqno, acked = acked_seqno);
 
-                to_remove.push((entry.retrans_time, *seqno));
+                to_remove.push((entry.retrans_time, seqno));
                 entry.retrans_time = now_rto;
-                self.rtos.entry(now_rto).or_default().push(*seqno);
-            } else {
-                break;
+                entry.marked_lost = true;
             }
         }
 
-        for (a, b) in to_remove {
-            self.remove_rto(a, b)
+        for (retrans_time, seqno) in to_remove {
+            self.rtos.remove(retrans_time, seqno);
+            self.rtos.insert(now_rto, seqno);
         }
+    }
 
-        let now = Instant::now();
+    /// Marks a particular inflight packet as acknowledged. Returns whether or not there was actually such an inflight packet.
+    fn remove_acked(&mut self, acked_seqno: Seqno) -> bool {
+        let now = runtime::now();
 
-        if let Some(acked_seg) = self.segments.remove(&acked_seqno) {
+        if let Some(acked_seg) = self.segments.remove(acked_seqno) {
+            if let Some(frto) = self.frto.as_mut() {
+                if acked_seg.retrans == 0
+                    && acked_seg.send_time < frto.since
+                    && frto
+                        .probe_acked_by
+                        .is_some_and(|acked_by| acked_by < self.ack_serial)
+                {
+                    frto.originals_acked = true;
+                }
+            }
             // record RTT
             if acked_seg.retrans == 0 {
-                self.rtt
-                    .record_sample(now.saturating_duration_since(acked_seg.send_time));
+                let sample = now.saturating_duration_since(acked_seg.send_time);
+                self.rtt.record_sample(sample);
+                self.latest_rtt = Some(sample);
+            } else if acked_seg.spurious_known {
+                // already counted
+            } else if self.rtt.measured_min_rtt().is_some_and(|min_rtt| {
+                now.saturating_duration_since(acked_seg.last_send_time) < min_rtt
+            }) {
+                // an ack faster than any round trip must be for an earlier transmission, so the retransmission was spurious
+                self.record_spurious(acked_seg.send_time, acked_seg.last_send_time);
+                if let Some(frto) = self.frto.as_mut() {
+                    frto.originals_acked |= frto.seqno == acked_seqno;
+                }
+            } else {
+                if let Some(frto) = self.frto.as_mut() {
+                    if frto.seqno == acked_seqno {
+                        frto.probe_acked_by = Some(self.ack_serial);
+                    }
+                }
+                self.recent_retrans
+                    .insert(acked_seqno, (acked_seg.send_time, acked_seg.last_send_time));
+                while self.recent_retrans.len() > MAX_RECENT_RETRANS {
+                    self.recent_retrans.pop_first();
+                }
             }
             // record bandwidth
             self.bw.on_ack(acked_seg.delivered, acked_seg.send_time);
+            // record the loss pattern
+            self.loss.record(acked_seg.retrans > 0);
+            if let Some(routes) = &self.routes {
+                routes.on_acked(acked_seqno, acked_seg.retrans > 0);
+            }
             // remove from rtos
-            self.remove_rto(acked_seg.retrans_time, acked_seqno);
+            self.rtos.remove(acked_seg.retrans_time, acked_seqno);
 
             true
         } else {
@@ -125,13 +245,16 @@ impl Inflight {
     /// Inserts a packet to the inflight.
     pub fn insert(&mut self, msg: StreamMessage) {
         let seqno = msg.seqno();
-        let now = Instant::now();
+        let now = runtime::now();
         let rto_duration = self.rtt.rto();
         let rto = now + rto_duration;
         let prev = self.segments.insert(
             seqno,
             InflightEntry {
                 send_time: now,
+                last_send_time: now,
+                marked_lost: false,
+                spurious_known: false,
                 payload: msg,
                 retrans: 0,
                 retrans_time: rto,
@@ -141,69 +264,388 @@ impl Inflight {
         );
         assert!(prev.is_none());
         // we insert into RTOs.
-        self.rtos.entry(rto).or_default().push(seqno);
+        self.rtos.insert(rto, seqno);
         self.sent += 1;
     }
 
     /// Returns the retransmission time of the first possibly retransmitted packet, as well as its seqno. This skips all known-lost packets.
     pub fn first_rto(&self) -> Option<(Seqno, Instant)> {
-        self.rtos
-            .iter()
-            .next()
-            .map(|(instant, seqno)| (seqno[0], *instant))
+        self.rtos.first().map(|(instant, seqno)| (seqno, instant))
     }
 
     /// Retransmits a particular seqno
     pub fn retransmit(&mut self, seqno: Seqno) -> Option<StreamMessage> {
         let rto = self.rtt.rto();
         let (payload, old_retrans, new_retrans) = {
-            let entry = self.segments.get_mut(&seqno);
+            let entry = self.segments.get_mut(seqno);
             entry.map(|entry| {
                 let old_retrans = entry.retrans_time;
                 entry.retrans += 1;
+                entry.last_send_time = runtime::now();
 
                 entry.retrans_time =
-                    Instant::now() + rto.mul_f64(2.0f64.powi(entry.retrans as i32).min(60.0));
+                    runtime::now() + rto.mul_f64(2.0f64.powi(entry.retrans as i32).min(60.0));
 
                 (entry.payload.clone(), old_retrans, entry.retrans_time)
             })?
         };
         // eprintln!("retransmit {}", seqno);
-        self.remove_rto(old_retrans, seqno);
-        self.rtos.entry(new_retrans).or_default().push(seqno);
+        if let Some(routes) = &self.routes {
+            routes.on_lost(seqno);
+        }
+        self.rtos.remove(old_retrans, seqno);
+        self.rtos.insert(new_retrans, seqno);
         self.sent += 1;
         self.retrans += 1;
-        log::debug!(
-            "retransmission {:.2}% ({}/{})",
-            (self.retrans as f64) / (self.sent as f64) * 100.0,
-            self.retrans,
-            self.sent
-        );
         Some(payload)
     }
 
-    fn remove_rto(&mut self, retrans_time: Instant, seqno: Seqno) {
-        let rto_entry = self.rtos.entry(retrans_time);
-        if let Entry::Occupied(mut o) = rto_entry {
-            o.get_mut().retain(|v| *v != seqno);
-            if o.get().is_empty() {
-                o.remove();
+    /// Makes every packet in flight due for retransmission now, regardless of how far its timeout was backed off.
+    pub fn expedite_all(&mut self, now: Instant) {
+        for (seqno, entry) in self.segments.iter_mut() {
+            if entry.retrans_time > now {
+                self.rtos.remove(entry.retrans_time, seqno);
+                entry.retrans_time = now;
+                self.rtos.insert(now, seqno);
+            }
+        }
+    }
+
+    /// Handles the receiver reporting that it got a packet more than once. If we retransmitted that packet, the retransmission was spurious.
+    pub fn on_duplicate_reported(&mut self, seqno: Seqno) {
+        if let Some((send_time, last_send_time)) = self.recent_retrans.remove(&seqno) {
+            self.record_spurious(send_time, last_send_time);
+        }
+    }
+
+    fn record_spurious(&mut self, send_time: Instant, last_send_time: Instant) {
+        self.spurious += 1;
+        self.spurious_retrans.push(last_send_time);
+        if self.eifel_response {
+            // the original transmission took at least this long
+            self.rtt
+                .on_spurious_timeout(runtime::now().saturating_duration_since(send_time));
+        }
+    }
+
+    /// Whether a packet timed out, rather than being retransmitted before or shown to be lost by acks
+    pub fn timed_out_first(&self, seqno: Seqno) -> bool {
+        self.segments
+            .get(seqno)
+            .is_some_and(|entry| entry.retrans == 0 && !entry.marked_lost)
+    }
+
+    /// Whether acks failed to show anything within a retransmission timeout of the probe
+    pub fn frto_expired(&self, now: Instant) -> bool {
+        self.frto
+            .as_ref()
+            .is_some_and(|frto| frto.since + self.rtt.rto() <= now)
+    }
+
+    /// Starts watching acks to learn whether the timeout of the given packet, which was just retransmitted, was spurious
+    pub fn start_frto(&mut self, seqno: Seqno, now: Instant) {
+        self.frto = Some(FrtoProbe {
+            seqno,
+            since: now,
+            start_ack: self.ack_serial,
+            probe_acked_by: None,
+            originals_acked: false,
+        });
+    }
+
+    /// Must be called before processing each incoming ack
+    pub fn start_ack(&mut self) {
+        self.ack_serial += 1;
+    }
+
+    /// Returns whether the timeout being watched was spurious, or None if acks have not shown it yet
+    pub fn frto_outcome(&self) -> Option<bool> {
+        let frto = self.frto.as_ref()?;
+        if frto.originals_acked {
+            return Some(true);
+        }
+        if self.ack_serial == frto.start_ack {
+            return None;
+        }
+        if frto.probe_acked_by != Some(frto.start_ack + 1) {
+            // the first ack did not cover the probe
+            return Some(false);
+        }
+        let originals_left = self
+            .segments
+            .iter()
+            .map(|(_, entry)| entry)
+            .take_while(|entry| entry.send_time < frto.since)
+            .any(|entry| entry.retrans == 0);
+        if !originals_left || self.ack_serial > frto.start_ack + 1 {
+            // either nothing is left to tell, or a later ack did not ack what was sent before the timeout
+            Some(false)
+        } else {
+            None
+        }
+    }
+
+    /// Stops watching for a spurious timeout. If it was spurious, the probe is counted as a spurious retransmission, and packets that timed out along with it get fresh timers instead of being retransmitted.
+    pub fn end_frto(&mut self, spurious: bool, now: Instant) {
+        let Some(frto) = self.frto.take() else {
+            return;
+        };
+        if !spurious {
+            return;
+        }
+        if let Some(entry) = self.segments.get_mut(frto.seqno) {
+            entry.spurious_known = true;
+            let (send_time, last_send_time) = (entry.send_time, entry.last_send_time);
+            self.record_spurious(send_time, last_send_time);
+        } else if let Some((send_time, last_send_time)) = self.recent_retrans.remove(&frto.seqno) {
+            self.record_spurious(send_time, last_send_time);
+        }
+        let rto = now + self.rtt.rto();
+        let expired: Vec<(Instant, Seqno)> = self
+            .rtos
+            .due(now)
+            .filter(|(_, seqno)| self.timed_out_first(*seqno))
+            .collect();
+        for (time, seqno) in expired {
+            self.rtos.remove(time, seqno);
+            if let Some(entry) = self.segments.get_mut(seqno) {
+                entry.retrans_time = rto;
             }
+            self.rtos.insert(rto, seqno);
         }
     }
 
+    /// Number of retransmissions found to be spurious so far
+    pub fn spurious(&self) -> u64 {
+        self.spurious
+    }
+
+    /// Takes the times at which the retransmissions found to be spurious since the last call were sent
+    pub fn take_spurious_retrans(&mut self) -> Vec<Instant> {
+        std::mem::take(&mut self.spurious_retrans)
+    }
+
+    /// Sets whether spurious retransmissions make the RTO more conservative
+    pub fn set_eifel_response(&mut self, enabled: bool) {
+        self.eifel_response = enabled;
+    }
+
+    /// Reports acks and losses from now on to the pipes that packets went out over
+    pub fn set_routes(&mut self, routes: StreamRoutes) {
+        self.routes = Some(routes);
+    }
+
+    /// The pipes that packets went out over, if the multiplex keeps track of them
+    pub fn routes(&self) -> Option<&StreamRoutes> {
+        self.routes.as_ref()
+    }
+
+    pub fn routes_mut(&mut self) -> Option<&mut StreamRoutes> {
+        self.routes.as_mut()
+    }
+
+    /// Sets how many packets sent after an unacked one must be acked before it counts as lost
+    pub fn set_fast_retransmit_threshold(&mut self, threshold: u64) {
+        self.fast_retransmit_threshold = threshold;
+    }
+
     /// The total bdp of the link, in packets
     pub fn bdp(&self) -> usize {
         (self.bw.delivery_rate() * self.rtt.min_rtt().as_secs_f64()) as usize
     }
 
+    /// Seeds the RTT estimates before any sample has been taken
+    pub fn seed_rtt(&mut self, rtt: Duration, rtt_var: Duration) {
+        self.rtt.seed(rtt, rtt_var)
+    }
+
     /// Minimum RTT
     pub fn min_rtt(&self) -> Duration {
         self.rtt.min_rtt()
     }
 
+    /// Minimum RTT, if it has actually been measured
+    pub fn measured_min_rtt(&self) -> Option<Duration> {
+        self.rtt.measured_min_rtt()
+    }
+
+    /// Retransmission timeout
+    pub fn rto(&self) -> Duration {
+        self.rtt.rto()
+    }
+
+    /// Smoothed RTT
+    pub fn srtt(&self) -> Duration {
+        self.rtt.srtt()
+    }
+
+    /// The latest RTT sample, if any
+    pub fn latest_rtt(&self) -> Option<Duration> {
+        self.latest_rtt
+    }
+
+    /// Checks that every packet in flight has exactly one retransmission timer, and was actually sent, and that the bookkeeping around them stays bounded.
+    #[cfg(all(test, feature = "soak"))]
+    pub fn check_invariants(&self, next_seqno: Seqno) -> anyhow::Result<()> {
+        let timers = self.rtos.check_invariants()?;
+        anyhow::ensure!(
+            timers == self.segments.len(),
+            "{timers} retransmission timers for {} packets in flight",
+            self.segments.len()
+        );
+        for (seqno, entry) in self.segments.iter() {
+            anyhow::ensure!(seqno < next_seqno, "{seqno} in flight but never sent");
+            anyhow::ensure!(
+                self.rtos.contains(entry.retrans_time, seqno),
+                "{seqno} in flight without a retransmission timer"
+            );
+        }
+        let lost = self.lost_at(runtime::now());
+        anyhow::ensure!(
+            lost <= self.inflight(),
+            "{lost} lost of {} in flight",
+            self.inflight()
+        );
+        anyhow::ensure!(self.retrans <= self.sent, "more retransmitted than sent");
+        anyhow::ensure!(self.recent_retrans.len() <= MAX_RECENT_RETRANS);
+        Ok(())
+    }
+
+    /// Statistics about the pattern of losses seen so far
+    pub fn loss_stats(&self) -> &LossStats {
+        &self.loss
+    }
+
     /// The estimated delivery rate of the link
     pub fn delivery_rate(&self) -> f64 {
         self.bw.delivery_rate()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use stdcode::StdcodeSerializeExt;
+
+    use super::*;
+    use crate::{
+        frame::StreamId,
+        multiplex::stream::{
+            sack::{encode_compact, CompactSack, SackRanges},
+            RelKind,
+        },
+    };
+
+    fn inflight_with(seqnos: impl Iterator<Item = u64>) -> Inflight {
+        let mut inflight = Inflight::new();
+        for seqno in seqnos {
+            inflight.insert(StreamMessage::Reliable {
+                kind: RelKind::Data,
+                stream_id: StreamId(0),
+                seqno: Seqno(seqno),
+                payload: Bytes::new(),
+            });
+        }
+        inflight
+    }
+
+    fn unacked(inflight: &Inflight) -> Vec<u64> {
+        inflight.segments.iter().map(|(seqno, _)| seqno.0).collect()
+    }
+
+    #[test]
+    fn selective_acks_round_trip() {
+        let lowest_unseen = Seqno(2);
+        // 0 arrived twice
+        let received = [13, 3, 9, 4, 12, 5, 0, 4].map(Seqno).to_vec();
+        let ranges: SackRanges =
+            stdcode::deserialize(&SackRanges::new(lowest_unseen, received.clone()).stdcode())
+                .unwrap();
+        let compact = encode_compact(lowest_unseen, &mut received.clone());
+        let compact = CompactSack::parse(&compact).unwrap();
+        let expected = [(3, 6), (9, 10), (12, 14)].map(|(start, end)| (Seqno(start), Seqno(end)));
+        assert!(ranges.ranges(lowest_unseen).eq(expected));
+        assert!(compact.ranges(lowest_unseen).eq(expected));
+        assert_eq!(ranges.duplicates(), [Seqno(0)]);
+        assert!(compact.duplicates(lowest_unseen).eq([Seqno(0)]));
+
+        // both encodings ack the same packets
+        for (duplicates, ranges) in [
+            (
+                ranges.duplicates().to_vec(),
+                ranges.ranges(lowest_unseen).collect::<Vec<_>>(),
+            ),
+            (
+                compact.duplicates(lowest_unseen).collect(),
+                compact.ranges(lowest_unseen).collect(),
+            ),
+        ] {
+            let mut inflight = inflight_with(0..15);
+            assert_eq!(inflight.mark_acked_lt(lowest_unseen), 2);
+            assert_eq!(
+                inflight.on_selective_acks(duplicates.into_iter(), ranges.into_iter()),
+                6
+            );
+            assert_eq!(unacked(&inflight), [2, 6, 7, 8, 10, 11, 14]);
+        }
+    }
+
+    #[test]
+    fn overlapping_and_out_of_window_ranges() {
+        let mut inflight = inflight_with(10..20);
+        let ranges = [
+            (12, 15),
+            // overlapping the one before, and each packet counts once
+            (13, 17),
+            // partly below the window
+            (5, 11),
+            // partly past what was sent, and entirely past it
+            (18, 40),
+            (30, 50),
+            // empty, and backwards
+            (16, 16),
+            (19, 18),
+        ]
+        .map(|(start, end)| (Seqno(start), Seqno(end)));
+        assert_eq!(
+            inflight.on_selective_acks(std::iter::empty(), ranges.into_iter()),
+            8
+        );
+        assert_eq!(unacked(&inflight), [11, 17]);
+        assert_eq!(
+            inflight.on_selective_acks(std::iter::empty(), ranges.into_iter()),
+            0
+        );
+        assert_eq!(inflight.mark_acked_range(Seqno(0), Seqno(10)), 0);
+        assert_eq!(inflight.inflight(), 2);
+    }
+
+    #[test]
+    fn fast_retransmit_detection() {
+        let mut inflight = inflight_with(0..12);
+        let now = Instant::now();
+        assert_eq!(inflight.lost_at(now), 0);
+
+        // more than the threshold of packets after them were acked, so 0 and 1 are lost; 2 may just be late
+        inflight.mark_acked_range(Seqno(7), Seqno(8));
+        let now = Instant::now();
+        assert_eq!(inflight.lost_at(now), 2);
+        assert_eq!(inflight.first_rto().map(|(seqno, _)| seqno), Some(Seqno(0)));
+        assert!(!inflight.timed_out_first(Seqno(1)));
+        assert!(inflight.timed_out_first(Seqno(2)));
+
+        // one of them is retransmitted, which does not keep later acks from showing more packets lost
+        inflight.retransmit(Seqno(0)).unwrap();
+        inflight.mark_acked_range(Seqno(9), Seqno(10));
+        let now = Instant::now();
+        assert_eq!(inflight.lost_at(now), 3);
+        assert!(!inflight.timed_out_first(Seqno(3)));
+        assert!(inflight.timed_out_first(Seqno(4)));
+
+        // a higher threshold needs more packets acked after a lost one
+        inflight.set_fast_retransmit_threshold(10);
+        inflight.mark_acked_range(Seqno(11), Seqno(12));
+        assert!(inflight.timed_out_first(Seqno(4)));
+        assert_eq!(inflight.lost_at(Instant::now()), 3);
+    }
+}
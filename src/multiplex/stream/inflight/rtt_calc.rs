@@ -1,11 +1,16 @@
 use std::time::{Duration, Instant};
 
+use crate::utilities::runtime;
+
 pub struct RttCalculator {
     estimated_rtt: Duration,
     dev_rtt: Duration,
     min_rtt: Duration,
     min_rtt_time: Instant,
     rtt_time: Instant,
+    // whether min_rtt is a guess that the first sample should replace
+    min_rtt_is_hint: bool,
+    sampled: bool,
 }
 
 impl Default for RttCalculator {
@@ -14,23 +19,37 @@ impl Default for RttCalculator {
             estimated_rtt: Duration::from_secs(1),
             dev_rtt: Duration::from_secs(0),
             min_rtt: Duration::from_secs(1),
-            min_rtt_time: Instant::now(),
-            rtt_time: Instant::now(),
+            min_rtt_time: runtime::now(),
+            rtt_time: runtime::now(),
+            min_rtt_is_hint: false,
+            sampled: false,
         }
     }
 }
 
 impl RttCalculator {
+    /// Starts from the given guesses instead of the defaults. The minimum RTT is replaced by the first real sample, even if that sample is larger.
+    pub fn seed(&mut self, rtt: Duration, rtt_var: Duration) {
+        self.estimated_rtt = rtt;
+        self.dev_rtt = rtt_var;
+        self.min_rtt = rtt;
+        self.min_rtt_is_hint = true;
+    }
+
     pub fn record_sample(&mut self, sample: Duration) {
         let alpha: f64 = 0.125;
         let beta: f64 = 0.25;
-        let now = Instant::now();
+        let now = runtime::now();
+        self.sampled = true;
 
         // Update minimum RTT
-        if sample < self.min_rtt || now.saturating_duration_since(self.min_rtt_time).as_secs() > 30
+        if self.min_rtt_is_hint
+            || sample < self.min_rtt
+            || now.saturating_duration_since(self.min_rtt_time).as_secs() > 30
         {
             self.min_rtt = sample;
             self.min_rtt_time = now;
+            self.min_rtt_is_hint = false;
         }
         if now.saturating_duration_since(self.rtt_time) > self.estimated_rtt {
             // Update EstimatedRTT and DevRTT
@@ -45,6 +64,13 @@ impl RttCalculator {
         }
     }
 
+    /// Adapts to a retransmission timeout that turned out to be spurious, given the RTT of the original transmission, as in the Eifel response algorithm (RFC 4015).
+    pub fn on_spurious_timeout(&mut self, original_rtt: Duration) {
+        self.estimated_rtt = self.estimated_rtt.max(original_rtt);
+        self.dev_rtt = self.dev_rtt.max(original_rtt / 2);
+        self.rtt_time = runtime::now();
+    }
+
     pub fn rto(&self) -> Duration {
         (self.estimated_rtt + Duration::from_secs_f64(4.0 * self.dev_rtt.as_secs_f64()))
             + Duration::from_millis(250)
@@ -53,6 +79,15 @@ impl RttCalculator {
     pub fn min_rtt(&self) -> Duration {
         self.min_rtt
     }
+
+    pub fn srtt(&self) -> Duration {
+        self.estimated_rtt
+    }
+
+    /// The minimum RTT, if it has actually been measured rather than guessed.
+    pub fn measured_min_rtt(&self) -> Option<Duration> {
+        self.sampled.then_some(self.min_rtt)
+    }
 }
 
 pub struct BwCalculator {
@@ -68,9 +103,9 @@ impl Default for BwCalculator {
     fn default() -> Self {
         Self {
             delivered: 0,
-            delivered_time: Instant::now(),
+            delivered_time: runtime::now(),
             max_speed: 0.0,
-            max_speed_time: Instant::now(),
+            max_speed_time: runtime::now(),
         }
     }
 }
@@ -78,7 +113,7 @@ impl Default for BwCalculator {
 impl BwCalculator {
     /// On ack
     pub fn on_ack(&mut self, packet_delivered: u64, packet_delivered_time: Instant) {
-        let now = Instant::now();
+        let now = runtime::now();
         self.delivered += 1;
         self.delivered_time = now;
         let delivery_rate = (self.delivered - packet_delivered) as f64
@@ -103,6 +138,7 @@ impl BwCalculator {
     }
 
     /// Gets the current delivered time
+    #[allow(dead_code)]
     pub fn delivered_time(&self) -> Instant {
         self.delivered_time
     }
@@ -0,0 +1,217 @@
+use std::collections::VecDeque;
+
+use crate::frame::Seqno;
+
+/// A map from sequence numbers to values, stored as a ring buffer indexed by sequence number. Lookups, insertions and removals take constant time, as long as the sequence numbers in use at any time are close together, as those of packets in flight are.
+pub struct SeqnoRing<T> {
+    // the sequence number of the front slot; when nonempty, the front and back slots are always occupied
+    base: Seqno,
+    slots: VecDeque<Option<T>>,
+    len: usize,
+}
+
+impl<T> Default for SeqnoRing<T> {
+    fn default() -> Self {
+        Self {
+            base: Seqno::ZERO,
+            slots: VecDeque::new(),
+            len: 0,
+        }
+    }
+}
+
+impl<T> SeqnoRing<T> {
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    // only public for the benchmarks
+    #[cfg(feature = "bench")]
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// The lowest sequence number present.
+    pub fn first(&self) -> Option<Seqno> {
+        (self.len > 0).then_some(self.base)
+    }
+
+    fn index(&self, seqno: Seqno) -> Option<usize> {
+        let index = usize::try_from(seqno.checked_since(self.base)?).ok()?;
+        (index < self.slots.len()).then_some(index)
+    }
+
+    pub fn get(&self, seqno: Seqno) -> Option<&T> {
+        self.slots.get(self.index(seqno)?)?.as_ref()
+    }
+
+    pub fn get_mut(&mut self, seqno: Seqno) -> Option<&mut T> {
+        let index = self.index(seqno)?;
+        self.slots.get_mut(index)?.as_mut()
+    }
+
+    /// Inserts a value, returning the one previously at that sequence number, if any.
+    pub fn insert(&mut self, seqno: Seqno, value: T) -> Option<T> {
+        if self.slots.is_empty() {
+            self.base = seqno;
+        }
+        while seqno < self.base {
+            self.slots.push_front(None);
+            self.base -= 1;
+        }
+        let index = seqno.since(self.base) as usize;
+        if index >= self.slots.len() {
+            self.slots.resize_with(index + 1, || None);
+        }
+        let prev = self.slots[index].replace(value);
+        if prev.is_none() {
+            self.len += 1;
+        }
+        prev
+    }
+
+    pub fn remove(&mut self, seqno: Seqno) -> Option<T> {
+        let index = self.index(seqno)?;
+        let value = self.slots[index].take()?;
+        self.len -= 1;
+        // keep the ends occupied
+        while matches!(self.slots.front(), Some(None)) {
+            self.slots.pop_front();
+            self.base += 1;
+        }
+        while matches!(self.slots.back(), Some(None)) {
+            self.slots.pop_back();
+        }
+        Some(value)
+    }
+
+    /// Iterates over the values present, in order of sequence number.
+    pub fn iter(&self) -> impl Iterator<Item = (Seqno, &T)> + '_ {
+        let base = self.base;
+        self.slots
+            .iter()
+            .enumerate()
+            .filter_map(move |(i, slot)| Some((base + i as u64, slot.as_ref()?)))
+    }
+
+    /// Iterates mutably over the values present, in order of sequence number.
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (Seqno, &mut T)> + '_ {
+        let base = self.base;
+        self.slots
+            .iter_mut()
+            .enumerate()
+            .filter_map(move |(i, slot)| Some((base + i as u64, slot.as_mut()?)))
+    }
+
+    /// The sequence numbers present from `start` up to, but not including, `end`.
+    pub fn seqnos_in(&self, start: Seqno, end: Seqno) -> impl Iterator<Item = Seqno> + '_ {
+        let from = start.since(self.base).min(self.slots.len() as u64) as usize;
+        let to = end.since(self.base).min(self.slots.len() as u64) as usize;
+        let base = self.base;
+        self.slots
+            .range(from..to.max(from))
+            .enumerate()
+            .filter(|(_, slot)| slot.is_some())
+            .map(move |(i, _)| base + (from + i) as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn seqnos<T>(ring: &SeqnoRing<T>) -> Vec<u64> {
+        ring.iter().map(|(seqno, _)| seqno.0).collect()
+    }
+
+    #[test]
+    fn sliding_window_wraps_around() {
+        let mut ring = SeqnoRing::default();
+        // the window slides far past its size, so the slots wrap around many times
+        for seqno in 0..10_000u64 {
+            assert!(ring.insert(Seqno(seqno), seqno).is_none());
+            if seqno >= 16 {
+                assert_eq!(ring.remove(Seqno(seqno - 16)), Some(seqno - 16));
+            }
+            assert!(ring.slots.len() <= 17);
+        }
+        assert_eq!(ring.len(), 16);
+        assert_eq!(ring.first(), Some(Seqno(9984)));
+        assert_eq!(seqnos(&ring), (9984..10_000).collect::<Vec<_>>());
+
+        // the end of the seqno space, where seqnos saturate rather than wrap
+        let mut ring = SeqnoRing::default();
+        ring.insert(Seqno::MAX, 'b');
+        ring.insert(Seqno::MAX - 2, 'a');
+        assert_eq!(ring.first(), Some(Seqno::MAX - 2));
+        assert_eq!(ring.get(Seqno::MAX), Some(&'b'));
+        assert_eq!(
+            ring.seqnos_in(Seqno::ZERO, Seqno::MAX).collect::<Vec<_>>(),
+            [Seqno::MAX - 2]
+        );
+    }
+
+    #[test]
+    fn out_of_window_seqnos() {
+        let mut ring = SeqnoRing::default();
+        for seqno in 100..110 {
+            ring.insert(Seqno(seqno), seqno);
+        }
+        // before the front and past the back, nothing is found, and nothing changes
+        for seqno in [0, 99, 110, 1_000_000, u64::MAX] {
+            assert_eq!(ring.get(Seqno(seqno)), None);
+            assert_eq!(ring.get_mut(Seqno(seqno)), None);
+            assert_eq!(ring.remove(Seqno(seqno)), None);
+        }
+        assert_eq!(ring.len(), 10);
+        assert_eq!(ring.slots.len(), 10);
+        assert_eq!(ring.seqnos_in(Seqno(0), Seqno(100)).count(), 0);
+        assert_eq!(ring.seqnos_in(Seqno(110), Seqno(200)).count(), 0);
+        assert_eq!(ring.seqnos_in(Seqno(105), Seqno(103)).count(), 0);
+
+        // inserting before the front moves the front back
+        assert_eq!(ring.insert(Seqno(95), 95), None);
+        assert_eq!(ring.first(), Some(Seqno(95)));
+        assert_eq!(ring.len(), 11);
+        assert_eq!(ring.insert(Seqno(95), 0), Some(95));
+        assert_eq!(ring.len(), 11);
+        *ring.get_mut(Seqno(95)).unwrap() += 1;
+        assert_eq!(ring.get(Seqno(95)), Some(&1));
+    }
+
+    #[test]
+    fn removal_from_the_middle() {
+        let mut ring = SeqnoRing::default();
+        for seqno in 0..8 {
+            ring.insert(Seqno(seqno), seqno);
+        }
+        assert_eq!(ring.remove(Seqno(3)), Some(3));
+        assert_eq!(ring.remove(Seqno(3)), None);
+        assert_eq!(ring.remove(Seqno(5)), Some(5));
+        // the holes stay until the front reaches them
+        assert_eq!(ring.len(), 6);
+        assert_eq!(ring.first(), Some(Seqno(0)));
+        assert_eq!(seqnos(&ring), [0, 1, 2, 4, 6, 7]);
+        assert_eq!(
+            ring.seqnos_in(Seqno(2), Seqno(7)).collect::<Vec<_>>(),
+            [Seqno(2), Seqno(4), Seqno(6)]
+        );
+        for (_, value) in ring.iter_mut() {
+            *value *= 10;
+        }
+        assert_eq!(ring.get(Seqno(6)), Some(&60));
+
+        // removing the front skips past the holes behind it, and removing the back those before it
+        for seqno in 0..3 {
+            ring.remove(Seqno(seqno));
+        }
+        assert_eq!(ring.first(), Some(Seqno(4)));
+        ring.remove(Seqno(7));
+        ring.remove(Seqno(6));
+        assert_eq!(ring.slots.len(), 1);
+        ring.remove(Seqno(4));
+        assert_eq!(ring.len(), 0);
+        assert_eq!(ring.first(), None);
+        assert!(ring.slots.is_empty());
+    }
+}
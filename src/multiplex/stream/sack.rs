@@ -0,0 +1,156 @@
+use bytes::{BufMut, Bytes, BytesMut};
+use serde::{Deserialize, Serialize};
+
+use crate::frame::Seqno;
+
+/// The payload of a [super::RelKind::DataAckRanges]: the packets received above the cumulative ack, as contiguous ranges, plus duplicates received below it.
+///
+/// Each range is encoded as its distance from the end of the previous one (or from the cumulative ack, for the first) and its length, so that a long run of received packets costs a couple of bytes instead of a few per packet.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct SackRanges {
+    ranges: Vec<(u64, u64)>,
+    duplicates: Vec<Seqno>,
+}
+
+impl SackRanges {
+    /// Collects selective acks above the cumulative ack `lowest_unseen` into ranges; those below it are duplicates.
+    pub fn new(lowest_unseen: Seqno, mut seqnos: Vec<Seqno>) -> Self {
+        seqnos.sort_unstable();
+        seqnos.dedup();
+        let split = seqnos.partition_point(|seqno| *seqno < lowest_unseen);
+        let duplicates = seqnos[..split].to_vec();
+        let mut ranges: Vec<(u64, u64)> = vec![];
+        let mut prev_end = lowest_unseen;
+        for seqno in seqnos[split..].iter().copied() {
+            match ranges.last_mut() {
+                Some((_, len)) if seqno == prev_end => *len += 1,
+                _ => ranges.push((seqno.since(prev_end), 1)),
+            }
+            prev_end = seqno + 1;
+        }
+        Self { ranges, duplicates }
+    }
+
+    /// The acknowledged ranges as `start..end` pairs, in ascending order.
+    pub fn ranges(&self, lowest_unseen: Seqno) -> impl Iterator<Item = (Seqno, Seqno)> + '_ {
+        let mut prev_end = lowest_unseen;
+        self.ranges.iter().map(move |(gap, len)| {
+            let start = prev_end + *gap;
+            prev_end = start + *len;
+            (start, prev_end)
+        })
+    }
+
+    /// Packets received again below the cumulative ack.
+    pub fn duplicates(&self) -> &[Seqno] {
+        &self.duplicates
+    }
+}
+
+/// Encodes the payload of a [super::RelKind::DataAckCompact]: the same information as [SackRanges], laid out so that it is written and read in place, without serde or intermediate allocations.
+///
+/// The layout is the number of ranges and of duplicates, as little-endian `u16`s, then for each range, its distance from the end of the previous one (or from the cumulative ack, for the first) and its length, then for each duplicate, its distance below the cumulative ack, all as little-endian `u32`s. Whatever does not fit is left out, which only delays acking it.
+pub fn encode_compact(lowest_unseen: Seqno, seqnos: &mut Vec<Seqno>) -> Bytes {
+    seqnos.sort_unstable();
+    seqnos.dedup();
+    let split = seqnos.partition_point(|seqno| *seqno < lowest_unseen);
+    let (duplicates, above) = seqnos.split_at(split);
+    let range_count = above
+        .windows(2)
+        .filter(|pair| pair[1] != pair[0] + 1)
+        .count()
+        + usize::from(!above.is_empty());
+    let range_count = range_count.min(u16::MAX as usize);
+    let duplicate_count = duplicates.len().min(u16::MAX as usize);
+    let mut payload = BytesMut::with_capacity(4 + range_count * 8 + duplicate_count * 4);
+    payload.put_u16_le(range_count as u16);
+    payload.put_u16_le(duplicate_count as u16);
+    let mut written = 0;
+    let mut prev_end = lowest_unseen;
+    let mut rest = above;
+    while let Some(&start) = rest.first() {
+        if written == range_count {
+            break;
+        }
+        let len = rest
+            .iter()
+            .zip(start.0..)
+            .take_while(|(seqno, expected)| seqno.0 == *expected)
+            .count();
+        payload.put_u32_le(u32::try_from(start.since(prev_end)).unwrap_or(u32::MAX));
+        payload.put_u32_le(u32::try_from(len).unwrap_or(u32::MAX));
+        written += 1;
+        prev_end = start + len as u64;
+        rest = &rest[len..];
+    }
+    for seqno in duplicates.iter().rev().take(duplicate_count) {
+        payload.put_u32_le(u32::try_from(lowest_unseen.since(*seqno)).unwrap_or(u32::MAX));
+    }
+    payload.freeze()
+}
+
+/// A [super::RelKind::DataAckCompact] payload, read in place. See [encode_compact] for the layout.
+pub struct CompactSack<'a> {
+    ranges: &'a [u8],
+    duplicates: &'a [u8],
+}
+
+impl<'a> CompactSack<'a> {
+    /// Checks that a payload is laid out correctly.
+    pub fn parse(payload: &'a [u8]) -> Option<Self> {
+        let range_count = u16::from_le_bytes(payload.get(0..2)?.try_into().ok()?) as usize;
+        let duplicate_count = u16::from_le_bytes(payload.get(2..4)?.try_into().ok()?) as usize;
+        let (ranges, duplicates) = payload[4..].split_at_checked(range_count * 8)?;
+        (duplicates.len() == duplicate_count * 4).then_some(Self { ranges, duplicates })
+    }
+
+    /// The acknowledged ranges as `start..end` pairs, in ascending order.
+    pub fn ranges(&self, lowest_unseen: Seqno) -> impl Iterator<Item = (Seqno, Seqno)> + 'a {
+        let mut prev_end = lowest_unseen;
+        self.ranges.chunks_exact(8).map(move |range| {
+            let start = prev_end + read_u32(&range[..4]);
+            prev_end = start + read_u32(&range[4..]);
+            (start, prev_end)
+        })
+    }
+
+    /// Packets received again below the cumulative ack.
+    pub fn duplicates(&self, lowest_unseen: Seqno) -> impl Iterator<Item = Seqno> + 'a {
+        self.duplicates
+            .chunks_exact(4)
+            .filter_map(move |distance| lowest_unseen.checked_back(read_u32(distance)))
+    }
+}
+
+fn read_u32(bytes: &[u8]) -> u64 {
+    u32::from_le_bytes(bytes.try_into().expect("four bytes")) as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compact_round_trip() {
+        let mut seqnos = [14, 3, 11, 12, 20, 9, 12, 21, 22, 7].map(Seqno).to_vec();
+        let payload = encode_compact(Seqno(10), &mut seqnos);
+        assert_eq!(payload.len(), 4 + 3 * 8 + 3 * 4);
+        let sacks = CompactSack::parse(&payload).unwrap();
+        assert_eq!(
+            sacks
+                .ranges(Seqno(10))
+                .map(|(start, end)| (start.0, end.0))
+                .collect::<Vec<_>>(),
+            vec![(11, 13), (14, 15), (20, 23)]
+        );
+        assert_eq!(
+            sacks
+                .duplicates(Seqno(10))
+                .map(|seqno| seqno.0)
+                .collect::<Vec<_>>(),
+            vec![9, 7, 3]
+        );
+        assert!(CompactSack::parse(&payload[..payload.len() - 1]).is_none());
+        assert!(CompactSack::parse(&[]).is_none());
+    }
+}
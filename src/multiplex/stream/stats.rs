@@ -0,0 +1,52 @@
+use std::time::Duration;
+
+/// A snapshot of the internal state of a stream, returned by [crate::Stream::stats].
+#[derive(Clone, Debug, Default)]
+pub struct StreamStats {
+    /// Received packets currently held back because an earlier one is missing.
+    pub reorder_buffer: usize,
+    /// How far the highest received sequence number is ahead of the next one the reader is waiting for; 0 when everything arrived in order.
+    pub reorder_depth: u64,
+    /// The largest distance by which any packet arrived behind a later one.
+    pub max_reorder_distance: u64,
+    /// Data packets received more than once, e.g. because of needless retransmissions by the other side. These are discarded.
+    pub duplicate_data: u64,
+    /// Retransmissions by this side that turned out to be needless, because the original transmission was acked first.
+    pub spurious_retransmissions: u64,
+    /// Acks received that acknowledged nothing new.
+    pub duplicate_acks: u64,
+    /// Estimated rate at which the other side acknowledges data sent on this stream, in bytes per second. See [crate::Stream::estimated_throughput].
+    pub send_throughput: f64,
+    /// Estimated rate at which data from the other side is delivered to this stream, in bytes per second.
+    pub recv_throughput: f64,
+    /// How fast the application on the other side reads this stream, in bytes per second, as last reported by the other side, which caps how fast this side sends. 0 when not reported. See [crate::Multiplex::set_read_rate_feedback].
+    pub peer_read_rate: f64,
+    /// The congestion window, in packets.
+    pub cwnd: f64,
+    /// Packets sent but not yet acknowledged.
+    pub inflight: usize,
+    /// Data packets sent for the first time.
+    pub data_packets_sent: u64,
+    /// Data packets sent again because they were thought lost, including the spurious retransmissions.
+    pub retransmissions: u64,
+    /// Pieces sent when retransmitting data packets that were larger than the MSS had since become. See [crate::Multiplex::set_mss].
+    pub fragments_sent: u64,
+    /// The fraction of all data packets sent that were retransmissions.
+    pub retransmission_ratio: f64,
+    /// The estimated rate at which the path delivers this stream's packets, in packets per second, taken as the highest seen over the last few seconds.
+    pub delivery_rate: f64,
+    /// The lowest RTT measured on this stream, or `None` before the first measurement.
+    pub min_rtt: Option<Duration>,
+    /// Bytes received and waiting to be read by the application.
+    pub read_queue_bytes: usize,
+    /// Bytes written by the application and waiting to be sent. Bytes sent but not yet acknowledged are counted in `inflight` instead, as packets.
+    pub write_queue_bytes: usize,
+    /// Unreliable datagrams dropped because they arrived before the stream was established. See [crate::UrelPolicy].
+    pub urel_dropped_early: u64,
+    /// Unreliable datagrams dropped because the stream was closed. See [crate::UrelPolicy].
+    pub urel_dropped_closed: u64,
+    /// Unreliable datagrams dropped because too many were waiting to be sent. See [crate::UrelPolicy].
+    pub urel_dropped_queue_full: u64,
+    /// Received unreliable datagrams dropped because too many were waiting to be received. See [crate::StreamOptions].
+    pub urel_dropped_recv_full: u64,
+}
@@ -1,5 +1,6 @@
 use std::{
-    io::{Read, Write},
+    collections::VecDeque,
+    io::Read,
     sync::Arc,
     time::{Duration, Instant},
 };
@@ -12,13 +13,32 @@ use parking_lot::Mutex;
 use stdcode::StdcodeSerializeExt;
 
 use crate::{
-    multiplex::stream::{RelKind, StreamMessage},
+    frame::{Seqno, StreamId},
+    multiplex::{
+        constants::{
+            DEFAULT_RETRANSMIT_BURST, MIN_QUEUE_CAP, MSS, PERSIST_INTERVAL, READ_RATE_BACKLOG,
+            READ_RATE_INTERVAL, READ_RATE_TTL, RESUME_REPEAT, SYN_RESEND_INTERVAL,
+        },
+        path_profile::PathSeed,
+        routes::{Routes, StreamRoutes},
+        stream::{
+            CloseReason, PacingMode, PacingPolicy, ProtocolViolation, RelKind, ResetCode,
+            StreamMessage, UrelPolicy,
+        },
+        trace::{proto_event, trace_event},
+    },
+    utilities::{reorderer::Reorderer, runtime},
     Stream,
 };
 
-use super::{inflight::Inflight, reorderer::Reorderer, StreamQueues};
-const MSS: usize = 1150;
-
+use super::{
+    congestion::{AckEvent, CongestionAlgorithm, CongestionControl, SharedCongestion},
+    fragment::{self, Reassembler},
+    inflight::{Inflight, LossStats},
+    sack::{self, CompactSack, SackRanges},
+    throughput::ThroughputEstimator,
+    StreamQueues, StreamStats,
+};
 /// The raw internal state of a stream.
 ///
 /// This is exposed so that crates other than `sosistab2` itself can use the reliable-stream logic of `sosistab2`, outside the context of multiplexing streams over a `sosistab2::Multiplex`.
@@ -33,7 +53,7 @@ const MSS: usize = 1150;
 /// As long as the above holds, the `Stream` corresponding to the `StreamState`, which is returned from the `StreamState` constructor as well, will work properly.
 pub struct StreamState {
     phase: Phase,
-    stream_id: u16,
+    stream_id: StreamId,
     additional_data: String,
     incoming_queue: Vec<StreamMessage>,
     queues: Arc<Mutex<StreamQueues>>,
@@ -41,31 +61,108 @@ pub struct StreamState {
     tick_notify: Arc<dyn Fn() + Send + Sync + 'static>,
 
     // read variables
-    next_unseen_seqno: u64,
+    next_unseen_seqno: Seqno,
     reorderer: Reorderer<Bytes>,
+    highest_seen_seqno: Option<Seqno>,
+    read_paused: bool,
+    resume_repeat_until: Instant,
+    // bytes ever put into the read queue and taken out of it, for measuring how fast the application reads
+    delivered_bytes: u64,
+    app_read_bytes: u64,
+    app_read_throughput: ThroughputEstimator,
+    read_rate_feedback: bool,
+    // how acks are encoded, which depends on what the other side understands
+    ack_kind: RelKind,
+    // reused between ticks, so that acking does not allocate
+    to_ack: Vec<Seqno>,
+    // whether the other side understands window updates
+    window_updates: bool,
+    // the stream offset up to which the other side was last told it may send
+    advertised_window: u64,
+    reporting_read_rate: bool,
+    next_read_rate_report: Instant,
+    // the seqno that the other side finished writing before, once it said so
+    peer_eof: Option<Seqno>,
+    // segments that arrive in pieces
+    reassembler: Reassembler,
 
     // write variables
     inflight: Inflight,
-    next_write_seqno: u64,
-    cwnd: f64,
-    ssthresh: f64,
+    next_write_seqno: Seqno,
+    // stream offset just past each segment that is not yet known to be acked
+    segment_ends: VecDeque<(Seqno, u64)>,
+    write_offset: u64,
+    mss: usize,
+    cc: Box<dyn CongestionControl>,
+    // the controller that cc is a member of, if it is shared with other streams
+    shared_cc: Option<Arc<SharedCongestion>>,
 
     in_recovery: bool,
-    last_write_time: Instant,
+    // when the last recovery started, for undoing it if it was spurious
+    recovery_started: Option<Instant>,
+    eifel_response: bool,
+    frto: Frto,
+    frto_enabled: bool,
+    // retransmissions allowed per round trip, and how many were sent in the current one
+    retrans_burst: usize,
+    retrans_window_start: Instant,
+    retrans_in_window: usize,
+    early_data: bool,
+    // while latency-sensitive streams share the multiplex, how long this stream's data may wait in queues along the path
+    queue_budget: Option<Duration>,
+    pacing: PacingBucket,
+    peer_paused: bool,
+    next_probe: Instant,
+    // the stream offset up to which the other side accepts data, once it told us
+    peer_window: Option<u64>,
+    // the read rate last reported by the other side, in bytes per second, and when
+    peer_read_rate: Option<(f64, Instant)>,
+    // whether the other side understands half-closing
+    half_close: bool,
+    // whether the other side understands being paused and resumed
+    pause: bool,
+    // whether the other side understands being told the application's read rate
+    read_rate: bool,
+    // whether the other side resets messages for streams it does not know as such, so that early data overtaking the SYN is not taken for a refusal
+    unknown_stream_resets: bool,
+    // whether the other side puts pieces of segments back together, so that segments larger than the MSS can be retransmitted in pieces
+    fragments: bool,
+    // when to repeat telling the other side that this side finished writing, until it answers
+    eof_resend: Option<Instant>,
+    eof_acked: bool,
+    // streams that have written less than this stay on one pipe while the multiplex stripes
+    single_path_below: u64,
+
+    // bandwidth sharing
+    group: Option<String>,
+    weight: f64,
+
+    stats: StreamStats,
+    urel_policy: UrelPolicy,
+    pacing_policy: PacingPolicy,
+    send_throughput: ThroughputEstimator,
+    recv_throughput: ThroughputEstimator,
+    #[cfg(feature = "tracing")]
+    span: tracing::Span,
 }
 
 impl Drop for StreamState {
     fn drop(&mut self) {
-        self.queues.lock().closed = true;
+        self.queues.lock().close(CloseReason::MultiplexClosed);
         self.local_notify.notify_all();
     }
 }
 
 impl StreamState {
+    /// Records why the stream is about to be dropped with its multiplex, for the handle to report instead of [CloseReason::MultiplexClosed].
+    pub fn set_close_reason(&self, reason: CloseReason) {
+        self.queues.lock().set_close_reason(reason);
+    }
+
     /// Creates a new StreamState, in the pre-SYN-sent state. Also returns the "user-facing" handle.
     pub fn new_pending(
         tick_notify: impl Fn() + Send + Sync + 'static,
-        stream_id: u16,
+        stream_id: StreamId,
         label: String,
     ) -> (Self, Stream) {
         Self::new_in_phase(tick_notify, stream_id, Phase::Pending, label)
@@ -74,7 +171,7 @@ impl StreamState {
     /// Creates a new StreamState, in the established state. Also returns the "user-facing" handle.
     pub fn new_established(
         tick_notify: impl Fn() + Send + Sync + 'static,
-        stream_id: u16,
+        stream_id: StreamId,
         label: String,
     ) -> (Self, Stream) {
         Self::new_in_phase(tick_notify, stream_id, Phase::Established, label)
@@ -83,11 +180,11 @@ impl StreamState {
     /// Creates a new StreamState, in the specified state. Also returns the "user-facing" handle.
     fn new_in_phase(
         tick_notify: impl Fn() + Send + Sync + 'static,
-        stream_id: u16,
+        stream_id: StreamId,
         phase: Phase,
         label: String,
     ) -> (Self, Stream) {
-        let queues = Arc::new(Mutex::new(StreamQueues::default()));
+        let queues = Arc::new(Mutex::new(StreamQueues::new()));
         let ready = Arc::new(async_event::Event::new());
         let tick_notify: Arc<dyn Fn() + Send + Sync + 'static> = Arc::new(tick_notify);
         let handle = Stream::new(
@@ -98,6 +195,8 @@ impl StreamState {
         );
 
         static START: Lazy<Instant> = Lazy::new(Instant::now);
+        #[cfg(feature = "tracing")]
+        let span = tracing::debug_span!("stream", stream_id = stream_id.0, label = %label);
         let state = Self {
             phase,
             stream_id,
@@ -105,22 +204,366 @@ impl StreamState {
             queues,
             local_notify: ready,
 
-            next_unseen_seqno: 0,
+            next_unseen_seqno: Seqno::ZERO,
             reorderer: Reorderer::default(),
+            highest_seen_seqno: None,
+            read_paused: false,
+            resume_repeat_until: *START,
+            delivered_bytes: 0,
+            app_read_bytes: 0,
+            app_read_throughput: ThroughputEstimator::default(),
+            read_rate_feedback: false,
+            ack_kind: RelKind::DataAck,
+            to_ack: vec![],
+            window_updates: false,
+            advertised_window: 0,
+            reporting_read_rate: false,
+            next_read_rate_report: *START,
+            peer_eof: None,
+            reassembler: Reassembler::default(),
             inflight: Inflight::new(),
-            next_write_seqno: 0,
-            cwnd: 4.0,
-            ssthresh: 0.0,
+            next_write_seqno: Seqno::ZERO,
+            segment_ends: VecDeque::new(),
+            write_offset: 0,
+            mss: MSS,
+            cc: CongestionAlgorithm::default().build(),
+            shared_cc: None,
             tick_notify,
 
             in_recovery: false,
+            recovery_started: None,
+            eifel_response: false,
+            frto: Frto::Off,
+            frto_enabled: true,
+            retrans_burst: DEFAULT_RETRANSMIT_BURST,
+            retrans_window_start: *START,
+            retrans_in_window: 0,
+            early_data: false,
+            queue_budget: None,
 
             additional_data: label,
-            last_write_time: *START,
+            pacing: PacingBucket {
+                tokens: 0.0,
+                refilled: *START,
+            },
+            peer_paused: false,
+            next_probe: *START,
+            peer_window: None,
+            peer_read_rate: None,
+            half_close: false,
+            pause: false,
+            read_rate: false,
+            unknown_stream_resets: false,
+            fragments: false,
+            eof_resend: None,
+            eof_acked: false,
+            single_path_below: 0,
+
+            group: None,
+            weight: 1.0,
+
+            stats: StreamStats::default(),
+            urel_policy: UrelPolicy::default(),
+            pacing_policy: PacingPolicy::default(),
+            send_throughput: ThroughputEstimator::default(),
+            recv_throughput: ThroughputEstimator::default(),
+            #[cfg(feature = "tracing")]
+            span,
         };
         (state, handle)
     }
 
+    /// Sets the maximum segment size for data written from now on.
+    ///
+    /// Segments already in flight keep their seqnos, so those larger than the new value are retransmitted in pieces, if the other side can put them back together.
+    pub fn set_mss(&mut self, mss: usize) {
+        if mss != self.mss {
+            log::debug!("stream {} mss changed {} => {}", self.stream_id, self.mss, mss);
+            self.mss = mss;
+        }
+    }
+
+    /// Seeds the RTT estimates, and thus the RTO and pacing rate, and possibly the congestion window, from what is known about the path. Only meaningful before anything has been sent.
+    pub(crate) fn seed_path(&mut self, seed: PathSeed) {
+        self.inflight.seed_rtt(seed.rtt, seed.rtt_var);
+        if let Some(cwnd) = seed.cwnd {
+            self.cc.set_cwnd(cwnd);
+        }
+    }
+
+    /// Replaces the congestion control algorithm. The new one takes over the current congestion window.
+    pub fn set_congestion_control(&mut self, mut cc: Box<dyn CongestionControl>) {
+        cc.set_cwnd(self.cc.cwnd());
+        if let Err(err) = cc.set_weight(self.weight) {
+            log::warn!("stream {} is no longer weighted: {err}", self.stream_id);
+            self.weight = 1.0;
+        }
+        self.cc = cc;
+        self.shared_cc = None;
+    }
+
+    /// Makes this stream share the given congestion controller with the other streams that joined it, until it is given one of its own.
+    pub(crate) fn share_congestion_control(&mut self, shared: &Arc<SharedCongestion>) {
+        self.cc = shared.join(self.stream_id);
+        self.shared_cc = Some(shared.clone());
+    }
+
+    /// Counts the segments of this stream against the congestion windows of the pipes they go out over.
+    pub(crate) fn set_routes(&mut self, routes: Arc<Routes>) {
+        self.inflight
+            .set_routes(StreamRoutes::new(routes, self.stream_id));
+        self.sync_single_path();
+    }
+
+    /// The routes of this stream's segments, while the multiplex stripes them over several pipes.
+    fn striped_routes(&self) -> Option<&StreamRoutes> {
+        self.inflight.routes().filter(|routes| routes.is_striping())
+    }
+
+    /// Picks up whether the segments of this stream stay on one pipe, as set through the user-facing handle, or because the stream is still short.
+    fn sync_single_path(&mut self) {
+        let single_path = {
+            let queues = self.queues.lock();
+            queues.options.single_path || queues.written_bytes < self.single_path_below
+        };
+        if let Some(routes) = self.inflight.routes_mut() {
+            routes.set_single_path(single_path);
+        }
+    }
+
+    /// Tells the other streams sharing the congestion controller, if any, how much of the window this one uses.
+    fn report_shared_congestion(&self) {
+        if let Some(shared) = &self.shared_cc {
+            shared.report(
+                self.stream_id,
+                self.inflight.inflight(),
+                self.has_pending_data(),
+            );
+        }
+    }
+
+    /// Sets whether retransmissions found to be spurious undo the congestion window reduction they caused and make the retransmission timeout more conservative, in the manner of the Eifel response algorithm.
+    pub(crate) fn set_eifel_response(&mut self, enabled: bool) {
+        self.eifel_response = enabled;
+        self.inflight.set_eifel_response(enabled);
+    }
+
+    /// Sets whether retransmission timeouts are checked for being spurious, F-RTO style, before retransmitting everything that timed out.
+    pub(crate) fn set_frto(&mut self, enabled: bool) {
+        self.frto_enabled = enabled;
+    }
+
+    /// Sets how many bytes the stream may write before its segments are striped over several pipes along with those of other streams, rather than all going over the pipe with the lowest RTT.
+    pub(crate) fn set_single_path_below(&mut self, bytes: u64) {
+        self.single_path_below = bytes;
+    }
+
+    /// Sets whether to tell the other side how fast the application reads this stream once unread data piles up, so that it does not send faster than that.
+    pub(crate) fn set_read_rate_feedback(&mut self, enabled: bool) {
+        self.read_rate_feedback = enabled;
+    }
+
+    /// Sets the protocol version of the other side, which decides how acks are encoded, and what else it is sent. Peers that cannot be paused only stop sending once the read buffer fills up.
+    pub(crate) fn set_peer_version(&mut self, version: u64) {
+        self.ack_kind = match version {
+            0..=2 => RelKind::DataAck,
+            3 => RelKind::DataAckRanges,
+            _ => RelKind::DataAckCompact,
+        };
+        self.window_updates = version >= 5;
+        self.half_close = version >= 8;
+        self.fragments = version >= 12;
+        self.pause = version >= 13;
+        self.read_rate = version >= 14;
+        self.unknown_stream_resets = version >= 15;
+    }
+
+    /// Sets how many packets may be retransmitted per round trip.
+    pub(crate) fn set_retransmit_burst(&mut self, burst: usize) {
+        self.retrans_burst = burst.max(1);
+    }
+
+    /// Sets how many packets sent after an unacked one must be acked before it is considered lost and retransmitted, without waiting for a timeout.
+    pub(crate) fn set_fast_retransmit_threshold(&mut self, threshold: u64) {
+        self.inflight
+            .set_fast_retransmit_threshold(threshold.max(1));
+    }
+
+    /// Lets a stream that is being opened send data right behind its SYN, within the initial congestion window, instead of waiting for the SYN-ACK. Peers that predate this are only sent data once they accept the stream.
+    pub(crate) fn allow_early_data(&mut self) {
+        self.early_data = true;
+    }
+
+    /// Whether data is sent before the SYN-ACK: only if the other side can tell us that data overtook the SYN, since any other reset means the stream was refused.
+    fn sends_early_data(&self) -> bool {
+        self.early_data && self.unknown_stream_resets
+    }
+
+    /// Sets which unreliable datagrams this stream drops instead of delivering.
+    pub(crate) fn set_urel_policy(&mut self, policy: UrelPolicy) {
+        self.urel_policy = policy;
+        self.queues.lock().urel_policy = policy;
+    }
+
+    /// Sets how long the stream keeps delivering what was written to it once the last handle is dropped, or to close it right away with `None`.
+    pub(crate) fn set_drop_linger(&mut self, linger: Option<Duration>) {
+        self.queues.lock().drop_linger = linger;
+    }
+
+    /// Sets how this stream is paced, and whether it is exempt.
+    pub(crate) fn set_pacing_policy(&mut self, policy: PacingPolicy) {
+        self.pacing_policy = policy;
+    }
+
+    /// Whether sending is spread out at the pacing rate, rather than limited only by the congestion window.
+    fn paced(&self) -> bool {
+        if self.pacing_policy.mode == PacingMode::Off {
+            return false;
+        }
+        let queues = self.queues.lock();
+        let small = queues.written_bytes < self.pacing_policy.exempt_below;
+        let interactive =
+            self.pacing_policy.exempt_latency_sensitive && queues.options.latency_budget.is_some();
+        !(small || interactive)
+    }
+
+    /// Returns the bandwidth-sharing group the user-facing handle last put this stream in.
+    pub fn group(&self) -> Option<&str> {
+        self.group.as_deref()
+    }
+
+    /// Picks up a group change made through the user-facing handle. Returns whether the group changed.
+    pub(crate) fn sync_group(&mut self) -> bool {
+        let queues = self.queues.lock();
+        if queues.group != self.group {
+            self.group = queues.group.clone();
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Picks up a congestion control algorithm chosen through the user-facing handle.
+    fn sync_congestion(&mut self) {
+        let algo = self.queues.lock().congestion.take();
+        if let Some(algo) = algo {
+            self.set_congestion_control(algo.build());
+        }
+    }
+
+    /// Sets the weight of this stream relative to other streams sharing a bottleneck. A stream with weight `w` grows and backs off its congestion window like `w` normal streams would their combined window, which gives it a correspondingly larger share.
+    ///
+    /// Algorithms that cannot honour the weight, such as BBR, leave the stream unweighted.
+    pub fn set_weight(&mut self, weight: f64) {
+        let weight = weight.max(0.01);
+        self.weight = match self.cc.set_weight(weight) {
+            Ok(()) => weight,
+            Err(err) => {
+                log::warn!("stream {} stays unweighted: {err}", self.stream_id);
+                1.0
+            }
+        };
+    }
+
+    /// Returns the weight of this stream relative to other streams sharing a bottleneck.
+    pub(crate) fn weight(&self) -> f64 {
+        self.weight
+    }
+
+    /// How many data packets this stream may send per round when the multiplex interleaves streams.
+    pub(crate) fn priority(&self) -> usize {
+        self.queues.lock().options.priority as usize
+    }
+
+    /// The latency budget this stream was tagged with, if any.
+    pub(crate) fn latency_budget(&self) -> Option<Duration> {
+        self.queues.lock().options.latency_budget
+    }
+
+    /// Sets the smallest latency budget among the streams of the multiplex. Unless this stream is latency-sensitive itself, it then keeps no more of its data queued along the path than drains within that time.
+    pub(crate) fn set_queue_budget(&mut self, budget: Option<Duration>) {
+        self.queue_budget = budget.filter(|_| self.latency_budget().is_none());
+    }
+
+    /// Whether this stream has data waiting to be sent or in flight.
+    pub(crate) fn has_pending_data(&self) -> bool {
+        self.inflight.inflight() > 0 || !self.queues.lock().write_stream.is_empty()
+    }
+
+    /// Checks that what was sent, acked and delivered adds up, between ticks.
+    #[cfg(all(test, feature = "soak"))]
+    pub(crate) fn check_invariants(&self) -> anyhow::Result<()> {
+        let id = self.stream_id;
+        self.inflight
+            .check_invariants(self.next_write_seqno)
+            .map_err(|e| e.context(format!("stream {id}")))?;
+        anyhow::ensure!(
+            self.segment_ends
+                .iter()
+                .zip(self.segment_ends.iter().skip(1))
+                .all(|(a, b)| a.0 < b.0 && a.1 <= b.1),
+            "stream {id}: segment ends out of order"
+        );
+        if let Some(&(seqno, end)) = self.segment_ends.back() {
+            anyhow::ensure!(
+                seqno < self.next_write_seqno && end <= self.write_offset,
+                "stream {id}: segment {seqno} ends at {end}, past what was sent"
+            );
+        }
+        // every segment in flight is still waiting to be acked
+        anyhow::ensure!(
+            self.segment_ends.len() >= self.inflight.inflight(),
+            "stream {id}: {} segments in flight, {} unacked",
+            self.inflight.inflight(),
+            self.segment_ends.len()
+        );
+        anyhow::ensure!(
+            self.reorderer.next_seq() == self.next_unseen_seqno.0,
+            "stream {id}: reorderer at {}, expecting {}",
+            self.reorderer.next_seq(),
+            self.next_unseen_seqno
+        );
+        let queues = self.queues.lock();
+        anyhow::ensure!(
+            queues.acked_bytes <= self.write_offset,
+            "stream {id}: {} bytes acked, {} sent",
+            queues.acked_bytes,
+            self.write_offset
+        );
+        anyhow::ensure!(
+            queues.written_bytes - self.write_offset == queues.write_stream.len() as u64,
+            "stream {id}: {} bytes written, {} sent, {} waiting",
+            queues.written_bytes,
+            self.write_offset,
+            queues.write_stream.len()
+        );
+        anyhow::ensure!(
+            queues.read_stream.len() as u64 <= self.delivered_bytes,
+            "stream {id}: more to read than was delivered"
+        );
+        Ok(())
+    }
+
+    /// Returns the estimated rate, in bytes per second, at which the other side is acking data.
+    pub(crate) fn send_throughput(&self) -> f64 {
+        self.send_throughput.estimate()
+    }
+
+    /// Returns statistics about this stream, as also seen through [Stream::stats].
+    pub fn stats(&self) -> &StreamStats {
+        &self.stats
+    }
+
+    /// Returns the same snapshot of statistics as [Stream::stats].
+    pub fn snapshot(&self) -> StreamStats {
+        self.queues.lock().stats()
+    }
+
+    /// Returns statistics about the loss pattern this stream has seen.
+    pub fn loss_stats(&self) -> &LossStats {
+        self.inflight.loss_stats()
+    }
+
     /// Injects an incoming message.
     pub fn inject_incoming(&mut self, msg: StreamMessage) {
         self.incoming_queue.push(msg);
@@ -132,8 +575,14 @@ impl StreamState {
     /// Returns None if the correct option is to delete the whole thing.
     pub fn tick(&mut self, mut outgoing_callback: impl FnMut(StreamMessage)) -> Option<Instant> {
         log::trace!("ticking {} at {:?}", self.stream_id, self.phase);
+        #[cfg(feature = "tracing")]
+        let span = self.span.clone();
+        #[cfg(feature = "tracing")]
+        let _entered = span.enter();
 
-        let now: Instant = Instant::now();
+        let now: Instant = runtime::now();
+        self.sync_congestion();
+        self.sync_single_path();
 
         match self.phase {
             Phase::Pending => {
@@ -141,41 +590,71 @@ impl StreamState {
                 outgoing_callback(StreamMessage::Reliable {
                     kind: RelKind::Syn,
                     stream_id: self.stream_id,
-                    seqno: 0,
+                    seqno: Seqno::ZERO,
                     payload: Bytes::copy_from_slice(self.additional_data.as_bytes()),
                 });
-                let next_resend = now + Duration::from_secs(1);
+                let next_resend = now + SYN_RESEND_INTERVAL;
                 self.phase = Phase::SynSent { next_resend };
-                Some(next_resend)
+                Some(self.tick_early_data(now, next_resend, &mut outgoing_callback))
             }
             Phase::SynSent { next_resend } => {
-                if self.incoming_queue.drain(..).any(|msg| {
-                    matches!(
-                        msg,
+                let mut synacked = false;
+                let mut reset = None;
+                // anything else, such as acks for early data, is kept for when the stream is established
+                let mut rest = vec![];
+                let sends_early_data = self.sends_early_data();
+                for msg in self.incoming_queue.drain(..) {
+                    match &msg {
                         StreamMessage::Reliable {
                             kind: RelKind::SynAck,
-                            stream_id: _,
-                            seqno: _,
-                            payload: _
+                            ..
+                        } => synacked = true,
+                        StreamMessage::Reliable {
+                            kind: RelKind::Rst,
+                            payload,
+                            ..
+                        } => {
+                            let code = ResetCode::from_payload(payload);
+                            // early data that overtook the SYN gets reset as being for an unknown stream, which is not a refusal
+                            if !(sends_early_data && code == ResetCode::UnknownStream) {
+                                reset = Some(code);
+                            }
+                        }
+                        StreamMessage::Unreliable { .. }
+                            if self.urel_policy.drop_before_established =>
+                        {
+                            self.stats.urel_dropped_early += 1;
+                            self.queues.lock().stats = self.stats.clone();
                         }
-                    )
-                }) {
+                        _ => rest.push(msg),
+                    }
+                }
+                self.incoming_queue = rest;
+                if synacked {
                     self.phase = Phase::Established;
                     self.queues.lock().connected = true;
                     self.local_notify.notify_all();
                     Some(now)
+                } else if let Some(code) = reset {
+                    log::debug!("stream {} refused: {:?}", self.stream_id, code);
+                    let mut queues = self.queues.lock();
+                    queues.reset_code = Some(code);
+                    queues.set_close_reason(CloseReason::from_reset(code, false));
+                    drop(queues);
+                    self.phase = Phase::Closed;
+                    Some(now)
                 } else if now >= next_resend {
                     outgoing_callback(StreamMessage::Reliable {
                         kind: RelKind::Syn,
                         stream_id: self.stream_id,
-                        seqno: 0,
+                        seqno: Seqno::ZERO,
                         payload: Bytes::copy_from_slice(self.additional_data.as_bytes()),
                     });
-                    let next_resend = now + Duration::from_secs(1);
+                    let next_resend = now + SYN_RESEND_INTERVAL;
                     self.phase = Phase::SynSent { next_resend };
-                    Some(next_resend)
+                    Some(self.tick_early_data(now, next_resend, &mut outgoing_callback))
                 } else {
-                    Some(next_resend)
+                    Some(self.tick_early_data(now, next_resend, &mut outgoing_callback))
                 }
             }
             Phase::Established => {
@@ -183,21 +662,37 @@ impl StreamState {
                 self.tick_read(now, &mut outgoing_callback);
                 // Then, handle sending packets. This involves congestion control, so it's the harder part.
                 self.tick_write(now, &mut outgoing_callback);
+                self.tick_close_write(now, &mut outgoing_callback);
+                self.tick_dropped(now);
                 // If closed, then die
-                if self.queues.lock().closed {
+                if self.queues.lock().closed && !matches!(self.phase, Phase::Closed) {
+                    // closed on this side, so tell the other side, whose reads would otherwise wait until it next sends something and gets reset. If this is lost, that is still what happens.
+                    outgoing_callback(StreamMessage::Reliable {
+                        kind: RelKind::Fin,
+                        stream_id: self.stream_id,
+                        seqno: Seqno::ZERO,
+                        payload: Default::default(),
+                    });
                     self.phase = Phase::Closed;
                 }
-                // Finally, calculate the next interval.
-                Some(self.retick_time(now))
+                if matches!(self.phase, Phase::Closed) {
+                    self.drop_urel_after_close();
+                    // finish closing right away, so that readers wake up even if nothing else would tick an idle stream
+                    Some(now)
+                } else {
+                    // Finally, calculate the next interval.
+                    Some(self.retick_time(now))
+                }
             }
             Phase::Closed => {
                 self.queues.lock().closed = true;
+                self.drop_urel_after_close();
                 self.local_notify.notify_all();
                 for _ in self.incoming_queue.drain(..) {
                     outgoing_callback(StreamMessage::Reliable {
                         kind: RelKind::Rst,
                         stream_id: self.stream_id,
-                        seqno: 0,
+                        seqno: Seqno::ZERO,
                         payload: Default::default(),
                     });
                 }
@@ -206,18 +701,89 @@ impl StreamState {
         }
     }
 
-    fn tick_read(&mut self, _now: Instant, mut outgoing_callback: impl FnMut(StreamMessage)) {
+    fn tick_read(&mut self, now: Instant, mut outgoing_callback: impl FnMut(StreamMessage)) {
         // Put all incoming packets into the reorderer.
-        let mut to_ack = vec![];
+        let mut to_ack = std::mem::take(&mut self.to_ack);
+        to_ack.clear();
+        // packets received again after they were already delivered, reported back so that the sender learns about its spurious retransmissions
+        let mut duplicates = vec![];
+        // If the receive queue is too large, then we pretend like we don't see anything. The sender will eventually retransmit.
+        // This unifies flow control with congestion control at the cost of a bit of efficiency.
+        let (read_queue_full, read_paused, unread, read_buffer) = {
+            let queues = self.queues.lock();
+            let unread = queues.read_stream.len();
+            (
+                unread > queues.options.read_buffer,
+                queues.read_paused,
+                unread,
+                queues.options.read_buffer as u64,
+            )
+        };
+        let app_read_bytes = self.delivered_bytes - unread as u64;
+        if app_read_bytes > self.app_read_bytes {
+            self.app_read_throughput
+                .on_delivered(app_read_bytes - self.app_read_bytes, now);
+            self.app_read_bytes = app_read_bytes;
+        }
+        // Tell the other side right away if the reader paused or resumed.
+        if read_paused != self.read_paused {
+            self.read_paused = read_paused;
+            if !read_paused {
+                self.resume_repeat_until = now + RESUME_REPEAT;
+            }
+            if self.pause {
+                outgoing_callback(self.pause_msg(read_paused));
+            }
+        }
+        // whether the other side asked how much it may send
+        let mut window_asked = false;
+        // the first message that no correct peer sends, which resets the stream
+        let mut violation = None;
         // log::debug!("processing incoming queue of {}", self.incoming_queue.len());
-        for packet in self.incoming_queue.drain(..) {
-            // If the receive queue is too large, then we pretend like we don't see anything. The sender will eventually retransmit.
-            // This unifies flow control with congestion control at the cost of a bit of efficiency.
-            if self.queues.lock().read_stream.len() > 10_000_000 {
+        let mut incoming_queue = std::mem::take(&mut self.incoming_queue);
+        for packet in incoming_queue.drain(..) {
+            // pause, resume and window updates must never be ignored, or the sender could stay stopped forever
+            if read_queue_full
+                && !matches!(
+                    packet,
+                    StreamMessage::Reliable {
+                        kind: RelKind::Pause | RelKind::Resume | RelKind::WindowUpdate,
+                        ..
+                    }
+                )
+            {
                 continue;
             }
 
             match packet {
+                StreamMessage::Reliable {
+                    kind: RelKind::DataFrag,
+                    stream_id,
+                    seqno,
+                    payload,
+                } => {
+                    log::trace!("incoming fragment of seqno {stream_id}/{seqno}");
+                    if self.reorderer.is_duplicate(seqno.0) {
+                        // already received whole, so there is nothing to put together, only the ack to repeat
+                        self.stats.duplicate_data += 1;
+                        if seqno < self.next_unseen_seqno {
+                            duplicates.push(seqno);
+                        } else {
+                            to_ack.push(seqno);
+                        }
+                        continue;
+                    }
+                    match self.reassembler.insert(seqno, &payload) {
+                        Ok(Some(payload)) => {
+                            self.on_data(seqno, payload, &mut to_ack, &mut duplicates)
+                        }
+                        Ok(None) => {}
+                        Err(_) => {
+                            violation = Some(ProtocolViolation::MalformedFragment);
+                            break;
+                        }
+                    }
+                }
                 StreamMessage::Reliable {
                     kind: RelKind::Data,
                     stream_id,
@@ -225,44 +791,94 @@ impl StreamState {
                     payload,
                 } => {
                     log::trace!("incoming seqno {stream_id}/{seqno}");
-                    if self.reorderer.insert(seqno, payload) {
-                        to_ack.push(seqno);
-                    }
+                    self.on_data(seqno, payload, &mut to_ack, &mut duplicates);
                 }
                 StreamMessage::Reliable {
-                    kind: RelKind::DataAck,
+                    kind:
+                        kind @ (RelKind::DataAck | RelKind::DataAckRanges | RelKind::DataAckCompact),
                     stream_id: _,
                     seqno: lowest_unseen_seqno, // *one greater* than the last packet that got to the other side
                     payload: selective_acks,
                 } => {
+                    // acking what was never sent would make us think the path is faster than it is
+                    if lowest_unseen_seqno > self.next_write_seqno {
+                        violation = Some(ProtocolViolation::AckBeyondSent);
+                        break;
+                    }
+                    self.inflight.start_ack();
                     // mark every packet whose seqno is less than the given seqno as acked.
                     let mut ack_count = self.inflight.mark_acked_lt(lowest_unseen_seqno);
-                    // then, we interpret the payload as a vector of acks that should additionally be taken care of.
-                    if let Ok(sacks) = stdcode::deserialize::<Vec<u64>>(&selective_acks) {
-                        for sack in sacks {
-                            if self.inflight.mark_acked(sack) {
-                                ack_count += 1;
+                    // then, we interpret the payload as acks that should additionally be taken care of: a plain list of seqnos from the oldest peers, ranges from newer ones.
+                    let sacked = match kind {
+                        RelKind::DataAckCompact => match CompactSack::parse(&selective_acks) {
+                            Some(sacks) => check_sack_ranges(
+                                sacks.ranges(lowest_unseen_seqno),
+                                self.next_write_seqno,
+                            )
+                            .map(|_| {
+                                self.inflight.on_selective_acks(
+                                    sacks.duplicates(lowest_unseen_seqno),
+                                    sacks.ranges(lowest_unseen_seqno),
+                                )
+                            }),
+                            None => Err(ProtocolViolation::MalformedAck),
+                        },
+                        _ => {
+                            let sacks = if kind == RelKind::DataAck {
+                                stdcode::deserialize::<Vec<Seqno>>(&selective_acks)
+                                    .map(|sacks| SackRanges::new(lowest_unseen_seqno, sacks))
+                            } else {
+                                stdcode::deserialize::<SackRanges>(&selective_acks)
+                            };
+                            match sacks {
+                                Ok(sacks) => check_sack_ranges(
+                                    sacks.ranges(lowest_unseen_seqno),
+                                    self.next_write_seqno,
+                                )
+                                .map(|_| {
+                                    self.inflight.on_selective_acks(
+                                        sacks.duplicates().iter().copied(),
+                                        sacks.ranges(lowest_unseen_seqno),
+                                    )
+                                }),
+                                Err(_) => Err(ProtocolViolation::MalformedAck),
                             }
                         }
+                    };
+                    match sacked {
+                        Ok(sacked) => ack_count += sacked,
+                        Err(err) => {
+                            violation = Some(err);
+                            break;
+                        }
+                    }
+                    if ack_count == 0 {
+                        self.stats.duplicate_acks += 1;
                     }
 
-                    // use BIC
-                    for _ in 0..ack_count {
-                        let bic_inc = if self.cwnd < self.ssthresh {
-                            (self.ssthresh - self.cwnd) / 2.0
-                        } else {
-                            self.cwnd - self.ssthresh
-                        }
-                        .max(1.0)
-                        .min(50.0)
-                        .min(self.cwnd);
-                        self.cwnd += bic_inc / self.cwnd;
+                    if ack_count > 0 {
+                        self.cc.on_ack(&AckEvent {
+                            now,
+                            acked: ack_count,
+                            inflight: self.inflight.inflight(),
+                            min_rtt: self.inflight.min_rtt(),
+                            srtt: self.inflight.srtt(),
+                            latest_rtt: self.inflight.latest_rtt(),
+                            delivery_rate: self.inflight.delivery_rate(),
+                        });
                     }
 
+                    trace_event!(
+                        tracing::Level::TRACE,
+                        acked = ack_count,
+                        inflight = self.inflight.inflight(),
+                        cwnd = self.cc.cwnd(),
+                        "ack"
+                    );
                     log::debug!(
                         "ack_count = {ack_count}; send window {}; cwnd {:.1}; bdp {}; write queue {}",
                         self.inflight.inflight(),
-                        self.cwnd,
+                        self.cc.cwnd(),
                         self.inflight.bdp(),
                         self.queues.lock().write_stream.len()
                     );
@@ -283,57 +899,422 @@ impl StreamState {
                     });
                 }
                 StreamMessage::Reliable {
-                    kind: RelKind::Rst | RelKind::Fin,
+                    kind: RelKind::Pause,
                     stream_id: _,
                     seqno: _,
                     payload: _,
                 } => {
+                    if !self.peer_paused {
+                        log::debug!("stream {} paused by the other side", self.stream_id);
+                        self.peer_paused = true;
+                        self.next_probe = now + PERSIST_INTERVAL;
+                    }
+                }
+                StreamMessage::Reliable {
+                    kind: RelKind::Resume,
+                    stream_id: _,
+                    seqno: _,
+                    payload: _,
+                } => {
+                    if self.peer_paused {
+                        log::debug!("stream {} resumed by the other side", self.stream_id);
+                        self.peer_paused = false;
+                    }
+                }
+                StreamMessage::Reliable {
+                    kind: RelKind::WindowUpdate,
+                    stream_id: _,
+                    seqno: _,
+                    payload,
+                } => match <[u8; 8]>::try_from(payload.as_ref()) {
+                    // the window shrinks if the other side shrinks its buffer; anything already sent beyond it is dropped and retransmitted, as with peers that do not send window updates
+                    Ok(window) => self.peer_window = Some(u64::from_le_bytes(window)),
+                    Err(_) if payload.is_empty() => window_asked = true,
+                    Err(_) => {
+                        violation = Some(ProtocolViolation::MalformedWindowUpdate);
+                        break;
+                    }
+                },
+                StreamMessage::Reliable {
+                    kind: RelKind::ReadRate,
+                    stream_id: _,
+                    seqno: _,
+                    payload,
+                } => {
+                    // a rate of zero means the reader caught up
+                    self.peer_read_rate = stdcode::deserialize::<u64>(&payload)
+                        .ok()
+                        .filter(|rate| *rate > 0)
+                        .map(|rate| (rate as f64, now));
+                    self.stats.peer_read_rate = self.peer_read_rate.map_or(0.0, |(rate, _)| rate);
+                }
+                StreamMessage::Reliable {
+                    kind: RelKind::Eof,
+                    stream_id,
+                    seqno,
+                    payload: _,
+                } => {
+                    if self.peer_eof.is_none() {
+                        log::debug!("stream {stream_id} finished writing at {seqno}");
+                        self.peer_eof = Some(seqno);
+                    }
+                    outgoing_callback(StreamMessage::Reliable {
+                        kind: RelKind::FinAck,
+                        stream_id,
+                        seqno,
+                        payload: Bytes::new(),
+                    });
+                }
+                StreamMessage::Reliable {
+                    kind: RelKind::FinAck,
+                    ..
+                } => self.eof_acked = true,
+                StreamMessage::Reliable {
+                    kind: kind @ (RelKind::Rst | RelKind::Fin),
+                    stream_id: _,
+                    seqno: _,
+                    payload,
+                } => {
+                    let mut queues = self.queues.lock();
+                    if kind == RelKind::Rst {
+                        let code = ResetCode::from_payload(&payload);
+                        queues.reset_code = Some(code);
+                        queues.set_close_reason(CloseReason::from_reset(code, true));
+                    } else {
+                        queues.set_close_reason(CloseReason::PeerClosed);
+                    }
+                    drop(queues);
                     self.phase = Phase::Closed;
                 }
+                StreamMessage::Unreliable { .. }
+                    if self.urel_policy.drop_after_close
+                        && matches!(self.phase, Phase::Closed) =>
+                {
+                    self.stats.urel_dropped_closed += 1;
+                }
                 StreamMessage::Unreliable {
                     stream_id: _,
                     payload,
                 } => {
-                    self.queues.lock().recv_urel.push_back(payload);
+                    let mut queues = self.queues.lock();
+                    if let Some(limit) = queues.options.urel_recv_queue_limit {
+                        while queues.recv_urel.len() >= limit.max(1) {
+                            queues.recv_urel.pop_front();
+                            self.stats.urel_dropped_recv_full += 1;
+                        }
+                    }
+                    queues.recv_urel.push_back(payload);
+                    drop(queues);
                     self.local_notify.notify_all();
                 }
                 _ => log::warn!("discarding out-of-turn packet {:?}", packet),
             }
         }
-        // Then, drain the reorderer
-        for (seqno, packet) in self.reorderer.take() {
-            self.next_unseen_seqno = seqno + 1;
-            self.queues.lock().read_stream.write_all(&packet).unwrap();
+        self.incoming_queue = incoming_queue;
+        if let Some(violation) = violation {
+            // only this stream is affected; the rest of the multiplex carries on
+            log::warn!("resetting stream {}: {violation}", self.stream_id);
+            let mut queues = self.queues.lock();
+            queues.violation = Some(violation);
+            queues.set_close_reason(CloseReason::ProtocolViolation(violation));
+            drop(queues);
+            outgoing_callback(StreamMessage::Reliable {
+                kind: RelKind::Rst,
+                stream_id: self.stream_id,
+                seqno: Seqno::ZERO,
+                payload: ResetCode::ProtocolViolation.to_payload(),
+            });
+            self.phase = Phase::Closed;
+            return;
         }
+        // Then, drain the reorderer into the read queue in one go, waking up readers at most once
+        let delivered = self.reorderer.take();
+        if !delivered.is_empty() {
+            let mut queues = self.queues.lock();
+            for (seqno, packet) in delivered {
+                self.next_unseen_seqno = Seqno(seqno).next();
+                self.recv_throughput.on_delivered(packet.len() as u64, now);
+                self.delivered_bytes += packet.len() as u64;
+                queues.read_stream.extend(&packet[..]);
+            }
+            if queues.read_ready() {
+                self.local_notify.notify_all();
+            }
+            self.reassembler.forget_before(self.next_unseen_seqno);
+        }
+        // end of file once everything written before the other side finished is delivered
+        if self
+            .peer_eof
+            .is_some_and(|eof| self.next_unseen_seqno >= eof)
+        {
+            let mut queues = self.queues.lock();
+            if !queues.read_eof {
+                queues.read_eof = true;
+                self.local_notify.notify_all();
+            }
+        }
+        self.stats.reorder_buffer = self.reorderer.len();
+        self.stats.reorder_depth = self
+            .highest_seen_seqno
+            .map_or(0, |highest| highest.next().since(self.next_unseen_seqno));
+        if self
+            .peer_read_rate
+            .is_some_and(|(_, at)| now.saturating_duration_since(at) > READ_RATE_TTL)
+        {
+            self.peer_read_rate = None;
+            self.stats.peer_read_rate = 0.0;
+        }
+        self.update_acked_bytes(now);
+        self.check_spurious_recovery();
+        self.check_frto();
+        self.stats.send_throughput = self.send_throughput.estimate();
+        self.stats.recv_throughput = self.recv_throughput.estimate();
+        self.queues.lock().stats = self.stats.clone();
 
         // Then, generate an ack.
-        if !to_ack.is_empty() {
-            self.local_notify.notify_all();
+        if !to_ack.is_empty() || !duplicates.is_empty() {
             to_ack.retain(|a| a >= &self.next_unseen_seqno);
+            // duplicates go below the cumulative ack, where older senders simply ignore them
+            to_ack.extend(duplicates);
+            let payload = match self.ack_kind {
+                RelKind::DataAckCompact => {
+                    sack::encode_compact(self.next_unseen_seqno, &mut to_ack)
+                }
+                RelKind::DataAckRanges => SackRanges::new(self.next_unseen_seqno, to_ack.clone())
+                    .stdcode()
+                    .into(),
+                _ => to_ack.stdcode().into(),
+            };
             outgoing_callback(StreamMessage::Reliable {
-                kind: RelKind::DataAck,
+                kind: self.ack_kind,
                 stream_id: self.stream_id,
                 seqno: self.next_unseen_seqno,
-                payload: to_ack.stdcode().into(),
+                payload,
             });
+            // Pause and resume messages can get lost, so we repeat them alongside acks. While paused, acks only flow for the sender's occasional probes, so this is cheap.
+            if self.pause && self.read_paused {
+                outgoing_callback(self.pause_msg(true));
+            } else if self.pause && now < self.resume_repeat_until {
+                outgoing_callback(self.pause_msg(false));
+            }
+        }
+        self.to_ack = to_ack;
+        // Tell the other side how far it may send once that moved by a good part of the buffer, rather than after every read, or when the buffer shrank, or when it asks.
+        if self.window_updates {
+            let window = self.app_read_bytes + read_buffer;
+            if window_asked
+                || window >= self.advertised_window + read_buffer / 4
+                || window < self.advertised_window
+            {
+                self.advertised_window = window;
+                outgoing_callback(self.window_msg(Some(window)));
+            }
+        }
+        if let Some(msg) = self.read_rate_msg(now) {
+            outgoing_callback(msg);
         }
     }
 
-    fn start_recovery(&mut self) {
-        if !self.in_recovery {
-            log::debug!("*** START RECOVRY AT CWND = {}", self.cwnd);
+    /// Handles a data segment received whole, or put back together from pieces.
+    fn on_data(
+        &mut self,
+        seqno: Seqno,
+        payload: Bytes,
+        to_ack: &mut Vec<Seqno>,
+        duplicates: &mut Vec<Seqno>,
+    ) {
+        if self.reorderer.is_duplicate(seqno.0) {
+            self.stats.duplicate_data += 1;
+            if seqno < self.next_unseen_seqno {
+                duplicates.push(seqno);
+            }
+        }
+        match self.highest_seen_seqno {
+            Some(highest) if seqno < highest => {
+                self.stats.max_reorder_distance =
+                    self.stats.max_reorder_distance.max(highest.since(seqno));
+            }
+            _ => self.highest_seen_seqno = Some(seqno),
+        }
+        if self.reorderer.insert(seqno.0, payload) {
+            to_ack.push(seqno);
+        }
+    }
 
-            // BIC
-            let beta = 0.15;
-            if self.cwnd < self.ssthresh {
-                self.ssthresh = self.cwnd * (2.0 - beta) / 2.0;
-            } else {
-                self.ssthresh = self.cwnd;
+    /// Reports the application's read rate while unread data piles up, and reports once more when it no longer does, if read rate feedback is on and the other side understands it.
+    fn read_rate_msg(&mut self, now: Instant) -> Option<StreamMessage> {
+        if !self.read_rate_feedback || !self.read_rate {
+            return None;
+        }
+        let backlogged = self.queues.lock().read_stream.len() > READ_RATE_BACKLOG;
+        let rate = if backlogged && now >= self.next_read_rate_report {
+            // never report a rate of zero, which would mean the reader caught up
+            let rate = (self.app_read_throughput.estimate() as u64).max(1);
+            self.next_read_rate_report = now + READ_RATE_INTERVAL;
+            self.reporting_read_rate = true;
+            rate
+        } else if !backlogged && self.reporting_read_rate {
+            self.reporting_read_rate = false;
+            0
+        } else {
+            return None;
+        };
+        log::debug!("stream {} reporting read rate {}", self.stream_id, rate);
+        Some(StreamMessage::Reliable {
+            kind: RelKind::ReadRate,
+            stream_id: self.stream_id,
+            seqno: Seqno::ZERO,
+            payload: rate.stdcode().into(),
+        })
+    }
+
+    /// Sends whatever early data the congestion window allows while waiting for the SYN-ACK, returning when to tick next.
+    fn tick_early_data(
+        &mut self,
+        now: Instant,
+        next_resend: Instant,
+        outgoing_callback: impl FnMut(StreamMessage),
+    ) -> Instant {
+        if self.sends_early_data() {
+            self.tick_write(now, outgoing_callback);
+            next_resend.min(self.retick_time(now))
+        } else {
+            next_resend
+        }
+    }
+
+    /// Retransmits everything in flight as soon as pacing allows, since the pipe it went over died and the timeouts would only say so much later.
+    pub(crate) fn on_failover(&mut self) {
+        let now = runtime::now();
+        if matches!(self.frto, Frto::Probing) {
+            self.inflight.end_frto(false, now);
+        }
+        // these timeouts are known to be real, so there is nothing for F-RTO to find out
+        self.frto = Frto::Conventional {
+            until_seqno: self.next_write_seqno,
+        };
+        self.inflight.expedite_all(now);
+    }
+
+    /// Counts the retransmissions that turned out to be spurious, undoing the last recovery if one of its retransmissions was spurious.
+    fn check_spurious_recovery(&mut self) {
+        let spurious_retrans = self.inflight.take_spurious_retrans();
+        self.stats.spurious_retransmissions = self.inflight.spurious();
+        if !self.eifel_response {
+            return;
+        }
+        if let Some(started) = self.recovery_started {
+            if spurious_retrans.iter().any(|sent| *sent >= started) {
+                self.undo_recovery();
             }
+        }
+    }
 
-            self.cwnd *= 1.0 - beta;
-            self.cwnd = self.cwnd.max(1.0);
+    /// Acts on what acks showed about the retransmission timeout being watched, if any.
+    fn check_frto(&mut self) {
+        let now = runtime::now();
+        match self.frto {
+            Frto::Probing => match self.inflight.frto_outcome() {
+                Some(true) => {
+                    log::debug!(
+                        "stream {} retransmission timeout was spurious",
+                        self.stream_id
+                    );
+                    self.inflight.end_frto(true, now);
+                    self.stats.spurious_retransmissions = self.inflight.spurious();
+                    self.frto = Frto::Off;
+                    self.undo_recovery();
+                }
+                Some(false) => {
+                    self.inflight.end_frto(false, now);
+                    self.frto = Frto::Conventional {
+                        until_seqno: self.next_write_seqno,
+                    };
+                }
+                None => {}
+            },
+            Frto::Conventional { until_seqno } => {
+                if self
+                    .inflight
+                    .first_unacked()
+                    .is_none_or(|seqno| seqno >= until_seqno)
+                {
+                    self.frto = Frto::Off;
+                }
+            }
+            Frto::Off => {}
+        }
+    }
+
+    /// Restores the congestion state from before the last recovery, which turned out to be spurious.
+    fn undo_recovery(&mut self) {
+        if self.recovery_started.take().is_some() {
+            let cwnd = self.cc.cwnd();
+            self.cc.on_spurious_loss();
+            log::debug!(
+                "stream {} undoing spurious recovery, cwnd {:.1} => {:.1}",
+                self.stream_id,
+                cwnd,
+                self.cc.cwnd()
+            );
+        }
+    }
+
+    /// Drops the datagrams nobody received before the stream closed, if the policy says so.
+    fn drop_urel_after_close(&mut self) {
+        if self.urel_policy.drop_after_close {
+            let mut queues = self.queues.lock();
+            if !queues.recv_urel.is_empty() {
+                self.stats.urel_dropped_closed += queues.recv_urel.len() as u64;
+                queues.recv_urel.clear();
+                queues.stats = self.stats.clone();
+            }
+        }
+    }
+
+    fn pause_msg(&self, paused: bool) -> StreamMessage {
+        StreamMessage::Reliable {
+            kind: if paused {
+                RelKind::Pause
+            } else {
+                RelKind::Resume
+            },
+            stream_id: self.stream_id,
+            seqno: Seqno::ZERO,
+            payload: Bytes::new(),
+        }
+    }
+
+    /// A window update, telling the other side up to which stream offset it may send, or with `None`, asking it for its window.
+    fn window_msg(&self, window: Option<u64>) -> StreamMessage {
+        StreamMessage::Reliable {
+            kind: RelKind::WindowUpdate,
+            stream_id: self.stream_id,
+            seqno: Seqno::ZERO,
+            payload: window.map_or(Bytes::new(), |window| {
+                Bytes::copy_from_slice(&window.to_le_bytes())
+            }),
+        }
+    }
+
+    /// How many more bytes the other side accepts, if it told us.
+    fn window_left(&self) -> Option<u64> {
+        self.peer_window
+            .map(|window| window.saturating_sub(self.write_offset))
+    }
 
+    fn start_recovery(&mut self) {
+        if !self.in_recovery {
+            proto_event!(Recovery, stream = self.stream_id, cwnd = self.cc.cwnd());
+            trace_event!(
+                tracing::Level::DEBUG,
+                inflight = self.inflight.inflight(),
+                cwnd = self.cc.cwnd(),
+                "loss"
+            );
+            let now = runtime::now();
+            self.recovery_started = Some(now);
+            self.cc.on_loss(now);
             self.in_recovery = true;
         }
     }
@@ -343,7 +1324,57 @@ impl StreamState {
     }
 
     fn congested(&self, now: Instant) -> bool {
-        self.inflight.inflight() - self.inflight.lost_at(now) >= self.cwnd as usize
+        let window = match self.queue_cap() {
+            Some(cap) => cap.min(self.cc.cwnd() as usize),
+            None => self.cc.cwnd() as usize,
+        };
+        if self.inflight.inflight() - self.inflight.lost_at(now) >= window {
+            return true;
+        }
+        // striped segments also need room on some pipe, except for a due retransmission, since sending that is what frees room on the pipe it was lost on
+        self.striped_routes().is_some_and(|routes| {
+            let retransmission_due = !matches!(self.frto, Frto::Probing)
+                && self
+                    .inflight
+                    .first_rto()
+                    .is_some_and(|(_, retrans_time)| now >= retrans_time);
+            !retransmission_due && !routes.has_room(now)
+        })
+    }
+
+    /// How many packets may be in flight so that, beyond a round trip's worth, no more than the queue budget's worth wait in queues along the path. None without a budget, or before the delivery rate is known.
+    fn queue_cap(&self) -> Option<usize> {
+        let budget = self.queue_budget?;
+        let rate = self.inflight.delivery_rate();
+        if rate <= 0.0 {
+            return None;
+        }
+        let cap = rate * (self.inflight.min_rtt() + budget).as_secs_f64();
+        Some((cap as usize).max(MIN_QUEUE_CAP))
+    }
+
+    /// Publishes how much of the written data is acked: everything up to the first segment still in flight.
+    fn update_acked_bytes(&mut self, now: Instant) {
+        let first_unacked = self
+            .inflight
+            .first_unacked()
+            .unwrap_or(self.next_write_seqno);
+        let mut acked = None;
+        while let Some(&(seqno, end)) = self.segment_ends.front() {
+            if seqno >= first_unacked {
+                break;
+            }
+            acked = Some(end);
+            self.segment_ends.pop_front();
+        }
+        if let Some(acked) = acked {
+            let previously_acked = {
+                let mut queues = self.queues.lock();
+                std::mem::replace(&mut queues.acked_bytes, acked)
+            };
+            self.send_throughput.on_delivered(acked - previously_acked, now);
+            self.local_notify.notify_all();
+        }
     }
 
     fn tick_write(&mut self, now: Instant, mut outgoing_callback: impl FnMut(StreamMessage)) {
@@ -357,6 +1388,7 @@ impl StreamState {
                     payload,
                 });
             }
+            self.stats.urel_dropped_queue_full = queues.urel_dropped_queue_full;
         }
 
         if self.inflight.lost_at(now) > 0 {
@@ -364,41 +1396,112 @@ impl StreamState {
         } else {
             self.stop_recovery();
         }
+        self.report_shared_congestion();
 
         // speed here is calculated based on the idea that we should be able to transmit a whole cwnd of things in an rtt.
-        let speed = self.speed();
-        let mut writes_allowed = (now
-            .saturating_duration_since(self.last_write_time)
-            .as_secs_f64()
-            * speed) as usize;
+        let speed = self.pacing_rate();
+        let mut writes_allowed = if self.paced() {
+            self.pacing.refill(now, speed, self.pacing_policy.max_burst)
+        } else {
+            usize::MAX
+        };
 
         while !self.congested(now) && writes_allowed > 0 {
             // we do any retransmissions if necessary
             if let Some((seqno, retrans_time)) = self.inflight.first_rto() {
                 if now >= retrans_time {
-                    log::debug!(
-                        "inflight = {}, lost = {}, cwnd = {}",
-                        self.inflight.inflight(),
-                        self.inflight.lost_at(now),
-                        self.cwnd
+                    match self.frto {
+                        Frto::Off if self.frto_enabled && self.inflight.timed_out_first(seqno) => {
+                            if self.retrans_budget_spent(now) {
+                                break;
+                            }
+                            // retransmit just this one, then send new data until acks show whether the timeout was spurious
+                            proto_event!(FrtoProbe, stream = self.stream_id, seqno = seqno);
+                            trace_event!(
+                                tracing::Level::DEBUG,
+                                seqno = seqno.0,
+                                timeout = true,
+                                "retransmit"
+                            );
+                            let first = self.inflight.retransmit(seqno).expect("no first");
+                            self.inflight.start_frto(seqno, now);
+                            self.frto = Frto::Probing;
+                            self.retrans_in_window += 1;
+                            self.stats.retransmissions += 1;
+                            self.pacing.take(self.pacing_policy.max_burst);
+                            writes_allowed -= 1;
+                            self.send_retransmission(first, &mut outgoing_callback);
+                            continue;
+                        }
+                        Frto::Probing
+                            if self.inflight.frto_expired(now)
+                                || self.queues.lock().write_stream.is_empty() =>
+                        {
+                            // without new data to send or acks showing anything, there is nothing left to tell by, so treat the timeout as real
+                            self.inflight.end_frto(false, now);
+                            self.frto = Frto::Conventional {
+                                until_seqno: self.next_write_seqno,
+                            };
+                        }
+                        _ => {}
+                    }
+                }
+                if now >= retrans_time && !matches!(self.frto, Frto::Probing) {
+                    if self.retrans_budget_spent(now) {
+                        // don't send new data ahead of the retransmissions either
+                        break;
+                    }
+                    proto_event!(
+                        Retransmit,
+                        stream = self.stream_id,
+                        seqno = seqno,
+                        inflight = self.inflight.inflight(),
+                        lost = self.inflight.lost_at(now),
+                        cwnd = self.cc.cwnd(),
+                        speed = speed,
+                    );
+                    trace_event!(
+                        tracing::Level::DEBUG,
+                        seqno = seqno.0,
+                        timeout = self.inflight.timed_out_first(seqno),
+                        "retransmit"
                     );
-                    log::debug!("*** retransmit {}", seqno);
                     let first = self.inflight.retransmit(seqno).expect("no first");
+                    self.retrans_in_window += 1;
+                    self.stats.retransmissions += 1;
+                    self.pacing.take(self.pacing_policy.max_burst);
                     writes_allowed -= 1;
-                    log::debug!("RETRANSMIT {seqno} at {:.2} pkts/s", speed);
-                    outgoing_callback(first);
+                    self.send_retransmission(first, &mut outgoing_callback);
                     continue;
                 }
             }
 
-            // okay, we don't have retransmissions. this means we get to send a "normal" packet.
+            // okay, we don't have retransmissions. this means we get to send a "normal" packet, unless the other side paused us and it's not yet time for a probe.
+            if self.peer_paused {
+                if now < self.next_probe {
+                    break;
+                }
+                self.next_probe = now + PERSIST_INTERVAL;
+            }
             let mut queues = self.queues.lock();
             if !queues.write_stream.is_empty() {
-                let mut buffer = vec![0; MSS];
+                let window_left = self.window_left().unwrap_or(u64::MAX);
+                if window_left == 0 {
+                    // the other side has no room, so ask now and then whether it made some, in case its update got lost
+                    if now >= self.next_probe {
+                        self.next_probe = now + PERSIST_INTERVAL;
+                        outgoing_callback(self.window_msg(None));
+                    }
+                    break;
+                }
+                let mut buffer =
+                    vec![0; self.mss.min(window_left.try_into().unwrap_or(usize::MAX))];
                 let n = queues.write_stream.read(&mut buffer).unwrap();
                 buffer.truncate(n);
                 let seqno = self.next_write_seqno;
                 self.next_write_seqno += 1;
+                self.write_offset += n as u64;
+                self.segment_ends.push_back((seqno, self.write_offset));
                 let msg = StreamMessage::Reliable {
                     kind: RelKind::Data,
                     stream_id: self.stream_id,
@@ -406,12 +1509,20 @@ impl StreamState {
                     payload: buffer.into(),
                 };
                 self.inflight.insert(msg.clone());
+                self.stats.data_packets_sent += 1;
                 self.local_notify.notify_all();
 
                 outgoing_callback(msg);
-                self.last_write_time = now;
+                self.pacing.take(self.pacing_policy.max_burst);
                 writes_allowed -= 1;
-                log::debug!("{seqno} at {:.2} pkts/s", speed);
+                proto_event!(
+                    Send,
+                    stream = self.stream_id,
+                    seqno = seqno,
+                    len = n,
+                    speed = speed
+                );
+                trace_event!(tracing::Level::TRACE, seqno = seqno.0, len = n, "send");
                 continue;
             } else {
                 queues.write_stream.shrink_to_fit();
@@ -419,23 +1530,175 @@ impl StreamState {
 
             break;
         }
+        self.report_shared_congestion();
+        self.update_send_stats();
+    }
+
+    /// Sends a retransmitted segment, in pieces if it no longer fits the MSS and the other side can put them back together.
+    fn send_retransmission(
+        &mut self,
+        msg: StreamMessage,
+        outgoing_callback: &mut impl FnMut(StreamMessage),
+    ) {
+        match msg {
+            StreamMessage::Reliable {
+                kind: RelKind::Data,
+                stream_id,
+                seqno,
+                payload,
+            } if self.fragments && payload.len() > self.mss => {
+                for piece in fragment::split(&payload, self.mss) {
+                    self.stats.fragments_sent += 1;
+                    outgoing_callback(StreamMessage::Reliable {
+                        kind: RelKind::DataFrag,
+                        stream_id,
+                        seqno,
+                        payload: piece,
+                    });
+                }
+            }
+            msg => outgoing_callback(msg),
+        }
+    }
+
+    /// Tells the other side that the handle finished writing, once everything it wrote is acked, repeating that until the other side answers. A side that does not understand that gets the whole stream closed instead.
+    fn tick_close_write(&mut self, now: Instant, mut outgoing_callback: impl FnMut(StreamMessage)) {
+        if self.eof_acked || !self.queues.lock().write_closed || self.has_pending_data() {
+            return;
+        }
+        if !self.half_close {
+            self.queues.lock().close(CloseReason::LocalShutdown);
+            return;
+        }
+        if self.eof_resend.is_none_or(|resend| now >= resend) {
+            outgoing_callback(StreamMessage::Reliable {
+                kind: RelKind::Eof,
+                stream_id: self.stream_id,
+                seqno: self.next_write_seqno,
+                payload: Bytes::new(),
+            });
+            self.eof_resend = Some(now + self.inflight.rto());
+        }
+    }
+
+    /// Closes the stream once its last handle was dropped, as soon as everything written to it is acked, or once it lingered for long enough.
+    fn tick_dropped(&mut self, now: Instant) {
+        let Some(deadline) = self.queues.lock().linger_until else {
+            return;
+        };
+        if now >= deadline || !self.has_pending_data() {
+            self.queues.lock().close(CloseReason::LocalShutdown);
+        }
+    }
+
+    /// Copies the state of the sending side into the stats, and publishes them.
+    fn update_send_stats(&mut self) {
+        self.stats.cwnd = self.cc.cwnd();
+        self.stats.inflight = self.inflight.inflight();
+        self.stats.retransmission_ratio = if self.stats.retransmissions == 0 {
+            0.0
+        } else {
+            self.stats.retransmissions as f64
+                / (self.stats.data_packets_sent + self.stats.retransmissions) as f64
+        };
+        self.stats.delivery_rate = self.inflight.delivery_rate();
+        self.stats.min_rtt = self.inflight.measured_min_rtt();
+        self.queues.lock().stats = self.stats.clone();
+    }
+
+    /// Whether this round trip's retransmissions have used up the burst limit, so that more must wait for the next one.
+    fn retrans_budget_spent(&mut self, now: Instant) -> bool {
+        if now.saturating_duration_since(self.retrans_window_start) >= self.inflight.min_rtt() {
+            self.retrans_window_start = now;
+            self.retrans_in_window = 0;
+        }
+        self.retrans_in_window >= self.retrans_burst
     }
 
     fn speed(&self) -> f64 {
-        (self.cwnd / self.inflight.min_rtt().as_secs_f64()).max(1.0)
+        let speed = self.cc.pacing_rate(self.inflight.min_rtt());
+        match self.peer_read_rate {
+            // a reader that speeds up drains its backlog and lifts the limit
+            Some((rate, _)) => speed.min(rate / self.mss as f64),
+            None => speed,
+        }
+        .max(1.0)
+    }
+
+    /// The rate at which pacing allows packets to be sent, per second.
+    fn pacing_rate(&self) -> f64 {
+        match self.pacing_policy.mode {
+            PacingMode::FixedRate(rate) => (rate / self.mss as f64).max(1.0),
+            PacingMode::Off | PacingMode::Cwnd => self.speed(),
+        }
     }
 
     fn retick_time(&self, now: Instant) -> Instant {
-        let idle = { self.inflight.inflight() == 0 && self.queues.lock().write_stream.is_empty() };
+        let (idle, linger_until) = {
+            let queues = self.queues.lock();
+            (
+                self.inflight.inflight() == 0 && queues.write_stream.is_empty(),
+                queues.linger_until,
+            )
+        };
 
-        if idle {
+        let next = if let Some(resend) = self.eof_resend.filter(|_| !self.eof_acked) {
+            resend
+        } else if idle {
             now + Duration::from_secs(100000)
+        } else if (self.peer_paused || self.window_left() == Some(0))
+            && self.inflight.inflight() == 0
+        {
+            self.next_probe
         } else {
-            now + Duration::from_secs_f64((1.0 / self.speed()))
+            now + Duration::from_secs_f64(1.0 / self.pacing_rate())
+        };
+        // a dropped stream closes at the latest when it is done lingering
+        linger_until.map_or(next, |deadline| next.min(deadline))
+    }
+}
+
+/// The packets that pacing allows a stream to send, a token bucket as deep as [PacingPolicy::max_burst].
+struct PacingBucket {
+    tokens: f64,
+    // when tokens were last added
+    refilled: Instant,
+}
+
+impl PacingBucket {
+    /// Adds the packets that became due at the given rate since the last refill, returning how many may be sent now.
+    fn refill(&mut self, now: Instant, rate: f64, max_burst: Option<usize>) -> usize {
+        let elapsed = now.saturating_duration_since(self.refilled);
+        self.refilled = now;
+        self.tokens += elapsed.as_secs_f64() * rate;
+        if let Some(burst) = max_burst {
+            self.tokens = self.tokens.min(burst.max(1) as f64);
         }
+        self.tokens as usize
+    }
+
+    /// Takes a packet's worth of tokens for a packet that was sent.
+    fn take(&mut self, max_burst: Option<usize>) {
+        self.tokens = match max_burst {
+            Some(_) => (self.tokens - 1.0).max(0.0),
+            // whatever was due goes out now, or not at all
+            None => 0.0,
+        };
     }
 }
 
+/// Where a stream is in detecting spurious retransmission timeouts.
+#[derive(Clone, Copy, Debug)]
+enum Frto {
+    Off,
+    /// A timed-out packet was retransmitted alone, and acks will tell whether the timeout was spurious.
+    Probing,
+    /// The timeout was real, so everything that times out is retransmitted until everything sent before `until_seqno` is acked.
+    Conventional {
+        until_seqno: Seqno,
+    },
+}
+
 #[derive(Clone, Copy, Debug)]
 enum Phase {
     Pending,
@@ -443,3 +1706,743 @@ enum Phase {
     Established,
     Closed,
 }
+
+/// Checks that selective acks only cover packets that were sent.
+fn check_sack_ranges(
+    ranges: impl Iterator<Item = (Seqno, Seqno)>,
+    next_write_seqno: Seqno,
+) -> Result<(), ProtocolViolation> {
+    // the ranges are in ascending order
+    match ranges.last() {
+        Some((_, end)) if end > next_write_seqno => Err(ProtocolViolation::AckBeyondSent),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use smol::prelude::*;
+    use stdcode::StdcodeSerializeExt;
+
+    use super::*;
+
+    fn reliable(kind: RelKind, seqno: Seqno, payload: impl Into<Bytes>) -> StreamMessage {
+        StreamMessage::Reliable {
+            kind,
+            stream_id: StreamId(1),
+            seqno,
+            payload: payload.into(),
+        }
+    }
+
+    /// Feeds the messages to a fresh stream, returning what it sends back and what reading it then returns.
+    fn feed(msgs: Vec<StreamMessage>) -> (Vec<StreamMessage>, std::io::Result<usize>) {
+        let (mut state, mut stream) =
+            StreamState::new_established(|| {}, StreamId(1), String::new());
+        for msg in msgs {
+            state.inject_incoming(msg);
+        }
+        let mut sent = vec![];
+        // the second tick finishes closing the stream, if it was reset
+        for _ in 0..2 {
+            state.tick(|msg| sent.push(msg));
+        }
+        let mut buf = [0u8; 10];
+        let read = smol::future::block_on(
+            stream
+                .read(&mut buf)
+                .or(async { Err(std::io::ErrorKind::WouldBlock.into()) }),
+        );
+        (sent, read)
+    }
+
+    fn reset_code(sent: &[StreamMessage]) -> Option<ResetCode> {
+        sent.iter().find_map(|msg| match msg {
+            StreamMessage::Reliable {
+                kind: RelKind::Rst,
+                payload,
+                ..
+            } => Some(ResetCode::from_payload(payload)),
+            _ => None,
+        })
+    }
+
+    fn violation(read: std::io::Result<usize>) -> Option<ProtocolViolation> {
+        read.err()?
+            .into_inner()?
+            .downcast::<ProtocolViolation>()
+            .ok()
+            .map(|violation| *violation)
+    }
+
+    #[test]
+    fn data_at_the_last_seqno() {
+        // this used to overflow while computing the reorder depth
+        let (sent, read) = feed(vec![reliable(RelKind::Data, Seqno::MAX, "x")]);
+        assert_eq!(reset_code(&sent), None);
+        assert_eq!(read.unwrap_err().kind(), std::io::ErrorKind::WouldBlock);
+    }
+
+    #[test]
+    fn old_style_ack_of_the_last_seqno() {
+        // this used to overflow while turning the acks into ranges
+        let (sent, read) = feed(vec![reliable(
+            RelKind::DataAck,
+            Seqno::ZERO,
+            vec![Seqno::MAX].stdcode(),
+        )]);
+        assert_eq!(reset_code(&sent), Some(ResetCode::ProtocolViolation));
+        assert_eq!(violation(read), Some(ProtocolViolation::AckBeyondSent));
+    }
+
+    #[test]
+    fn ack_beyond_sent() {
+        let (sent, read) = feed(vec![reliable(
+            RelKind::DataAckCompact,
+            Seqno(5),
+            vec![0; 4],
+        )]);
+        assert_eq!(reset_code(&sent), Some(ResetCode::ProtocolViolation));
+        assert_eq!(violation(read), Some(ProtocolViolation::AckBeyondSent));
+    }
+
+    #[test]
+    fn malformed_messages() {
+        let (_, read) = feed(vec![reliable(
+            RelKind::DataAckCompact,
+            Seqno::ZERO,
+            vec![1, 0],
+        )]);
+        assert_eq!(violation(read), Some(ProtocolViolation::MalformedAck));
+        let (_, read) = feed(vec![reliable(
+            RelKind::WindowUpdate,
+            Seqno::ZERO,
+            vec![1, 2, 3],
+        )]);
+        assert_eq!(
+            violation(read),
+            Some(ProtocolViolation::MalformedWindowUpdate)
+        );
+        let (_, read) = feed(vec![reliable(
+            RelKind::DataFrag,
+            Seqno::ZERO,
+            vec![1, 2, 3],
+        )]);
+        assert_eq!(violation(read), Some(ProtocolViolation::MalformedFragment));
+    }
+
+    #[test]
+    fn peer_reset_over_violation() {
+        let (_, read) = feed(vec![reliable(
+            RelKind::Rst,
+            Seqno::ZERO,
+            ResetCode::ProtocolViolation.to_payload(),
+        )]);
+        assert_eq!(
+            read.unwrap_err().kind(),
+            std::io::ErrorKind::ConnectionReset
+        );
+    }
+
+    #[test]
+    fn send_stats() {
+        let (mut state, mut stream) =
+            StreamState::new_established(|| {}, StreamId(1), String::new());
+        smol::future::block_on(stream.write_all(&[0; 5000])).unwrap();
+        assert_eq!(stream.stats().write_queue_bytes, 5000);
+        // the pacing starts slow, at a few packets a second
+        std::thread::sleep(Duration::from_millis(500));
+        let mut sent = 0;
+        state.tick(|msg| {
+            if matches!(
+                msg,
+                StreamMessage::Reliable {
+                    kind: RelKind::Data,
+                    ..
+                }
+            ) {
+                sent += 1;
+            }
+        });
+        let stats = stream.stats();
+        assert!(sent > 0);
+        assert_eq!(stats.data_packets_sent, sent);
+        assert_eq!(stats.inflight, sent as usize);
+        assert_eq!(stats.retransmissions, 0);
+        assert!(stats.cwnd > 0.0);
+        assert!(stats.write_queue_bytes < 5000);
+        assert_eq!(state.snapshot().inflight, stats.inflight);
+    }
+
+    #[test]
+    fn delivery_receipts() {
+        let (mut state, mut stream) =
+            StreamState::new_established(|| {}, StreamId(1), String::new());
+        state.set_pacing_policy(PacingPolicy {
+            mode: PacingMode::Off,
+            ..Default::default()
+        });
+        smol::future::block_on(stream.write_all(&vec![0; MSS * 2 + 100])).unwrap();
+        let mut segment_lens = vec![];
+        state.tick(|msg| {
+            if let StreamMessage::Reliable {
+                kind: RelKind::Data,
+                payload,
+                ..
+            } = msg
+            {
+                segment_lens.push(payload.len() as u64);
+            }
+        });
+        assert_eq!(segment_lens.len(), 3);
+        assert_eq!(stream.bytes_written(), segment_lens.iter().sum::<u64>());
+        let mut ack = |lowest_unseen: u64, sacks: Vec<Seqno>| {
+            state.inject_incoming(reliable(
+                RelKind::DataAck,
+                Seqno(lowest_unseen),
+                sacks.stdcode(),
+            ));
+            state.tick(|_| {});
+            stream.bytes_acked()
+        };
+
+        // a later segment arriving does not count until everything before it has too
+        assert_eq!(ack(0, vec![Seqno(1)]), 0);
+        assert_eq!(ack(1, vec![]), segment_lens[0] + segment_lens[1]);
+        let acked = smol::future::block_on(
+            stream
+                .wait_until_acked(None)
+                .or(async { Err(std::io::ErrorKind::WouldBlock.into()) }),
+        );
+        assert_eq!(acked.unwrap_err().kind(), std::io::ErrorKind::WouldBlock);
+        assert_eq!(ack(3, vec![]), stream.bytes_written());
+        smol::future::block_on(stream.wait_until_acked(None)).unwrap();
+
+        // what was written since still waits, until the stream closes under it
+        smol::future::block_on(stream.write_all(b"more")).unwrap();
+        let upto = stream.bytes_written();
+        state.inject_incoming(reliable(RelKind::Rst, Seqno::ZERO, Bytes::new()));
+        for _ in 0..2 {
+            state.tick(|_| {});
+        }
+        assert!(smol::future::block_on(stream.wait_until_acked(Some(upto))).is_err());
+    }
+
+    #[test]
+    fn pacing_exemption() {
+        let data_sent = |state: &mut StreamState| {
+            let mut sent = 0;
+            state.tick(|msg| {
+                if matches!(
+                    msg,
+                    StreamMessage::Reliable {
+                        kind: RelKind::Data,
+                        ..
+                    }
+                ) {
+                    sent += 1;
+                }
+            });
+            sent
+        };
+        for (policy, exempt) in [
+            (PacingPolicy::default(), false),
+            (
+                PacingPolicy {
+                    exempt_below: 100_000,
+                    ..Default::default()
+                },
+                true,
+            ),
+        ] {
+            let (mut state, mut stream) =
+                StreamState::new_established(|| {}, StreamId(1), String::new());
+            state.set_pacing_policy(policy);
+            smol::future::block_on(stream.write_all(&[0; 100])).unwrap();
+            // the pacing starts slow, at a few packets a second
+            std::thread::sleep(Duration::from_millis(500));
+            assert_eq!(data_sent(&mut state), 1);
+            // right after a packet, the pacing rate allows no more, but the congestion window does
+            smol::future::block_on(stream.write_all(&[0; 2000])).unwrap();
+            let sent = data_sent(&mut state);
+            assert_eq!(sent, if exempt { 2 } else { 0 }, "{policy:?}");
+        }
+    }
+
+    #[test]
+    fn pacing_bursts() {
+        let data_sent = |state: &mut StreamState| {
+            let mut sent = 0;
+            state.tick(|msg| {
+                if matches!(
+                    msg,
+                    StreamMessage::Reliable {
+                        kind: RelKind::Data,
+                        ..
+                    }
+                ) {
+                    sent += 1;
+                }
+            });
+            sent
+        };
+        let (mut state, mut stream) =
+            StreamState::new_established(|| {}, StreamId(1), String::new());
+        state.set_pacing_policy(PacingPolicy {
+            mode: PacingMode::FixedRate(MSS as f64 * 1000.0),
+            max_burst: Some(2),
+            ..Default::default()
+        });
+        smol::future::block_on(stream.write_all(&[0; 100_000])).unwrap();
+        // a packet a millisecond, but no more than 2 saved up
+        std::thread::sleep(Duration::from_millis(50));
+        assert_eq!(data_sent(&mut state), 2);
+        assert_eq!(data_sent(&mut state), 0);
+
+        // without pacing, only the congestion window holds the stream back
+        state.set_pacing_policy(PacingPolicy {
+            mode: PacingMode::Off,
+            ..Default::default()
+        });
+        assert!(data_sent(&mut state) > 0);
+    }
+
+    #[test]
+    fn mss_shrinks_in_flight() {
+        let payload_len = |msg: &StreamMessage| match msg {
+            StreamMessage::Reliable { payload, .. } => payload.len(),
+            _ => 0,
+        };
+        let (mut sender, mut opened) =
+            StreamState::new_established(|| {}, StreamId(1), String::new());
+        let (mut receiver, mut accepted) =
+            StreamState::new_established(|| {}, StreamId(1), String::new());
+        for state in [&mut sender, &mut receiver] {
+            state.set_peer_version(crate::frame::PROTOCOL_VERSION);
+            state.set_pacing_policy(PacingPolicy {
+                mode: PacingMode::Off,
+                ..Default::default()
+            });
+            state
+                .inflight
+                .seed_rtt(Duration::from_millis(20), Duration::ZERO);
+        }
+        let data: Vec<u8> = (0..5000u32).map(|i| i as u8).collect();
+        smol::future::block_on(opened.write_all(&data)).unwrap();
+        // full-sized segments go out, and then the path stops carrying them
+        let mut first = vec![];
+        sender.tick(|msg| first.push(msg));
+        assert!(first.iter().any(|msg| payload_len(msg) == MSS));
+        sender.set_mss(300);
+
+        let mut received = vec![0u8; data.len()];
+        let mut read = 0;
+        let start = Instant::now();
+        while read < data.len() {
+            assert!(start.elapsed() < Duration::from_secs(10), "stuck at {read}");
+            std::thread::sleep(Duration::from_millis(50));
+            let mut to_receiver = vec![];
+            sender.tick(|msg| to_receiver.push(msg));
+            for msg in to_receiver {
+                if payload_len(&msg) <= 300 {
+                    receiver.inject_incoming(msg);
+                }
+            }
+            let mut to_sender = vec![];
+            receiver.tick(|msg| to_sender.push(msg));
+            for msg in to_sender {
+                sender.inject_incoming(msg);
+            }
+            read +=
+                smol::future::block_on(accepted.read(&mut received[read..]).or(async { Ok(0) }))
+                    .unwrap();
+        }
+        assert_eq!(received, data);
+        assert!(opened.stats().fragments_sent > 0);
+    }
+
+    fn sends_kind(state: &mut StreamState, kind: RelKind) -> bool {
+        let mut sent = vec![];
+        state.tick(|msg| sent.push(msg));
+        sent.iter()
+            .any(|msg| matches!(msg, StreamMessage::Reliable { kind: k, .. } if *k == kind))
+    }
+
+    #[test]
+    fn pause_only_reaches_peers_that_understand_it() {
+        for (version, understood) in [(12, false), (crate::frame::PROTOCOL_VERSION, true)] {
+            let (mut state, stream) =
+                StreamState::new_established(|| {}, StreamId(1), String::new());
+            state.set_peer_version(version);
+            stream.pause_reading();
+            assert_eq!(sends_kind(&mut state, RelKind::Pause), understood);
+            stream.resume_reading();
+            assert_eq!(sends_kind(&mut state, RelKind::Resume), understood);
+        }
+    }
+
+    #[test]
+    fn early_data_only_goes_to_peers_that_understand_it() {
+        for (version, understood) in [(14, false), (crate::frame::PROTOCOL_VERSION, true)] {
+            let (mut state, mut stream) =
+                StreamState::new_pending(|| {}, StreamId(1), String::new());
+            state.set_peer_version(version);
+            state.set_pacing_policy(PacingPolicy {
+                mode: PacingMode::Off,
+                ..Default::default()
+            });
+            state.allow_early_data();
+            smol::future::block_on(stream.write_all(b"request")).unwrap();
+            assert_eq!(sends_kind(&mut state, RelKind::Data), understood);
+
+            // data that overtook the SYN is no refusal
+            state.inject_incoming(reliable(
+                RelKind::Rst,
+                Seqno::ZERO,
+                ResetCode::UnknownStream.to_payload(),
+            ));
+            // the second tick finishes closing the stream, if it was refused
+            for _ in 0..2 {
+                state.tick(|_| {});
+            }
+            assert_eq!(
+                stream.close_reason(),
+                (!understood).then_some(CloseReason::Refused)
+            );
+        }
+
+        // while any other reset is
+        let (mut state, stream) = StreamState::new_pending(|| {}, StreamId(1), String::new());
+        state.set_peer_version(crate::frame::PROTOCOL_VERSION);
+        state.allow_early_data();
+        state.tick(|_| {});
+        state.inject_incoming(reliable(RelKind::Rst, Seqno::ZERO, Bytes::new()));
+        for _ in 0..2 {
+            state.tick(|_| {});
+        }
+        assert_eq!(stream.close_reason(), Some(CloseReason::Refused));
+    }
+
+    #[test]
+    fn read_rate_only_reaches_peers_that_understand_it() {
+        for (version, understood) in [(13, false), (crate::frame::PROTOCOL_VERSION, true)] {
+            let (mut state, _stream) =
+                StreamState::new_established(|| {}, StreamId(1), String::new());
+            state.set_peer_version(version);
+            state.set_read_rate_feedback(true);
+            // a backlog of unread data
+            for i in 0..READ_RATE_BACKLOG / 1000 + 1 {
+                state.inject_incoming(reliable(RelKind::Data, Seqno(i as u64), vec![0; 1000]));
+            }
+            assert_eq!(sends_kind(&mut state, RelKind::ReadRate), understood);
+        }
+    }
+
+    /// A sender with `len` bytes to send, and a receiver whose read buffer holds `read_buffer` bytes, both unpaced and speaking the current protocol.
+    fn window_pair(
+        len: usize,
+        read_buffer: usize,
+    ) -> (StreamState, crate::Stream, StreamState, crate::Stream) {
+        let (mut sender, opened) = StreamState::new_established(|| {}, StreamId(1), String::new());
+        let (mut receiver, accepted) =
+            StreamState::new_established(|| {}, StreamId(1), String::new());
+        for state in [&mut sender, &mut receiver] {
+            state.set_peer_version(crate::frame::PROTOCOL_VERSION);
+            state.set_pacing_policy(PacingPolicy {
+                mode: PacingMode::Off,
+                ..Default::default()
+            });
+            state
+                .inflight
+                .seed_rtt(Duration::from_millis(20), Duration::ZERO);
+        }
+        opened.set_options(crate::StreamOptions {
+            write_buffer: len,
+            ..Default::default()
+        });
+        accepted.set_options(crate::StreamOptions {
+            read_buffer,
+            ..Default::default()
+        });
+        smol::future::block_on(opened.clone().write_all(&vec![0u8; len])).unwrap();
+        (sender, opened, receiver, accepted)
+    }
+
+    /// Ticks the receiver and then the sender a few times, passing along what each sends except what `lost` picks out of the receiver's messages.
+    fn exchange(
+        sender: &mut StreamState,
+        receiver: &mut StreamState,
+        mut lost: impl FnMut(&StreamMessage) -> bool,
+    ) {
+        for _ in 0..20 {
+            let mut to_sender = vec![];
+            receiver.tick(|msg| to_sender.push(msg));
+            for msg in to_sender {
+                if !lost(&msg) {
+                    sender.inject_incoming(msg);
+                }
+            }
+            let mut to_receiver = vec![];
+            sender.tick(|msg| to_receiver.push(msg));
+            for msg in to_receiver {
+                receiver.inject_incoming(msg);
+            }
+        }
+    }
+
+    fn is_window_update(msg: &StreamMessage) -> bool {
+        matches!(
+            msg,
+            StreamMessage::Reliable {
+                kind: RelKind::WindowUpdate,
+                payload,
+                ..
+            } if payload.len() == 8
+        )
+    }
+
+    #[test]
+    fn slow_reader_stops_the_sender() {
+        let (mut sender, _opened, mut receiver, mut accepted) = window_pair(100_000, 10_000);
+        exchange(&mut sender, &mut receiver, |_| false);
+        // the sender stops right at the end of the window, however long it waits
+        assert_eq!(sender.write_offset, 10_000);
+        exchange(&mut sender, &mut receiver, |_| false);
+        assert_eq!(sender.write_offset, 10_000);
+
+        // reading makes room for as much more
+        let mut buf = vec![0u8; 4000];
+        smol::future::block_on(accepted.read_exact(&mut buf)).unwrap();
+        exchange(&mut sender, &mut receiver, |_| false);
+        assert_eq!(sender.write_offset, 14_000);
+    }
+
+    #[test]
+    fn window_probe_recovers_a_lost_update() {
+        let (mut sender, _opened, mut receiver, mut accepted) = window_pair(100_000, 10_000);
+        exchange(&mut sender, &mut receiver, |_| false);
+        assert_eq!(sender.write_offset, 10_000);
+
+        // the update that opens the window is lost, and is not sent again by itself
+        let mut buf = vec![0u8; 5000];
+        smol::future::block_on(accepted.read_exact(&mut buf)).unwrap();
+        exchange(&mut sender, &mut receiver, is_window_update);
+        assert_eq!(sender.write_offset, 10_000);
+        exchange(&mut sender, &mut receiver, |_| false);
+        assert_eq!(sender.write_offset, 10_000);
+
+        // until the sender asks for it
+        std::thread::sleep(PERSIST_INTERVAL);
+        exchange(&mut sender, &mut receiver, |_| false);
+        assert_eq!(sender.write_offset, 15_000);
+    }
+
+    #[test]
+    fn malformed_window_update_resets() {
+        let (mut sender, opened, mut receiver, _accepted) = window_pair(1000, 10_000);
+        exchange(&mut sender, &mut receiver, |_| false);
+        sender.inject_incoming(reliable(RelKind::WindowUpdate, Seqno::ZERO, vec![0; 9]));
+        let mut sent = vec![];
+        for _ in 0..2 {
+            sender.tick(|msg| sent.push(msg));
+        }
+        assert_eq!(reset_code(&sent), Some(ResetCode::ProtocolViolation));
+        assert_eq!(
+            opened.close_reason(),
+            Some(CloseReason::ProtocolViolation(
+                ProtocolViolation::MalformedWindowUpdate
+            ))
+        );
+    }
+
+    fn data_seqnos(msgs: &[StreamMessage]) -> Vec<u64> {
+        msgs.iter()
+            .filter_map(|msg| match msg {
+                StreamMessage::Reliable {
+                    kind: RelKind::Data,
+                    seqno,
+                    ..
+                } => Some(seqno.0),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Delivers the messages to the receiver, and its acks back to the sender, returning what the sender sends next.
+    fn deliver(
+        sender: &mut StreamState,
+        receiver: &mut StreamState,
+        msgs: impl IntoIterator<Item = StreamMessage>,
+    ) -> Vec<StreamMessage> {
+        for msg in msgs {
+            receiver.inject_incoming(msg);
+        }
+        let mut acks = vec![];
+        receiver.tick(|msg| acks.push(msg));
+        for msg in acks {
+            sender.inject_incoming(msg);
+        }
+        let mut sent = vec![];
+        sender.tick(|msg| sent.push(msg));
+        sent
+    }
+
+    /// Sends the initial window, lets it time out without any ack, and returns the original transmissions and what the sender sends after the timeout, along with the window before the timeout.
+    fn time_out_first_window(
+        sender: &mut StreamState,
+    ) -> (Vec<StreamMessage>, Vec<StreamMessage>, f64) {
+        let mut originals = vec![];
+        sender.tick(|msg| originals.push(msg));
+        let cwnd = sender.cc.cwnd();
+        std::thread::sleep(sender.inflight.rto());
+        let mut after = vec![];
+        sender.tick(|msg| after.push(msg));
+        (originals, after, cwnd)
+    }
+
+    #[test]
+    fn frto_spurious_timeout() {
+        let (mut sender, _opened, mut receiver, _accepted) = window_pair(20 * MSS, 1_000_000);
+        let (originals, after, cwnd) = time_out_first_window(&mut sender);
+        assert_eq!(data_seqnos(&originals), [0, 1, 2, 3]);
+        // only the first packet is retransmitted, and new data goes out behind it
+        assert_eq!(data_seqnos(&after), [0, 4, 5]);
+        assert!(matches!(sender.frto, Frto::Probing));
+        assert!(sender.cc.cwnd() < cwnd);
+
+        // the originals were only late: the first ack covers the first of them, the next the rest
+        let mut originals = originals.into_iter().filter(|msg| {
+            matches!(
+                msg,
+                StreamMessage::Reliable {
+                    kind: RelKind::Data,
+                    ..
+                }
+            )
+        });
+        let mut sent = deliver(&mut sender, &mut receiver, originals.next());
+        assert!(matches!(sender.frto, Frto::Probing));
+        sent.extend(deliver(&mut sender, &mut receiver, originals));
+        assert!(matches!(sender.frto, Frto::Off));
+        // the window is back where it was before the timeout, and then some for the acks
+        assert!(sender.cc.cwnd() >= cwnd);
+        assert_eq!(sender.stats.spurious_retransmissions, 1);
+        assert!(data_seqnos(&sent).iter().all(|seqno| *seqno > 5));
+    }
+
+    #[test]
+    fn frto_genuine_timeout() {
+        let (mut sender, _opened, mut receiver, _accepted) = window_pair(20 * MSS, 1_000_000);
+        let (_, after, _) = time_out_first_window(&mut sender);
+        assert_eq!(data_seqnos(&after), [0, 4, 5]);
+
+        // the originals are gone, so the acks of the probe and of the new data leave them unacked
+        let mut sent = deliver(&mut sender, &mut receiver, after);
+        assert!(matches!(sender.frto, Frto::Probing));
+        let mut retransmitted = vec![];
+        for _ in 0..10 {
+            retransmitted.extend(data_seqnos(&sent).into_iter().filter(|seqno| *seqno < 4));
+            sent = deliver(&mut sender, &mut receiver, sent);
+        }
+        // and everything that timed out is retransmitted
+        assert_eq!(retransmitted, [1, 2, 3]);
+        assert!(sender.inflight.first_unacked().is_none_or(|seqno| seqno.0 >= 4));
+        assert_eq!(sender.stats.spurious_retransmissions, 0);
+    }
+
+    #[test]
+    fn close_reasons() {
+        for (msg, reason) in [
+            (
+                reliable(RelKind::Fin, Seqno::ZERO, Bytes::new()),
+                CloseReason::PeerClosed,
+            ),
+            (
+                reliable(RelKind::Rst, Seqno::ZERO, Bytes::new()),
+                CloseReason::PeerReset,
+            ),
+            (
+                reliable(RelKind::DataAckCompact, Seqno(5), vec![0; 4]),
+                CloseReason::ProtocolViolation(ProtocolViolation::AckBeyondSent),
+            ),
+        ] {
+            let (mut state, stream) =
+                StreamState::new_established(|| {}, StreamId(1), String::new());
+            let clone = stream.clone();
+            assert_eq!(clone.close_reason(), None);
+            state.inject_incoming(msg);
+            for _ in 0..2 {
+                state.tick(|_| {});
+            }
+            assert_eq!(clone.close_reason(), Some(reason));
+            let err = smol::future::block_on(stream.recv_urel()).unwrap_err();
+            assert_eq!(
+                err.get_ref()
+                    .and_then(|err| err.downcast_ref::<CloseReason>()),
+                Some(&reason)
+            );
+        }
+        let (state, stream) = StreamState::new_established(|| {}, StreamId(1), String::new());
+        drop(state);
+        assert_eq!(stream.close_reason(), Some(CloseReason::MultiplexClosed));
+    }
+
+    #[test]
+    fn read_buffer_min_is_clamped() {
+        let (mut state, mut stream) =
+            StreamState::new_established(|| {}, StreamId(1), String::new());
+        stream.set_options(crate::StreamOptions {
+            read_buffer: 10,
+            ..Default::default()
+        });
+        // more than the other side ever sends at once, so readers are woken by a full read buffer instead
+        stream.set_read_buffer_min(1000);
+        state.inject_incoming(reliable(RelKind::Data, Seqno::ZERO, vec![7u8; 10]));
+        state.tick(|_| {});
+        let mut buf = [0u8; 100];
+        let read = smol::future::block_on(
+            stream
+                .read(&mut buf)
+                .or(async { Err(std::io::ErrorKind::WouldBlock.into()) }),
+        );
+        assert_eq!(read.unwrap(), 10);
+
+        // a mark of zero wakes readers once anything arrives, as the default does, rather than right away
+        stream.set_read_buffer_min(0);
+        let read = smol::future::block_on(
+            stream
+                .read(&mut buf)
+                .or(async { Err(std::io::ErrorKind::WouldBlock.into()) }),
+        );
+        assert_eq!(read.unwrap_err().kind(), std::io::ErrorKind::WouldBlock);
+    }
+
+    #[test]
+    fn half_close() {
+        smol::block_on(async {
+            let server_sk = crate::MuxSecret::generate();
+            let server = crate::Multiplex::new(server_sk.clone(), None);
+            let client =
+                crate::Multiplex::new(crate::MuxSecret::generate(), Some(server_sk.to_public()));
+            let (client_pipe, server_pipe) = crate::sim::sim_pipe_pair(Default::default());
+            client.add_pipe(client_pipe);
+            server.add_pipe(server_pipe);
+
+            let mut opened = client.open_conn("").await.unwrap();
+            let mut accepted = server.accept_conn().await.unwrap();
+            opened.write_all(b"request").await.unwrap();
+            opened.close().await.unwrap();
+            assert!(opened.write_all(b"more").await.is_err());
+            // the other side reads to the end, and can still answer
+            let mut request = vec![];
+            accepted.read_to_end(&mut request).await.unwrap();
+            assert_eq!(request, b"request");
+            accepted.write_all(b"response").await.unwrap();
+            accepted.close_write();
+            let mut response = vec![];
+            opened.read_to_end(&mut response).await.unwrap();
+            assert_eq!(response, b"response");
+            assert_eq!(opened.close_reason(), None);
+        })
+    }
+}
@@ -0,0 +1,54 @@
+use std::time::{Duration, Instant};
+
+/// Throughput is sampled over windows of at least this long.
+const SAMPLE_INTERVAL: Duration = Duration::from_millis(250);
+/// A gap this long without any delivery means the stream was idle, which should not count as slowness.
+const IDLE_GAP: Duration = Duration::from_millis(500);
+/// The weight of each new sample in the moving average.
+const ALPHA: f64 = 0.25;
+
+/// An exponentially weighted moving average of delivered bytes per second, taken only over the periods when data was actually flowing.
+#[derive(Default)]
+pub struct ThroughputEstimator {
+    ewma: f64,
+    window_start: Option<Instant>,
+    window_bytes: u64,
+    last_delivery: Option<Instant>,
+}
+
+impl ThroughputEstimator {
+    /// Records that some bytes were delivered.
+    pub fn on_delivered(&mut self, bytes: u64, now: Instant) {
+        if self
+            .last_delivery
+            .is_some_and(|last| now.saturating_duration_since(last) > IDLE_GAP)
+        {
+            // the stream was idle, so start over rather than averaging the idle time in
+            self.window_start = None;
+        }
+        self.last_delivery = Some(now);
+        let Some(window_start) = self.window_start else {
+            // the first delivery of a busy period only marks its start, since we don't know how long it took
+            self.window_start = Some(now);
+            self.window_bytes = 0;
+            return;
+        };
+        self.window_bytes += bytes;
+        let elapsed = now.saturating_duration_since(window_start);
+        if elapsed >= SAMPLE_INTERVAL {
+            let sample = self.window_bytes as f64 / elapsed.as_secs_f64();
+            self.ewma = if self.ewma == 0.0 {
+                sample
+            } else {
+                ALPHA * sample + (1.0 - ALPHA) * self.ewma
+            };
+            self.window_start = Some(now);
+            self.window_bytes = 0;
+        }
+    }
+
+    /// The current estimate, in bytes per second.
+    pub fn estimate(&self) -> f64 {
+        self.ewma
+    }
+}
@@ -0,0 +1,50 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+
+use crate::{Pipe, Stream};
+
+/// A [Pipe] that carries datagrams as the unreliable datagrams of a [Stream], so that a [crate::Multiplex] can run inside a stream of another one, e.g. to chain through several relays onion-style.
+///
+/// Both ends of the stream should be wrapped. Since the inner multiplex does its own retransmission, nothing is retransmitted twice; but the outer multiplex's overhead makes the inner datagrams too large for most paths, so the inner multiplex should be given a smaller [crate::Multiplex::set_mss], e.g. 1000.
+pub struct StreamPipe {
+    stream: Stream,
+    peer_addr: String,
+}
+
+impl StreamPipe {
+    /// Wraps a stream in a pipe.
+    pub fn new(stream: Stream) -> Self {
+        static COUNTER: AtomicU64 = AtomicU64::new(0);
+        let peer_addr = format!(
+            "stream-{}-{}",
+            stream.label(),
+            COUNTER.fetch_add(1, Ordering::Relaxed)
+        );
+        Self { stream, peer_addr }
+    }
+}
+
+#[async_trait]
+impl Pipe for StreamPipe {
+    fn send(&self, to_send: Bytes) {
+        self.stream.push_urel(to_send)
+    }
+
+    async fn recv(&self) -> std::io::Result<Bytes> {
+        self.stream.recv_urel().await
+    }
+
+    fn protocol(&self) -> &str {
+        "stream"
+    }
+
+    fn peer_metadata(&self) -> &str {
+        self.stream.label()
+    }
+
+    fn peer_addr(&self) -> String {
+        self.peer_addr.clone()
+    }
+}
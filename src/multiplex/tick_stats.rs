@@ -0,0 +1,84 @@
+#[cfg(feature = "metrics")]
+use super::windowed::{WindowRates, Windowed, WindowedCounters};
+
+/// Counts of the work done by the task that drives a [crate::Multiplex]'s streams. [crate::Multiplex::tick_rates] gives their recent rates, e.g. to check that a busy multiplex is not woken up more often than needed.
+#[cfg(feature = "metrics")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TickStats {
+    /// Times the multiplex ticked its streams.
+    pub ticks: u64,
+    /// Times any single stream was ticked.
+    pub stream_ticks: u64,
+    /// Times a stream asked to be ticked, because of reads, writes, or incoming packets.
+    pub notifications: u64,
+    /// Notifications that were folded into a tick the stream had already asked for, and so cost nothing extra.
+    pub coalesced: u64,
+}
+
+/// Rates of [TickStats], per second. See [crate::Multiplex::tick_rates].
+#[cfg(feature = "metrics")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TickRates {
+    pub ticks: f64,
+    pub stream_ticks: f64,
+    pub notifications: f64,
+    pub coalesced: f64,
+}
+
+#[cfg(feature = "metrics")]
+impl Windowed for TickStats {
+    type Rates = TickRates;
+
+    fn rates_since(&self, earlier: &Self, secs: f64) -> TickRates {
+        TickRates {
+            ticks: (self.ticks - earlier.ticks) as f64 / secs,
+            stream_ticks: (self.stream_ticks - earlier.stream_ticks) as f64 / secs,
+            notifications: (self.notifications - earlier.notifications) as f64 / secs,
+            coalesced: (self.coalesced - earlier.coalesced) as f64 / secs,
+        }
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct TickCounters {
+    #[cfg(feature = "metrics")]
+    counters: WindowedCounters<TickStats>,
+}
+
+impl TickCounters {
+    pub fn on_tick(&self) {
+        #[cfg(feature = "metrics")]
+        self.counters.update(|stats| stats.ticks += 1);
+    }
+
+    pub fn on_stream_tick(&self) {
+        #[cfg(feature = "metrics")]
+        self.counters.update(|stats| stats.stream_ticks += 1);
+    }
+
+    #[cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+    pub fn on_notification(&self, coalesced: bool) {
+        #[cfg(feature = "metrics")]
+        self.counters.update(|stats| {
+            stats.notifications += 1;
+            if coalesced {
+                stats.coalesced += 1;
+            }
+        });
+    }
+
+    #[cfg(feature = "metrics")]
+    pub fn snapshot(&self) -> TickStats {
+        self.counters.snapshot()
+    }
+
+    #[cfg(feature = "metrics")]
+    pub fn rates(&self) -> WindowRates<TickRates> {
+        self.counters.rates()
+    }
+
+    #[cfg(feature = "metrics")]
+    pub fn reset(&self) -> TickStats {
+        self.counters.reset()
+    }
+}
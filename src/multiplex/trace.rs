@@ -1,69 +1,71 @@
-use once_cell::sync::Lazy;
-use std::io::Write;
-use std::{fs::File, time::Instant};
-
 use crate::multiplex::stream::StreamMessage;
 
-static START: Lazy<Instant> = Lazy::new(Instant::now);
-
-pub fn trace_outgoing_msg(msg: &StreamMessage) {
-    static TRACE_OUTGOING: Lazy<Option<File>> = Lazy::new(|| {
-        if let Ok(fname) = std::env::var("SOSISTAB_TRACE_OUTGOING") {
-            let mut file =
-                File::create(fname).expect("cannot create file for SOSISTAB_TRACE_OUTGOING");
-            writeln!(file, "time,kind,stream_id,seqno,payload_len").unwrap();
-            Some(file)
-        } else {
-            None
-        }
-    });
+/// Emits a [tracing](https://docs.rs/tracing) event at the given level, like `tracing::event!`, when the `tracing` feature is enabled; otherwise, does nothing. Events are emitted inside the span of the multiplex and stream they concern.
+macro_rules! trace_event {
+    ($($args:tt)*) => {
+        #[cfg(feature = "tracing")]
+        tracing::event!($($args)*);
+    };
+}
+pub(crate) use trace_event;
 
-    if let Some(mut inner) = TRACE_OUTGOING.as_ref() {
-        if let StreamMessage::Reliable {
-            kind,
-            stream_id,
-            seqno,
-            payload,
-        } = msg
+/// Counts a [crate::ProtoEvent] and logs it, rate-limited, with the given fields when the `protolog` feature is enabled; otherwise, does nothing, without even evaluating the fields. Meant for per-packet events, which would cost too much to log one by one.
+macro_rules! proto_event {
+    ($event:ident $(, $key:ident = $value:expr)* $(,)?) => {
+        #[cfg(feature = "protolog")]
         {
-            let _ = writeln!(
-                inner,
-                "{},{:?},{stream_id},{seqno},{}",
-                START.elapsed().as_secs_f64() * 1000.0,
-                kind,
-                payload.len()
-            );
+            use $crate::multiplex::protolog::{record, ProtoEvent, TARGET};
+            if record(ProtoEvent::$event) {
+                log::debug!(
+                    target: TARGET,
+                    concat!(stringify!($event) $(, " ", stringify!($key), "={}")*)
+                    $(, $value)*
+                );
+            }
         }
-    }
+    };
+}
+pub(crate) use proto_event;
+
+/// Records a stream message that a multiplex sent to the trace file named by `SOSISTAB_TRACE_OUTGOING`, if the `metrics` feature is enabled.
+#[cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+pub fn trace_outgoing_msg(msg: &StreamMessage) {
+    #[cfg(feature = "metrics")]
+    super::trace_file::record_outgoing(msg);
 }
 
+/// Records a stream message that a multiplex received to the trace file named by `SOSISTAB_TRACE_INCOMING`, if the `metrics` feature is enabled.
+#[cfg_attr(not(feature = "metrics"), allow(unused_variables))]
 pub fn trace_incoming_msg(msg: &StreamMessage) {
-    static TRACE_INCOMING: Lazy<Option<File>> = Lazy::new(|| {
-        if let Ok(fname) = std::env::var("SOSISTAB_TRACE_INCOMING") {
-            let mut file =
-                File::create(fname).expect("cannot create file for SOSISTAB_TRACE_INCOMING");
-            writeln!(file, "time,kind,stream_id,seqno,payload_len").unwrap();
-            Some(file)
-        } else {
-            None
-        }
-    });
+    #[cfg(feature = "metrics")]
+    super::trace_file::record_incoming(msg);
+}
 
-    if let Some(mut inner) = TRACE_INCOMING.as_ref() {
-        if let StreamMessage::Reliable {
-            kind,
-            stream_id,
-            seqno,
-            payload,
-        } = msg
-        {
-            let _ = writeln!(
-                inner,
-                "{},{:?},{stream_id},{seqno},{}",
-                START.elapsed().as_secs_f64() * 1000.0,
-                kind,
-                payload.len()
-            );
-        }
+/// Records something other than a stream message that happened to a multiplex or one of its pipes, as a tracing event, and to the trace file named by `SOSISTAB_TRACE_EVENTS` if the `metrics` feature is enabled. `pipe` is empty if the event concerns no pipe in particular.
+#[cfg_attr(
+    not(any(feature = "metrics", feature = "tracing")),
+    allow(unused_variables)
+)]
+pub(crate) fn trace_lifecycle(event: &str, pipe: &str, detail: impl std::fmt::Display) {
+    trace_event!(tracing::Level::DEBUG, event, pipe, %detail, "lifecycle event");
+    #[cfg(feature = "metrics")]
+    super::trace_file::record_lifecycle(event, pipe, &detail);
+}
+
+#[cfg(all(test, not(feature = "protolog")))]
+mod tests {
+    use std::cell::Cell;
+
+    #[test]
+    fn proto_event_compiled_out() {
+        let evaluated = Cell::new(false);
+        super::proto_event!(
+            Send,
+            seqno = {
+                evaluated.set(true);
+                0
+            }
+        );
+        assert!(!evaluated.get());
     }
 }
@@ -0,0 +1,247 @@
+//! Trace files, which record what multiplexes send, receive and go through, for replaying with [crate::replay_trace] and for debugging. Only built with the `metrics` feature.
+
+use bytes::Bytes;
+use once_cell::sync::Lazy;
+use parking_lot::{Mutex, RwLock};
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+use std::sync::Arc;
+use std::{fs::File, time::Instant};
+
+use crate::multiplex::stream::StreamMessage;
+
+static START: Lazy<Instant> = Lazy::new(Instant::now);
+
+const HEADER: &str = "time,kind,stream_id,seqno,payload_len,payload,checksum";
+const LEGACY_HEADER: &str = "time,kind,stream_id,seqno,payload_len";
+const EVENT_HEADER: &str = "time,event,pipe,detail,checksum";
+
+/// Whether to record payloads, as set through `SOSISTAB_TRACE_PAYLOADS`.
+static TRACE_PAYLOADS: Lazy<bool> = Lazy::new(|| std::env::var("SOSISTAB_TRACE_PAYLOADS").is_ok());
+
+static REDACTOR: Lazy<RwLock<Option<TraceRedactor>>> = Lazy::new(Default::default);
+
+/// A function that, given the stream id and payload of a message, returns what should be recorded in trace files instead of the payload.
+pub type TraceRedactor = Arc<dyn Fn(u16, &[u8]) -> Vec<u8> + Send + Sync + 'static>;
+
+/// Sets a hook that redacts payloads before they are written to trace files, or removes it with `None`. Only matters when payloads are traced, i.e. when `SOSISTAB_TRACE_PAYLOADS` is set.
+pub fn set_trace_redactor(redactor: Option<TraceRedactor>) {
+    *REDACTOR.write() = redactor;
+}
+
+/// A trace file being written. Every line ends with a checksum chaining together everything written before, so that edits, corruption, and truncation in the middle can be detected by [read_trace].
+struct TraceFile {
+    file: File,
+    chain: blake3::Hash,
+}
+
+impl TraceFile {
+    fn from_env(var: &str, header: &str) -> Option<Mutex<Self>> {
+        let fname = std::env::var(var).ok()?;
+        let mut file =
+            File::create(fname).unwrap_or_else(|_| panic!("cannot create file for {var}"));
+        writeln!(file, "{header}").unwrap();
+        Some(Mutex::new(Self {
+            file,
+            chain: blake3::hash(header.as_bytes()),
+        }))
+    }
+
+    fn write_line(&mut self, line: &str) {
+        self.chain = chain_next(&self.chain, line);
+        let _ = writeln!(self.file, "{line},{}", checksum_hex(&self.chain));
+    }
+
+    fn record(&mut self, msg: &StreamMessage) {
+        if let StreamMessage::Reliable {
+            kind,
+            stream_id,
+            seqno,
+            payload,
+        } = msg
+        {
+            let payload_hex = if *TRACE_PAYLOADS {
+                match REDACTOR.read().as_ref() {
+                    Some(redactor) => hex::encode(redactor(stream_id.0, payload)),
+                    None => hex::encode(payload),
+                }
+            } else {
+                String::new()
+            };
+            let line = format!(
+                "{},{:?},{stream_id},{seqno},{},{payload_hex}",
+                START.elapsed().as_secs_f64() * 1000.0,
+                kind,
+                payload.len()
+            );
+            self.write_line(&line);
+        }
+    }
+}
+
+fn chain_next(prev: &blake3::Hash, line: &str) -> blake3::Hash {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(prev.as_bytes());
+    hasher.update(line.as_bytes());
+    hasher.finalize()
+}
+
+fn checksum_hex(chain: &blake3::Hash) -> String {
+    hex::encode(&chain.as_bytes()[..8])
+}
+
+pub(crate) fn record_outgoing(msg: &StreamMessage) {
+    static TRACE_OUTGOING: Lazy<Option<Mutex<TraceFile>>> =
+        Lazy::new(|| TraceFile::from_env("SOSISTAB_TRACE_OUTGOING", HEADER));
+
+    if let Some(inner) = TRACE_OUTGOING.as_ref() {
+        inner.lock().record(msg);
+    }
+}
+
+pub(crate) fn record_incoming(msg: &StreamMessage) {
+    static TRACE_INCOMING: Lazy<Option<Mutex<TraceFile>>> =
+        Lazy::new(|| TraceFile::from_env("SOSISTAB_TRACE_INCOMING", HEADER));
+
+    if let Some(inner) = TRACE_INCOMING.as_ref() {
+        inner.lock().record(msg);
+    }
+}
+
+pub(crate) fn record_lifecycle(event: &str, pipe: &str, detail: &dyn std::fmt::Display) {
+    static TRACE_EVENTS: Lazy<Option<Mutex<TraceFile>>> =
+        Lazy::new(|| TraceFile::from_env("SOSISTAB_TRACE_EVENTS", EVENT_HEADER));
+
+    if let Some(inner) = TRACE_EVENTS.as_ref() {
+        // fields must not break up the line
+        let escape = |field: &str| field.replace([',', '\n'], ";");
+        let line = format!(
+            "{},{event},{},{}",
+            START.elapsed().as_secs_f64() * 1000.0,
+            escape(pipe),
+            escape(&detail.to_string())
+        );
+        inner.lock().write_line(&line);
+    }
+}
+
+/// A single line of a trace file written through `SOSISTAB_TRACE_OUTGOING` or `SOSISTAB_TRACE_INCOMING`.
+#[derive(Clone, Debug)]
+pub struct TraceRecord {
+    /// Milliseconds since tracing started.
+    pub time_ms: f64,
+    /// The kind of reliable message, e.g. `Data` or `DataAck`.
+    pub kind: String,
+    pub stream_id: u16,
+    pub seqno: u64,
+    pub payload_len: usize,
+    /// The (possibly redacted) payload, if payloads were traced.
+    pub payload: Option<Bytes>,
+}
+
+/// Reads a trace file, verifying its checksums. Files written before checksums were introduced are read without verification.
+pub fn read_trace(path: impl AsRef<Path>) -> std::io::Result<Vec<TraceRecord>> {
+    let invalid = |line: &str| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("invalid trace line {:?}", line),
+        )
+    };
+    let mut lines = BufReader::new(File::open(path)?).lines();
+    let header = lines.next().transpose()?.unwrap_or_default();
+    let checksummed = match header.as_str() {
+        HEADER => true,
+        LEGACY_HEADER => false,
+        _ => return Err(invalid(&header)),
+    };
+    let mut chain = blake3::hash(HEADER.as_bytes());
+    let mut records = vec![];
+    for line in lines {
+        let line = line?;
+        let fields: Vec<&str> = line.split(',').collect();
+        let payload = if checksummed {
+            if fields.len() != 7 {
+                return Err(invalid(&line));
+            }
+            let (content, checksum) = line.rsplit_once(',').unwrap();
+            chain = chain_next(&chain, content);
+            if checksum != checksum_hex(&chain) {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("trace checksum mismatch at line {:?}", line),
+                ));
+            }
+            if fields[5].is_empty() {
+                None
+            } else {
+                Some(hex::decode(fields[5]).map_err(|_| invalid(&line))?.into())
+            }
+        } else {
+            if fields.len() != 5 {
+                return Err(invalid(&line));
+            }
+            None
+        };
+        records.push(TraceRecord {
+            time_ms: fields[0].parse().map_err(|_| invalid(&line))?,
+            kind: fields[1].to_owned(),
+            stream_id: fields[2].parse().map_err(|_| invalid(&line))?,
+            seqno: fields[3].parse().map_err(|_| invalid(&line))?,
+            payload_len: fields[4].parse().map_err(|_| invalid(&line))?,
+            payload,
+        });
+    }
+    Ok(records)
+}
+
+/// A single line of a trace file written through `SOSISTAB_TRACE_EVENTS`: something that happened to a multiplex or one of its pipes, other than a stream message.
+#[derive(Clone, Debug)]
+pub struct TraceEvent {
+    /// Milliseconds since tracing started, on the same clock as [TraceRecord::time_ms].
+    pub time_ms: f64,
+    /// What happened: `PipeAdded`, `PipeEvicted`, `PipeClosed`, `PipeDead`, `PipeAlive` or `PipeSelected` for pipes; `ClientHelloSent`, `ClientHelloReceived`, `ServerHelloSent`, `ServerHelloReceived`, `IdentityResolved`, `SendKeyRegistered` or `RecvKeyRegistered` for the handshake; `SendKeyRatcheted` or `RecvKeyRatcheted` for rekeys.
+    pub event: String,
+    /// The pipe it happened to, as `protocol/peer address`, or empty if it concerns no pipe in particular.
+    pub pipe: String,
+    /// Whatever else there is to know, such as why a pipe was selected or which key generation was ratcheted to. Commas and newlines are replaced with semicolons.
+    pub detail: String,
+}
+
+/// Reads a trace file written through `SOSISTAB_TRACE_EVENTS`, verifying its checksums.
+pub fn read_trace_events(path: impl AsRef<Path>) -> std::io::Result<Vec<TraceEvent>> {
+    let invalid = |line: &str| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("invalid trace line {:?}", line),
+        )
+    };
+    let mut lines = BufReader::new(File::open(path)?).lines();
+    let header = lines.next().transpose()?.unwrap_or_default();
+    if header != EVENT_HEADER {
+        return Err(invalid(&header));
+    }
+    let mut chain = blake3::hash(EVENT_HEADER.as_bytes());
+    let mut events = vec![];
+    for line in lines {
+        let line = line?;
+        let fields: Vec<&str> = line.split(',').collect();
+        if fields.len() != 5 {
+            return Err(invalid(&line));
+        }
+        let (content, checksum) = line.rsplit_once(',').unwrap();
+        chain = chain_next(&chain, content);
+        if checksum != checksum_hex(&chain) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("trace checksum mismatch at line {:?}", line),
+            ));
+        }
+        events.push(TraceEvent {
+            time_ms: fields[0].parse().map_err(|_| invalid(&line))?,
+            event: fields[1].to_owned(),
+            pipe: fields[2].to_owned(),
+            detail: fields[3].to_owned(),
+        });
+    }
+    Ok(events)
+}
@@ -0,0 +1,159 @@
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+use parking_lot::Mutex;
+
+use crate::utilities::runtime;
+
+/// Rates of a set of counters over the last second, ten seconds and minute, in events per second, as returned by [crate::Multiplex::drop_rates] and [crate::Multiplex::tick_rates].
+///
+/// Counters are sampled at whole seconds since they were created or last reset, so each window reaches back to the latest sample at least its length ago, and spans up to a second more. A window that would reach back further than the last reset spans only the time since, and is zero if no time has passed.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct WindowRates<R> {
+    pub last_1s: R,
+    pub last_10s: R,
+    pub last_60s: R,
+}
+
+/// Counters whose rates can be taken between two snapshots.
+pub(crate) trait Windowed: Copy + Default {
+    type Rates: Default;
+
+    /// The rates at which the counters went from `earlier` to `self` over the given number of seconds.
+    fn rates_since(&self, earlier: &Self, secs: f64) -> Self::Rates;
+}
+
+/// Counters that keep a minute of per-second samples of themselves, so that their recent rates can be taken without the caller keeping snapshots, and that can be reset without losing concurrent updates.
+pub(crate) struct WindowedCounters<T> {
+    inner: Mutex<Inner<T>>,
+}
+
+struct Inner<T> {
+    totals: T,
+    since: Instant,
+    // the totals at each whole second since `since`, oldest first, and which second the newest is
+    samples: VecDeque<T>,
+    newest: u64,
+}
+
+const WINDOWS: [u64; 3] = [1, 10, 60];
+
+impl<T: Windowed> Default for WindowedCounters<T> {
+    fn default() -> Self {
+        Self {
+            inner: Mutex::new(Inner::new(runtime::now())),
+        }
+    }
+}
+
+impl<T: Windowed> Inner<T> {
+    fn new(now: Instant) -> Self {
+        Self {
+            totals: T::default(),
+            since: now,
+            samples: VecDeque::from([T::default()]),
+            newest: 0,
+        }
+    }
+
+    /// Samples the totals at every whole second that has passed since the newest sample.
+    fn roll(&mut self, now: Instant) {
+        let second = now.saturating_duration_since(self.since).as_secs();
+        let max = *WINDOWS.last().unwrap();
+        // seconds that are too old to matter are skipped
+        let from = (self.newest + 1).max(second.saturating_sub(max));
+        for _ in from..=second {
+            self.samples.push_back(self.totals);
+        }
+        self.newest = self.newest.max(second);
+        while self.samples.len() as u64 > max + 1 {
+            self.samples.pop_front();
+        }
+    }
+}
+
+impl<T: Windowed> WindowedCounters<T> {
+    /// Updates the counters.
+    pub fn update(&self, f: impl FnOnce(&mut T)) {
+        let mut inner = self.inner.lock();
+        inner.roll(runtime::now());
+        f(&mut inner.totals);
+    }
+
+    pub fn snapshot(&self) -> T {
+        self.inner.lock().totals
+    }
+
+    pub fn rates(&self) -> WindowRates<T::Rates> {
+        let now = runtime::now();
+        let mut inner = self.inner.lock();
+        inner.roll(now);
+        let [last_1s, last_10s, last_60s] = WINDOWS.map(|window| {
+            let oldest = inner.newest + 1 - inner.samples.len() as u64;
+            let second = inner.newest.saturating_sub(window).max(oldest);
+            let secs = now
+                .saturating_duration_since(inner.since + Duration::from_secs(second))
+                .as_secs_f64();
+            if secs > 0.0 {
+                let earlier = &inner.samples[(second - oldest) as usize];
+                inner.totals.rates_since(earlier, secs)
+            } else {
+                T::Rates::default()
+            }
+        });
+        WindowRates {
+            last_1s,
+            last_10s,
+            last_60s,
+        }
+    }
+
+    /// Zeroes the counters and forgets their history, returning what they were, as one step that no concurrent update can fall between.
+    pub fn reset(&self) -> T {
+        let mut inner = self.inner.lock();
+        std::mem::replace(&mut *inner, Inner::new(runtime::now())).totals
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::{Inner, Windowed, WindowedCounters};
+
+    #[derive(Clone, Copy, Default)]
+    struct Count(u64);
+
+    impl Windowed for Count {
+        type Rates = f64;
+
+        fn rates_since(&self, earlier: &Self, secs: f64) -> f64 {
+            (self.0 - earlier.0) as f64 / secs
+        }
+    }
+
+    #[test]
+    fn rates_over_windows() {
+        let counters = WindowedCounters::<Count>::default();
+        // pretend the counters were created two minutes ago, and counted 10 a second since
+        let start = Instant::now() - Duration::from_secs(120);
+        *counters.inner.lock() = Inner::new(start);
+        for second in 0..120 {
+            let mut inner = counters.inner.lock();
+            inner.roll(start + Duration::from_secs(second));
+            inner.totals.0 += 10;
+        }
+        let rates = counters.rates();
+        for rate in [rates.last_1s, rates.last_10s, rates.last_60s] {
+            assert!(rate > 5.0 && rate <= 10.0, "{rate}");
+        }
+        assert_eq!(counters.inner.lock().samples.len(), 61);
+
+        assert_eq!(counters.reset().0, 1200);
+        assert_eq!(counters.snapshot().0, 0);
+        counters.update(|count| count.0 += 1);
+        assert_eq!(counters.snapshot().0, 1);
+    }
+}
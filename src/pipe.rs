@@ -1,11 +1,85 @@
-use std::{ops::Deref, sync::Arc};
+#[cfg(feature = "local")]
+mod local;
+mod pow;
+#[cfg(feature = "quic")]
+mod quic;
+#[cfg(feature = "tls")]
+mod tls;
+#[cfg(any(feature = "tls", feature = "ws", feature = "quic"))]
+mod tls_verify;
+#[cfg(feature = "udp")]
+mod udp;
+#[cfg(feature = "ws")]
+mod ws;
+
+use std::{io::ErrorKind, ops::Deref, sync::Arc, time::Duration};
 
 use async_trait::async_trait;
 use bytes::Bytes;
 
 use smol::future::FutureExt;
+use thiserror::Error;
+
+#[cfg(feature = "local")]
+pub use local::{LocalListener, LocalPipe};
+pub use pow::{PowListener, PowPipe};
+#[cfg(feature = "quic")]
+pub use quic::{QuicListener, QuicPipe};
+#[cfg(feature = "tls")]
+pub use tls::{TlsListener, TlsPipe};
+#[cfg(any(feature = "tls", feature = "quic"))]
+pub use tls_verify::TlsVerify;
+#[cfg(feature = "udp")]
+pub use udp::{UdpListener, UdpPipe};
+#[cfg(feature = "ws")]
+pub use ws::{WsListener, WsPipe};
 
 /// Abstracts over any "pipe" that can carry datagrams along one particular path. This should almost always be used in conjunction with [crate::Multiplex].
+///
+/// Transports outside this crate, such as ICMP or DNS tunnels, plug in by implementing this trait and handing their pipes to [crate::Multiplex::add_pipe]. A pipe only has to carry datagrams, and may lose, duplicate, or reorder them: the multiplex encrypts them, detects replays, and retransmits what was lost. Implementations use [macro@async_trait], which this crate re-exports.
+///
+/// ```
+/// use bytes::Bytes;
+/// use smol::channel::{Receiver, Sender};
+/// use sosistab2::{async_trait, Multiplex, MuxSecret, Pipe};
+///
+/// // a pipe over an in-memory channel, standing in for a real transport
+/// struct ChannelPipe {
+///     send: Sender<Bytes>,
+///     recv: Receiver<Bytes>,
+/// }
+///
+/// #[async_trait]
+/// impl Pipe for ChannelPipe {
+///     fn send(&self, to_send: Bytes) {
+///         let _ = self.send.try_send(to_send);
+///     }
+///
+///     async fn recv(&self) -> std::io::Result<Bytes> {
+///         self.recv
+///             .recv()
+///             .await
+///             .map_err(|_| std::io::ErrorKind::BrokenPipe.into())
+///     }
+///
+///     fn protocol(&self) -> &str {
+///         "channel"
+///     }
+///
+///     fn peer_metadata(&self) -> &str {
+///         ""
+///     }
+///
+///     fn peer_addr(&self) -> String {
+///         "memory".into()
+///     }
+/// }
+///
+/// let (send, recv) = smol::channel::bounded(100);
+/// let pipe: Box<dyn Pipe> = Box::new(ChannelPipe { send, recv });
+/// let mux = Multiplex::new(MuxSecret::generate(), None);
+/// mux.add_pipe(pipe);
+/// ```
 #[async_trait]
 pub trait Pipe: Send + Sync + 'static {
     /// Sends a datagram to the other side. Should never block; if the datagram cannot be sent quickly it should simply be dropped.
@@ -24,6 +98,74 @@ pub trait Pipe: Send + Sync + 'static {
 
     /// Return a protocol-specific address identifying the other side.
     fn peer_addr(&self) -> String;
+
+    /// Returns how long each step of dialing this pipe took, or `None` for pipes that were accepted rather than dialed, or that do not keep track. The default returns `None`.
+    fn dial_timings(&self) -> Option<DialTimings> {
+        None
+    }
+}
+
+/// How long each step of dialing a [Pipe] took, as returned by [Pipe::dial_timings]. Comparing these across endpoints shows which one is fastest to reach, and where the time goes.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DialTimings {
+    /// Resolving the host name, or `None` if the pipe was given an address.
+    pub dns: Option<Duration>,
+    /// Opening the underlying connection: the TCP handshake, or for QUIC, just binding the UDP socket.
+    pub dial: Duration,
+    /// Setting up the layers that disguise the traffic, such as the TLS, QUIC, or WebSocket handshakes, and telling the server our metadata.
+    pub obfs: Duration,
+}
+
+impl DialTimings {
+    /// The time all the steps took together.
+    pub fn total(&self) -> Duration {
+        self.dns.unwrap_or_default() + self.dial + self.obfs
+    }
+}
+
+/// Why dialing a pipe, or connecting to a bridge with [crate::BridgeDescriptor::connect], failed, sorted by what the caller can do about it: try again later, try another endpoint, or tell the user.
+#[derive(Error, Debug)]
+pub enum ConnectError {
+    /// The host name could not be resolved.
+    #[error("could not resolve {host}: {source}")]
+    Dns {
+        host: String,
+        #[source]
+        source: std::io::Error,
+    },
+    /// Nothing answered at the address: the connection was refused, or no route led there.
+    #[error("could not reach the server: {0}")]
+    Unreachable(#[source] std::io::Error),
+    /// The server did not finish the handshake in time, as when it is down or something on the way drops the traffic.
+    #[error("handshake timed out")]
+    HandshakeTimeout,
+    /// The server's certificate was not the one expected, or the server turned ours down.
+    #[error("authentication failed: {0}")]
+    Authentication(String),
+    /// The server speaks another protocol, or another version of it: no QUIC version, TLS version or ALPN protocol in common, or a refused WebSocket upgrade.
+    #[error("the server speaks another protocol version: {0}")]
+    VersionMismatch(String),
+    /// What to connect to was unusable, such as a malformed URL or server name, or a bridge line without endpoints.
+    #[error("invalid input: {0}")]
+    InvalidInput(String),
+    /// The connection failed partway through for some other reason.
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+impl From<ConnectError> for std::io::Error {
+    fn from(err: ConnectError) -> Self {
+        let kind = match &err {
+            ConnectError::Dns { .. } => ErrorKind::NotFound,
+            ConnectError::Unreachable(source) => source.kind(),
+            ConnectError::HandshakeTimeout => ErrorKind::TimedOut,
+            ConnectError::Authentication(_) => ErrorKind::PermissionDenied,
+            ConnectError::VersionMismatch(_) => ErrorKind::InvalidData,
+            ConnectError::InvalidInput(_) => ErrorKind::InvalidInput,
+            ConnectError::Io(source) => source.kind(),
+        };
+        std::io::Error::new(kind, err)
+    }
 }
 
 #[async_trait]
@@ -47,6 +189,10 @@ impl<P: Pipe + ?Sized, T: Deref<Target = P> + Send + Sync + 'static> Pipe for T
     fn peer_addr(&self) -> String {
         self.deref().peer_addr()
     }
+
+    fn dial_timings(&self) -> Option<DialTimings> {
+        self.deref().dial_timings()
+    }
 }
 
 /// Abstracts over any "listener" that can receive [Pipe]s.
@@ -0,0 +1,318 @@
+use std::{
+    io::{BufReader, BufWriter, ErrorKind, Read, Write},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread::JoinHandle,
+    time::Instant,
+};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use interprocess::local_socket::{
+    prelude::*, GenericNamespaced, ListenerOptions, Name, RecvHalf, SendHalf,
+};
+use smol::channel::{Receiver, Sender};
+
+use crate::{ConnectError, DialTimings, Pipe, PipeListener};
+
+/// How many datagrams may wait to be written to the connection, or to be received, before further ones are dropped. The same goes for pipes waiting to be accepted.
+const QUEUE_LEN: usize = 1000;
+
+/// A [Pipe] between two processes on the same machine, over a Unix domain socket, or on Windows a named pipe, so that a [crate::Multiplex] can connect local services just as it connects hosts over the network.
+///
+/// Each datagram is prefixed with its length, and datagrams that are empty or longer than 65535 bytes are dropped. An empty datagram instead tells the other side that the pipe was dropped, since named pipes cannot be shut down halfway. Named pipes cannot be waited on by the async runtime either, so each pipe reads and writes on threads of its own.
+pub struct LocalPipe {
+    send_queue: Sender<Bytes>,
+    recv_queue: Receiver<Bytes>,
+    name: String,
+    peer_metadata: String,
+    dial_timings: Option<DialTimings>,
+}
+
+impl LocalPipe {
+    /// Connects to the [LocalListener] listening under `name`. The listener's [Pipe::peer_metadata] is set to `metadata`.
+    pub async fn connect(name: &str, metadata: &str) -> Result<Self, ConnectError> {
+        if metadata.len() > u16::MAX as usize {
+            return Err(ConnectError::InvalidInput("metadata too long".into()));
+        }
+        let socket_name = local_name(name)
+            .map_err(|err| ConnectError::InvalidInput(format!("bad name {name}: {err}")))?
+            .into_owned();
+        let metadata = metadata.to_owned();
+        let start = Instant::now();
+        // connecting blocks on Windows while every instance of the named pipe is busy
+        let (conn, dial) = smol::unblock(move || {
+            let mut conn =
+                LocalSocketStream::connect(socket_name).map_err(ConnectError::Unreachable)?;
+            let dial = start.elapsed();
+            // the metadata goes first, as a datagram of its own
+            write_datagram(&mut conn, metadata.as_bytes())?;
+            conn.flush()?;
+            Ok::<_, ConnectError>((conn, dial))
+        })
+        .await?;
+        let mut pipe = Self::start(conn, name.to_owned(), String::new())?;
+        pipe.dial_timings = Some(DialTimings {
+            dns: None,
+            dial,
+            obfs: start.elapsed() - dial,
+        });
+        Ok(pipe)
+    }
+
+    fn start(
+        conn: LocalSocketStream,
+        name: String,
+        peer_metadata: String,
+    ) -> std::io::Result<Self> {
+        let (send_queue, to_send) = smol::channel::bounded::<Bytes>(QUEUE_LEN);
+        let (received, recv_queue) = smol::channel::bounded(QUEUE_LEN);
+        let (reader, writer) = conn.split();
+        spawn_thread("sosistab2-local-send", {
+            let name = name.clone();
+            move || {
+                if let Err(err) = upload(writer, to_send) {
+                    log::debug!("local pipe {name} failed to send: {:?}", err);
+                }
+            }
+        })?;
+        // the queues close when either direction fails, which fails recv
+        spawn_thread("sosistab2-local-recv", {
+            let name = name.clone();
+            move || {
+                if let Err(err) = download(reader, received) {
+                    log::debug!("local pipe {name} failed to receive: {:?}", err);
+                }
+            }
+        })?;
+        Ok(Self {
+            send_queue,
+            recv_queue,
+            name,
+            peer_metadata,
+            dial_timings: None,
+        })
+    }
+}
+
+/// Writes out datagrams until the pipe is dropped, and then tells the other side.
+fn upload(writer: SendHalf, to_send: Receiver<Bytes>) -> std::io::Result<()> {
+    let mut writer = BufWriter::new(writer);
+    while let Ok(datagram) = to_send.recv_blocking() {
+        write_datagram(&mut writer, &datagram)?;
+        // write out whatever queued up at once
+        if to_send.is_empty() {
+            writer.flush()?;
+        }
+    }
+    write_datagram(&mut writer, &[])?;
+    writer.flush()
+}
+
+/// Reads datagrams until the other side drops its pipe or the connection fails.
+fn download(reader: RecvHalf, received: Sender<Bytes>) -> std::io::Result<()> {
+    let mut reader = BufReader::new(reader);
+    loop {
+        let datagram = read_datagram(&mut reader)?;
+        if datagram.is_empty() {
+            return Ok(());
+        }
+        // when the application falls behind, drop datagrams as a congested link would
+        let _ = received.try_send(datagram);
+    }
+}
+
+#[async_trait]
+impl Pipe for LocalPipe {
+    fn send(&self, to_send: Bytes) {
+        if !to_send.is_empty() && to_send.len() <= u16::MAX as usize {
+            let _ = self.send_queue.try_send(to_send);
+        }
+    }
+
+    async fn recv(&self) -> std::io::Result<Bytes> {
+        self.recv_queue
+            .recv()
+            .await
+            .map_err(|_| std::io::Error::new(ErrorKind::BrokenPipe, "local connection closed"))
+    }
+
+    fn protocol(&self) -> &str {
+        "local"
+    }
+
+    fn peer_metadata(&self) -> &str {
+        &self.peer_metadata
+    }
+
+    fn peer_addr(&self) -> String {
+        self.name.clone()
+    }
+
+    fn dial_timings(&self) -> Option<DialTimings> {
+        self.dial_timings
+    }
+}
+
+/// A [PipeListener] that accepts [LocalPipe]s. Any process on the machine can connect, but, as over the network, only clients that know the multiplex's public key get a session.
+pub struct LocalListener {
+    incoming: Receiver<LocalPipe>,
+    name: String,
+    stopped: Arc<AtomicBool>,
+    accept_thread: Option<JoinHandle<()>>,
+}
+
+impl LocalListener {
+    /// Listens under `name`: on Windows, the named pipe `\\.\pipe\{name}`; on Linux, a Unix domain socket in the abstract namespace; and on other Unix systems, the socket file `/tmp/{name}`, removed once the listener is dropped.
+    pub async fn bind(name: &str) -> std::io::Result<Self> {
+        let listener = ListenerOptions::new()
+            .name(local_name(name)?)
+            .create_sync()?;
+        let (send_incoming, incoming) = smol::channel::bounded(QUEUE_LEN);
+        let stopped = Arc::new(AtomicBool::new(false));
+        let accept_thread = spawn_thread("sosistab2-local-accept", {
+            let name = name.to_owned();
+            let stopped = stopped.clone();
+            move || {
+                for conn in listener.incoming() {
+                    if stopped.load(Ordering::Relaxed) {
+                        return;
+                    }
+                    let mut conn = match conn {
+                        Ok(conn) => conn,
+                        Err(err) => {
+                            log::warn!("local listener {name} failed to accept: {:?}", err);
+                            continue;
+                        }
+                    };
+                    let pipe_name = name.clone();
+                    let send_incoming = send_incoming.clone();
+                    // handshakes happen on their own, so that a slow client does not hold up others
+                    let spawned = spawn_thread("sosistab2-local-handshake", move || {
+                        let pipe = read_datagram(&mut conn).and_then(|metadata| {
+                            let metadata = String::from_utf8_lossy(&metadata).into_owned();
+                            LocalPipe::start(conn, pipe_name.clone(), metadata)
+                        });
+                        match pipe {
+                            Ok(pipe) => {
+                                let _ = send_incoming.try_send(pipe);
+                            }
+                            Err(err) => {
+                                log::debug!("local handshake on {pipe_name} failed: {:?}", err)
+                            }
+                        }
+                    });
+                    if let Err(err) = spawned {
+                        log::warn!("local listener {name} could not spawn a thread: {:?}", err);
+                    }
+                }
+            }
+        })?;
+        Ok(Self {
+            incoming,
+            name: name.to_owned(),
+            stopped,
+            accept_thread: Some(accept_thread),
+        })
+    }
+}
+
+impl Drop for LocalListener {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::Relaxed);
+        // wakes up the thread waiting for connections, which then stops listening, so that the name is free once this returns
+        if let Ok(name) = local_name(&self.name) {
+            if LocalSocketStream::connect(name).is_ok() {
+                if let Some(thread) = self.accept_thread.take() {
+                    let _ = thread.join();
+                }
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl PipeListener for LocalListener {
+    async fn accept_pipe(&self) -> std::io::Result<Arc<dyn Pipe>> {
+        let pipe =
+            self.incoming.recv().await.map_err(|_| {
+                std::io::Error::new(ErrorKind::BrokenPipe, "local listener stopped")
+            })?;
+        Ok(Arc::new(pipe))
+    }
+}
+
+fn local_name(name: &str) -> std::io::Result<Name<'_>> {
+    name.to_ns_name::<GenericNamespaced>()
+}
+
+fn spawn_thread(name: &str, f: impl FnOnce() + Send + 'static) -> std::io::Result<JoinHandle<()>> {
+    std::thread::Builder::new().name(name.into()).spawn(f)
+}
+
+fn write_datagram(writer: &mut impl Write, datagram: &[u8]) -> std::io::Result<()> {
+    writer.write_all(&(datagram.len() as u16).to_be_bytes())?;
+    writer.write_all(datagram)
+}
+
+fn read_datagram(reader: &mut impl Read) -> std::io::Result<Bytes> {
+    let mut len = [0u8; 2];
+    reader.read_exact(&mut len)?;
+    let mut datagram = vec![0u8; u16::from_be_bytes(len) as usize];
+    reader.read_exact(&mut datagram)?;
+    Ok(datagram.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unique_name() -> String {
+        format!("sosistab2-test-{}", rand::random::<u64>())
+    }
+
+    #[test]
+    fn pipes_carry_datagrams_both_ways() {
+        smol::block_on(async {
+            let name = unique_name();
+            let listener = LocalListener::bind(&name).await.unwrap();
+            let client = LocalPipe::connect(&name, "hello").await.unwrap();
+            let server = listener.accept_pipe().await.unwrap();
+            assert_eq!(server.peer_metadata(), "hello");
+            assert_eq!(server.protocol(), "local");
+
+            let large = Bytes::from(vec![7u8; 60_000]);
+            for datagram in [Bytes::from_static(b"ping"), large] {
+                client.send(datagram.clone());
+                assert_eq!(server.recv().await.unwrap(), datagram);
+                server.send(datagram.clone());
+                assert_eq!(client.recv().await.unwrap(), datagram);
+            }
+
+            // dropping a pipe ends the other one
+            drop(client);
+            assert_eq!(
+                server.recv().await.unwrap_err().kind(),
+                ErrorKind::BrokenPipe
+            );
+        })
+    }
+
+    #[test]
+    fn dropped_listeners_stop_listening() {
+        smol::block_on(async {
+            let name = unique_name();
+            drop(LocalListener::bind(&name).await.unwrap());
+            assert!(matches!(
+                LocalPipe::connect(&name, "").await,
+                Err(ConnectError::Unreachable(_))
+            ));
+            // and the name can be listened under again
+            let listener = LocalListener::bind(&name).await.unwrap();
+            let _client = LocalPipe::connect(&name, "").await.unwrap();
+            listener.accept_pipe().await.unwrap();
+        })
+    }
+}
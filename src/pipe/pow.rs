@@ -0,0 +1,367 @@
+use std::{
+    io::ErrorKind,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use bytes::{BufMut, Bytes, BytesMut};
+use parking_lot::Mutex;
+use smol::{channel::Receiver, future::FutureExt};
+
+use crate::{
+    utilities::runtime::{self, TimeoutExt},
+    DialTimings, Pipe, PipeListener,
+};
+
+/// How many admitted pipes may wait to be accepted before further ones are dropped.
+const QUEUE_LEN: usize = 1000;
+/// How many pipes may be solving puzzles at once. Pipes beyond that are dropped right away.
+const MAX_PENDING: usize = 4096;
+/// Every doubling of the pipes solving puzzles beyond this many makes the puzzle one bit harder.
+const LOAD_STEP: usize = 32;
+/// The hardest puzzle a listener sets, and a client agrees to solve: about 16 million hashes on average.
+const MAX_DIFFICULTY: u8 = 24;
+/// How long a client has to solve its puzzle, and how often it is resent in the meantime in case it was lost.
+const PUZZLE_TIMEOUT: Duration = Duration::from_secs(30);
+const CHALLENGE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Datagrams carrying a challenge, followed by the 32 random bytes to key the hash with and the difficulty, and an answer, followed by the challenge and the solution.
+const CHALLENGE: &[u8; 8] = b"!!pow?!!";
+const ANSWER: &[u8; 8] = b"!!pow=!!";
+
+/// A [PipeListener] that only hands out the pipes of another listener once their clients solve a proof-of-work puzzle, as an opt-in defense against floods of handshakes: each client pays a little CPU time before a [crate::Multiplex] spends anything on it. Clients must wrap the pipes they dial in a [PowPipe].
+///
+/// The puzzle is to find a number whose BLAKE3 hash, keyed with a random challenge, starts with as many zero bits as the difficulty. The difficulty rises by a bit with every doubling of the pipes solving puzzles at once beyond 32, so that puzzles get harder while the listener is under load, up to 24 bits. Anything a client sends before its solution is dropped.
+///
+/// The challenges and answers are recognizable, so over pipes that carry datagrams as they are, such as [crate::UdpPipe], this gives the protocol away.
+pub struct PowListener {
+    incoming: Receiver<Arc<dyn Pipe>>,
+    pending: Arc<AtomicUsize>,
+    difficulty: u8,
+    _task: runtime::Task<()>,
+}
+
+impl PowListener {
+    /// Wraps the listener, setting puzzles of at least the given difficulty in bits; 16 takes a phone a fraction of a second.
+    pub fn new<L: PipeListener + 'static>(inner: L, difficulty: u8) -> Self {
+        let difficulty = difficulty.min(MAX_DIFFICULTY);
+        let pending = Arc::new(AtomicUsize::new(0));
+        let (send_incoming, incoming) = smol::channel::bounded(QUEUE_LEN);
+        let task = runtime::spawn({
+            let pending = pending.clone();
+            async move {
+                loop {
+                    let pipe = match inner.accept_pipe().await {
+                        Ok(pipe) => pipe,
+                        Err(err) => {
+                            log::debug!("listener behind proof of work stopped: {:?}", err);
+                            return;
+                        }
+                    };
+                    let load = pending.fetch_add(1, Ordering::Relaxed);
+                    if load >= MAX_PENDING {
+                        pending.fetch_sub(1, Ordering::Relaxed);
+                        continue;
+                    }
+                    let pending = pending.clone();
+                    let send_incoming = send_incoming.clone();
+                    // puzzles are solved on their own, so that a slow client does not hold up others
+                    runtime::spawn(async move {
+                        let admitted = admit(&pipe, difficulty_for(difficulty, load))
+                            .timeout(PUZZLE_TIMEOUT)
+                            .await;
+                        pending.fetch_sub(1, Ordering::Relaxed);
+                        match admitted {
+                            Some(Ok(())) => {
+                                let _ = send_incoming.try_send(Arc::new(AdmittedPipe(pipe)) as _);
+                            }
+                            Some(Err(err)) => log::debug!(
+                                "pipe from {} failed before solving its puzzle: {:?}",
+                                pipe.peer_addr(),
+                                err
+                            ),
+                            None => log::debug!(
+                                "pipe from {} did not solve its puzzle in time",
+                                pipe.peer_addr()
+                            ),
+                        }
+                    })
+                    .detach();
+                }
+            }
+        });
+        Self {
+            incoming,
+            pending,
+            difficulty,
+            _task: task,
+        }
+    }
+
+    /// The difficulty of the puzzles set right now, which rises with the number of pipes solving them.
+    pub fn current_difficulty(&self) -> u8 {
+        difficulty_for(self.difficulty, self.pending.load(Ordering::Relaxed))
+    }
+}
+
+#[async_trait]
+impl PipeListener for PowListener {
+    async fn accept_pipe(&self) -> std::io::Result<Arc<dyn Pipe>> {
+        self.incoming.recv().await.map_err(|_| {
+            std::io::Error::new(ErrorKind::BrokenPipe, "proof-of-work listener stopped")
+        })
+    }
+}
+
+/// The difficulty of a puzzle set while the given number of other pipes are solving theirs.
+fn difficulty_for(base: u8, pending: usize) -> u8 {
+    let extra = (pending / LOAD_STEP)
+        .checked_ilog2()
+        .map_or(0, |bits| bits + 1);
+    (base as u32 + extra).min(MAX_DIFFICULTY as u32) as u8
+}
+
+/// Sets a puzzle over the pipe, resending it until it is solved.
+async fn admit(pipe: &Arc<dyn Pipe>, difficulty: u8) -> std::io::Result<()> {
+    let challenge: [u8; 32] = rand::random();
+    let mut msg = BytesMut::with_capacity(CHALLENGE.len() + 33);
+    msg.put_slice(CHALLENGE);
+    msg.put_slice(&challenge);
+    msg.put_u8(difficulty);
+    let msg = msg.freeze();
+    let resend = async {
+        loop {
+            pipe.send(msg.clone());
+            runtime::Timer::after(CHALLENGE_INTERVAL).await;
+        }
+    };
+    let solved = async {
+        loop {
+            let pkt = pipe.recv().await?;
+            if parse_answer(&pkt).is_some_and(|(answered, solution)| {
+                answered == challenge && solves(&challenge, difficulty, solution)
+            }) {
+                return Ok(());
+            }
+        }
+    };
+    resend.or(solved).await
+}
+
+fn parse_answer(pkt: &[u8]) -> Option<([u8; 32], u64)> {
+    let body = pkt.strip_prefix(ANSWER)?;
+    if body.len() != 40 {
+        return None;
+    }
+    Some((
+        body[..32].try_into().unwrap(),
+        u64::from_le_bytes(body[32..].try_into().unwrap()),
+    ))
+}
+
+fn parse_challenge(pkt: &[u8]) -> Option<([u8; 32], u8)> {
+    let body = pkt.strip_prefix(CHALLENGE)?;
+    if body.len() != 33 {
+        return None;
+    }
+    Some((body[..32].try_into().unwrap(), body[32]))
+}
+
+/// Whether the hash of the solution, keyed with the challenge, starts with at least `difficulty` zero bits.
+fn solves(challenge: &[u8; 32], difficulty: u8, solution: u64) -> bool {
+    let hash = blake3::keyed_hash(challenge, &solution.to_le_bytes());
+    let leading = u128::from_be_bytes(hash.as_bytes()[..16].try_into().unwrap());
+    leading.leading_zeros() >= difficulty as u32
+}
+
+fn solve(challenge: &[u8; 32], difficulty: u8) -> u64 {
+    (0..)
+        .find(|solution| solves(challenge, difficulty, *solution))
+        .expect("some solution exists")
+}
+
+/// A pipe let through by a [PowListener], which drops answers to its puzzle that arrive after the first.
+struct AdmittedPipe(Arc<dyn Pipe>);
+
+#[async_trait]
+impl Pipe for AdmittedPipe {
+    fn send(&self, to_send: Bytes) {
+        self.0.send(to_send)
+    }
+
+    async fn recv(&self) -> std::io::Result<Bytes> {
+        loop {
+            let pkt = self.0.recv().await?;
+            if !pkt.starts_with(ANSWER) {
+                return Ok(pkt);
+            }
+        }
+    }
+
+    fn protocol(&self) -> &str {
+        self.0.protocol()
+    }
+
+    fn peer_metadata(&self) -> &str {
+        self.0.peer_metadata()
+    }
+
+    fn peer_addr(&self) -> String {
+        self.0.peer_addr()
+    }
+
+    fn dial_timings(&self) -> Option<DialTimings> {
+        self.0.dial_timings()
+    }
+}
+
+/// A dialed pipe that solves the puzzles a [PowListener] sets, for a [crate::Multiplex] to use like any other. Puzzles harder than any listener sets are ignored, so that a server cannot keep the client busy forever.
+pub struct PowPipe<P> {
+    inner: P,
+    // the last puzzle solved, whose answer is sent again if the challenge is resent
+    solved: Mutex<Option<([u8; 32], u64)>>,
+}
+
+impl<P: Pipe> PowPipe<P> {
+    /// Wraps a pipe dialed to a [PowListener].
+    pub fn new(inner: P) -> Self {
+        Self {
+            inner,
+            solved: Mutex::new(None),
+        }
+    }
+}
+
+#[async_trait]
+impl<P: Pipe> Pipe for PowPipe<P> {
+    fn send(&self, to_send: Bytes) {
+        self.inner.send(to_send)
+    }
+
+    async fn recv(&self) -> std::io::Result<Bytes> {
+        loop {
+            let pkt = self.inner.recv().await?;
+            let Some((challenge, difficulty)) = parse_challenge(&pkt) else {
+                return Ok(pkt);
+            };
+            if difficulty > MAX_DIFFICULTY {
+                continue;
+            }
+            let cached = *self.solved.lock();
+            let solution = match cached {
+                Some((solved, solution)) if solved == challenge => solution,
+                // solving takes a while, so it is kept off the executor
+                _ => smol::unblock(move || solve(&challenge, difficulty)).await,
+            };
+            *self.solved.lock() = Some((challenge, solution));
+            let mut answer = BytesMut::with_capacity(ANSWER.len() + 40);
+            answer.put_slice(ANSWER);
+            answer.put_slice(&challenge);
+            answer.put_u64_le(solution);
+            self.inner.send(answer.freeze());
+        }
+    }
+
+    fn protocol(&self) -> &str {
+        self.inner.protocol()
+    }
+
+    fn peer_metadata(&self) -> &str {
+        self.inner.peer_metadata()
+    }
+
+    fn peer_addr(&self) -> String {
+        self.inner.peer_addr()
+    }
+
+    fn dial_timings(&self) -> Option<DialTimings> {
+        self.inner.dial_timings()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use smol::prelude::*;
+
+    use super::*;
+    use crate::{
+        sim::{sim_pipe_pair, SimLink, SimPipe},
+        Multiplex, MuxSecret,
+    };
+
+    /// Hands out the server ends of simulated pipes.
+    struct SimListener(Receiver<SimPipe>);
+
+    #[async_trait]
+    impl PipeListener for SimListener {
+        async fn accept_pipe(&self) -> std::io::Result<Arc<dyn Pipe>> {
+            let pipe = self
+                .0
+                .recv()
+                .await
+                .map_err(|_| std::io::Error::from(ErrorKind::BrokenPipe))?;
+            Ok(Arc::new(pipe))
+        }
+    }
+
+    #[test]
+    fn only_solvers_get_through() {
+        smol::block_on(async {
+            let (send_pipes, recv_pipes) = smol::channel::unbounded();
+            let listener = PowListener::new(SimListener(recv_pipes), 12);
+            let link = SimLink {
+                delay: Duration::from_millis(5),
+                ..Default::default()
+            };
+
+            // a client without a puzzle solver is never let through
+            let (_lazy, lazy_server) = sim_pipe_pair(link);
+            send_pipes.try_send(lazy_server).unwrap();
+            let (client_pipe, server_pipe) = sim_pipe_pair(link);
+            send_pipes.try_send(server_pipe).unwrap();
+
+            let server_sk = MuxSecret::generate();
+            let client = Multiplex::new(MuxSecret::generate(), Some(server_sk.to_public()));
+            client.add_pipe(PowPipe::new(client_pipe));
+            let server = Multiplex::new(server_sk, None);
+            server.add_pipe(listener.accept_pipe().await.unwrap());
+            let mut stream = client.open_conn("").await.unwrap();
+            stream.write_all(b"hello").await.unwrap();
+            let mut accepted = server.accept_conn().await.unwrap();
+            let mut buf = [0u8; 5];
+            accepted.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"hello");
+
+            assert!(listener
+                .accept_pipe()
+                .timeout(Duration::from_secs(2))
+                .await
+                .is_none());
+            assert_eq!(listener.current_difficulty(), 12);
+        })
+    }
+
+    #[test]
+    fn puzzles_harden_under_load() {
+        assert_eq!(difficulty_for(12, 0), 12);
+        assert_eq!(difficulty_for(12, LOAD_STEP - 1), 12);
+        assert_eq!(difficulty_for(12, LOAD_STEP), 13);
+        assert_eq!(difficulty_for(12, 4 * LOAD_STEP), 15);
+        assert_eq!(difficulty_for(20, MAX_PENDING), MAX_DIFFICULTY);
+    }
+
+    #[test]
+    fn solutions_are_checked() {
+        let challenge: [u8; 32] = rand::random();
+        let solution = solve(&challenge, 10);
+        assert!(solves(&challenge, 10, solution));
+        // the same number does not solve another challenge, or solves it only by the rarest chance
+        assert!(!(0..100).all(|_| solves(&rand::random(), 10, solution)));
+        assert!(parse_challenge(b"!!pow?!!short").is_none());
+        assert!(parse_answer(&[&ANSWER[..], &[0; 40]].concat()).is_some());
+    }
+}
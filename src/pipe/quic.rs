@@ -0,0 +1,482 @@
+use std::{
+    future::Future,
+    io::ErrorKind,
+    net::{SocketAddr, UdpSocket},
+    pin::Pin,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use quinn::{
+    AsyncStdRuntime, AsyncTimer, AsyncUdpSocket, ClientConfig, Connection, ConnectionError,
+    Endpoint, EndpointConfig, Runtime, SendStream, TransportConfig, ZeroRttAccepted,
+};
+use rustls::{AlertDescription, ServerConfig};
+use smol::{
+    channel::{Receiver, Sender},
+    future::FutureExt,
+};
+
+use super::tls_verify::{alert_error, TlsVerify};
+use crate::{
+    utilities::runtime::{self, TimeoutExt},
+    ConnectError, DialTimings, Pipe, PipeListener,
+};
+
+/// How many datagrams too large for a QUIC datagram may wait to be sent, or to be received, before further ones are dropped. The same goes for pipes waiting to be accepted.
+const QUEUE_LEN: usize = 1000;
+/// How long a connection may take to become a pipe, including the QUIC handshake and learning the peer's metadata.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// How often an idle connection is kept alive, well within QUIC's idle timeout, so that the pipe does not die while the multiplex above it has nothing to say.
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(5);
+/// The protocol clients offer in ALPN, that of HTTP/3.
+const ALPN: [&[u8]; 1] = [b"h3"];
+
+/// Runs QUIC's background tasks on the same executor as everything else, rather than on a runtime of their own. Its timers and sockets are Tokio's whenever its tasks are, see [runtime].
+#[derive(Debug)]
+struct SharedRuntime;
+
+impl Runtime for SharedRuntime {
+    fn new_timer(&self, i: Instant) -> Pin<Box<dyn AsyncTimer>> {
+        #[cfg(feature = "tokio")]
+        if tokio::runtime::Handle::try_current().is_ok() {
+            return quinn::TokioRuntime.new_timer(i);
+        }
+        AsyncStdRuntime.new_timer(i)
+    }
+
+    fn spawn(&self, future: Pin<Box<dyn Future<Output = ()> + Send>>) {
+        runtime::spawn(future).detach();
+    }
+
+    fn wrap_udp_socket(&self, t: UdpSocket) -> std::io::Result<Box<dyn AsyncUdpSocket>> {
+        #[cfg(feature = "tokio")]
+        if tokio::runtime::Handle::try_current().is_ok() {
+            return quinn::TokioRuntime.wrap_udp_socket(t);
+        }
+        AsyncStdRuntime.wrap_udp_socket(t)
+    }
+}
+
+fn transport_config() -> Arc<TransportConfig> {
+    let mut config = TransportConfig::default();
+    config.keep_alive_interval(Some(KEEPALIVE_INTERVAL));
+    Arc::new(config)
+}
+
+fn endpoint(
+    socket: UdpSocket,
+    server_config: Option<quinn::ServerConfig>,
+) -> std::io::Result<Endpoint> {
+    Endpoint::new(
+        EndpointConfig::default(),
+        server_config,
+        socket,
+        Arc::new(SharedRuntime),
+    )
+}
+
+/// A [Pipe] that carries datagrams as QUIC datagrams, so that to middleboxes it looks like HTTP/3 to the server named in the handshake. QUIC's own reliability is not used for them: a lost datagram is lost, and it is the multiplex above that resends data, just as over a plain UDP pipe. QUIC still paces them with its own congestion control, and drops the oldest ones when they come faster than that allows.
+///
+/// A QUIC datagram must fit in a single packet, so datagrams larger than [Connection::max_datagram_size], typically around 1200 bytes, are instead sent each on a stream of its own, which costs a little more. Lowering the MSS of the multiplex with [crate::Multiplex::set_mss] keeps data packets under that size.
+pub struct QuicPipe {
+    conn: Connection,
+    send_large: Sender<Bytes>,
+    recv_large: Receiver<Bytes>,
+    peer_metadata: String,
+    dial_timings: Option<DialTimings>,
+    _endpoint: Option<Endpoint>,
+    _task: runtime::Task<()>,
+}
+
+impl QuicPipe {
+    /// Connects to the [QuicListener] at `addr`, naming `server_name` as the server in the handshake and checking its certificate as `verify` says. As with [crate::TlsPipe], the name need not have anything to do with `addr`. The server's [Pipe::peer_metadata] is set to `metadata`.
+    ///
+    /// Connecting again to a server connected to before with the same `verify` resumes the earlier session: the pipe is returned at once, and what is sent over it goes out along with the handshake, saving a round trip. See [QuicListener] for how the server keeps that from being replayed.
+    pub async fn connect(
+        addr: SocketAddr,
+        server_name: &str,
+        verify: TlsVerify,
+        metadata: &str,
+    ) -> Result<Self, ConnectError> {
+        let start = Instant::now();
+        let bind_addr: SocketAddr = if addr.is_ipv6() {
+            "[::]:0".parse().unwrap()
+        } else {
+            "0.0.0.0:0".parse().unwrap()
+        };
+        let endpoint = endpoint(UdpSocket::bind(bind_addr)?, None)?;
+        let dial = start.elapsed();
+        let mut client_config = ClientConfig::new(verify.client_config(&ALPN));
+        client_config.transport_config(transport_config());
+        async {
+            let connecting = endpoint
+                .connect_with(client_config, addr, server_name)
+                .map_err(|err| ConnectError::InvalidInput(err.to_string()))?;
+            let mut pipe = match connecting.into_0rtt() {
+                // resuming an earlier session, so the pipe can be used before the handshake is done
+                Ok((conn, accepted)) => {
+                    // opened before anything else can open a stream for a large datagram
+                    let mut stream = conn.open_uni().await.map_err(connection_error)?;
+                    stream
+                        .write_all(metadata.as_bytes())
+                        .await
+                        .map_err(std::io::Error::from)?;
+                    finish_metadata_0rtt(conn.clone(), stream, accepted, metadata.to_owned());
+                    Self::start(conn, String::new(), Some(endpoint.clone()))
+                }
+                Err(connecting) => {
+                    let conn = connecting.await.map_err(connection_error)?;
+                    send_metadata(&conn, metadata.as_bytes()).await?;
+                    Self::start(conn, String::new(), Some(endpoint.clone()))
+                }
+            };
+            pipe.dial_timings = Some(DialTimings {
+                dns: None,
+                dial,
+                obfs: start.elapsed() - dial,
+            });
+            Ok(pipe)
+        }
+        .timeout(HANDSHAKE_TIMEOUT)
+        .await
+        .unwrap_or(Err(ConnectError::HandshakeTimeout))
+    }
+
+    fn start(conn: Connection, peer_metadata: String, endpoint: Option<Endpoint>) -> Self {
+        let (send_large, to_send) = smol::channel::bounded::<Bytes>(QUEUE_LEN);
+        let (received, recv_large) = smol::channel::bounded(QUEUE_LEN);
+        let upload = {
+            let conn = conn.clone();
+            async move {
+                while let Ok(datagram) = to_send.recv().await {
+                    let mut stream = conn.open_uni().await?;
+                    stream.write_all(&datagram).await?;
+                    stream.finish().await?;
+                }
+                Ok(())
+            }
+        };
+        let download = {
+            let conn = conn.clone();
+            async move {
+                loop {
+                    let mut stream = conn.accept_uni().await?;
+                    let received = received.clone();
+                    // a stream stuck halfway through must not hold up the ones behind it
+                    runtime::spawn(async move {
+                        if let Ok(datagram) = stream.read_to_end(u16::MAX as usize).await {
+                            // when the application falls behind, drop datagrams as a congested link would
+                            let _ = received.try_send(datagram.into());
+                        }
+                    })
+                    .detach();
+                }
+            }
+        };
+        let addr = conn.remote_address();
+        let task = runtime::spawn(async move {
+            let result: std::io::Result<()> = upload.race(download).await;
+            if let Err(err) = result {
+                log::debug!("QUIC pipe to {addr} failed: {:?}", err);
+            }
+        });
+        Self {
+            conn,
+            send_large,
+            recv_large,
+            peer_metadata,
+            dial_timings: None,
+            _endpoint: endpoint,
+            _task: task,
+        }
+    }
+}
+
+/// Sorts out why a QUIC handshake failed. TLS alerts, sent by either side, come as crypto errors.
+fn connection_error(err: ConnectionError) -> ConnectError {
+    let code = match &err {
+        ConnectionError::VersionMismatch => return ConnectError::VersionMismatch(err.to_string()),
+        ConnectionError::TimedOut => return ConnectError::HandshakeTimeout,
+        ConnectionError::TransportError(transport) => u64::from(transport.code),
+        ConnectionError::ConnectionClosed(close) => u64::from(close.error_code),
+        _ => return ConnectError::Io(err.into()),
+    };
+    let alert = (0x100..0x200)
+        .contains(&code)
+        .then(|| AlertDescription::from((code - 0x100) as u8))
+        .and_then(|alert| alert_error(alert, err.to_string()));
+    alert.unwrap_or_else(|| ConnectError::Io(err.into()))
+}
+
+/// Sends the metadata, which goes first, on the first stream.
+async fn send_metadata(conn: &Connection, metadata: &[u8]) -> std::io::Result<()> {
+    let mut stream = conn.open_uni().await?;
+    stream.write_all(metadata).await?;
+    stream.finish().await?;
+    Ok(())
+}
+
+/// Finishes the stream carrying the metadata in the first flight of a resumed connection, without holding up the pipe. If the server turns down the early data, the metadata is sent again once the handshake is done; the datagrams sent along with it are lost, and left to the multiplex to resend.
+fn finish_metadata_0rtt(
+    conn: Connection,
+    mut stream: SendStream,
+    accepted: ZeroRttAccepted,
+    metadata: String,
+) {
+    runtime::spawn(async move {
+        let sent = async {
+            if stream.finish().await.is_err() || !accepted.await {
+                send_metadata(&conn, metadata.as_bytes()).await?;
+            }
+            std::io::Result::Ok(())
+        };
+        let err = match sent.timeout(HANDSHAKE_TIMEOUT).await {
+            Some(Ok(())) => return,
+            Some(Err(err)) => err,
+            None => ErrorKind::TimedOut.into(),
+        };
+        log::debug!("could not send metadata over QUIC: {:?}", err);
+        conn.close(0u32.into(), b"");
+    })
+    .detach();
+}
+
+impl Drop for QuicPipe {
+    fn drop(&mut self) {
+        // tell the other side at once, instead of leaving it to time out
+        self.conn.close(0u32.into(), b"");
+    }
+}
+
+#[async_trait]
+impl Pipe for QuicPipe {
+    fn send(&self, to_send: Bytes) {
+        if to_send.len() <= self.conn.max_datagram_size().unwrap_or(0) {
+            let _ = self.conn.send_datagram(to_send);
+        } else if to_send.len() <= u16::MAX as usize {
+            let _ = self.send_large.try_send(to_send);
+        }
+    }
+
+    async fn recv(&self) -> std::io::Result<Bytes> {
+        let datagram = async { Ok(self.conn.read_datagram().await?) };
+        let large = async {
+            self.recv_large
+                .recv()
+                .await
+                .map_err(|_| std::io::Error::new(ErrorKind::BrokenPipe, "QUIC connection closed"))
+        };
+        datagram.or(large).await
+    }
+
+    fn protocol(&self) -> &str {
+        "quic"
+    }
+
+    fn peer_metadata(&self) -> &str {
+        &self.peer_metadata
+    }
+
+    fn peer_addr(&self) -> String {
+        self.conn.remote_address().to_string()
+    }
+
+    fn dial_timings(&self) -> Option<DialTimings> {
+        self.dial_timings
+    }
+}
+
+/// A [PipeListener] that accepts [QuicPipe]s. As with [crate::TlsListener], which certificate clients get is up to the given TLS configuration, which must allow TLS 1.3 and should accept `h3` in ALPN, the protocol clients offer.
+///
+/// Clients reconnecting to the same listener resume their earlier session, and send their first datagrams along with the handshake instead of after it. To keep such early data from being replayed, each session can be resumed only once, which rustls only ensures with its default, stateful session storage: with a [rustls::server::ProducesTickets] set as the `ticketer`, or with session storage shared among several servers, clients instead always wait for the handshake.
+pub struct QuicListener {
+    incoming: Receiver<QuicPipe>,
+    local_addr: SocketAddr,
+    _task: runtime::Task<()>,
+}
+
+impl QuicListener {
+    /// Listens on the given address, with the given TLS configuration.
+    pub async fn bind(addr: SocketAddr, tls_config: Arc<ServerConfig>) -> std::io::Result<Self> {
+        let mut tls_config = (*tls_config).clone();
+        // QUIC either allows early data or not, with no limit of its own
+        tls_config.max_early_data_size = u32::MAX;
+        let mut server_config = quinn::ServerConfig::with_crypto(Arc::new(tls_config));
+        server_config.transport_config(transport_config());
+        let endpoint = endpoint(UdpSocket::bind(addr)?, Some(server_config))?;
+        let local_addr = endpoint.local_addr()?;
+        let (send_incoming, incoming) = smol::channel::bounded(QUEUE_LEN);
+        let task = runtime::spawn(async move {
+            while let Some(connecting) = endpoint.accept().await {
+                let peer_addr = connecting.remote_address();
+                let send_incoming = send_incoming.clone();
+                // handshakes happen on their own, so that a slow client does not hold up others
+                runtime::spawn(async move {
+                    let handshake = async {
+                        // takes in early data from resuming clients before the handshake is done
+                        let conn = match connecting.into_0rtt() {
+                            Ok((conn, _)) => conn,
+                            Err(connecting) => connecting.await?,
+                        };
+                        let metadata = conn
+                            .accept_uni()
+                            .await?
+                            .read_to_end(u16::MAX as usize)
+                            .await
+                            .map_err(|err| std::io::Error::new(ErrorKind::InvalidData, err))?;
+                        let metadata = String::from_utf8_lossy(&metadata).into_owned();
+                        std::io::Result::Ok(QuicPipe::start(conn, metadata, None))
+                    };
+                    match handshake.timeout(HANDSHAKE_TIMEOUT).await {
+                        Some(Ok(pipe)) => {
+                            let _ = send_incoming.try_send(pipe);
+                        }
+                        Some(Err(err)) => {
+                            log::debug!("QUIC handshake with {peer_addr} failed: {:?}", err)
+                        }
+                        None => log::debug!("QUIC handshake with {peer_addr} timed out"),
+                    }
+                })
+                .detach();
+            }
+        });
+        Ok(Self {
+            incoming,
+            local_addr,
+            _task: task,
+        })
+    }
+
+    /// The address this listener listens on, e.g. to learn which port was picked when binding to port 0.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+}
+
+#[async_trait]
+impl PipeListener for QuicListener {
+    async fn accept_pipe(&self) -> std::io::Result<Arc<dyn Pipe>> {
+        let pipe = self
+            .incoming
+            .recv()
+            .await
+            .map_err(|_| std::io::Error::new(ErrorKind::BrokenPipe, "QUIC listener stopped"))?;
+        Ok(Arc::new(pipe))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use smol::net::UdpSocket;
+
+    use super::*;
+
+    /// A listener with a fresh self-signed certificate, and how to connect to it.
+    async fn listener(server_name: &str) -> (QuicListener, TlsVerify) {
+        let cert = rcgen::generate_simple_self_signed(vec![server_name.into()]).unwrap();
+        let cert_der = cert.serialize_der().unwrap();
+        let mut tls_config = ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(
+                vec![rustls::Certificate(cert_der.clone())],
+                rustls::PrivateKey(cert.serialize_private_key_der()),
+            )
+            .unwrap();
+        tls_config.alpn_protocols = vec![b"h3".to_vec()];
+        let listener = QuicListener::bind("127.0.0.1:0".parse().unwrap(), Arc::new(tls_config))
+            .await
+            .unwrap();
+        let pin = ring::digest::digest(&ring::digest::SHA256, &cert_der);
+        (
+            listener,
+            TlsVerify::Pinned(pin.as_ref().try_into().unwrap()),
+        )
+    }
+
+    /// Forwards UDP to `server` with `delay` each way, returning the address to send to instead.
+    async fn delayed(server: SocketAddr, delay: Duration) -> (SocketAddr, runtime::Task<()>) {
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let addr = socket.local_addr().unwrap();
+        let task = runtime::spawn(async move {
+            let mut client = None;
+            let mut buf = [0u8; 2048];
+            while let Ok((n, from)) = socket.recv_from(&mut buf).await {
+                let to = if from == server {
+                    match client {
+                        Some(client) => client,
+                        None => continue,
+                    }
+                } else {
+                    client = Some(from);
+                    server
+                };
+                let socket = socket.clone();
+                let pkt = buf[..n].to_vec();
+                runtime::spawn(async move {
+                    runtime::Timer::after(delay).await;
+                    let _ = socket.send_to(&pkt, to).await;
+                })
+                .detach();
+            }
+        });
+        (addr, task)
+    }
+
+    #[test]
+    fn pipes_carry_small_and_large_datagrams() {
+        smol::block_on(async {
+            let (listener, verify) = listener("quic.example.com").await;
+            let client =
+                QuicPipe::connect(listener.local_addr(), "quic.example.com", verify, "hello")
+                    .await
+                    .unwrap();
+            let server = listener.accept_pipe().await.unwrap();
+            assert_eq!(server.peer_metadata(), "hello");
+            assert_eq!(server.protocol(), "quic");
+
+            // one fits in a QUIC datagram, the other goes on a stream of its own
+            let large = Bytes::from(vec![7u8; 5000]);
+            assert!(large.len() > client.conn.max_datagram_size().unwrap());
+            for datagram in [Bytes::from_static(b"ping"), large] {
+                client.send(datagram.clone());
+                assert_eq!(server.recv().await.unwrap(), datagram);
+                server.send(datagram.clone());
+                assert_eq!(client.recv().await.unwrap(), datagram);
+            }
+        })
+    }
+
+    #[test]
+    fn reconnects_send_data_in_the_first_flight() {
+        smol::block_on(async {
+            let (listener, verify) = listener("resume.example.com").await;
+            let delay = Duration::from_millis(100);
+            let (addr, _proxy) = delayed(listener.local_addr(), delay).await;
+            // how long it takes from dialing until the server gets the first datagram
+            let first_datagram = || async {
+                let start = Instant::now();
+                let client = QuicPipe::connect(addr, "resume.example.com", verify.clone(), "")
+                    .await
+                    .unwrap();
+                client.send(Bytes::from_static(b"ping"));
+                let server = listener.accept_pipe().await.unwrap();
+                assert_eq!(&server.recv().await.unwrap()[..], b"ping");
+                let elapsed = start.elapsed();
+                // the session ticket comes after the handshake
+                server.send(Bytes::from_static(b"pong"));
+                client.recv().await.unwrap();
+                elapsed
+            };
+
+            // a full handshake takes a round trip before anything is sent
+            let fresh = first_datagram().await;
+            assert!(fresh >= delay * 3, "{fresh:?}");
+            let resumed = first_datagram().await;
+            assert!(resumed < delay * 2, "{resumed:?}");
+        })
+    }
+}
@@ -0,0 +1,335 @@
+use std::{
+    io::ErrorKind,
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures_rustls::{TlsAcceptor, TlsConnector};
+use rustls::{ServerConfig, ServerName};
+use smol::{
+    channel::{Receiver, Sender},
+    future::FutureExt,
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufWriter},
+    net::{TcpListener, TcpStream},
+};
+
+use super::tls_verify::{handshake_error, TlsVerify};
+use crate::{
+    utilities::runtime::{self, TimeoutExt},
+    ConnectError, DialTimings, Pipe, PipeListener,
+};
+
+/// How many datagrams may wait to be written to the connection, or to be received, before further ones are dropped.
+const QUEUE_LEN: usize = 1000;
+/// How long a connection may take to become a pipe, including the TLS handshake and learning the peer's metadata.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Protocols clients offer in ALPN, the same as browsers do.
+const ALPN: [&[u8]; 2] = [b"h2", b"http/1.1"];
+
+/// A [Pipe] that carries datagrams over a TLS connection, so that to middleboxes it looks like any other HTTPS connection to the server named in the SNI. Unlike [crate::WsPipe], nothing is spoken inside TLS except the datagrams themselves, each prefixed with its length.
+///
+/// Since this runs over TCP, a lost packet holds up everything behind it, so this is a fallback for when datagram-based pipes are blocked rather than a replacement for them. Datagrams longer than 65535 bytes are dropped.
+pub struct TlsPipe {
+    send_queue: Sender<Bytes>,
+    recv_queue: Receiver<Bytes>,
+    peer_metadata: String,
+    peer_addr: String,
+    dial_timings: Option<DialTimings>,
+    _task: runtime::Task<()>,
+}
+
+impl TlsPipe {
+    /// Connects to the [TlsListener] at `addr`, naming `sni` as the server in the handshake and checking its certificate as `verify` says. The SNI need not have anything to do with `addr`, which lets the connection pass as one to a different, innocuous site. The server's [Pipe::peer_metadata] is set to `metadata`.
+    pub async fn connect(
+        addr: SocketAddr,
+        sni: &str,
+        verify: TlsVerify,
+        metadata: &str,
+    ) -> Result<Self, ConnectError> {
+        if metadata.len() > u16::MAX as usize {
+            return Err(ConnectError::InvalidInput("metadata too long".into()));
+        }
+        let server_name = ServerName::try_from(sni)
+            .map_err(|err| ConnectError::InvalidInput(format!("bad SNI {sni}: {err}")))?;
+        async {
+            let start = Instant::now();
+            let tcp = TcpStream::connect(addr)
+                .await
+                .map_err(ConnectError::Unreachable)?;
+            tcp.set_nodelay(true)?;
+            let dial = start.elapsed();
+            let mut tls = TlsConnector::from(verify.client_config(&ALPN))
+                .connect(server_name, tcp)
+                .await
+                .map_err(handshake_error)?;
+            // the metadata goes first, as a datagram of its own
+            write_datagram(&mut tls, metadata.as_bytes()).await?;
+            tls.flush().await?;
+            let mut pipe = Self::start(tls, String::new(), addr.to_string());
+            pipe.dial_timings = Some(DialTimings {
+                dns: None,
+                dial,
+                obfs: start.elapsed() - dial,
+            });
+            Ok(pipe)
+        }
+        .timeout(HANDSHAKE_TIMEOUT)
+        .await
+        .unwrap_or(Err(ConnectError::HandshakeTimeout))
+    }
+
+    fn start<S: AsyncRead + AsyncWrite + Unpin + Send + 'static>(
+        conn: S,
+        peer_metadata: String,
+        peer_addr: String,
+    ) -> Self {
+        let (send_queue, to_send) = smol::channel::bounded::<Bytes>(QUEUE_LEN);
+        let (received, recv_queue) = smol::channel::bounded(QUEUE_LEN);
+        let (mut reader, writer) = smol::io::split(conn);
+        let upload = async move {
+            let mut writer = BufWriter::new(writer);
+            while let Ok(datagram) = to_send.recv().await {
+                write_datagram(&mut writer, &datagram).await?;
+                // write out whatever queued up at once
+                if to_send.is_empty() {
+                    writer.flush().await?;
+                }
+            }
+            Ok(())
+        };
+        let download = async move {
+            loop {
+                let datagram = read_datagram(&mut reader).await?;
+                // when the application falls behind, drop datagrams as a congested link would
+                let _ = received.try_send(datagram);
+            }
+        };
+        let addr = peer_addr.clone();
+        // the queues close when either direction fails, which fails recv
+        let task = runtime::spawn(async move {
+            let result: std::io::Result<()> = upload.race(download).await;
+            if let Err(err) = result {
+                log::debug!("TLS pipe to {addr} failed: {:?}", err);
+            }
+        });
+        Self {
+            send_queue,
+            recv_queue,
+            peer_metadata,
+            peer_addr,
+            dial_timings: None,
+            _task: task,
+        }
+    }
+}
+
+#[async_trait]
+impl Pipe for TlsPipe {
+    fn send(&self, to_send: Bytes) {
+        if to_send.len() <= u16::MAX as usize {
+            let _ = self.send_queue.try_send(to_send);
+        }
+    }
+
+    async fn recv(&self) -> std::io::Result<Bytes> {
+        self.recv_queue
+            .recv()
+            .await
+            .map_err(|_| std::io::Error::new(ErrorKind::BrokenPipe, "TLS connection closed"))
+    }
+
+    fn protocol(&self) -> &str {
+        "tls"
+    }
+
+    fn peer_metadata(&self) -> &str {
+        &self.peer_metadata
+    }
+
+    fn peer_addr(&self) -> String {
+        self.peer_addr.clone()
+    }
+
+    fn dial_timings(&self) -> Option<DialTimings> {
+        self.dial_timings
+    }
+}
+
+/// A [PipeListener] that accepts [TlsPipe]s. Clients may name any server in the SNI; which certificate they get is up to the given TLS configuration, which should also accept one of the protocols clients offer in ALPN, `h2` or `http/1.1`, for the handshake to look like a web server's.
+pub struct TlsListener {
+    incoming: Receiver<TlsPipe>,
+    local_addr: SocketAddr,
+    _task: runtime::Task<()>,
+}
+
+impl TlsListener {
+    /// Listens on the given address, with the given TLS configuration.
+    pub async fn bind(addr: SocketAddr, tls_config: Arc<ServerConfig>) -> std::io::Result<Self> {
+        let listener = TcpListener::bind(addr).await?;
+        let local_addr = listener.local_addr()?;
+        let acceptor = TlsAcceptor::from(tls_config);
+        let (send_incoming, incoming) = smol::channel::bounded(QUEUE_LEN);
+        let task = runtime::spawn(async move {
+            loop {
+                let (tcp, peer_addr) = match listener.accept().await {
+                    Ok(accepted) => accepted,
+                    Err(err) => {
+                        log::warn!("TLS listener failed to accept: {:?}", err);
+                        continue;
+                    }
+                };
+                let acceptor = acceptor.clone();
+                let send_incoming = send_incoming.clone();
+                // handshakes happen on their own, so that a slow client does not hold up others
+                runtime::spawn(async move {
+                    let handshake = async {
+                        tcp.set_nodelay(true)?;
+                        let mut tls = acceptor.accept(tcp).await?;
+                        let metadata = read_datagram(&mut tls).await?;
+                        let metadata = String::from_utf8_lossy(&metadata).into_owned();
+                        std::io::Result::Ok(TlsPipe::start(tls, metadata, peer_addr.to_string()))
+                    };
+                    match handshake.timeout(HANDSHAKE_TIMEOUT).await {
+                        Some(Ok(pipe)) => {
+                            let _ = send_incoming.try_send(pipe);
+                        }
+                        Some(Err(err)) => {
+                            log::debug!("TLS handshake with {peer_addr} failed: {:?}", err)
+                        }
+                        None => log::debug!("TLS handshake with {peer_addr} timed out"),
+                    }
+                })
+                .detach();
+            }
+        });
+        Ok(Self {
+            incoming,
+            local_addr,
+            _task: task,
+        })
+    }
+
+    /// The address this listener listens on, e.g. to learn which port was picked when binding to port 0.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+}
+
+#[async_trait]
+impl PipeListener for TlsListener {
+    async fn accept_pipe(&self) -> std::io::Result<Arc<dyn Pipe>> {
+        let pipe = self
+            .incoming
+            .recv()
+            .await
+            .map_err(|_| std::io::Error::new(ErrorKind::BrokenPipe, "TLS listener stopped"))?;
+        Ok(Arc::new(pipe))
+    }
+}
+
+async fn write_datagram(
+    writer: &mut (impl AsyncWrite + Unpin),
+    datagram: &[u8],
+) -> std::io::Result<()> {
+    writer
+        .write_all(&(datagram.len() as u16).to_be_bytes())
+        .await?;
+    writer.write_all(datagram).await
+}
+
+async fn read_datagram(reader: &mut (impl AsyncRead + Unpin)) -> std::io::Result<Bytes> {
+    let mut len = [0u8; 2];
+    reader.read_exact(&mut len).await?;
+    let mut datagram = vec![0u8; u16::from_be_bytes(len) as usize];
+    reader.read_exact(&mut datagram).await?;
+    Ok(datagram.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use parking_lot::Mutex;
+    use rustls::{
+        server::{ClientHello, ResolvesServerCert},
+        sign::CertifiedKey,
+        Certificate, PrivateKey,
+    };
+
+    use super::*;
+
+    /// Serves one self-signed certificate whatever the SNI, remembering the last SNI it was asked for.
+    struct RecordSni {
+        key: Arc<CertifiedKey>,
+        sni: Mutex<Option<String>>,
+    }
+
+    impl ResolvesServerCert for RecordSni {
+        fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+            *self.sni.lock() = client_hello.server_name().map(|sni| sni.to_owned());
+            Some(self.key.clone())
+        }
+    }
+
+    #[test]
+    fn sni_and_verification() {
+        smol::block_on(async {
+            let cert = rcgen::generate_simple_self_signed(vec!["example.com".into()]).unwrap();
+            let cert_der = cert.serialize_der().unwrap();
+            let key =
+                rustls::sign::any_supported_type(&PrivateKey(cert.serialize_private_key_der()))
+                    .unwrap();
+            let resolver = Arc::new(RecordSni {
+                key: Arc::new(CertifiedKey::new(vec![Certificate(cert_der.clone())], key)),
+                sni: Mutex::new(None),
+            });
+            let mut server_config = ServerConfig::builder()
+                .with_safe_defaults()
+                .with_no_client_auth()
+                .with_cert_resolver(resolver.clone());
+            server_config.alpn_protocols = vec![b"http/1.1".to_vec()];
+            let listener =
+                TlsListener::bind("127.0.0.1:0".parse().unwrap(), Arc::new(server_config))
+                    .await
+                    .unwrap();
+            let addr = listener.local_addr();
+
+            // the SNI names whatever site we like, and the pin accepts the certificate whatever it is for
+            let pin = ring::digest::digest(&ring::digest::SHA256, &cert_der);
+            let client = TlsPipe::connect(
+                addr,
+                "innocuous.example.org",
+                TlsVerify::Pinned(pin.as_ref().try_into().unwrap()),
+                "hello",
+            )
+            .await
+            .unwrap();
+            let server = listener.accept_pipe().await.unwrap();
+            assert_eq!(
+                resolver.sni.lock().as_deref(),
+                Some("innocuous.example.org")
+            );
+            assert_eq!(server.peer_metadata(), "hello");
+            client.send(Bytes::from_static(b"ping"));
+            assert_eq!(&server.recv().await.unwrap()[..], b"ping");
+            server.send(Bytes::from_static(b"pong"));
+            assert_eq!(&client.recv().await.unwrap()[..], b"pong");
+
+            assert!(
+                TlsPipe::connect(addr, "example.com", TlsVerify::Insecure, "")
+                    .await
+                    .is_ok()
+            );
+            // a self-signed certificate is no good to a browser
+            assert!(matches!(
+                TlsPipe::connect(addr, "example.com", TlsVerify::WebRoots, "").await,
+                Err(ConnectError::Authentication(_))
+            ));
+            assert!(matches!(
+                TlsPipe::connect(addr, "not a name", TlsVerify::Insecure, "").await,
+                Err(ConnectError::InvalidInput(_))
+            ));
+        })
+    }
+}
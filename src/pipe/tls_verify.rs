@@ -0,0 +1,156 @@
+//! How TLS-based pipes check the certificates of the servers they connect to, and tell why a handshake failed, shared by [crate::TlsPipe], [crate::QuicPipe] and, for `wss://`, [crate::WsPipe].
+
+use std::{collections::HashMap, sync::Arc, time::SystemTime};
+
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use rustls::{
+    client::{ServerCertVerified, ServerCertVerifier},
+    AlertDescription, Certificate, CertificateError, ClientConfig, RootCertStore, ServerName,
+};
+
+use crate::ConnectError;
+
+/// The usual web roots, which browsers trust.
+pub(crate) fn web_roots() -> RootCertStore {
+    let mut roots = RootCertStore::empty();
+    roots.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|anchor| {
+        rustls::OwnedTrustAnchor::from_subject_spki_name_constraints(
+            anchor.subject,
+            anchor.spki,
+            anchor.name_constraints,
+        )
+    }));
+    roots
+}
+
+/// How a [crate::TlsPipe] or [crate::QuicPipe] checks the certificate of the server it connects to.
+#[derive(Clone)]
+pub enum TlsVerify {
+    /// Checks the certificate against the usual web roots, for the SNI name, as a browser would. The server needs a real certificate for that name.
+    WebRoots,
+    /// Accepts only the certificate with this SHA-256 hash, whatever name it is for, e.g. a self-signed one.
+    Pinned([u8; 32]),
+    /// Accepts any certificate. The multiplex over the pipe still authenticates the server, if it was given the server's public key, but a man in the middle can then see that the traffic is not HTTPS.
+    Insecure,
+    /// Uses the given TLS configuration as is.
+    Custom(Arc<ClientConfig>),
+}
+
+/// Which client configuration a [TlsVerify] stands for, other than a custom one.
+#[derive(Clone, PartialEq, Eq, Hash)]
+enum ConfigKey {
+    WebRoots,
+    Pinned([u8; 32]),
+    Insecure,
+}
+
+/// Client configurations made so far, by what they verify and which protocols they offer. They are reused so that they remember the session tickets servers hand out, which lets reconnects to the same server resume the session, and over QUIC send data in the very first flight.
+static CLIENT_CONFIGS: Lazy<Mutex<ClientConfigs>> = Lazy::new(Default::default);
+
+type ClientConfigs = HashMap<(ConfigKey, Vec<Vec<u8>>), Arc<ClientConfig>>;
+
+impl TlsVerify {
+    /// The client configuration this stands for, offering the given protocols in ALPN unless it is [TlsVerify::Custom].
+    ///
+    /// Every connection with the same settings gets the same configuration, so that it can resume sessions that earlier ones started. A [TlsVerify::Custom] configuration resumes sessions if it is set up to, and reused across connections.
+    pub(crate) fn client_config(self, alpn: &[&[u8]]) -> Arc<ClientConfig> {
+        let key = match self {
+            TlsVerify::WebRoots => ConfigKey::WebRoots,
+            TlsVerify::Pinned(hash) => ConfigKey::Pinned(hash),
+            TlsVerify::Insecure => ConfigKey::Insecure,
+            TlsVerify::Custom(config) => return config,
+        };
+        let alpn: Vec<Vec<u8>> = alpn.iter().map(|protocol| protocol.to_vec()).collect();
+        CLIENT_CONFIGS
+            .lock()
+            .entry((key.clone(), alpn.clone()))
+            .or_insert_with(|| key.client_config(alpn))
+            .clone()
+    }
+}
+
+impl ConfigKey {
+    fn client_config(self, alpn: Vec<Vec<u8>>) -> Arc<ClientConfig> {
+        let builder = ClientConfig::builder().with_safe_defaults();
+        let mut config = match self {
+            ConfigKey::WebRoots => builder
+                .with_root_certificates(web_roots())
+                .with_no_client_auth(),
+            ConfigKey::Pinned(hash) => builder
+                .with_custom_certificate_verifier(Arc::new(AnyCertVerifier { pin: Some(hash) }))
+                .with_no_client_auth(),
+            ConfigKey::Insecure => builder
+                .with_custom_certificate_verifier(Arc::new(AnyCertVerifier { pin: None }))
+                .with_no_client_auth(),
+        };
+        config.alpn_protocols = alpn;
+        // only QUIC sends early data; over TCP, connections just resume
+        config.enable_early_data = true;
+        Arc::new(config)
+    }
+}
+
+/// Accepts any certificate, or only the one with the pinned hash. Handshake signatures are still checked against the certificate.
+struct AnyCertVerifier {
+    pin: Option<[u8; 32]>,
+}
+
+impl ServerCertVerifier for AnyCertVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        match self.pin {
+            Some(pin)
+                if ring::digest::digest(&ring::digest::SHA256, &end_entity.0).as_ref() != pin =>
+            {
+                Err(rustls::Error::InvalidCertificate(
+                    CertificateError::ApplicationVerificationFailure,
+                ))
+            }
+            _ => Ok(ServerCertVerified::assertion()),
+        }
+    }
+}
+
+/// Sorts out why a TLS handshake failed, from the error it failed with.
+pub(crate) fn handshake_error(err: std::io::Error) -> ConnectError {
+    let sorted = match err
+        .get_ref()
+        .and_then(|inner| inner.downcast_ref::<rustls::Error>())
+    {
+        Some(rustls::Error::InvalidCertificate(_)) => {
+            Some(ConnectError::Authentication(err.to_string()))
+        }
+        Some(rustls::Error::PeerIncompatible(_)) => {
+            Some(ConnectError::VersionMismatch(err.to_string()))
+        }
+        Some(rustls::Error::AlertReceived(alert)) => alert_error(*alert, err.to_string()),
+        _ => None,
+    };
+    sorted.unwrap_or(ConnectError::Io(err))
+}
+
+/// What a TLS alert, from either side, says about why a handshake failed, if it was over certificates or versions.
+pub(crate) fn alert_error(alert: AlertDescription, reason: String) -> Option<ConnectError> {
+    match alert {
+        AlertDescription::BadCertificate
+        | AlertDescription::UnsupportedCertificate
+        | AlertDescription::CertificateRevoked
+        | AlertDescription::CertificateExpired
+        | AlertDescription::CertificateUnknown
+        | AlertDescription::UnknownCA
+        | AlertDescription::AccessDenied
+        | AlertDescription::DecryptError => Some(ConnectError::Authentication(reason)),
+        AlertDescription::ProtocolVersion | AlertDescription::NoApplicationProtocol => {
+            Some(ConnectError::VersionMismatch(reason))
+        }
+        _ => None,
+    }
+}
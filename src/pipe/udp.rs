@@ -0,0 +1,266 @@
+use std::{
+    io::ErrorKind,
+    net::{SocketAddr, UdpSocket},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use ahash::AHashMap;
+use async_trait::async_trait;
+use bytes::{BufMut, Bytes, BytesMut};
+use smol::{
+    channel::{Receiver, Sender},
+    future::FutureExt,
+    Async,
+};
+
+use crate::{
+    utilities::runtime::{self, TimeoutExt},
+    ConnectError, DialTimings, Pipe, PipeListener,
+};
+
+/// How many received datagrams may wait for each pipe, and how many pipes may wait to be accepted, before further ones are dropped.
+const QUEUE_LEN: usize = 1000;
+/// How long a client waits for the listener to answer its hello, resending it in between.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+const HELLO_INTERVAL: Duration = Duration::from_millis(500);
+
+/// The first byte of every datagram: data for the multiplex, a client telling the listener its metadata, or the listener answering that.
+const DATA: u8 = 0;
+const HELLO: u8 = 1;
+const HELLO_ACK: u8 = 2;
+
+/// A [Pipe] that carries datagrams as plain UDP datagrams, with nothing to disguise them: the cheapest pipe there is, for networks that let UDP through and do not block sosistab2. Each datagram gains one byte, telling datagrams for the multiplex apart from the hello that carries the client's metadata.
+pub struct UdpPipe {
+    socket: Arc<Async<UdpSocket>>,
+    recv: PipeRecv,
+    peer_addr: SocketAddr,
+    peer_metadata: String,
+    dial_timings: Option<DialTimings>,
+}
+
+enum PipeRecv {
+    // a client's socket is its own
+    Socket,
+    // a listener's socket is shared among its pipes, and hands each one its datagrams
+    Queue(Receiver<Bytes>),
+}
+
+impl UdpPipe {
+    /// Connects to the [UdpListener] at `addr`, waiting for it to answer. The listener's [Pipe::peer_metadata] is set to `metadata`.
+    pub async fn connect(addr: SocketAddr, metadata: &str) -> Result<Self, ConnectError> {
+        let start = Instant::now();
+        let bind_addr: SocketAddr = if addr.is_ipv6() {
+            "[::]:0".parse().unwrap()
+        } else {
+            "0.0.0.0:0".parse().unwrap()
+        };
+        let socket = Async::<UdpSocket>::bind(bind_addr)?;
+        socket.get_ref().connect(addr)?;
+        let dial = start.elapsed();
+        let mut hello = BytesMut::with_capacity(1 + metadata.len());
+        hello.put_u8(HELLO);
+        hello.put_slice(metadata.as_bytes());
+        // UDP may lose the hello, or the answer, so the hello is sent until answered
+        let resend = async {
+            loop {
+                socket.send(&hello).await?;
+                runtime::Timer::after(HELLO_INTERVAL).await;
+            }
+        };
+        let answer = async {
+            let mut buf = [0u8; 1];
+            loop {
+                let n = socket.recv(&mut buf).await?;
+                if n == 1 && buf[0] == HELLO_ACK {
+                    return Ok(());
+                }
+            }
+        };
+        // an error here is most likely the ICMP message of a port nobody listens on
+        resend
+            .or(answer)
+            .timeout(HANDSHAKE_TIMEOUT)
+            .await
+            .ok_or(ConnectError::HandshakeTimeout)?
+            .map_err(ConnectError::Unreachable)?;
+        Ok(Self {
+            socket: Arc::new(socket),
+            recv: PipeRecv::Socket,
+            peer_addr: addr,
+            peer_metadata: String::new(),
+            dial_timings: Some(DialTimings {
+                dns: None,
+                dial,
+                obfs: start.elapsed() - dial,
+            }),
+        })
+    }
+}
+
+#[async_trait]
+impl Pipe for UdpPipe {
+    fn send(&self, to_send: Bytes) {
+        let mut datagram = BytesMut::with_capacity(1 + to_send.len());
+        datagram.put_u8(DATA);
+        datagram.put_slice(&to_send);
+        // the socket is nonblocking, so this never waits
+        let result = match self.recv {
+            PipeRecv::Socket => self.socket.get_ref().send(&datagram),
+            PipeRecv::Queue(_) => self.socket.get_ref().send_to(&datagram, self.peer_addr),
+        };
+        // a full socket buffer drops the datagram, as a congested link would
+        if let Err(err) = result {
+            log::trace!(
+                "could not send UDP datagram to {}: {:?}",
+                self.peer_addr,
+                err
+            );
+        }
+    }
+
+    async fn recv(&self) -> std::io::Result<Bytes> {
+        match &self.recv {
+            PipeRecv::Socket => {
+                let mut buf = vec![0u8; 65536];
+                loop {
+                    let n = self.socket.recv(&mut buf).await?;
+                    if n > 0 && buf[0] == DATA {
+                        return Ok(Bytes::copy_from_slice(&buf[1..n]));
+                    }
+                }
+            }
+            PipeRecv::Queue(queue) => queue
+                .recv()
+                .await
+                .map_err(|_| std::io::Error::new(ErrorKind::BrokenPipe, "UDP listener stopped")),
+        }
+    }
+
+    fn protocol(&self) -> &str {
+        "udp"
+    }
+
+    fn peer_metadata(&self) -> &str {
+        &self.peer_metadata
+    }
+
+    fn peer_addr(&self) -> String {
+        self.peer_addr.to_string()
+    }
+
+    fn dial_timings(&self) -> Option<DialTimings> {
+        self.dial_timings
+    }
+}
+
+/// A [PipeListener] that accepts [UdpPipe]s, all sharing one UDP socket. A client becomes a pipe once its hello arrives, and stays one for as long as the pipe is not dropped; datagrams from addresses that did not say hello are ignored.
+pub struct UdpListener {
+    incoming: Receiver<UdpPipe>,
+    local_addr: SocketAddr,
+    _task: runtime::Task<()>,
+}
+
+impl UdpListener {
+    /// Listens on the given address.
+    pub async fn bind(addr: SocketAddr) -> std::io::Result<Self> {
+        let socket = Arc::new(Async::<UdpSocket>::bind(addr)?);
+        let local_addr = socket.get_ref().local_addr()?;
+        let (send_incoming, incoming) = smol::channel::bounded(QUEUE_LEN);
+        let task = runtime::spawn(async move {
+            if let Err(err) = demultiplex(socket, send_incoming).await {
+                log::debug!("UDP listener on {local_addr} failed: {:?}", err);
+            }
+        });
+        Ok(Self {
+            incoming,
+            local_addr,
+            _task: task,
+        })
+    }
+
+    /// The address this listener listens on, e.g. to learn which port was picked when binding to port 0.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+}
+
+/// Hands the datagrams arriving at a listener's socket to the pipes of the addresses they come from, making new pipes for clients saying hello.
+async fn demultiplex(
+    socket: Arc<Async<UdpSocket>>,
+    incoming: Sender<UdpPipe>,
+) -> std::io::Result<()> {
+    let mut pipes: AHashMap<SocketAddr, Sender<Bytes>> = AHashMap::new();
+    let mut buf = vec![0u8; 65536];
+    loop {
+        let (n, from) = socket.recv_from(&mut buf).await?;
+        match buf[..n].first() {
+            Some(&DATA) => {
+                if let Some(pipe) = pipes.get(&from) {
+                    if pipe.is_closed() {
+                        pipes.remove(&from);
+                    } else {
+                        // when the application falls behind, drop datagrams as a congested link would
+                        let _ = pipe.try_send(Bytes::copy_from_slice(&buf[1..n]));
+                    }
+                }
+            }
+            Some(&HELLO) => {
+                if pipes.get(&from).is_none_or(|pipe| pipe.is_closed()) {
+                    let (send, recv) = smol::channel::bounded(QUEUE_LEN);
+                    let pipe = UdpPipe {
+                        socket: socket.clone(),
+                        recv: PipeRecv::Queue(recv),
+                        peer_addr: from,
+                        peer_metadata: String::from_utf8_lossy(&buf[1..n]).into_owned(),
+                        dial_timings: None,
+                    };
+                    if incoming.try_send(pipe).is_err() {
+                        continue;
+                    }
+                    pipes.insert(from, send);
+                }
+                // answered every time, in case an earlier answer was lost
+                let _ = socket.send_to(&[HELLO_ACK], from).await;
+            }
+            _ => {}
+        }
+    }
+}
+
+#[async_trait]
+impl PipeListener for UdpListener {
+    async fn accept_pipe(&self) -> std::io::Result<Arc<dyn Pipe>> {
+        let pipe = self
+            .incoming
+            .recv()
+            .await
+            .map_err(|_| std::io::Error::new(ErrorKind::BrokenPipe, "UDP listener stopped"))?;
+        Ok(Arc::new(pipe))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pipes_carry_datagrams_both_ways() {
+        smol::block_on(async {
+            let listener = UdpListener::bind("127.0.0.1:0".parse().unwrap())
+                .await
+                .unwrap();
+            let client = UdpPipe::connect(listener.local_addr(), "hello")
+                .await
+                .unwrap();
+            let server = listener.accept_pipe().await.unwrap();
+            assert_eq!(server.peer_metadata(), "hello");
+            assert_eq!(server.protocol(), "udp");
+
+            client.send(Bytes::from_static(b"ping"));
+            assert_eq!(&server.recv().await.unwrap()[..], b"ping");
+            server.send(Bytes::from_static(b"pong"));
+            assert_eq!(&client.recv().await.unwrap()[..], b"pong");
+        })
+    }
+}
@@ -0,0 +1,439 @@
+use std::{
+    io::ErrorKind,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use async_tungstenite::{
+    tungstenite::{client::IntoClientRequest, Message},
+    WebSocketStream,
+};
+use bytes::Bytes;
+use futures_rustls::{
+    rustls::{ClientConfig, ServerConfig, ServerName},
+    TlsAcceptor, TlsConnector,
+};
+use futures_util::{SinkExt, StreamExt};
+use once_cell::sync::Lazy;
+use smol::{
+    channel::{Receiver, Sender},
+    future::FutureExt,
+    io::{AsyncRead, AsyncWrite},
+    net::{TcpListener, TcpStream},
+};
+
+use super::tls_verify::{handshake_error, web_roots};
+use crate::{
+    utilities::runtime::{self, TimeoutExt},
+    ConnectError, DialTimings, Pipe, PipeListener,
+};
+
+/// How many datagrams may wait to be written to the connection, or to be received, before further ones are dropped.
+const QUEUE_LEN: usize = 1000;
+/// How long a connection may take to become a pipe, including the TLS and WebSocket handshakes and learning the peer's metadata.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+static WEB_ROOTS: Lazy<Arc<ClientConfig>> = Lazy::new(|| {
+    Arc::new(
+        ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(web_roots())
+            .with_no_client_auth(),
+    )
+});
+
+/// A [Pipe] that carries each datagram as a binary WebSocket message, over `ws://` or `wss://`, so that a [crate::Multiplex] can get through networks that only let HTTP(S) out.
+///
+/// Since WebSocket runs over TCP, a lost packet holds up everything behind it, so this is a fallback for when datagram-based pipes are blocked rather than a replacement for them. Like any pipe, it drops datagrams instead of blocking when the connection cannot keep up.
+pub struct WsPipe {
+    send_queue: Sender<Bytes>,
+    recv_queue: Receiver<Bytes>,
+    protocol: &'static str,
+    peer_metadata: String,
+    peer_addr: String,
+    dial_timings: Option<DialTimings>,
+    _task: runtime::Task<()>,
+}
+
+impl WsPipe {
+    /// Connects to the WebSocket server at `url`, which starts with `ws://` or `wss://`. The server's [Pipe::peer_metadata] is set to `metadata`. Over `wss://`, the server's certificate is checked against the usual web roots.
+    pub async fn connect(url: &str, metadata: &str) -> Result<Self, ConnectError> {
+        Self::connect_with_tls(url, metadata, WEB_ROOTS.clone()).await
+    }
+
+    /// Connects like [WsPipe::connect], but over `wss://`, uses the given TLS configuration, e.g. to trust a self-signed certificate.
+    pub async fn connect_with_tls(
+        url: &str,
+        metadata: &str,
+        tls_config: Arc<ClientConfig>,
+    ) -> Result<Self, ConnectError> {
+        let request = url
+            .into_client_request()
+            .map_err(|err| ConnectError::InvalidInput(err.to_string()))?;
+        let secure = match request.uri().scheme_str() {
+            Some("ws") => false,
+            Some("wss") => true,
+            _ => {
+                return Err(ConnectError::InvalidInput(format!(
+                    "not a WebSocket URL: {url}"
+                )))
+            }
+        };
+        let host = request
+            .uri()
+            .host()
+            .ok_or_else(|| ConnectError::InvalidInput(format!("no host in {url}")))?
+            // IPv6 addresses come in brackets
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .to_owned();
+        async {
+            let port = request
+                .uri()
+                .port_u16()
+                .unwrap_or(if secure { 443 } else { 80 });
+            let start = Instant::now();
+            let addrs = smol::net::resolve((host.as_str(), port))
+                .await
+                .map_err(|source| ConnectError::Dns {
+                    host: host.clone(),
+                    source,
+                })?;
+            // an address needs no lookup, so there is nothing to report
+            let dns = host.parse::<IpAddr>().is_err().then(|| start.elapsed());
+            let dialing = Instant::now();
+            let tcp = TcpStream::connect(addrs.as_slice())
+                .await
+                .map_err(ConnectError::Unreachable)?;
+            let dial = dialing.elapsed();
+            let peer_addr = tcp.peer_addr()?.to_string();
+            let mut pipe = if secure {
+                let server_name = ServerName::try_from(host.as_str())
+                    .map_err(|err| ConnectError::InvalidInput(err.to_string()))?;
+                let tls = TlsConnector::from(tls_config)
+                    .connect(server_name, tcp)
+                    .await
+                    .map_err(handshake_error)?;
+                let (ws, _) = async_tungstenite::client_async(request, tls)
+                    .await
+                    .map_err(upgrade_error)?;
+                Self::start_client(ws, metadata, "wss", peer_addr).await?
+            } else {
+                let (ws, _) = async_tungstenite::client_async(request, tcp)
+                    .await
+                    .map_err(upgrade_error)?;
+                Self::start_client(ws, metadata, "ws", peer_addr).await?
+            };
+            pipe.dial_timings = Some(DialTimings {
+                dns,
+                dial,
+                obfs: dialing.elapsed() - dial,
+            });
+            Ok(pipe)
+        }
+        .timeout(HANDSHAKE_TIMEOUT)
+        .await
+        .unwrap_or(Err(ConnectError::HandshakeTimeout))
+    }
+
+    /// Tells the server our metadata, which is the first message on every connection.
+    async fn start_client<S: AsyncRead + AsyncWrite + Unpin + Send + 'static>(
+        mut ws: WebSocketStream<S>,
+        metadata: &str,
+        protocol: &'static str,
+        peer_addr: String,
+    ) -> std::io::Result<Self> {
+        ws.send(Message::Text(metadata.to_owned()))
+            .await
+            .map_err(to_ioerror)?;
+        Ok(Self::start(ws, protocol, String::new(), peer_addr))
+    }
+
+    /// Learns the client's metadata from its first message.
+    async fn start_server<S: AsyncRead + AsyncWrite + Unpin + Send + 'static>(
+        mut ws: WebSocketStream<S>,
+        protocol: &'static str,
+        peer_addr: String,
+    ) -> std::io::Result<Self> {
+        match ws.next().await {
+            Some(Ok(Message::Text(metadata))) => Ok(Self::start(ws, protocol, metadata, peer_addr)),
+            Some(Err(err)) => Err(to_ioerror(err)),
+            _ => Err(std::io::Error::new(
+                ErrorKind::InvalidData,
+                "expected metadata as the first message",
+            )),
+        }
+    }
+
+    fn start<S: AsyncRead + AsyncWrite + Unpin + Send + 'static>(
+        ws: WebSocketStream<S>,
+        protocol: &'static str,
+        peer_metadata: String,
+        peer_addr: String,
+    ) -> Self {
+        let (send_queue, to_send) = smol::channel::bounded::<Bytes>(QUEUE_LEN);
+        let (received, recv_queue) = smol::channel::bounded(QUEUE_LEN);
+        let (mut sink, mut stream) = ws.split();
+        let upload = async move {
+            while let Ok(datagram) = to_send.recv().await {
+                sink.feed(Message::Binary(datagram.to_vec())).await?;
+                // write out whatever queued up at once
+                if to_send.is_empty() {
+                    sink.flush().await?;
+                }
+            }
+            Ok(())
+        };
+        let download = async move {
+            while let Some(msg) = stream.next().await {
+                match msg? {
+                    Message::Binary(datagram) => {
+                        // when the application falls behind, drop datagrams as a congested link would
+                        let _ = received.try_send(Bytes::from(datagram));
+                    }
+                    Message::Close(_) => break,
+                    _ => {}
+                }
+            }
+            Ok(())
+        };
+        let addr = peer_addr.clone();
+        // the queues close when either direction fails, which fails recv
+        let task = runtime::spawn(async move {
+            let result: async_tungstenite::tungstenite::Result<()> = upload.race(download).await;
+            if let Err(err) = result {
+                log::debug!("WebSocket pipe to {addr} failed: {:?}", err);
+            }
+        });
+        Self {
+            send_queue,
+            recv_queue,
+            protocol,
+            peer_metadata,
+            peer_addr,
+            dial_timings: None,
+            _task: task,
+        }
+    }
+}
+
+#[async_trait]
+impl Pipe for WsPipe {
+    fn send(&self, to_send: Bytes) {
+        let _ = self.send_queue.try_send(to_send);
+    }
+
+    async fn recv(&self) -> std::io::Result<Bytes> {
+        self.recv_queue
+            .recv()
+            .await
+            .map_err(|_| std::io::Error::new(ErrorKind::BrokenPipe, "WebSocket connection closed"))
+    }
+
+    fn protocol(&self) -> &str {
+        self.protocol
+    }
+
+    fn peer_metadata(&self) -> &str {
+        &self.peer_metadata
+    }
+
+    fn peer_addr(&self) -> String {
+        self.peer_addr.clone()
+    }
+
+    fn dial_timings(&self) -> Option<DialTimings> {
+        self.dial_timings
+    }
+}
+
+/// A [PipeListener] that accepts [WsPipe]s, on any path. Without TLS, it can also sit behind a reverse proxy or CDN that terminates TLS and forwards WebSocket connections.
+pub struct WsListener {
+    incoming: Receiver<WsPipe>,
+    local_addr: SocketAddr,
+    _task: runtime::Task<()>,
+}
+
+impl WsListener {
+    /// Listens for `ws://` connections.
+    pub async fn bind(addr: SocketAddr) -> std::io::Result<Self> {
+        Self::bind_inner(addr, None).await
+    }
+
+    /// Listens for `wss://` connections, with the given TLS configuration.
+    pub async fn bind_tls(
+        addr: SocketAddr,
+        tls_config: Arc<ServerConfig>,
+    ) -> std::io::Result<Self> {
+        Self::bind_inner(addr, Some(TlsAcceptor::from(tls_config))).await
+    }
+
+    async fn bind_inner(addr: SocketAddr, tls: Option<TlsAcceptor>) -> std::io::Result<Self> {
+        let listener = TcpListener::bind(addr).await?;
+        let local_addr = listener.local_addr()?;
+        let (send_incoming, incoming) = smol::channel::bounded(QUEUE_LEN);
+        let task = runtime::spawn(async move {
+            loop {
+                let (tcp, peer_addr) = match listener.accept().await {
+                    Ok(accepted) => accepted,
+                    Err(err) => {
+                        log::warn!("WebSocket listener failed to accept: {:?}", err);
+                        continue;
+                    }
+                };
+                let tls = tls.clone();
+                let send_incoming = send_incoming.clone();
+                // handshakes happen on their own, so that a slow client does not hold up others
+                runtime::spawn(async move {
+                    let handshake = async {
+                        let peer_addr = peer_addr.to_string();
+                        match tls {
+                            Some(tls) => {
+                                let tls = tls.accept(tcp).await?;
+                                let ws = async_tungstenite::accept_async(tls)
+                                    .await
+                                    .map_err(to_ioerror)?;
+                                WsPipe::start_server(ws, "wss", peer_addr).await
+                            }
+                            None => {
+                                let ws = async_tungstenite::accept_async(tcp)
+                                    .await
+                                    .map_err(to_ioerror)?;
+                                WsPipe::start_server(ws, "ws", peer_addr).await
+                            }
+                        }
+                    };
+                    match handshake.timeout(HANDSHAKE_TIMEOUT).await {
+                        Some(Ok(pipe)) => {
+                            let _ = send_incoming.try_send(pipe);
+                        }
+                        Some(Err(err)) => {
+                            log::debug!("WebSocket handshake with {peer_addr} failed: {:?}", err)
+                        }
+                        None => log::debug!("WebSocket handshake with {peer_addr} timed out"),
+                    }
+                })
+                .detach();
+            }
+        });
+        Ok(Self {
+            incoming,
+            local_addr,
+            _task: task,
+        })
+    }
+
+    /// The address this listener listens on, e.g. to learn which port was picked when binding to port 0.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+}
+
+#[async_trait]
+impl PipeListener for WsListener {
+    async fn accept_pipe(&self) -> std::io::Result<Arc<dyn Pipe>> {
+        let pipe = self.incoming.recv().await.map_err(|_| {
+            std::io::Error::new(ErrorKind::BrokenPipe, "WebSocket listener stopped")
+        })?;
+        Ok(Arc::new(pipe))
+    }
+}
+
+/// Sorts out why the WebSocket upgrade failed: a server answering with anything but the upgrade speaks some other protocol.
+fn upgrade_error(err: async_tungstenite::tungstenite::Error) -> ConnectError {
+    match err {
+        async_tungstenite::tungstenite::Error::Http(response) => ConnectError::VersionMismatch(
+            format!("WebSocket upgrade refused with {}", response.status()),
+        ),
+        async_tungstenite::tungstenite::Error::Io(err) => ConnectError::Io(err),
+        err => ConnectError::Io(to_ioerror(err)),
+    }
+}
+
+fn to_ioerror<T: Into<Box<dyn std::error::Error + Send + Sync>>>(val: T) -> std::io::Error {
+    std::io::Error::new(ErrorKind::ConnectionReset, val)
+}
+
+#[cfg(test)]
+mod tests {
+    use smol::io::AsyncWriteExt;
+
+    use super::*;
+
+    async fn round_trip(client: &WsPipe, listener: &WsListener) {
+        let server = listener.accept_pipe().await.unwrap();
+        assert_eq!(server.peer_metadata(), "hello");
+        assert_eq!(server.protocol(), client.protocol());
+        client.send(Bytes::from_static(b"ping"));
+        assert_eq!(&server.recv().await.unwrap()[..], b"ping");
+        server.send(Bytes::from_static(b"pong"));
+        assert_eq!(&client.recv().await.unwrap()[..], b"pong");
+    }
+
+    #[test]
+    fn pipes_carry_datagrams_both_ways() {
+        smol::block_on(async {
+            let listener = WsListener::bind("127.0.0.1:0".parse().unwrap())
+                .await
+                .unwrap();
+            let url = format!("ws://{}/any/path", listener.local_addr());
+            let client = WsPipe::connect(&url, "hello").await.unwrap();
+            assert_eq!(client.protocol(), "ws");
+            round_trip(&client, &listener).await;
+
+            let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+            let cert_der = rustls::Certificate(cert.serialize_der().unwrap());
+            let server_config = ServerConfig::builder()
+                .with_safe_defaults()
+                .with_no_client_auth()
+                .with_single_cert(
+                    vec![cert_der.clone()],
+                    rustls::PrivateKey(cert.serialize_private_key_der()),
+                )
+                .unwrap();
+            let listener =
+                WsListener::bind_tls("127.0.0.1:0".parse().unwrap(), Arc::new(server_config))
+                    .await
+                    .unwrap();
+            let mut roots = rustls::RootCertStore::empty();
+            roots.add(&cert_der).unwrap();
+            let client_config = ClientConfig::builder()
+                .with_safe_defaults()
+                .with_root_certificates(roots)
+                .with_no_client_auth();
+            let url = format!("wss://localhost:{}", listener.local_addr().port());
+            let client = WsPipe::connect_with_tls(&url, "hello", Arc::new(client_config))
+                .await
+                .unwrap();
+            assert_eq!(client.protocol(), "wss");
+            round_trip(&client, &listener).await;
+        })
+    }
+
+    #[test]
+    fn connect_errors() {
+        smol::block_on(async {
+            assert!(matches!(
+                WsPipe::connect("https://example.com", "").await,
+                Err(ConnectError::InvalidInput(_))
+            ));
+
+            // a web server that does not do WebSocket
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let url = format!("ws://{}", listener.local_addr().unwrap());
+            let _server = runtime::spawn(async move {
+                let (mut tcp, _) = listener.accept().await.unwrap();
+                let _ = tcp
+                    .write_all(b"HTTP/1.1 404 Not Found\r\ncontent-length: 0\r\n\r\n")
+                    .await;
+                smol::future::pending::<()>().await;
+            });
+            let Err(err) = WsPipe::connect(&url, "").await else {
+                panic!("connected to a server without WebSocket");
+            };
+            assert!(matches!(err, ConnectError::VersionMismatch(_)), "{err:?}");
+        })
+    }
+}
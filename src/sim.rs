@@ -0,0 +1,134 @@
+//! An in-process network simulator, made out of [Pipe]s that carry datagrams over a simulated link.
+//!
+//! This is mainly useful for testing and for reproducing performance problems without a real network.
+
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use parking_lot::Mutex;
+use smol::channel::{Receiver, Sender};
+
+use crate::{utilities::runtime, Pipe};
+
+/// Characteristics of a simulated one-way link.
+#[derive(Clone, Copy, Debug)]
+pub struct SimLink {
+    /// Propagation delay.
+    pub delay: Duration,
+    /// Probability that any given datagram is dropped.
+    pub loss: f64,
+    /// Bottleneck bandwidth in bytes per second, or `None` for unlimited.
+    pub bandwidth: Option<f64>,
+    /// Maximum number of bytes queued at the bottleneck before datagrams are dropped.
+    pub queue_limit: usize,
+    /// Datagrams larger than this are dropped, as over a path with this MTU that does not fragment them. `None` for unlimited.
+    pub mtu: Option<usize>,
+}
+
+impl Default for SimLink {
+    fn default() -> Self {
+        Self {
+            delay: Duration::from_millis(50),
+            loss: 0.0,
+            bandwidth: None,
+            queue_limit: 1_000_000,
+            mtu: None,
+        }
+    }
+}
+
+/// One end of a simulated link. Created in pairs by [sim_pipe_pair].
+pub struct SimPipe {
+    link: SimLink,
+    peer_addr: String,
+    // when the bottleneck finishes transmitting everything queued so far
+    busy_until: Mutex<Instant>,
+    send_delayed: Sender<(Instant, Bytes)>,
+    recv_incoming: Receiver<Bytes>,
+    _task: runtime::Task<()>,
+}
+
+/// Creates two connected [SimPipe]s, with both directions of the link having the given characteristics.
+pub fn sim_pipe_pair(link: SimLink) -> (SimPipe, SimPipe) {
+    static PAIR_COUNTER: AtomicU64 = AtomicU64::new(0);
+    let pair_id = PAIR_COUNTER.fetch_add(1, Ordering::Relaxed);
+    let (a_send, a_recv) = smol::channel::unbounded();
+    let (b_send, b_recv) = smol::channel::unbounded();
+    (
+        SimPipe::new(link, format!("sim-{pair_id}-b"), a_send, b_recv),
+        SimPipe::new(link, format!("sim-{pair_id}-a"), b_send, a_recv),
+    )
+}
+
+impl SimPipe {
+    fn new(
+        link: SimLink,
+        peer_addr: String,
+        send_outgoing: Sender<Bytes>,
+        recv_incoming: Receiver<Bytes>,
+    ) -> Self {
+        let (send_delayed, recv_delayed) = smol::channel::unbounded::<(Instant, Bytes)>();
+        let _task = runtime::spawn(async move {
+            while let Ok((deliver_at, pkt)) = recv_delayed.recv().await {
+                runtime::Timer::at(deliver_at).await;
+                if send_outgoing.send(pkt).await.is_err() {
+                    return;
+                }
+            }
+        });
+        Self {
+            link,
+            peer_addr,
+            busy_until: Mutex::new(runtime::now()),
+            send_delayed,
+            recv_incoming,
+            _task,
+        }
+    }
+}
+
+#[async_trait]
+impl Pipe for SimPipe {
+    fn send(&self, to_send: Bytes) {
+        let too_big = self.link.mtu.is_some_and(|mtu| to_send.len() > mtu);
+        if too_big || fastrand::f64() < self.link.loss {
+            return;
+        }
+        let now = runtime::now();
+        let departure = if let Some(bandwidth) = self.link.bandwidth {
+            let mut busy_until = self.busy_until.lock();
+            let start = (*busy_until).max(now);
+            let queued_bytes = (start - now).as_secs_f64() * bandwidth;
+            if queued_bytes > self.link.queue_limit as f64 {
+                return;
+            }
+            *busy_until = start + Duration::from_secs_f64(to_send.len() as f64 / bandwidth);
+            *busy_until
+        } else {
+            now
+        };
+        let _ = self.send_delayed.try_send((departure + self.link.delay, to_send));
+    }
+
+    async fn recv(&self) -> std::io::Result<Bytes> {
+        self.recv_incoming.recv().await.map_err(|_| {
+            std::io::Error::new(std::io::ErrorKind::BrokenPipe, "simulated link closed")
+        })
+    }
+
+    fn protocol(&self) -> &str {
+        "sim"
+    }
+
+    fn peer_metadata(&self) -> &str {
+        ""
+    }
+
+    fn peer_addr(&self) -> String {
+        self.peer_addr.clone()
+    }
+}
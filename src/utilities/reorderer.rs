@@ -0,0 +1,152 @@
+use ahash::AHashMap;
+
+/// How far ahead of the next expected sequence number items are accepted, unless configured otherwise.
+const DEFAULT_WINDOW: u64 = 20000;
+
+/// Puts items that arrive out of order, tagged with consecutive sequence numbers starting from 0, back in order.
+///
+/// Items are inserted as they arrive and taken out in order once everything before them has arrived. This is what reliable [crate::Stream]s use on the receiving side; on top of unreliable datagrams, where missing items may never arrive, [Reorderer::set_max_gap] and [Reorderer::skip_to] give up on them instead of holding everything after them back forever.
+///
+/// ```
+/// use sosistab2::Reorderer;
+///
+/// let mut reorderer = Reorderer::default();
+/// reorderer.set_max_gap(Some(2));
+/// reorderer.insert(1, "b");
+/// assert!(reorderer.take().is_empty());
+/// reorderer.insert(0, "a");
+/// assert_eq!(reorderer.take(), vec![(0, "a"), (1, "b")]);
+/// // 2 never arrives, and 5 is too far ahead to keep waiting for it
+/// reorderer.insert(3, "d");
+/// reorderer.insert(5, "f");
+/// assert_eq!(reorderer.take(), vec![(3, "d")]);
+/// assert_eq!(reorderer.skipped(), 1);
+/// ```
+#[derive(Clone)]
+pub struct Reorderer<T> {
+    pkts: AHashMap<u64, T>,
+    min: u64,
+    window: u64,
+    capacity: Option<usize>,
+    max_gap: Option<u64>,
+    // everything before this is given up on once missing
+    skip_to: u64,
+    skipped: u64,
+}
+
+impl<T> Default for Reorderer<T> {
+    fn default() -> Self {
+        Reorderer {
+            pkts: AHashMap::default(),
+            min: 0,
+            window: DEFAULT_WINDOW,
+            capacity: None,
+            max_gap: None,
+            skip_to: 0,
+            skipped: 0,
+        }
+    }
+}
+
+impl<T> Reorderer<T> {
+    /// Creates a reorderer that holds at most `capacity` items waiting for earlier ones.
+    pub fn with_capacity(capacity: usize) -> Self {
+        let mut reorderer = Self::default();
+        reorderer.set_capacity(Some(capacity));
+        reorderer
+    }
+
+    /// Sets how many items may wait for earlier ones, or removes the limit with `None`. Once the limit is reached, further items are refused. Unlimited by default, although the window still bounds memory use.
+    pub fn set_capacity(&mut self, capacity: Option<usize>) {
+        self.capacity = capacity;
+    }
+
+    /// Sets how far ahead of the next expected sequence number items are accepted. Items further ahead are refused. Defaults to 20000.
+    pub fn set_window(&mut self, window: u64) {
+        self.window = window;
+    }
+
+    /// Sets how far the newest item may get ahead of a missing one before the missing one is given up on, or with `None`, waits for missing items forever, which is the default.
+    pub fn set_max_gap(&mut self, max_gap: Option<u64>) {
+        self.max_gap = max_gap;
+    }
+
+    /// Inserts an item into the reorderer. Returns true iff the item is accepted or has been accepted in the past.
+    pub fn insert(&mut self, seq: u64, item: T) -> bool {
+        log::trace!("reorder seq={}, min={}", seq, self.min);
+        if seq >= self.min && seq <= self.min + self.window {
+            if self.capacity.is_some_and(|capacity| {
+                self.pkts.len() >= capacity && !self.pkts.contains_key(&seq)
+            }) {
+                log::debug!("reorderer full, refusing seq={}", seq);
+                return false;
+            }
+            if self.pkts.insert(seq, item).is_some() {
+                log::debug!("spurious in pending of {} received", seq);
+            }
+            if let Some(max_gap) = self.max_gap {
+                self.skip_to = self.skip_to.max(seq.saturating_sub(max_gap));
+            }
+            true
+        } else {
+            log::debug!("spurious in past of (seq={}, min={})", seq, self.min);
+            // if less than min, we still accept
+            seq < self.min
+        }
+    }
+
+    /// Gives up on every missing item before `seq`. Items already inserted are still taken out in order.
+    pub fn skip_to(&mut self, seq: u64) {
+        self.skip_to = self.skip_to.max(seq);
+    }
+
+    /// Returns whether the item was already inserted, whether or not it has been taken out since. Items that were given up on count as inserted.
+    pub fn is_duplicate(&self, seq: u64) -> bool {
+        seq < self.min || self.pkts.contains_key(&seq)
+    }
+
+    /// Returns how many items are waiting for an earlier one.
+    pub fn len(&self) -> usize {
+        self.pkts.len()
+    }
+
+    /// Returns whether no items are waiting.
+    pub fn is_empty(&self) -> bool {
+        self.pkts.is_empty()
+    }
+
+    /// Returns the sequence number of the next item to be taken out.
+    pub fn next_seq(&self) -> u64 {
+        self.min
+    }
+
+    /// Returns how many missing items were given up on so far.
+    pub fn skipped(&self) -> u64 {
+        self.skipped
+    }
+
+    /// Takes out, in order, every item whose predecessors have all been taken out or given up on.
+    pub fn take(&mut self) -> Vec<(u64, T)> {
+        let mut output = Vec::with_capacity(self.pkts.len());
+        loop {
+            if let Some(item) = self.pkts.remove(&self.min) {
+                output.push((self.min, item));
+                self.min += 1;
+            } else if self.min < self.skip_to {
+                // jump over the missing items, up to the next one that is here
+                let next = self
+                    .pkts
+                    .keys()
+                    .copied()
+                    .filter(|seq| *seq < self.skip_to)
+                    .min()
+                    .unwrap_or(self.skip_to);
+                self.skipped += next - self.min;
+                self.min = next;
+            } else {
+                break;
+            }
+        }
+        output
+    }
+}
@@ -0,0 +1,255 @@
+//! The little that sosistab2 needs from an async runtime, spawning tasks, timers and timeouts, behind one shim, so that it does not have to embed an executor of its own.
+//!
+//! Without the `tokio` feature, tasks run on smolscale and timers on async-io, as they always have. With it, anything spawned or timed from within a Tokio runtime is spawned onto that runtime and timed by its time driver, which must be enabled, so that a Tokio application can drive multiplexes and pipes without smolscale's threads. Tasks spawned that way spawn theirs onto the same runtime, while anything used outside of one still falls back to smolscale.
+//!
+//! Channels need no shim, since async-channel's work on any runtime. TCP sockets stay registered with async-io's reactor, which runs on a thread of its own and is not an executor; QUIC's UDP sockets and timers move to Tokio along with its tasks.
+
+use std::{
+    convert::Infallible,
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use pin_project::pin_project;
+
+/// A spawned task, which is cancelled when dropped unless detached, like a [smol::Task].
+pub(crate) struct Task<T>(Option<TaskInner<T>>);
+
+enum TaskInner<T> {
+    Smol(smol::Task<T>),
+    #[cfg(feature = "tokio")]
+    Tokio(tokio::task::JoinHandle<T>),
+}
+
+/// A task that never returns, and only stops when dropped.
+pub(crate) type Immortal = Task<Infallible>;
+
+/// Spawns a task onto the current Tokio runtime if there is one and the `tokio` feature is on, and onto smolscale otherwise.
+pub(crate) fn spawn<T: Send + 'static>(
+    future: impl Future<Output = T> + Send + 'static,
+) -> Task<T> {
+    #[cfg(feature = "tokio")]
+    if let Ok(handle) = tokio::runtime::Handle::try_current() {
+        return Task(Some(TaskInner::Tokio(handle.spawn(future))));
+    }
+    Task(Some(TaskInner::Smol(smolscale::spawn(future))))
+}
+
+impl<T> Task<T> {
+    pub fn is_finished(&self) -> bool {
+        match &self.0 {
+            Some(TaskInner::Smol(task)) => task.is_finished(),
+            #[cfg(feature = "tokio")]
+            Some(TaskInner::Tokio(handle)) => handle.is_finished(),
+            None => false,
+        }
+    }
+
+    /// Lets the task run on after this is dropped.
+    pub fn detach(mut self) {
+        match self.0.take() {
+            Some(TaskInner::Smol(task)) => task.detach(),
+            #[cfg(feature = "tokio")]
+            Some(TaskInner::Tokio(_)) => {}
+            None => {}
+        }
+    }
+}
+
+impl<T> Drop for Task<T> {
+    fn drop(&mut self) {
+        #[cfg(feature = "tokio")]
+        if let Some(TaskInner::Tokio(handle)) = &self.0 {
+            handle.abort();
+        }
+    }
+}
+
+impl<T> Future for Task<T> {
+    type Output = T;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<T> {
+        match self.0.as_mut().expect("polled a detached task") {
+            TaskInner::Smol(task) => Pin::new(task).poll(cx),
+            #[cfg(feature = "tokio")]
+            TaskInner::Tokio(handle) => match Pin::new(handle).poll(cx) {
+                Poll::Ready(Ok(val)) => Poll::Ready(val),
+                Poll::Ready(Err(err)) if err.is_panic() => {
+                    std::panic::resume_unwind(err.into_panic())
+                }
+                Poll::Ready(Err(err)) => panic!("task stopped by its runtime: {err}"),
+                Poll::Pending => Poll::Pending,
+            },
+        }
+    }
+}
+
+/// The current time. With the `soak` feature, this is the clock of the Tokio runtime it is called from, which the soak test pauses so that simulated hours pass as fast as there is work to do; timers use the same clock.
+pub(crate) fn now() -> Instant {
+    #[cfg(feature = "soak")]
+    {
+        tokio::time::Instant::now().into_std()
+    }
+    #[cfg(not(feature = "soak"))]
+    {
+        Instant::now()
+    }
+}
+
+/// How long ago an instant was, by [now].
+pub(crate) fn elapsed(since: Instant) -> Duration {
+    now().saturating_duration_since(since)
+}
+
+/// A timer that fires at an instant, and can be set to fire at another, like a [smol::Timer]. Resolves to the instant it was set to.
+pub(crate) struct Timer(TimerInner);
+
+enum TimerInner {
+    Smol(smol::Timer),
+    #[cfg(feature = "tokio")]
+    Tokio(Pin<Box<tokio::time::Sleep>>),
+}
+
+impl Timer {
+    pub fn at(deadline: Instant) -> Self {
+        #[cfg(feature = "tokio")]
+        if tokio::runtime::Handle::try_current().is_ok() {
+            return Self(TimerInner::Tokio(Box::pin(tokio::time::sleep_until(
+                deadline.into(),
+            ))));
+        }
+        Self(TimerInner::Smol(smol::Timer::at(deadline)))
+    }
+
+    pub fn after(duration: Duration) -> Self {
+        Self::at(now() + duration)
+    }
+
+    pub fn set_at(&mut self, deadline: Instant) {
+        match &mut self.0 {
+            TimerInner::Smol(timer) => timer.set_at(deadline),
+            #[cfg(feature = "tokio")]
+            TimerInner::Tokio(sleep) => sleep.as_mut().reset(deadline.into()),
+        }
+    }
+}
+
+impl Future for Timer {
+    type Output = Instant;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Instant> {
+        match &mut self.0 {
+            TimerInner::Smol(timer) => Pin::new(timer).poll(cx),
+            #[cfg(feature = "tokio")]
+            TimerInner::Tokio(sleep) => {
+                sleep.as_mut().poll(cx).map(|_| sleep.deadline().into_std())
+            }
+        }
+    }
+}
+
+/// Puts a time limit on futures, using the timers of [Timer].
+pub(crate) trait TimeoutExt: Future + Sized {
+    /// Resolves to `None` unless the future resolves within the given duration.
+    fn timeout(self, duration: Duration) -> Timeout<Self> {
+        Timeout {
+            fut: self,
+            timer: Timer::after(duration),
+        }
+    }
+}
+
+impl<F: Future> TimeoutExt for F {}
+
+/// Future returned by [TimeoutExt::timeout].
+#[pin_project]
+pub(crate) struct Timeout<F> {
+    #[pin]
+    fut: F,
+    timer: Timer,
+}
+
+impl<F: Future> Future for Timeout<F> {
+    type Output = Option<F::Output>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+        if let Poll::Ready(val) = this.fut.poll(cx) {
+            return Poll::Ready(Some(val));
+        }
+        Pin::new(this.timer).poll(cx).map(|_| None)
+    }
+}
+
+#[cfg(all(test, feature = "tokio"))]
+mod tests {
+    use std::{pin::Pin, time::Duration};
+
+    use smol::prelude::*;
+
+    use super::{spawn, TaskInner};
+    use crate::{
+        sim::{sim_pipe_pair, SimLink},
+        Multiplex, MuxSecret,
+    };
+
+    #[test]
+    fn driven_by_tokio() {
+        // a single thread, so everything has to run on it
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            assert!(matches!(spawn(async {}).0, Some(TaskInner::Tokio(_))));
+
+            let server_sk = MuxSecret::generate();
+            let server = Multiplex::new(server_sk.clone(), None);
+            let client = Multiplex::new(MuxSecret::generate(), Some(server_sk.to_public()));
+            let (client_pipe, server_pipe) = sim_pipe_pair(SimLink {
+                delay: Duration::from_millis(20),
+                ..Default::default()
+            });
+            client.add_pipe(client_pipe);
+            server.add_pipe(server_pipe);
+
+            let mut stream = client.open_conn("hello").await.unwrap();
+            stream.write_all(b"hello world").await.unwrap();
+            let mut accepted = server.accept_conn().await.unwrap();
+            let mut buf = [0u8; 11];
+            accepted.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"hello world");
+
+            // through Tokio's traits as well
+            let written = std::future::poll_fn(|cx| {
+                tokio::io::AsyncWrite::poll_write(Pin::new(&mut accepted), cx, b"hi")
+            })
+            .await
+            .unwrap();
+            assert_eq!(written, 2);
+            std::future::poll_fn(|cx| {
+                tokio::io::AsyncWrite::poll_shutdown(Pin::new(&mut accepted), cx)
+            })
+            .await
+            .unwrap();
+            let mut received = vec![];
+            loop {
+                let mut buf = [0u8; 1];
+                let mut buf = tokio::io::ReadBuf::new(&mut buf);
+                std::future::poll_fn(|cx| {
+                    tokio::io::AsyncRead::poll_read(Pin::new(&mut stream), cx, &mut buf)
+                })
+                .await
+                .unwrap();
+                if buf.filled().is_empty() {
+                    break;
+                }
+                received.extend_from_slice(buf.filled());
+            }
+            assert_eq!(received, b"hi");
+            assert!(client.ping().await.unwrap() >= Duration::from_millis(40));
+        });
+    }
+}
@@ -0,0 +1,263 @@
+use std::{
+    collections::VecDeque,
+    hash::Hash,
+    time::{Duration, Instant},
+};
+
+use ahash::AHashMap;
+
+use super::runtime;
+
+/// The number of slots, each covering one granule; timers further out than the wheel spans share slots with nearer ones.
+const SLOTS: u64 = 1024;
+const GRANULARITY: Duration = Duration::from_millis(1);
+
+/// Timers, each for a key such as a seqno, kept in a hashed timer wheel so that adding, removing and finding the earliest timer take constant time in the common case, where timers are spread over less than the span of the wheel and mostly added in order.
+///
+/// Each slot is kept sorted, so the timers of the earliest turn of the wheel come first in it, and timers added in order go at the back.
+pub struct TimerWheel<K> {
+    epoch: Instant,
+    // allocated on first use, since a multiplex may hold many streams that never send
+    slots: Vec<VecDeque<(Instant, K)>>,
+    // no timer is in an earlier granule than this one, and when there are timers, one is in this granule
+    cursor: u64,
+    len: usize,
+}
+
+impl<K: Ord + Copy> Default for TimerWheel<K> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K: Ord + Copy> TimerWheel<K> {
+    pub fn new() -> Self {
+        Self {
+            epoch: runtime::now(),
+            slots: vec![],
+            cursor: 0,
+            len: 0,
+        }
+    }
+
+    fn granule(&self, time: Instant) -> u64 {
+        (time.saturating_duration_since(self.epoch).as_nanos() / GRANULARITY.as_nanos()) as u64
+    }
+
+    fn slot(&self, granule: u64) -> &VecDeque<(Instant, K)> {
+        &self.slots[(granule % SLOTS) as usize]
+    }
+
+    pub fn insert(&mut self, time: Instant, key: K) {
+        if self.slots.is_empty() {
+            self.slots.resize_with(SLOTS as usize, VecDeque::new);
+        }
+        let granule = self.granule(time);
+        if self.len == 0 || granule < self.cursor {
+            self.cursor = granule;
+        }
+        let slot = &mut self.slots[(granule % SLOTS) as usize];
+        let position = slot.partition_point(|timer| *timer < (time, key));
+        slot.insert(position, (time, key));
+        self.len += 1;
+    }
+
+    /// Removes a timer, returning whether it was there.
+    pub fn remove(&mut self, time: Instant, key: K) -> bool {
+        if self.len == 0 {
+            return false;
+        }
+        let granule = self.granule(time);
+        let slot = &mut self.slots[(granule % SLOTS) as usize];
+        let Ok(position) = slot.binary_search(&(time, key)) else {
+            return false;
+        };
+        slot.remove(position);
+        self.len -= 1;
+        if granule == self.cursor {
+            self.advance_cursor();
+        }
+        true
+    }
+
+    /// Moves the cursor up to the earliest timer, after the last one in its granule is removed.
+    fn advance_cursor(&mut self) {
+        if self.len == 0 {
+            return;
+        }
+        for _ in 0..SLOTS {
+            let cursor = self.cursor;
+            if self
+                .slot(cursor)
+                .front()
+                .is_some_and(|(time, _)| self.granule(*time) == cursor)
+            {
+                return;
+            }
+            self.cursor += 1;
+        }
+        // every timer is more than a whole turn of the wheel away
+        self.cursor = self
+            .slots
+            .iter()
+            .flatten()
+            .map(|(time, _)| self.granule(*time))
+            .min()
+            .expect("timers left");
+    }
+
+    /// The earliest timer, and the key it is for. Ties go to the lowest key.
+    pub fn first(&self) -> Option<(Instant, K)> {
+        if self.len == 0 {
+            return None;
+        }
+        self.slot(self.cursor).front().copied()
+    }
+
+    /// The timers that expire no later than `now`, in no particular order.
+    pub fn due(&self, now: Instant) -> impl Iterator<Item = (Instant, K)> + '_ {
+        let last = self.granule(now);
+        // once a whole turn is due, every slot has to be looked at; before that, only the slots from the cursor on, whose later-turn timers are not due yet
+        let granules = if self.len == 0 || last < self.cursor {
+            0..0
+        } else if last - self.cursor >= SLOTS {
+            0..SLOTS
+        } else {
+            self.cursor..last + 1
+        };
+        granules
+            .flat_map(move |granule| {
+                self.slot(granule)
+                    .iter()
+                    .take_while(move |(time, _)| *time <= now)
+            })
+            .copied()
+    }
+
+    /// Whether the given timer is set.
+    #[cfg(all(test, feature = "soak"))]
+    pub fn contains(&self, time: Instant, key: K) -> bool {
+        self.len > 0
+            && self
+                .slot(self.granule(time))
+                .binary_search(&(time, key))
+                .is_ok()
+    }
+
+    /// Checks that the count, the cursor and the slots agree with each other.
+    #[cfg(all(test, feature = "soak"))]
+    pub fn check_invariants(&self) -> anyhow::Result<usize>
+    where
+        K: std::fmt::Display,
+    {
+        let mut len = 0;
+        let mut at_cursor = false;
+        for (index, slot) in self.slots.iter().enumerate() {
+            anyhow::ensure!(
+                slot.iter().zip(slot.iter().skip(1)).all(|(a, b)| a < b),
+                "slot {index} is out of order"
+            );
+            for (time, key) in slot {
+                let granule = self.granule(*time);
+                anyhow::ensure!(
+                    granule % SLOTS == index as u64,
+                    "timer for {key} in the wrong slot"
+                );
+                anyhow::ensure!(
+                    granule >= self.cursor,
+                    "timer for {key} is before the cursor"
+                );
+                at_cursor |= granule == self.cursor;
+            }
+            len += slot.len();
+        }
+        anyhow::ensure!(len == self.len, "{len} timers, counted {}", self.len);
+        anyhow::ensure!(len == 0 || at_cursor, "no timer at the cursor");
+        Ok(len)
+    }
+}
+
+/// A [TimerWheel] with at most one timer per key, which can be set, moved and removed by key alone, such as when each stream of a multiplex is next due to tick.
+pub struct KeyedTimers<K> {
+    wheel: TimerWheel<K>,
+    times: AHashMap<K, Instant>,
+}
+
+impl<K: Ord + Copy + Hash> Default for KeyedTimers<K> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K: Ord + Copy + Hash> KeyedTimers<K> {
+    pub fn new() -> Self {
+        Self {
+            wheel: TimerWheel::new(),
+            times: AHashMap::new(),
+        }
+    }
+
+    /// Sets the timer of a key, moving it if it was already set.
+    pub fn set(&mut self, key: K, time: Instant) {
+        if let Some(old) = self.times.insert(key, time) {
+            if old == time {
+                return;
+            }
+            self.wheel.remove(old, key);
+        }
+        self.wheel.insert(time, key);
+    }
+
+    /// Removes the timer of a key, returning when it was set for.
+    pub fn remove(&mut self, key: K) -> Option<Instant> {
+        let time = self.times.remove(&key)?;
+        self.wheel.remove(time, key);
+        Some(time)
+    }
+
+    /// The earliest timer, and the key it is for.
+    pub fn first(&self) -> Option<(Instant, K)> {
+        self.wheel.first()
+    }
+
+    /// The keys with a timer set, in no particular order.
+    #[cfg(all(test, feature = "soak"))]
+    pub fn keys(&self) -> impl Iterator<Item = &K> + '_ {
+        self.times.keys()
+    }
+
+    pub fn clear(&mut self) {
+        self.wheel = TimerWheel::new();
+        self.times.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::KeyedTimers;
+
+    #[test]
+    fn keyed_timers() {
+        let now = Instant::now();
+        let mut timers = KeyedTimers::new();
+        timers.set(1u16, now + Duration::from_millis(50));
+        timers.set(2, now + Duration::from_millis(20));
+        // beyond a whole turn of the wheel
+        timers.set(3, now + Duration::from_secs(5));
+        assert_eq!(timers.first(), Some((now + Duration::from_millis(20), 2)));
+
+        // moving a timer replaces it
+        timers.set(2, now + Duration::from_secs(10));
+        assert_eq!(timers.first(), Some((now + Duration::from_millis(50), 1)));
+        assert_eq!(timers.remove(1), Some(now + Duration::from_millis(50)));
+        assert_eq!(timers.remove(1), None);
+        assert_eq!(timers.first(), Some((now + Duration::from_secs(5), 3)));
+        timers.remove(3);
+        assert_eq!(timers.first(), Some((now + Duration::from_secs(10), 2)));
+
+        timers.clear();
+        assert_eq!(timers.first(), None);
+    }
+}